@@ -0,0 +1,180 @@
+//! SCTE-35 ad marker mapping
+//!
+//! Some CF publishers carry SCTE-35 `splice_info_section`s in a data track, either raw
+//! or behind a hang timestamp. The data track itself is forwarded byte-for-byte (see
+//! `ForwardOptions::passthrough`); optionally we also re-emit each marker on a
+//! dedicated JSON metadata track, so downstream ad insertion doesn't need its own
+//! SCTE-35 parser.
+//!
+//! Markers carried in object extension headers can't be preserved: the moq-lite model
+//! doesn't expose extensions.
+
+use base64::Engine;
+use bytes::Bytes;
+use moq_lite::{BroadcastConsumer, Track};
+use serde_json::json;
+
+use crate::inject::Injector;
+use crate::media::MediaFrame;
+
+/// The table id every splice_info_section starts with
+const TABLE_ID: u8 = 0xfc;
+
+/// Where markers are read from and re-emitted to
+#[derive(Clone, Debug)]
+pub struct AdMarkerOptions {
+    /// The upstream data track carrying SCTE-35
+    pub source: String,
+    /// The relay-side metadata track the JSON markers are written to
+    pub output: String,
+}
+
+/// Re-emit markers from `source` as JSON until the broadcast closes
+pub async fn map_markers(stream_id: &str, upstream: BroadcastConsumer, injector: Injector, options: AdMarkerOptions) {
+    let mut source = upstream.subscribe_track(&Track::new(&options.source));
+    let Some(mut output) = injector.track(&options.output) else {
+        return;
+    };
+
+    tracing::info!(stream_id, source = %options.source, output = %options.output, "mapping ad markers");
+
+    loop {
+        let mut group = tokio::select! {
+            res = source.next_group() => match res {
+                Ok(Some(group)) => group,
+                Ok(None) => break,
+                Err(err) => {
+                    tracing::debug!(%err, stream_id, "ad marker track failed");
+                    break;
+                }
+            },
+            _ = upstream.closed() => break,
+        };
+
+        while let Ok(Some(frame)) = group.read_frame().await {
+            match parse_marker(&frame) {
+                Some(marker) => output.write_frame(marker.to_string()),
+                None => tracing::debug!(stream_id, size = frame.len(), "ignoring non SCTE-35 frame"),
+            }
+        }
+    }
+}
+
+/// Describe one data track frame as JSON, or None if it isn't a splice_info_section
+fn parse_marker(frame: &Bytes) -> Option<serde_json::Value> {
+    // Raw sections start with the table id, which never parses as a sane timestamp prefix
+    let (timestamp, section) = match frame.first() {
+        Some(&TABLE_ID) => (None, frame.clone()),
+        _ => {
+            let frame = MediaFrame::decode(frame)?;
+            (Some(frame.timestamp), frame.payload)
+        }
+    };
+
+    let mut marker = parse_section(&section)?;
+    marker["timestamp"] = json!(timestamp);
+    marker["scte35"] = json!(base64::engine::general_purpose::STANDARD.encode(&section));
+    Some(marker)
+}
+
+/// Pull the interesting fields out of a splice_info_section
+fn parse_section(section: &[u8]) -> Option<serde_json::Value> {
+    let mut bits = BitReader::new(section);
+    if bits.read(8)? != TABLE_ID as u64 {
+        return None;
+    }
+
+    bits.skip(4)?; // section_syntax_indicator, private_indicator, sap_type
+    bits.skip(12)?; // section_length
+    bits.skip(8)?; // protocol_version
+    if bits.read(1)? == 1 {
+        // The command of an encrypted section is unreadable
+        return Some(json!({ "command": "encrypted" }));
+    }
+    bits.skip(6)?; // encryption_algorithm
+    let pts_adjustment = bits.read(33)?;
+    bits.skip(8 + 12 + 12)?; // cw_index, tier, splice_command_length
+
+    let command_type = bits.read(8)?;
+    let mut marker = json!({ "pts_adjustment": pts_adjustment });
+
+    match command_type {
+        0x00 => marker["command"] = json!("splice_null"),
+        0x05 => {
+            marker["command"] = json!("splice_insert");
+            marker["event_id"] = json!(bits.read(32)?);
+            let cancel = bits.read(1)? == 1;
+            bits.skip(7)?;
+            marker["cancel"] = json!(cancel);
+
+            if !cancel {
+                let out_of_network = bits.read(1)? == 1;
+                let program_splice = bits.read(1)? == 1;
+                let has_duration = bits.read(1)? == 1;
+                let immediate = bits.read(1)? == 1;
+                bits.skip(4)?;
+
+                marker["out_of_network"] = json!(out_of_network);
+                marker["immediate"] = json!(immediate);
+
+                // Component splices are rare enough that we just pass the raw section
+                if !program_splice {
+                    return Some(marker);
+                }
+                if !immediate {
+                    marker["pts"] = json!(read_splice_time(&mut bits)?);
+                }
+                if has_duration {
+                    marker["auto_return"] = json!(bits.read(1)? == 1);
+                    bits.skip(6)?;
+                    marker["duration"] = json!(bits.read(33)? as f64 / 90_000.0);
+                }
+            }
+        }
+        0x06 => {
+            marker["command"] = json!("time_signal");
+            marker["pts"] = json!(read_splice_time(&mut bits)?);
+        }
+        other => marker["command"] = json!(format!("0x{other:02x}")),
+    }
+
+    Some(marker)
+}
+
+/// A splice_time(), as a 90kHz PTS or null if unspecified
+fn read_splice_time(bits: &mut BitReader) -> Option<Option<u64>> {
+    if bits.read(1)? == 1 {
+        bits.skip(6)?;
+        Some(Some(bits.read(33)?))
+    } else {
+        bits.skip(7)?;
+        Some(None)
+    }
+}
+
+/// Reads big-endian bit fields
+struct BitReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> BitReader<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        Self { data, pos: 0 }
+    }
+
+    fn read(&mut self, bits: usize) -> Option<u64> {
+        let mut value = 0;
+        for _ in 0..bits {
+            let byte = self.data.get(self.pos / 8)?;
+            let bit = (byte >> (7 - self.pos % 8)) & 1;
+            value = (value << 1) | bit as u64;
+            self.pos += 1;
+        }
+        Some(value)
+    }
+
+    fn skip(&mut self, bits: usize) -> Option<()> {
+        self.read(bits).map(|_| ())
+    }
+}
@@ -0,0 +1,219 @@
+//! Admin API
+//!
+//! With `--admin`, the embedded HTTP server lists the running bridges and controls
+//! them one at a time, the same way a [BridgeHandle] does:
+//!
+//! - `GET /bridges` and `GET /bridges/{stream_id}` return their stats as JSON
+//! - `POST /bridges/{stream_id}/stop`, `/pause` and `/resume`
+//! - `GET /bridges/{stream_id}/tap` streams what the bridge forwards, see [tap](crate::tap)
+//! - `GET /history` lists the bridges that ended, with `--history-db`, see
+//!   [history](crate::history)
+//! - `GET /ready` returns 200 once the main relay and a CF session are connected, and
+//!   503 while either isn't; it needs no token, so probes can use it as is
+//!
+//! Controls return 404 for streams that aren't bridged and 409 when the bridge is
+//! already stopping, paused or running.
+
+use std::sync::Arc;
+
+use axum::body::Body;
+use axum::extract::{Path, Query, State};
+use axum::http::{header, HeaderMap, StatusCode};
+use axum::response::{IntoResponse, Response};
+use axum::routing::{get, post};
+use axum::{Json, Router};
+use serde_json::{json, Value};
+use tokio::sync::watch;
+
+use crate::handle::BridgeHandle;
+#[cfg(feature = "history")]
+use crate::history::{HistoryQuery, SessionHistory};
+use crate::manager::BridgeLookup;
+use crate::tap::{self, TapQuery};
+
+pub(crate) struct Admin {
+    bridges: BridgeLookup,
+    /// Required as a bearer token when set
+    token: Option<String>,
+    relay_up: watch::Receiver<bool>,
+    /// How many CF sessions are connected
+    connected: watch::Receiver<usize>,
+    #[cfg(feature = "history")]
+    history: Option<Arc<SessionHistory>>,
+}
+
+impl Admin {
+    pub(crate) fn new(
+        bridges: BridgeLookup,
+        token: Option<String>,
+        relay_up: watch::Receiver<bool>,
+        connected: watch::Receiver<usize>,
+    ) -> Self {
+        Self {
+            bridges,
+            token,
+            relay_up,
+            connected,
+            #[cfg(feature = "history")]
+            history: None,
+        }
+    }
+
+    /// Serve the sessions in `history` at /history
+    #[cfg(feature = "history")]
+    pub(crate) fn with_history(mut self, history: Option<Arc<SessionHistory>>) -> Self {
+        self.history = history;
+        self
+    }
+
+    fn authorized(&self, headers: &HeaderMap) -> bool {
+        let Some(token) = &self.token else {
+            return true;
+        };
+        let bearer = headers
+            .get(header::AUTHORIZATION)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.strip_prefix("Bearer "));
+        bearer == Some(token.as_str())
+    }
+}
+
+/// The admin routes, to be merged into the embedded HTTP server
+pub(crate) fn routes(admin: Arc<Admin>) -> Router {
+    let router = Router::new()
+        .route("/bridges", get(list_bridges))
+        .route("/bridges/{stream_id}", get(get_bridge))
+        .route("/bridges/{stream_id}/tap", get(tap_bridge))
+        .route("/bridges/{stream_id}/{action}", post(control_bridge))
+        .route("/ready", get(ready));
+    #[cfg(feature = "history")]
+    let router = router.route("/history", get(list_history));
+    router.with_state(admin)
+}
+
+async fn ready(State(admin): State<Arc<Admin>>) -> Response {
+    let (relay, sessions) = (*admin.relay_up.borrow(), *admin.connected.borrow());
+    let status = match relay && sessions > 0 {
+        true => StatusCode::OK,
+        false => StatusCode::SERVICE_UNAVAILABLE,
+    };
+    let body = json!({ "relay": relay, "cloudflare_sessions": sessions });
+    (status, Json(body)).into_response()
+}
+
+async fn list_bridges(State(admin): State<Arc<Admin>>, headers: HeaderMap) -> Response {
+    if !admin.authorized(&headers) {
+        return StatusCode::UNAUTHORIZED.into_response();
+    }
+
+    let bridges: Vec<_> = admin.bridges.all().await.iter().map(stats).collect();
+    Json(bridges).into_response()
+}
+
+async fn get_bridge(State(admin): State<Arc<Admin>>, Path(stream_id): Path<String>, headers: HeaderMap) -> Response {
+    if !admin.authorized(&headers) {
+        return StatusCode::UNAUTHORIZED.into_response();
+    }
+
+    match admin.bridges.get(&stream_id).await {
+        Some(bridge) => Json(stats(&bridge)).into_response(),
+        None => StatusCode::NOT_FOUND.into_response(),
+    }
+}
+
+async fn tap_bridge(
+    State(admin): State<Arc<Admin>>,
+    Path(stream_id): Path<String>,
+    Query(query): Query<TapQuery>,
+    headers: HeaderMap,
+) -> Response {
+    if !admin.authorized(&headers) {
+        return StatusCode::UNAUTHORIZED.into_response();
+    }
+
+    let Some(forwarded) = admin.bridges.get(&stream_id).await.and_then(|bridge| bridge.forwarded()) else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+    let body = Body::from_stream(tap::tap(forwarded, query));
+    ([(header::CONTENT_TYPE, "application/x-ndjson")], body).into_response()
+}
+
+#[cfg(feature = "history")]
+async fn list_history(
+    State(admin): State<Arc<Admin>>,
+    Query(query): Query<HistoryQuery>,
+    headers: HeaderMap,
+) -> Response {
+    if !admin.authorized(&headers) {
+        return StatusCode::UNAUTHORIZED.into_response();
+    }
+
+    let Some(history) = &admin.history else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+    match history.sessions(query).await {
+        Ok(sessions) => Json(sessions).into_response(),
+        Err(err) => {
+            tracing::warn!(err = format!("{err:#}"), "failed to query bridge history");
+            (StatusCode::INTERNAL_SERVER_ERROR, format!("{err:#}")).into_response()
+        }
+    }
+}
+
+async fn control_bridge(
+    State(admin): State<Arc<Admin>>,
+    Path((stream_id, action)): Path<(String, String)>,
+    headers: HeaderMap,
+) -> Response {
+    if !admin.authorized(&headers) {
+        return StatusCode::UNAUTHORIZED.into_response();
+    }
+
+    let Some(bridge) = admin.bridges.get(&stream_id).await else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+    let done = match action.as_str() {
+        "stop" => bridge.stop(),
+        "pause" => bridge.pause(),
+        "resume" => bridge.resume(),
+        _ => return StatusCode::NOT_FOUND.into_response(),
+    };
+
+    tracing::info!(stream_id, action, done, "admin bridge control");
+    match done {
+        true => StatusCode::NO_CONTENT.into_response(),
+        false => StatusCode::CONFLICT.into_response(),
+    }
+}
+
+fn stats(bridge: &BridgeHandle) -> Value {
+    let stats = bridge.stats();
+    json!({
+        "stream_id": stats.stream_id,
+        "uptime_secs": stats.uptime.as_secs(),
+        "active": stats.active,
+        "paused": stats.paused,
+        "groups": stats.groups,
+        "failed_groups": stats.failed_groups,
+        "dropped_groups": stats.dropped_groups,
+        "frames": stats.frames,
+        "bytes": stats.bytes,
+        "stalls": stats.stalls,
+        "latency_ms": stats.latency.map(|latency| json!({
+            "adapter": latency.adapter.as_secs_f64() * 1000.0,
+            "relay": latency.relay.map(|relay| relay.as_secs_f64() * 1000.0),
+        })),
+        "capture_latency_ms": stats.capture_latency.map(|latency| json!({
+            "cloudflare": latency.cloudflare.as_secs_f64() * 1000.0,
+            "republish": latency.republish.as_secs_f64() * 1000.0,
+        })),
+        "tracks": stats.tracks.iter().map(|loss| json!({
+            "track": loss.track,
+            "dropped_groups": loss.dropped_groups,
+            "skipped_groups": loss.skipped_groups,
+            "expired_groups": loss.expired_groups,
+            "dropped_objects": loss.dropped_objects,
+            "expired_objects": loss.expired_objects,
+        })).collect::<Vec<_>>(),
+    })
+}
@@ -0,0 +1,51 @@
+//! Track renaming
+//!
+//! Aliases are written as `[stream_id=]upstream->downstream`, e.g. `video_hd->video/0`,
+//! so CF track names can be made to match what our hang-based players expect.
+
+use crate::filter::scoped_rules;
+
+/// Maps CF track names to the names we publish on the relay
+#[derive(Clone, Debug, Default)]
+pub struct TrackAliases {
+    // (upstream, downstream) pairs
+    aliases: Vec<(String, String)>,
+}
+
+impl TrackAliases {
+    /// Build the aliases for `stream_id` from the configured `--track-alias` rules
+    pub fn for_stream(rules: &[String], stream_id: &str) -> Self {
+        let aliases = scoped_rules(rules, stream_id)
+            .into_iter()
+            .filter_map(|rule| match rule.split_once("->") {
+                Some((from, to)) => Some((from.trim().to_string(), to.trim().to_string())),
+                None => {
+                    tracing::warn!(rule, "ignoring track alias without '->'");
+                    None
+                }
+            })
+            .collect();
+
+        Self { aliases }
+    }
+
+    /// The relay-side name for an upstream track
+    pub fn downstream<'a>(&'a self, upstream: &'a str) -> &'a str {
+        self.aliases
+            .iter()
+            .find(|(from, _)| from == upstream)
+            .map_or(upstream, |(_, to)| to.as_str())
+    }
+
+    /// The upstream name for a relay-side track, or None if that name was aliased away
+    pub fn upstream<'a>(&'a self, downstream: &'a str) -> Option<&'a str> {
+        if let Some((from, _)) = self.aliases.iter().find(|(_, to)| to == downstream) {
+            return Some(from);
+        }
+
+        match self.aliases.iter().any(|(from, _)| from == downstream) {
+            true => None,
+            false => Some(downstream),
+        }
+    }
+}
@@ -0,0 +1,127 @@
+//! Announcing CF broadcasts
+//!
+//! CloudFlare never sends PUBLISH_NAMESPACE, so a bridge calls `announce_remote` to
+//! make the session subscribe to the broadcast and add it to our origin. That happens
+//! asynchronously, so instead of hoping it's done after a fixed delay we wait for the
+//! origin to announce the broadcast, announcing again if it doesn't show up in time.
+//! Upstreams that announce their broadcasts themselves (see [upstream](crate::upstream))
+//! are only waited for, for as long as all the attempts would take.
+//!
+//! After a restart every listed stream starts bridging at once, so the number of
+//! announcements in flight is capped, and the streams started by one registry poll
+//! report their outcome together instead of one log line each.
+
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use moq_lite::{BroadcastConsumer, OriginConsumer, Path};
+use tokio::sync::Semaphore;
+
+use crate::error::BridgeError;
+use crate::pool::SessionLease;
+
+/// How long to wait for an announced broadcast, and how often to try
+#[derive(Clone, Copy, Debug)]
+pub struct AnnounceOptions {
+    pub timeout: Duration,
+    pub attempts: u32,
+    /// Call `announce_remote`, rather than wait for the upstream's own announcement
+    pub announce_remote: bool,
+}
+
+/// Announce `namespace` on the leased session and wait for its broadcast to appear
+///
+/// Waits for a `limit` permit first, and holds it until the broadcast shows up.
+pub async fn announce(
+    lease: &SessionLease,
+    from_cloudflare: &OriginConsumer,
+    namespace: &str,
+    options: AnnounceOptions,
+    limit: &Semaphore,
+) -> Result<BroadcastConsumer, BridgeError> {
+    let _permit = limit.acquire().await.map_err(|err| BridgeError::Announce(err.into()))?;
+
+    // Subscribe to announcements first, so we can't miss it
+    let mut announced = from_cloudflare
+        .consume_only(&[Path::new(namespace)])
+        .ok_or_else(|| BridgeError::Announce(anyhow::anyhow!("namespace not allowed: {namespace}")))?;
+
+    let attempts = options.attempts.max(1);
+    for attempt in 1..=attempts {
+        let session = lease.session().ok_or(BridgeError::NotConnected)?;
+        if options.announce_remote {
+            session.announce_remote(namespace).await.map_err(|err| BridgeError::Announce(err.into()))?;
+            tracing::debug!(namespace, attempt, session = lease.index(), "announced remote broadcast");
+        }
+        drop(session);
+
+        let wait = async {
+            while let Some((path, broadcast)) = announced.announced().await {
+                if let (true, Some(broadcast)) = (path.as_str() == namespace, broadcast) {
+                    return Some(broadcast);
+                }
+            }
+            None
+        };
+
+        match tokio::time::timeout(options.timeout, wait).await {
+            Ok(Some(broadcast)) => return Ok(broadcast),
+            Ok(None) => return Err(BridgeError::Announce(anyhow::anyhow!("cloudflare origin closed"))),
+            Err(_) => tracing::debug!(namespace, attempt, "broadcast not announced yet"),
+        }
+    }
+
+    Err(BridgeError::NotFound {
+        namespace: namespace.to_string(),
+        attempts,
+    })
+}
+
+/// The announcements started by one registry poll
+pub struct AnnounceBatch {
+    started: Instant,
+    size: usize,
+    state: Mutex<BatchState>,
+}
+
+#[derive(Default)]
+struct BatchState {
+    announced: usize,
+    failed: Vec<String>,
+}
+
+impl AnnounceBatch {
+    pub fn new(size: usize) -> Arc<Self> {
+        Arc::new(Self {
+            started: Instant::now(),
+            size,
+            state: Default::default(),
+        })
+    }
+
+    /// Record how announcing one stream went, logging the totals once every stream has reported
+    pub fn report<T>(&self, stream_id: &str, result: &Result<T, BridgeError>) {
+        let mut state = self.state.lock().unwrap();
+        match result {
+            Ok(_) => state.announced += 1,
+            Err(err) => state.failed.push(format!("{stream_id}: {err:#}")),
+        }
+
+        if state.announced + state.failed.len() < self.size {
+            return;
+        }
+
+        let elapsed = self.started.elapsed();
+        if state.failed.is_empty() {
+            tracing::info!(announced = state.announced, ?elapsed, "announced new bridges");
+        } else {
+            tracing::warn!(
+                announced = state.announced,
+                failed = state.failed.len(),
+                errors = ?state.failed,
+                ?elapsed,
+                "some new bridges failed to announce"
+            );
+        }
+    }
+}
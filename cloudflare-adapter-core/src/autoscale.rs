@@ -0,0 +1,118 @@
+//! Autoscaling signals
+//!
+//! A few gauges for a HorizontalPodAutoscaler (through prometheus-adapter) or KEDA to
+//! scale adapter replicas by, with names and meanings kept stable across releases:
+//!
+//! - `autoscale_pending_streams`: streams this replica would bridge but has no slot for,
+//!   waiting in the `--max-bridges` queue
+//! - `autoscale_saturation_percent`: its running bridges against its capacity, which is
+//!   `--max-bridges`, or `--session-capacity` streams for each of the `--cf-sessions`
+//! - `autoscale_session_utilization_percent{session}`: the bridges each CF session carries
+//!   against `--session-capacity`, the point where sessions hit flow-control limits
+//!
+//! They're updated every registry poll and served at `/metrics`, and with `--http-listen`
+//! also as JSON at `/autoscale`, for KEDA's metrics-api scaler:
+//!
+//! ```json
+//! {"pending_streams": 3, "active_bridges": 20, "capacity": 20, "saturation": 1.0,
+//!  "sessions": [{"session": 0, "streams": 20, "utilization": 0.67}]}
+//! ```
+//!
+//! Scaling out on pending streams only makes sense where a new replica takes some of them,
+//! like replicas sharing streams through `--lease-redis-url`.
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+#[cfg(feature = "http")]
+use axum::extract::State;
+#[cfg(feature = "http")]
+use axum::routing::get;
+#[cfg(feature = "http")]
+use axum::{Json, Router};
+#[cfg(feature = "http")]
+use serde_json::{json, Value};
+
+use crate::metrics::Gauge;
+use crate::pool::SessionPool;
+
+/// The demand on one replica, as of the last registry poll
+pub struct Autoscale {
+    pool: Arc<SessionPool>,
+    /// The bridges this replica can run
+    capacity: usize,
+    /// The bridges one CF session can carry
+    session_capacity: usize,
+    pending: AtomicUsize,
+    active: AtomicUsize,
+}
+
+impl Autoscale {
+    pub fn new(pool: Arc<SessionPool>, max_bridges: Option<usize>, session_capacity: usize) -> Arc<Self> {
+        let session_capacity = session_capacity.max(1);
+        Arc::new(Self {
+            capacity: max_bridges.unwrap_or(pool.len() * session_capacity).max(1),
+            pool,
+            session_capacity,
+            pending: AtomicUsize::new(0),
+            active: AtomicUsize::new(0),
+        })
+    }
+
+    /// Record what a poll left: streams still waiting for a slot, and bridges running
+    pub fn update(&self, pending: usize, active: usize) {
+        self.pending.store(pending, Ordering::Relaxed);
+        self.active.store(active, Ordering::Relaxed);
+
+        Gauge::new("autoscale_pending_streams", "Streams waiting for a bridge slot on this replica", &[])
+            .set(pending as i64);
+        Gauge::new("autoscale_saturation_percent", "Running bridges against this replica's capacity", &[])
+            .set(percent(active, self.capacity));
+        for (session, streams) in self.pool.loads().into_iter().enumerate() {
+            let help = "Bridges on each CloudFlare session against --session-capacity";
+            Gauge::new("autoscale_session_utilization_percent", help, &[("session", &session.to_string())])
+                .set(percent(streams, self.session_capacity));
+        }
+    }
+
+    /// The signals as served at `/autoscale`
+    #[cfg(feature = "http")]
+    pub fn report(&self) -> Value {
+        let active = self.active.load(Ordering::Relaxed);
+        let sessions: Vec<_> = self
+            .pool
+            .loads()
+            .into_iter()
+            .enumerate()
+            .map(|(session, streams)| {
+                json!({
+                    "session": session,
+                    "streams": streams,
+                    "utilization": streams as f64 / self.session_capacity as f64,
+                })
+            })
+            .collect();
+        json!({
+            "pending_streams": self.pending.load(Ordering::Relaxed),
+            "active_bridges": active,
+            "capacity": self.capacity,
+            "saturation": active as f64 / self.capacity as f64,
+            "sessions": sessions,
+        })
+    }
+}
+
+fn percent(used: usize, capacity: usize) -> i64 {
+    (used * 100 / capacity) as i64
+}
+
+/// The autoscaling route, to be merged into the embedded HTTP server
+#[cfg(feature = "http")]
+pub fn routes(autoscale: Arc<Autoscale>) -> Router {
+    Router::new().route("/autoscale", get(serve_autoscale)).with_state(autoscale)
+}
+
+#[cfg(feature = "http")]
+async fn serve_autoscale(State(autoscale): State<Arc<Autoscale>>) -> Json<Value> {
+    Json(autoscale.report())
+}
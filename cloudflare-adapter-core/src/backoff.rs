@@ -0,0 +1,59 @@
+//! Retry delays
+//!
+//! Every reconnect loop waits longer after each consecutive failure, up to a cap, and
+//! spreads its delay by a random jitter so a fleet of adapters doesn't retry a flapping
+//! upstream in lockstep.
+
+use std::time::Duration;
+
+use rand::Rng;
+
+/// How retry delays grow
+#[derive(Clone, Copy, Debug)]
+pub struct BackoffPolicy {
+    pub initial: Duration,
+    pub multiplier: f64,
+    pub max: Duration,
+    /// The fraction each delay is randomly shortened or lengthened by
+    pub jitter: f64,
+}
+
+impl BackoffPolicy {
+    pub fn start(self) -> Backoff {
+        Backoff {
+            policy: self,
+            failures: 0,
+        }
+    }
+}
+
+/// The retry state of one loop
+#[derive(Clone, Debug)]
+pub struct Backoff {
+    policy: BackoffPolicy,
+    failures: u32,
+}
+
+impl Backoff {
+    /// The delay before the next attempt, after another failure
+    pub fn next(&mut self) -> Duration {
+        let policy = &self.policy;
+        let base = policy.initial.as_secs_f64() * policy.multiplier.max(1.0).powi(self.failures as i32);
+        let base = base.min(policy.max.as_secs_f64());
+        self.failures = self.failures.saturating_add(1);
+
+        let jitter = policy.jitter.clamp(0.0, 1.0);
+        let factor = if jitter > 0.0 { rand::rng().random_range(1.0 - jitter..=1.0 + jitter) } else { 1.0 };
+        Duration::from_secs_f64(base * factor)
+    }
+
+    /// Start over after a success
+    pub fn reset(&mut self) {
+        self.failures = 0;
+    }
+
+    /// Wait out the next delay
+    pub async fn wait(&mut self) {
+        tokio::time::sleep(self.next()).await;
+    }
+}
@@ -0,0 +1,112 @@
+//! What a track does when the relay falls behind
+//!
+//! The relay connection monitor (see `shed`) flags congestion from QUIC loss and RTT.
+//! While it's flagged, each new group follows the policy for its kind of track:
+//!
+//! - `latest` forwards the group at once. The relay session abandons older groups that
+//!   are still in flight in favour of it, so viewers skip ahead to the live edge.
+//! - `drop` drops the group, so the ones already in flight can finish.
+//! - `block` holds the group until the congestion clears. Groups are never cut short,
+//!   but latency grows, and groups that start in the meantime are skipped.
+//!
+//! Dropped groups are counted in the `dropped_groups_total` metric, by kind and reason, and
+//! in the bridge's stats and its track's [losses](crate::loss).
+
+use std::sync::Arc;
+
+use tokio::sync::watch;
+
+use crate::catalog::MediaKind;
+use crate::health::BridgeHealth;
+use crate::loss::TrackLoss;
+use crate::metrics::Counter;
+
+/// What to do with a new group while the relay is congested
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum DropPolicy {
+    /// Forward it and let the relay skip older groups
+    #[default]
+    Latest,
+    /// Drop it
+    Drop,
+    /// Wait for the congestion to clear
+    Block,
+}
+
+/// The policy for each kind of track
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Backpressure {
+    pub video: DropPolicy,
+    pub audio: DropPolicy,
+    /// Tracks the catalog doesn't list as video or audio
+    pub data: DropPolicy,
+}
+
+impl Backpressure {
+    /// The policy handle for one track; `kind` is None for data tracks
+    pub fn track(
+        &self,
+        kind: Option<MediaKind>,
+        congestion: watch::Receiver<bool>,
+        health: Arc<BridgeHealth>,
+        loss: Arc<TrackLoss>,
+    ) -> TrackPolicy {
+        let (policy, label) = match kind {
+            Some(MediaKind::Video) => (self.video, "video"),
+            Some(MediaKind::Audio) => (self.audio, "audio"),
+            None => (self.data, "data"),
+        };
+
+        TrackPolicy {
+            policy,
+            congestion,
+            kind: label,
+            health,
+            loss,
+        }
+    }
+}
+
+/// Applies the backpressure policy to the groups of one track
+pub struct TrackPolicy {
+    policy: DropPolicy,
+    congestion: watch::Receiver<bool>,
+    kind: &'static str,
+    /// The bridge's and the track's, which count the drops too
+    health: Arc<BridgeHealth>,
+    loss: Arc<TrackLoss>,
+}
+
+impl TrackPolicy {
+    /// Wait until a new group may be forwarded, returning false if it should be dropped
+    pub async fn admit(&mut self) -> bool {
+        if !*self.congestion.borrow_and_update() {
+            return true;
+        }
+
+        match self.policy {
+            DropPolicy::Latest => true,
+            DropPolicy::Drop => {
+                self.dropped("congestion");
+                false
+            }
+            DropPolicy::Block => {
+                // The monitor going away counts as the congestion clearing
+                let _ = self.congestion.wait_for(|congested| !congested).await;
+                true
+            }
+        }
+    }
+
+    /// Count a group of this track that wasn't forwarded
+    pub fn dropped(&self, reason: &str) {
+        self.health.dropped();
+        self.loss.dropped_group(reason);
+        Counter::new(
+            "dropped_groups_total",
+            "Groups that weren't forwarded to the relay",
+            &[("kind", self.kind), ("reason", reason)],
+        )
+        .inc();
+    }
+}
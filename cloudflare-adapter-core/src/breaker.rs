@@ -0,0 +1,75 @@
+//! Circuit breaker for the stream registry
+//!
+//! After enough consecutive failed polls the circuit opens: running bridges carry on
+//! as they are, the registry is only probed now and then, and failures are no longer
+//! logged one by one. The first successful probe closes the circuit again. The state
+//! is exported as the `registry_circuit_open` gauge.
+
+use std::time::Duration;
+
+use crate::metrics::{Counter, Gauge};
+
+/// When to open the circuit, and how often to probe while it's open
+#[derive(Clone, Copy, Debug)]
+pub struct BreakerOptions {
+    pub threshold: u32,
+    pub probe_interval: Duration,
+}
+
+pub struct CircuitBreaker {
+    options: BreakerOptions,
+    // Consecutive failures
+    failures: u32,
+    open: bool,
+    gauge: Gauge,
+    failed: Counter,
+}
+
+impl CircuitBreaker {
+    pub fn new(options: BreakerOptions) -> Self {
+        let gauge = Gauge::new("registry_circuit_open", "Whether registry polling has been suspended", &[]);
+        gauge.set(0);
+
+        Self {
+            options,
+            failures: 0,
+            open: false,
+            gauge,
+            failed: Counter::new("registry_failures_total", "Failed registry polls", &[]),
+        }
+    }
+
+    pub fn success(&mut self) {
+        if self.open {
+            tracing::info!(failures = self.failures, "registry recovered, closing circuit");
+            self.gauge.set(0);
+        }
+        self.failures = 0;
+        self.open = false;
+    }
+
+    pub fn failure(&mut self, err: &anyhow::Error) {
+        self.failures += 1;
+        self.failed.inc();
+
+        if self.open {
+            tracing::debug!(%err, failures = self.failures, "registry probe failed");
+        } else if self.failures >= self.options.threshold.max(1) {
+            self.open = true;
+            self.gauge.set(1);
+            tracing::error!(
+                %err,
+                failures = self.failures,
+                probe = ?self.options.probe_interval,
+                "registry keeps failing, opening circuit"
+            );
+        } else {
+            tracing::warn!(%err, failures = self.failures, "failed to fetch stream registry");
+        }
+    }
+
+    /// How long to wait before probing, if the circuit is open
+    pub fn probe_interval(&self) -> Option<Duration> {
+        self.open.then_some(self.options.probe_interval)
+    }
+}
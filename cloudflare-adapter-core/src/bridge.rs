@@ -0,0 +1,401 @@
+//! One stream's bridge
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use bytes::Bytes;
+use moq_lite::OriginConsumer;
+use tokio::sync::{oneshot, watch, Semaphore};
+use url::Url;
+
+use crate::admarker::AdMarkerOptions;
+use crate::announce::{AnnounceBatch, AnnounceOptions};
+use crate::duplicate::DuplicateWatch;
+use crate::error::BridgeError;
+use crate::forward::ForwardOptions;
+use crate::handle::BridgeHandle;
+use crate::health::EvictOptions;
+use crate::inject::Injectors;
+use crate::metrics::Counter;
+#[cfg(feature = "http")]
+use crate::package::{Formats, Packager};
+use crate::pool::{SessionLease, SessionPool};
+use crate::sink::BridgeSink;
+#[cfg(feature = "ffmpeg")]
+use crate::thumbnail::ThumbnailOptions;
+use crate::watchdog::Heartbeat;
+use crate::{admarker, announce, forward, health, idle, metadata, pool, sink, tasks, udp};
+#[cfg(feature = "ffmpeg")]
+use crate::thumbnail;
+
+/// Where a bridged broadcast goes, and who can add tracks to it
+pub(crate) struct BridgeOutputs {
+    /// The relay first, then the recorder and any embedder sinks
+    pub(crate) sinks: Vec<Arc<dyn BridgeSink>>,
+    #[cfg(feature = "http")]
+    pub(crate) packager: Arc<Packager>,
+    #[cfg(feature = "http")]
+    pub(crate) formats: Formats,
+    pub(crate) injectors: Arc<Injectors>,
+    /// Hands the forwarded broadcast out to taps
+    pub(crate) handle: BridgeHandle,
+    /// Served on the metadata track from the start, see `--stream-metadata`
+    pub(crate) metadata: Option<Bytes>,
+    pub(crate) ad_markers: Option<AdMarkerOptions>,
+    pub(crate) udp: Option<Url>,
+    pub(crate) idle_timeout: Option<Duration>,
+    pub(crate) stall_timeout: Option<Duration>,
+    pub(crate) evict: Option<EvictOptions>,
+    pub(crate) duplicates: Option<DuplicateWatch>,
+    pub(crate) heartbeat: Heartbeat,
+    #[cfg(feature = "ffmpeg")]
+    pub(crate) thumbnails: Option<ThumbnailOptions>,
+}
+
+/// Where bridges get their broadcasts from
+pub(crate) struct CloudFlareSource {
+    pub(crate) sessions: Arc<SessionPool>,
+    pub(crate) origin: OriginConsumer,
+    pub(crate) announce: AnnounceOptions,
+    /// Caps the announcements in flight across all bridges
+    pub(crate) announce_limit: Arc<Semaphore>,
+    /// The bridges started by the same registry poll
+    pub(crate) batch: Arc<AnnounceBatch>,
+    /// How long to wait for another session when a bridge's session drops
+    pub(crate) move_timeout: Duration,
+    /// How long a bridge whose broadcast ended waits for it to come back, see `--flap-grace`
+    pub(crate) flap_grace: Option<Duration>,
+}
+
+/// Bridge a single stream from CloudFlare to your relay
+pub(crate) async fn bridge_stream(
+    stream_id: &str,
+    namespace: &str,
+    options: ForwardOptions,
+    outputs: BridgeOutputs,
+    source: CloudFlareSource,
+    mut stopped: oneshot::Receiver<BridgeEnd>,
+) -> Result<BridgeEnd, BridgeError> {
+    tracing::info!(stream_id, namespace, "starting bridge");
+
+    // First, announce the remote broadcast, unless it's on demand and waits for the relay to want it
+    let demand = options.demand.clone();
+    let mut announced = match demand {
+        Some(_) => {
+            source.batch.report::<()>(stream_id, &Ok(()));
+            None
+        }
+        None => {
+            let announced = take(&source, namespace).await;
+            source.batch.report(stream_id, &announced);
+            Some(announced?)
+        }
+    };
+
+    // Publish it to your relay and the other sinks, forwarding track by track
+    // The upstream is replaced whenever the bridge moves to a new CF session
+    let (upstream, following) = watch::channel(announced.as_ref().map(|(_, broadcast)| broadcast.clone()));
+    let spill = options.spill.clone();
+    let probe = options.probe.clone();
+    let health = options.health.clone();
+    let bridge = forward::forward_broadcast(stream_id, following.clone(), options);
+    let (forwarded, injector) = (bridge.broadcast.clone(), bridge.injector.clone());
+    sink::publish_all(stream_id, &forwarded, outputs.sinks);
+    outputs.injectors.insert(stream_id, injector.clone());
+    outputs.handle.forwarding(&forwarded);
+    if let Some(frame) = outputs.metadata {
+        metadata::publish(&injector, frame);
+    }
+
+    if let Some(probe) = probe {
+        tasks::spawn(probe.run(injector.clone(), forwarded.clone()));
+    }
+
+    // Watches the relay for another broadcast at our path for as long as the bridge runs
+    let duplicate = async {
+        match outputs.duplicates {
+            Some(watch) => watch.run(injector.clone()).await,
+            None => std::future::pending().await,
+        }
+    };
+    tokio::pin!(duplicate);
+
+    if let Some(spill) = spill.clone() {
+        tasks::spawn(forward::follow(following.clone(), move |broadcast| spill.clone().run(broadcast)));
+    }
+
+    if let Some(ad_markers) = outputs.ad_markers {
+        let stream_id: Arc<str> = stream_id.into();
+        let injector = injector.clone();
+        tasks::spawn(forward::follow(following.clone(), move |broadcast| {
+            let (stream_id, injector, ad_markers) = (stream_id.clone(), injector.clone(), ad_markers.clone());
+            async move { admarker::map_markers(&stream_id, broadcast, injector, ad_markers).await }
+        }));
+    }
+
+    if let Some(url) = outputs.udp {
+        let stream_id = stream_id.to_string();
+        let forwarded = forwarded.clone();
+        tasks::spawn(async move { udp::run_udp_egress(&stream_id, forwarded, url).await });
+    }
+
+    #[cfg(feature = "ffmpeg")]
+    if let Some(thumbnails) = outputs.thumbnails {
+        let stream_id = stream_id.to_string();
+        let forwarded = forwarded.clone();
+        tasks::spawn(async move { thumbnail::run_thumbnails(&stream_id, forwarded, thumbnails).await });
+    }
+
+    #[cfg(feature = "http")]
+    if outputs.formats.any() {
+        outputs.packager.publish(stream_id, forwarded, outputs.formats);
+    }
+
+    tracing::info!(stream_id, namespace, "bridge active");
+
+    let res = 'bridge: loop {
+        let (mut lease, mut broadcast) = match announced.take() {
+            Some(announced) => announced,
+            None => {
+                if let Some(demand) = &demand {
+                    tracing::info!(stream_id, "bridge on standby until someone watches");
+                    tokio::select! {
+                        _ = outputs.heartbeat.pulse(demand.watched()) => {}
+                        Ok(end) = &mut stopped => break 'bridge Ok(end),
+                    }
+                }
+                match outputs.heartbeat.pulse(take(&source, namespace)).await {
+                    Ok(taken) => {
+                        upstream.send_replace(Some(taken.1.clone()));
+                        Counter::new("bridge_demand_takes_total", "Standby bridges taken up for relay viewers", &[]).inc();
+                        taken
+                    }
+                    Err(err) => break 'bridge Err(err),
+                }
+            }
+        };
+        tracing::info!(namespace, session = lease.index(), "announced remote broadcast");
+
+        // Keep the bridge alive until the broadcast ends or goes idle
+        let end = loop {
+            let idle = async {
+                match outputs.idle_timeout {
+                    Some(timeout) => idle::wait_idle(&broadcast, timeout).await,
+                    None => std::future::pending().await,
+                }
+            };
+            let stall = async {
+                match outputs.stall_timeout {
+                    Some(timeout) => idle::wait_stall(&broadcast, timeout).await,
+                    None => std::future::pending().await,
+                }
+            };
+            let unhealthy = async {
+                match &outputs.evict {
+                    Some(evict) => health::wait_unhealthy(&health, evict).await,
+                    None => std::future::pending().await,
+                }
+            };
+            let unwatched = async {
+                match &demand {
+                    Some(demand) => demand.unwatched().await,
+                    None => std::future::pending().await,
+                }
+            };
+            let end = outputs
+                .heartbeat
+                .pulse(async {
+                    tokio::select! {
+                        _ = broadcast.closed() => Some(BridgeEnd::Closed),
+                        _ = idle => Some(BridgeEnd::Idle),
+                        _ = stall => {
+                            tracing::warn!(stream_id, "bridge stalled, restarting");
+                            Counter::new("bridge_stalls_total", "Bridges restarted after their upstream stalled", &[]).inc();
+                            Some(BridgeEnd::Stalled)
+                        }
+                        rate = unhealthy => {
+                            tracing::warn!(stream_id, rate, "bridge error rate too high, evicting");
+                            Counter::new("bridge_evictions_total", "Bridges evicted for their error rate", &[]).inc();
+                            Some(BridgeEnd::Evicted)
+                        }
+                        _ = &mut duplicate => Some(BridgeEnd::Duplicate),
+                        Ok(end) = &mut stopped => Some(end),
+                        // Nobody's watching, so back to standby
+                        _ = unwatched => None,
+                    }
+                })
+                .await;
+
+            let Some(end) = end else {
+                tracing::info!(stream_id, "no one watching, letting go of the cloudflare broadcast");
+                upstream.send_replace(None);
+                Counter::new("bridge_demand_releases_total", "Bridges back on standby for lack of viewers", &[]).inc();
+                continue 'bridge;
+            };
+            if !matches!(end, BridgeEnd::Closed) {
+                break end;
+            }
+
+            // The publisher may only be reconnecting, so keep the relay side up for a while in case it's back
+            if !session_lost(&lease).await {
+                let Some(grace) = source.flap_grace else {
+                    break end;
+                };
+                tracing::info!(stream_id, ?grace, "broadcast ended, waiting for it to come back");
+                upstream.send_replace(None);
+                health.stalled();
+
+                let rejoined = outputs.heartbeat.pulse(rejoin(&lease, &source, namespace, &broadcast));
+                let rejoined = tokio::select! {
+                    rejoined = tokio::time::timeout(grace, rejoined) => rejoined.ok().flatten(),
+                    Ok(end) = &mut stopped => break end,
+                };
+                let Some(rejoined) = rejoined else {
+                    tracing::info!(stream_id, "broadcast didn't come back");
+                    break end;
+                };
+
+                broadcast = rejoined;
+                upstream.send_replace(Some(broadcast.clone()));
+                Counter::new("bridge_flaps_total", "Bridges whose broadcast came back after ending", &[]).inc();
+                tracing::info!(stream_id, "broadcast came back, resuming bridge");
+                continue;
+            }
+
+            // The broadcast also closes when its CF session drops, and then we move
+            tracing::warn!(stream_id, session = lease.index(), "cloudflare session lost, moving bridge");
+            upstream.send_replace(None);
+            health.stalled();
+            drop(lease);
+
+            let moved = outputs.heartbeat.pulse(reannounce(&source, namespace));
+            let Ok((moved_lease, moved_broadcast)) = tokio::time::timeout(source.move_timeout, moved).await else {
+                tracing::warn!(stream_id, timeout = ?source.move_timeout, "no cloudflare session to move bridge to");
+                break BridgeEnd::Closed;
+            };
+
+            (lease, broadcast) = (moved_lease, moved_broadcast);
+            upstream.send_replace(Some(broadcast.clone()));
+            Counter::new("bridge_moves_total", "Bridges moved to a new CF session after theirs dropped", &[]).inc();
+            tracing::info!(stream_id, session = lease.index(), "moved bridge to new cloudflare session");
+        };
+        break Ok(end);
+    };
+
+    // Unpublishes the broadcast from the relay, and ends the other sinks
+    drop(bridge);
+    if let Some(spill) = spill {
+        spill.clear().await;
+    }
+    outputs.injectors.remove(stream_id, &injector);
+
+    let end = res?;
+    tracing::info!(stream_id, ?end, "bridge closed");
+    Ok(end)
+}
+
+/// Lease a CF session and announce the stream on it, to trigger the subscription machinery
+///
+/// This is needed because CloudFlare doesn't send PUBLISH_NAMESPACE. The lease keeps the
+/// bridge counted against its session until we're done.
+async fn take(
+    source: &CloudFlareSource,
+    namespace: &str,
+) -> Result<(SessionLease, moq_lite::BroadcastConsumer), BridgeError> {
+    let lease = source.sessions.lease().ok_or(BridgeError::NotConnected)?;
+    let broadcast = announce::announce(&lease, &source.origin, namespace, source.announce, &source.announce_limit).await?;
+    Ok((lease, broadcast))
+}
+
+/// How long after a broadcast closes its session must be gone for the bridge to move
+const SESSION_GRACE: Duration = Duration::from_secs(1);
+
+/// Whether the leased session closed, rather than just the broadcast
+async fn session_lost(lease: &SessionLease) -> bool {
+    match lease.session() {
+        Some(session) => tokio::time::timeout(SESSION_GRACE, session.closed()).await.is_ok(),
+        None => true,
+    }
+}
+
+/// Announce a stream again on its session until a broadcast other than `ended` is back, or the session is gone
+async fn rejoin(
+    lease: &SessionLease,
+    source: &CloudFlareSource,
+    namespace: &str,
+    ended: &moq_lite::BroadcastConsumer,
+) -> Option<moq_lite::BroadcastConsumer> {
+    loop {
+        match announce::announce(lease, &source.origin, namespace, source.announce, &source.announce_limit).await {
+            // The origin may not have dropped the one that ended yet
+            Ok(broadcast) if broadcast.is_clone(ended) => tokio::time::sleep(source.announce.timeout).await,
+            Ok(broadcast) => return Some(broadcast),
+            Err(BridgeError::NotFound { .. }) => {}
+            Err(err) => {
+                tracing::debug!(%err, namespace, "stopped waiting for broadcast to come back");
+                return None;
+            }
+        }
+    }
+}
+
+/// Announce a stream again once a CF session is up, for a bridge whose session dropped
+async fn reannounce(source: &CloudFlareSource, namespace: &str) -> (SessionLease, moq_lite::BroadcastConsumer) {
+    let mut connected = source.sessions.connected();
+    loop {
+        if let Some(lease) = source.sessions.lease() {
+            match announce::announce(&lease, &source.origin, namespace, source.announce, &source.announce_limit).await {
+                Ok(broadcast) => return (lease, broadcast),
+                Err(err) => tracing::debug!(%err, namespace, session = lease.index(), "failed to move bridge"),
+            }
+        }
+        pool::next_connect(&mut connected).await;
+    }
+}
+
+/// Why a bridge stopped
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BridgeEnd {
+    /// The upstream broadcast ended
+    Closed,
+    /// The upstream broadcast stopped producing objects
+    Idle,
+    /// Stopped to make room for a more important stream
+    Preempted,
+    /// The upstream broadcast went silent after producing media
+    Stalled,
+    /// Too many of its groups failed
+    Evicted,
+    /// The adapter is shutting down
+    Shutdown,
+    /// Reloaded rules changed how the stream is bridged
+    Reconfigured,
+    /// Stopped with [BridgeHandle::stop](crate::BridgeHandle::stop)
+    Stopped,
+    /// Another replica owns the stream now, see `--lease-redis-url`
+    LeaseLost,
+    /// Another broadcast turned up at its relay path, see `--duplicates`
+    Duplicate,
+    /// The `expires_at` or `ttl` of its registry entry passed
+    Expired,
+}
+
+
+impl BridgeEnd {
+    /// How the session history and its queries call it
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Closed => "closed",
+            Self::Idle => "idle",
+            Self::Preempted => "preempted",
+            Self::Stalled => "stalled",
+            Self::Evicted => "evicted",
+            Self::Shutdown => "shutdown",
+            Self::Reconfigured => "reconfigured",
+            Self::Stopped => "stopped",
+            Self::LeaseLost => "lease_lost",
+            Self::Duplicate => "duplicate",
+            Self::Expired => "expired",
+        }
+    }
+}
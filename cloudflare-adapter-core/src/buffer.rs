@@ -0,0 +1,235 @@
+//! Caps on buffered media
+//!
+//! A forwarded group stays in memory until every relay subscriber has sent it or
+//! given up on it, so a slow relay link makes groups pile up. Each bridge charges the
+//! frames it forwards against its own cap and a global one, and releases them once
+//! the relay session drops the group.
+//!
+//! When a write leaves either cap exceeded, the bridge aborts its oldest group that's
+//! still being written: no more frames are buffered for it and its subscribers see
+//! it end early once they've drained what they have. Under sustained pressure that
+//! ends up being the group just started, so new data is dropped until memory drains.
+
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+use bytes::Bytes;
+use moq_lite::{Error, GroupProducer};
+use tokio::sync::Notify;
+
+use crate::metrics::Counter;
+
+/// The cap shared by every bridge
+#[derive(Debug)]
+pub struct BufferBudget {
+    limit: Option<usize>,
+    used: AtomicUsize,
+    /// Woken whenever `used` drops to zero
+    empty: Notify,
+}
+
+impl BufferBudget {
+    pub fn new(limit: Option<usize>) -> Arc<Self> {
+        Arc::new(Self {
+            limit,
+            used: AtomicUsize::new(0),
+            empty: Notify::new(),
+        })
+    }
+
+    /// Bytes forwarded by every bridge that the relay session isn't done with yet
+    pub fn used(&self) -> usize {
+        self.used.load(Ordering::Relaxed)
+    }
+
+    /// Wait until the relay session is done with everything forwarded
+    pub async fn drained(&self) {
+        loop {
+            // Registered before checking, so a release in between still wakes us
+            let mut empty = std::pin::pin!(self.empty.notified());
+            empty.as_mut().enable();
+            if self.used() == 0 {
+                return;
+            }
+            empty.await;
+        }
+    }
+
+    fn release(&self, bytes: usize) {
+        if self.used.fetch_sub(bytes, Ordering::Relaxed) == bytes {
+            self.empty.notify_waiters();
+        }
+    }
+
+    fn exceeded(&self) -> bool {
+        self.limit.is_some_and(|limit| self.used() > limit)
+    }
+}
+
+/// The buffered groups of one bridge
+pub struct BridgeBuffers {
+    limit: Option<usize>,
+    global: Arc<BufferBudget>,
+    used: AtomicUsize,
+    // Groups still being written, oldest first
+    open: Mutex<VecDeque<Arc<Entry>>>,
+}
+
+struct Entry {
+    // None once the group is closed or aborted
+    producer: Mutex<Option<GroupProducer>>,
+    bytes: AtomicUsize,
+}
+
+impl BridgeBuffers {
+    pub fn new(limit: Option<usize>, global: Arc<BufferBudget>) -> Arc<Self> {
+        Arc::new(Self {
+            limit,
+            global,
+            used: AtomicUsize::new(0),
+            open: Default::default(),
+        })
+    }
+
+    /// Start accounting for a group; frames must be written through the returned handle
+    pub fn open(self: &Arc<Self>, producer: GroupProducer) -> BufferedGroup {
+        let entry = Arc::new(Entry {
+            producer: Mutex::new(Some(producer)),
+            bytes: AtomicUsize::new(0),
+        });
+        self.open.lock().unwrap().push_back(entry.clone());
+
+        BufferedGroup {
+            buffers: self.clone(),
+            entry,
+        }
+    }
+
+    fn exceeded(&self) -> bool {
+        self.global.exceeded() || self.limit.is_some_and(|limit| self.used.load(Ordering::Relaxed) > limit)
+    }
+
+    fn charge(&self, bytes: usize) {
+        self.used.fetch_add(bytes, Ordering::Relaxed);
+        self.global.used.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    /// Stop tracking `entry` as open, and release its bytes once the relay is done with it
+    fn finish(self: &Arc<Self>, entry: &Arc<Entry>, producer: GroupProducer, err: Option<Error>) {
+        self.open.lock().unwrap().retain(|e| !Arc::ptr_eq(e, entry));
+
+        let unused = producer.unused();
+        match err {
+            Some(err) => producer.abort(err),
+            None => producer.close(),
+        }
+
+        let (buffers, entry) = (self.clone(), entry.clone());
+        tokio::spawn(async move {
+            unused.await;
+            let bytes = entry.bytes.swap(0, Ordering::Relaxed);
+            buffers.used.fetch_sub(bytes, Ordering::Relaxed);
+            buffers.global.release(bytes);
+        });
+    }
+
+    /// Abort the oldest group that's still being written
+    fn drop_oldest(self: &Arc<Self>) {
+        let Some(entry) = self.open.lock().unwrap().front().cloned() else {
+            return;
+        };
+        let Some(producer) = entry.producer.lock().unwrap().take() else {
+            return;
+        };
+
+        tracing::debug!(
+            bridge = self.used.load(Ordering::Relaxed),
+            global = self.global.used.load(Ordering::Relaxed),
+            "buffer cap exceeded, dropping oldest group"
+        );
+        Counter::new("buffer_dropped_groups_total", "Groups cut short to stay under the buffer caps", &[]).inc();
+        self.finish(&entry, producer, Some(Error::Cancel));
+    }
+}
+
+/// A group being forwarded, charged against its bridge's buffers
+pub struct BufferedGroup {
+    buffers: Arc<BridgeBuffers>,
+    entry: Arc<Entry>,
+}
+
+impl BufferedGroup {
+    /// Write a frame, returning false if the group was dropped to stay under the caps
+    pub fn write_frame(&self, frame: Bytes) -> bool {
+        let bytes = frame.len();
+        {
+            let mut producer = self.entry.producer.lock().unwrap();
+            let Some(producer) = producer.as_mut() else {
+                return false;
+            };
+            producer.write_frame(frame);
+
+            // Charged under the lock, so the bytes can't be released before they're added
+            self.entry.bytes.fetch_add(bytes, Ordering::Relaxed);
+            self.buffers.charge(bytes);
+        }
+
+        if self.buffers.exceeded() {
+            self.buffers.drop_oldest();
+        }
+
+        self.entry.producer.lock().unwrap().is_some()
+    }
+
+    pub fn close(self) {
+        self.end(None);
+    }
+
+    pub fn abort(self, err: Error) {
+        self.end(Some(err));
+    }
+
+    fn end(&self, err: Option<Error>) {
+        let producer = self.entry.producer.lock().unwrap().take();
+        if let Some(producer) = producer {
+            self.buffers.finish(&self.entry, producer, err);
+        }
+    }
+}
+
+impl Drop for BufferedGroup {
+    fn drop(&mut self) {
+        self.end(Some(Error::Cancel));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use moq_lite::Group;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn drains_once_the_relay_is_done() {
+        let global = BufferBudget::new(None);
+        global.drained().await;
+
+        let buffers = BridgeBuffers::new(None, global.clone());
+        let group = Group { sequence: 0 }.produce();
+        let written = buffers.open(group.producer);
+        assert!(written.write_frame(Bytes::from_static(b"frame")));
+        written.close();
+        assert_eq!(global.used(), 5);
+
+        // The relay session still holds the group
+        let waiting = tokio::time::timeout(Duration::from_millis(50), global.drained()).await;
+        assert!(waiting.is_err());
+
+        let drained = tokio::spawn(async move { global.drained().await });
+        drop(group.consumer);
+        tokio::time::timeout(Duration::from_secs(5), drained).await.unwrap().unwrap();
+    }
+}
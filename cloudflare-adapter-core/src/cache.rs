@@ -0,0 +1,156 @@
+//! Recent groups for late-joining relay subscribers
+//!
+//! When the relay subscribes to a track it hasn't been getting, we subscribe to CF
+//! and wait for its next group, which leaves viewers on a black screen for up to a
+//! GOP, typically right after a relay reconnect. With `--cache-groups N` each bridge
+//! keeps the last N upstream groups of every track it forwards (including the one
+//! still arriving) and serves them first when the track is requested again.
+//!
+//! `--video-retention`, `--audio-retention` and `--data-retention` set it per kind of
+//! track instead, as a number of groups or as a duration (`1s`, `500ms`): the groups that
+//! arrived within it, and the one before them so the whole span is covered. Groups
+//! forgotten to stay within them are counted in `cache_evictions_total`, by kind, to tune
+//! memory against how much late joiners get.
+//!
+//! Groups are kept as moq-lite consumers, so a cached group can be read from the start
+//! while it's still being written. Their memory isn't counted against the buffer caps.
+
+use std::collections::{HashMap, VecDeque};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use moq_lite::GroupConsumer;
+
+use crate::catalog::MediaKind;
+use crate::metrics::Counter;
+
+/// How many recent groups of a track to keep
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Retention {
+    /// The last N, including the one still arriving
+    Groups(usize),
+    /// Those that arrived this recently, and the one before them
+    Duration(Duration),
+}
+
+impl Retention {
+    fn keeps_any(&self) -> bool {
+        *self != Retention::Groups(0)
+    }
+}
+
+impl FromStr for Retention {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Ok(groups) = s.parse() {
+            return Ok(Self::Groups(groups));
+        }
+        let invalid = || format!("{s:?} isn't a number of groups or a duration like 1s or 500ms");
+        let (value, scale) = match (s.strip_suffix("ms"), s.strip_suffix('s')) {
+            (Some(value), _) => (value, 1),
+            (None, Some(value)) => (value, 1000),
+            (None, None) => return Err(invalid()),
+        };
+        let value: u64 = value.parse().map_err(|_| invalid())?;
+        Ok(Self::Duration(Duration::from_millis(value * scale)))
+    }
+}
+
+/// The retention for each kind of track
+#[derive(Clone, Copy, Debug)]
+pub struct CacheRetention {
+    pub video: Retention,
+    pub audio: Retention,
+    /// Tracks the catalog doesn't list as video or audio
+    pub data: Retention,
+}
+
+impl CacheRetention {
+    /// Whether any kind of track keeps groups at all
+    pub fn keeps_any(&self) -> bool {
+        [self.video, self.audio, self.data].iter().any(Retention::keeps_any)
+    }
+
+    fn kind(&self, kind: Option<MediaKind>) -> (Retention, &'static str) {
+        match kind {
+            Some(MediaKind::Video) => (self.video, "video"),
+            Some(MediaKind::Audio) => (self.audio, "audio"),
+            None => (self.data, "data"),
+        }
+    }
+}
+
+/// The recent groups of every track of one bridge, by upstream name
+pub struct GroupCache {
+    retention: CacheRetention,
+    tracks: Mutex<HashMap<String, VecDeque<(Instant, GroupConsumer)>>>,
+}
+
+impl GroupCache {
+    pub fn new(retention: CacheRetention) -> Arc<Self> {
+        Arc::new(Self {
+            retention,
+            tracks: Default::default(),
+        })
+    }
+
+    /// The cache of one track, kept for its kind; `kind` is None for data tracks
+    pub fn track(self: &Arc<Self>, name: &str, kind: Option<MediaKind>) -> TrackCache {
+        let (retention, label) = self.retention.kind(kind);
+        TrackCache {
+            cache: self.clone(),
+            name: name.to_string(),
+            retention,
+            kind: label,
+        }
+    }
+}
+
+/// The recent groups of one track
+pub struct TrackCache {
+    cache: Arc<GroupCache>,
+    name: String,
+    retention: Retention,
+    kind: &'static str,
+}
+
+impl TrackCache {
+    /// Remember a group that just arrived, forgetting the oldest beyond the retention
+    pub fn push(&self, group: &GroupConsumer) {
+        if !self.retention.keeps_any() {
+            return;
+        }
+
+        let mut tracks = self.cache.tracks.lock().unwrap();
+        let groups = tracks.entry(self.name.clone()).or_default();
+        groups.push_back((Instant::now(), group.clone()));
+
+        let mut evicted = 0;
+        loop {
+            let expired = match self.retention {
+                Retention::Groups(depth) => groups.len() > depth,
+                // The oldest is only needed while the one after it is recent
+                Retention::Duration(window) => groups.get(1).is_some_and(|(arrived, _)| arrived.elapsed() > window),
+            };
+            if !expired {
+                break;
+            }
+            groups.pop_front();
+            evicted += 1;
+        }
+
+        if evicted > 0 {
+            let labels = [("kind", self.kind)];
+            Counter::new("cache_evictions_total", "Groups forgotten by the group cache", &labels).add(evicted);
+        }
+    }
+
+    /// The cached groups, oldest first, each read from the start
+    pub fn groups(&self) -> Vec<GroupConsumer> {
+        let tracks = self.cache.tracks.lock().unwrap();
+        let groups = tracks.get(&self.name);
+        groups.map(|groups| groups.iter().map(|(_, group)| group.clone()).collect()).unwrap_or_default()
+    }
+}
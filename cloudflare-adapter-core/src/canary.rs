@@ -0,0 +1,17 @@
+//! Canary bridging
+//!
+//! A new adapter version is best tried on real traffic before it takes over every CF
+//! stream. `--canary 5` has an instance bridge about 5% of the streams it would otherwise
+//! bridge, and `--canary 5 --canary-rest` the other 95%, so a canary deployment and the
+//! stable one split the streams between them without overlapping. Which streams are in the
+//! sample only depends on their IDs, so it's the same on every instance and every restart,
+//! and raising the percentage only adds streams to it.
+
+/// Whether `stream_id` is among the `percent` of streams sampled
+pub fn sampled(stream_id: &str, percent: f64) -> bool {
+    // FNV-1a, which is stable across builds unlike std's hasher
+    let hash = stream_id.bytes().fold(0xcbf29ce484222325u64, |hash, byte| {
+        (hash ^ byte as u64).wrapping_mul(0x100000001b3)
+    });
+    ((hash % 10_000) as f64) < percent * 100.0
+}
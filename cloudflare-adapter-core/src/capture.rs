@@ -0,0 +1,95 @@
+//! Capture-to-republish latency
+//!
+//! Encoders that stamp frames with their wall-clock capture time, in microseconds since
+//! the Unix epoch, let us measure what viewers actually wait for. With
+//! `--capture-latency`, every media frame's timestamp is compared against the
+//! [clock](crate::clock), in two stages:
+//!
+//! - `cloudflare`: from capture to reading the frame from CF, the delay of the encoder
+//!   and the CF path, to set against ingesting to the relay directly
+//! - `republish`: from capture to writing it to the relay-side broadcast, adding ours
+//!
+//! The last frame of each bridge is exported as `bridge_capture_latency_us{stream_id,stage}`
+//! and in its [BridgeStats](crate::BridgeStats). Timestamps that can't be capture times,
+//! like presentation timestamps counting from zero, are more than a minute from our clock
+//! and ignored, so streams without them never show up.
+
+use std::sync::{Arc, OnceLock};
+use std::time::Duration;
+
+use bytes::Bytes;
+
+use crate::clock;
+use crate::health::BridgeHealth;
+use crate::media::decode_varint;
+use crate::metrics::Gauge;
+
+/// How far apart a capture timestamp and our clock can be, and still be it
+const PLAUSIBLE: Duration = Duration::from_secs(60);
+
+/// How long the last frame of a bridge with capture timestamps took
+#[derive(Clone, Copy, Debug, Default)]
+pub struct CaptureLatency {
+    /// From capture to reading the frame from CF
+    pub cloudflare: Duration,
+    /// From capture to writing it to the relay-side broadcast
+    pub republish: Duration,
+}
+
+/// Times the frames of one bridge from their capture
+pub struct CaptureMeter {
+    stream_id: Arc<str>,
+    health: Arc<BridgeHealth>,
+    /// Registered with the first capture timestamp, so streams without any don't export zeros
+    gauges: OnceLock<(Gauge, Gauge)>,
+}
+
+impl CaptureMeter {
+    pub fn new(stream_id: &str, health: Arc<BridgeHealth>) -> Arc<Self> {
+        Arc::new(Self {
+            stream_id: stream_id.into(),
+            health,
+            gauges: OnceLock::new(),
+        })
+    }
+
+    /// The capture timestamp of a hang frame just read from CF, and when it was read
+    pub fn read(&self, frame: &Bytes) -> Option<(Duration, Duration)> {
+        let (timestamp, _) = decode_varint(frame)?;
+        Some((Duration::from_micros(timestamp), clock::now()))
+    }
+
+    /// A frame `read` earlier was written to the relay-side broadcast
+    pub fn republished(&self, (captured, read): (Duration, Duration)) {
+        let now = clock::now();
+        if now.abs_diff(captured) > PLAUSIBLE {
+            return;
+        }
+
+        // Clocks a little apart can put capture after we read it
+        let latency = CaptureLatency {
+            cloudflare: read.saturating_sub(captured),
+            republish: now.saturating_sub(captured),
+        };
+        let (cloudflare, republish) = self.gauges.get_or_init(|| {
+            let gauge = |stage| {
+                let labels = [("stream_id", &*self.stream_id), ("stage", stage)];
+                Gauge::new("bridge_capture_latency_us", "Frame latency from its capture time, by stage", &labels)
+            };
+            (gauge("cloudflare"), gauge("republish"))
+        });
+        cloudflare.set(latency.cloudflare.as_micros() as i64);
+        republish.set(latency.republish.as_micros() as i64);
+        self.health.captured(latency);
+    }
+}
+
+impl Drop for CaptureMeter {
+    // Nothing's measured anymore, rather than showing the last frame forever
+    fn drop(&mut self) {
+        if let Some((cloudflare, republish)) = self.gauges.get() {
+            cloudflare.set(0);
+            republish.set(0);
+        }
+    }
+}
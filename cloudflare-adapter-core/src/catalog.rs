@@ -0,0 +1,215 @@
+//! hang catalog rewriting
+//!
+//! hang publishers describe their renditions in a JSON `catalog.json` track. When we
+//! drop tracks on the way through, we also drop them from the catalog so relay-side
+//! players never try to subscribe to something we won't serve, and rename any
+//! aliased tracks to their relay-side names. Priority overrides are written in too, see
+//! [crate::priority].
+
+use std::collections::{HashMap, HashSet};
+use std::sync::Mutex;
+
+use bytes::Bytes;
+use serde_json::Value;
+
+use crate::alias::TrackAliases;
+use crate::filter::TrackFilter;
+use crate::priority::PriorityOverrides;
+
+/// The name of the hang catalog track
+pub const CATALOG_TRACK: &str = "catalog.json";
+
+/// Whether a rendition carries video or audio
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MediaKind {
+    Video,
+    Audio,
+}
+
+/// A single rendition listed in a catalog
+#[derive(Clone, Debug)]
+pub struct Rendition {
+    pub track: String,
+    pub kind: MediaKind,
+    /// The WebCodecs-style decoder config (`codec`, `description`, `codedWidth`, ...)
+    pub config: Value,
+}
+
+/// List every rendition in a catalog frame, or None if it can't be parsed
+pub fn renditions(frame: &[u8]) -> Option<Vec<Rendition>> {
+    let catalog = serde_json::from_slice::<Value>(frame).ok()?;
+    let mut renditions = Vec::new();
+
+    for (section, kind) in [("video", MediaKind::Video), ("audio", MediaKind::Audio)] {
+        let Some(section) = catalog.get(section) else {
+            continue;
+        };
+
+        if let Some(map) = section.get("renditions").and_then(Value::as_object) {
+            for (track, config) in map {
+                renditions.push(Rendition {
+                    track: track.clone(),
+                    kind,
+                    config: config.clone(),
+                });
+            }
+        } else if let Some(list) = section.as_array() {
+            for entry in list {
+                if let Some(track) = entry.pointer("/track/name").and_then(Value::as_str) {
+                    renditions.push(Rendition {
+                        track: track.to_string(),
+                        kind,
+                        config: entry.get("config").cloned().unwrap_or(Value::Null),
+                    });
+                }
+            }
+        }
+    }
+
+    Some(renditions)
+}
+
+/// Upper bounds for the video renditions forwarded by one bridge
+#[derive(Clone, Debug, Default)]
+pub struct LayerLimits {
+    pub max_height: Option<u64>,
+    pub max_bitrate: Option<u64>,
+}
+
+impl LayerLimits {
+    /// Returns true if a video rendition with this config is over the limits
+    fn exceeds(&self, config: &Value) -> bool {
+        let over = |field: &str, max: Option<u64>| match (config.get(field).and_then(Value::as_u64), max) {
+            (Some(value), Some(max)) => value > max,
+            _ => false,
+        };
+
+        over("codedHeight", self.max_height) || over("bitrate", self.max_bitrate)
+    }
+}
+
+/// Decides which renditions a bridge forwards, based on track names and the catalog
+///
+/// Filters and limits apply to the upstream (CF) track names.
+pub struct CatalogFilter {
+    filter: TrackFilter,
+    limits: LayerLimits,
+    aliases: TrackAliases,
+    priorities: PriorityOverrides,
+    // Upstream renditions removed from the most recent catalog
+    dropped: Mutex<HashSet<String>>,
+    // The kind of every upstream rendition in the most recent catalog
+    kinds: Mutex<HashMap<String, MediaKind>>,
+}
+
+impl CatalogFilter {
+    pub fn new(filter: TrackFilter, limits: LayerLimits, aliases: TrackAliases, priorities: PriorityOverrides) -> Self {
+        Self {
+            filter,
+            limits,
+            aliases,
+            priorities,
+            dropped: Default::default(),
+            kinds: Default::default(),
+        }
+    }
+
+    /// Map a track requested by the relay to the upstream track, or None if it isn't bridged
+    pub fn resolve(&self, track: &str) -> Option<String> {
+        if track == CATALOG_TRACK {
+            return Some(track.to_string());
+        }
+
+        let upstream = self.aliases.upstream(track)?;
+        let allowed = self.filter.allows(upstream) && !self.dropped.lock().unwrap().contains(upstream);
+        allowed.then(|| upstream.to_string())
+    }
+
+    /// Whether an upstream track is video or audio, or None if the catalog doesn't list it
+    pub fn kind(&self, upstream: &str) -> Option<MediaKind> {
+        self.kinds.lock().unwrap().get(upstream).copied()
+    }
+
+    /// The priority to subscribe to an upstream track at, or None to keep the one asked for
+    pub fn priority(&self, upstream: &str) -> Option<u8> {
+        self.priorities.get(upstream, self.kind(upstream))
+    }
+
+    /// Remove filtered renditions from a catalog frame and apply aliases and priorities
+    ///
+    /// Unparsable frames are passed through untouched.
+    pub fn rewrite(&self, frame: Bytes) -> Bytes {
+        let Ok(mut catalog) = serde_json::from_slice::<Value>(&frame) else {
+            tracing::warn!("failed to parse catalog, forwarding as-is");
+            return frame;
+        };
+
+        let mut dropped = HashSet::new();
+        let mut kinds = HashMap::new();
+
+        for (kind, video) in [("video", true), ("audio", false)] {
+            let Some(section) = catalog.get_mut(kind) else {
+                continue;
+            };
+            let media = if video { MediaKind::Video } else { MediaKind::Audio };
+
+            retain_renditions(section, |name, config| {
+                kinds.insert(name.to_string(), media);
+                let keep = self.filter.allows(name) && !(video && self.limits.exceeds(config));
+                if !keep {
+                    dropped.insert(name.to_string());
+                }
+                keep
+            });
+
+            self.priorities.rewrite(section, media);
+            rename_renditions(section, |name| self.aliases.downstream(name).to_string());
+        }
+
+        if !dropped.is_empty() {
+            tracing::debug!(?dropped, "removed renditions from catalog");
+        }
+
+        *self.dropped.lock().unwrap() = dropped;
+        *self.kinds.lock().unwrap() = kinds;
+
+        match serde_json::to_vec(&catalog) {
+            Ok(json) => json.into(),
+            Err(_) => frame,
+        }
+    }
+}
+
+/// Keep the renditions of a catalog section for which `keep(name, config)` returns true
+///
+/// Handles both the current `{"renditions": {name: config}}` layout and the older
+/// `[{"track": {"name": ..}, "config": {..}}]` list layout.
+fn retain_renditions(section: &mut Value, mut keep: impl FnMut(&str, &Value) -> bool) {
+    if let Some(renditions) = section.get_mut("renditions").and_then(Value::as_object_mut) {
+        renditions.retain(|name, config| keep(name, config));
+    } else if let Some(list) = section.as_array_mut() {
+        list.retain(|entry| {
+            let name = entry.pointer("/track/name").and_then(Value::as_str);
+            let config = entry.get("config").unwrap_or(&Value::Null);
+            name.is_none_or(|name| keep(name, config))
+        });
+    }
+}
+
+/// Rename the renditions of a catalog section, in either layout
+fn rename_renditions(section: &mut Value, rename: impl Fn(&str) -> String) {
+    if let Some(renditions) = section.get_mut("renditions").and_then(Value::as_object_mut) {
+        let old = std::mem::take(renditions);
+        for (name, config) in old {
+            renditions.insert(rename(&name), config);
+        }
+    } else if let Some(list) = section.as_array_mut() {
+        for entry in list {
+            if let Some(name) = entry.pointer_mut("/track/name") {
+                if let Some(renamed) = name.as_str().map(&rename) {
+                    *name = Value::String(renamed);
+                }
+            }
+        }
+    }
+}
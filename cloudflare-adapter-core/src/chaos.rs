@@ -0,0 +1,177 @@
+//! Fault injection
+//!
+//! `--chaos` makes the adapter misbehave on purpose, so its recovery can be checked in
+//! staging before it's trusted with a live event. Each fault is written `name:arg`:
+//!
+//! - `delay-objects:<ms>[@<percent>]` holds back that share of objects (all of them by
+//!   default) for up to `ms`, delaying the rest of their group with them
+//! - `drop-groups:<percent>` skips that share of groups, as if they never arrived
+//! - `kill-session:<secs>` closes a connected CF session every `secs`
+//! - `registry-errors:<percent>` fails that share of discovery lists
+//!
+//! Injected faults are counted in `chaos_faults_total`, by fault, so dashboards and
+//! alerts can be checked against what was done to the adapter. Faults on a schedule
+//! are [soak](crate::soak) scenarios.
+
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use rand::Rng;
+
+use crate::discovery::{ChangeFuture, ListFuture, StreamDiscovery};
+use crate::metrics::Counter;
+use crate::pool::SessionPool;
+
+/// One fault, written as `name:arg`
+#[derive(Clone, Debug)]
+pub enum ChaosFault {
+    DelayObjects { max: Duration, percent: f64 },
+    DropGroups { percent: f64 },
+    KillSession { every: Duration },
+    RegistryErrors { percent: f64 },
+}
+
+impl FromStr for ChaosFault {
+    type Err = String;
+
+    fn from_str(spec: &str) -> Result<Self, Self::Err> {
+        let (name, arg) = spec.split_once(':').ok_or_else(|| format!("{spec:?} should be name:arg"))?;
+        let (name, arg) = (name.trim(), arg.trim());
+
+        match name {
+            "delay-objects" => {
+                let (max, percent) = match arg.split_once('@') {
+                    Some((max, percent)) => (max, parse_percent(percent)?),
+                    None => (arg, 100.0),
+                };
+                let max = max.trim().parse().map_err(|_| format!("{max:?} isn't a delay in milliseconds"))?;
+                Ok(Self::DelayObjects {
+                    max: Duration::from_millis(max),
+                    percent,
+                })
+            }
+            "drop-groups" => Ok(Self::DropGroups {
+                percent: parse_percent(arg)?,
+            }),
+            "kill-session" => match arg.parse() {
+                Ok(secs) if secs > 0 => Ok(Self::KillSession {
+                    every: Duration::from_secs(secs),
+                }),
+                _ => Err(format!("{arg:?} isn't an interval in seconds")),
+            },
+            "registry-errors" => Ok(Self::RegistryErrors {
+                percent: parse_percent(arg)?,
+            }),
+            _ => Err(format!("unknown fault {name:?}")),
+        }
+    }
+}
+
+fn parse_percent(percent: &str) -> Result<f64, String> {
+    match percent.trim().trim_end_matches('%').parse::<f64>() {
+        Ok(percent) if (0.0..=100.0).contains(&percent) => Ok(percent),
+        _ => Err(format!("{percent:?} isn't a percentage")),
+    }
+}
+
+/// The faults to inject, none unless `--chaos` is given
+#[derive(Debug, Default)]
+pub struct Chaos {
+    delay: Option<(Duration, f64)>,
+    drop_groups: Option<f64>,
+    kill_session: Option<Duration>,
+    registry_errors: Option<f64>,
+}
+
+impl Chaos {
+    /// Later faults of the same kind replace earlier ones
+    pub fn new(faults: &[ChaosFault]) -> Arc<Self> {
+        let mut chaos = Self::default();
+        for fault in faults {
+            match fault.clone() {
+                ChaosFault::DelayObjects { max, percent } => chaos.delay = Some((max, percent)),
+                ChaosFault::DropGroups { percent } => chaos.drop_groups = Some(percent),
+                ChaosFault::KillSession { every } => chaos.kill_session = Some(every),
+                ChaosFault::RegistryErrors { percent } => chaos.registry_errors = Some(percent),
+            }
+        }
+        if !faults.is_empty() {
+            tracing::warn!(?faults, "injecting faults");
+        }
+        Arc::new(chaos)
+    }
+
+    /// How long to hold back the next object, if at all
+    pub fn object_delay(&self) -> Option<Duration> {
+        let (max, percent) = self.delay?;
+        if !roll(percent) {
+            return None;
+        }
+        injected("delay-objects");
+        Some(max.mul_f64(rand::rng().random()))
+    }
+
+    /// Whether to drop the next group
+    pub fn drop_group(&self) -> bool {
+        let dropped = self.drop_groups.is_some_and(roll);
+        if dropped {
+            injected("drop-groups");
+        }
+        dropped
+    }
+
+    /// Put failing lists in front of `discovery`, if registry errors are injected
+    pub fn discovery(self: &Arc<Self>, discovery: Arc<dyn StreamDiscovery>) -> Arc<dyn StreamDiscovery> {
+        match self.registry_errors {
+            Some(percent) => Arc::new(FailingDiscovery { inner: discovery, percent }),
+            None => discovery,
+        }
+    }
+
+    /// Kill a CF session every interval, forever
+    pub async fn kill_sessions(&self, sessions: &SessionPool) {
+        let Some(every) = self.kill_session else {
+            return std::future::pending().await;
+        };
+
+        let mut ticks = tokio::time::interval(every);
+        ticks.tick().await;
+        loop {
+            ticks.tick().await;
+            if let Some(index) = sessions.kill() {
+                tracing::warn!(session = index, "killing cloudflare session");
+                injected("kill-session");
+            }
+        }
+    }
+}
+
+/// Whether something happening `percent` of the time happens now
+fn roll(percent: f64) -> bool {
+    rand::rng().random::<f64>() * 100.0 < percent
+}
+
+pub(crate) fn injected(fault: &str) {
+    Counter::new("chaos_faults_total", "Faults injected with --chaos", &[("fault", fault)]).inc();
+}
+
+/// Fails some of the lists of the discovery it wraps
+struct FailingDiscovery {
+    inner: Arc<dyn StreamDiscovery>,
+    percent: f64,
+}
+
+impl StreamDiscovery for FailingDiscovery {
+    fn list(&self) -> ListFuture<'_> {
+        if roll(self.percent) {
+            injected("registry-errors");
+            return Box::pin(async { anyhow::bail!("injected registry error") });
+        }
+        self.inner.list()
+    }
+
+    fn next_change(&self) -> ChangeFuture<'_> {
+        self.inner.next_change()
+    }
+}
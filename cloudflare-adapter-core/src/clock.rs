@@ -0,0 +1,101 @@
+//! The wall clock latency is measured against
+//!
+//! Capture-to-republish latency (see [capture](crate::capture)) compares our clock with
+//! the encoder's, so it's only as good as the two agree. By default the system clock is
+//! trusted, which is right where chrony or ptp4l/phc2sys keep it disciplined to NTP or
+//! PTP. Where they don't, `--ntp-server` asks an NTP server over SNTP every
+//! `--ntp-interval` and corrects our readings by the offset it finds, exported as
+//! `clock_offset_us` along with the round trip the offset was measured over as
+//! `clock_sync_delay_us`, which bounds its error.
+
+use std::sync::atomic::{AtomicI64, Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::Context;
+use tokio::net::UdpSocket;
+
+use crate::metrics::{Counter, Gauge};
+
+/// Seconds from the NTP epoch (1900) to the Unix one
+const NTP_EPOCH: u64 = 2_208_988_800;
+
+/// How long to wait for the server to answer
+const TIMEOUT: Duration = Duration::from_secs(2);
+
+/// What to add to the system clock, in microseconds
+static OFFSET: AtomicI64 = AtomicI64::new(0);
+
+/// The time since the Unix epoch, corrected by the last sync
+pub fn now() -> Duration {
+    let system = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+    let offset = OFFSET.load(Ordering::Relaxed);
+    if offset >= 0 {
+        system + Duration::from_micros(offset as u64)
+    } else {
+        system.saturating_sub(Duration::from_micros(offset.unsigned_abs()))
+    }
+}
+
+/// Sync with `server` (`host[:port]`) every `every`, forever
+pub async fn sync(server: String, every: Duration) {
+    let server = if server.contains(':') { server } else { format!("{server}:123") };
+    let mut ticks = tokio::time::interval(every);
+    loop {
+        ticks.tick().await;
+        match query(&server).await {
+            Ok((offset, delay)) => {
+                tracing::debug!(server, offset_us = offset, delay_us = delay, "clock synced");
+                OFFSET.store(offset, Ordering::Relaxed);
+                Gauge::new("clock_offset_us", "Correction applied to the system clock, from --ntp-server", &[]).set(offset);
+                Gauge::new("clock_sync_delay_us", "Round trip to --ntp-server at the last sync", &[]).set(delay);
+            }
+            Err(err) => {
+                tracing::warn!(server, err = format!("{err:#}"), "clock sync failed");
+                Counter::new("clock_sync_failures_total", "Failed syncs with --ntp-server", &[]).inc();
+            }
+        }
+    }
+}
+
+/// Ask `server` the time, returning our clock's offset from it and the round trip, in microseconds
+async fn query(server: &str) -> anyhow::Result<(i64, i64)> {
+    let socket = UdpSocket::bind("0.0.0.0:0").await?;
+    socket.connect(server).await.with_context(|| format!("can't reach {server}"))?;
+
+    // Version 4, client mode, our transmit time for the server to echo
+    let mut request = [0u8; 48];
+    request[0] = 0x23;
+    let sent = system_micros();
+    request[40..48].copy_from_slice(&to_ntp(sent).to_be_bytes());
+    socket.send(&request).await?;
+
+    let mut response = [0u8; 48];
+    let size = tokio::time::timeout(TIMEOUT, socket.recv(&mut response)).await.context("timed out")??;
+    let arrived = system_micros();
+    anyhow::ensure!(size == 48, "short response ({size} bytes)");
+    anyhow::ensure!(response[0] & 0x07 == 4, "not a server response");
+    anyhow::ensure!(response[24..32] == request[40..48], "response to another request");
+    anyhow::ensure!(response[1] != 0, "server isn't synchronized");
+
+    let field = |at: usize| from_ntp(u64::from_be_bytes(response[at..at + 8].try_into().unwrap()));
+    let (received, transmitted) = (field(32), field(40));
+    let offset = ((received - sent) + (transmitted - arrived)) / 2;
+    let delay = (arrived - sent) - (transmitted - received);
+    Ok((offset, delay))
+}
+
+fn system_micros() -> i64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_micros() as i64
+}
+
+/// Unix microseconds as an NTP timestamp: seconds since 1900 and a 32-bit fraction
+fn to_ntp(micros: i64) -> u64 {
+    let (secs, micros) = (micros as u64 / 1_000_000, micros as u64 % 1_000_000);
+    ((secs + NTP_EPOCH) << 32) | ((micros << 32) / 1_000_000)
+}
+
+fn from_ntp(timestamp: u64) -> i64 {
+    let secs = (timestamp >> 32).saturating_sub(NTP_EPOCH);
+    let micros = ((timestamp & 0xffff_ffff) * 1_000_000) >> 32;
+    (secs * 1_000_000 + micros) as i64
+}
@@ -0,0 +1,1054 @@
+//! Adapter options
+//!
+//! Every option can be given on the command line or in the environment, and most in a
+//! config file too (see `settings`).
+
+#[cfg(feature = "webrtc")]
+use std::net::IpAddr;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use anyhow::Context;
+use clap::Parser;
+use url::Url;
+
+use crate::admarker::AdMarkerOptions;
+use crate::announce::AnnounceOptions;
+use crate::backoff::BackoffPolicy;
+use crate::backpressure::{Backpressure, DropPolicy};
+use crate::breaker::BreakerOptions;
+use crate::cache::{CacheRetention, Retention};
+use crate::canary;
+use crate::chaos::ChaosFault;
+use crate::connect::{ConnectOptions, IpFamily, TransportKind};
+use crate::discovery::Discovery;
+use crate::duplicate::DuplicatePolicy;
+use crate::filter::{glob_match, Scoped, StreamPattern, TrackFilter};
+use crate::health::EvictOptions;
+#[cfg(feature = "history")]
+use crate::history::HistoryOptions;
+use crate::interceptor::BuiltinInterceptor;
+#[cfg(feature = "redis")]
+use crate::lease::LeaseOptions;
+use crate::namespace::{self, NamespaceEncoding};
+#[cfg(feature = "http")]
+use crate::package::{Formats, PackageOptions};
+use crate::paths::Collision;
+use crate::pin::SpkiPin;
+use crate::priority::{PriorityOverrides, TrackPriority};
+use crate::proxy::Proxy;
+use crate::quic::QuicSetting;
+use crate::record::RecordOptions;
+use crate::registry::StreamInfo;
+use crate::relay::NamedRelay;
+use crate::selftest::Endpoints;
+use crate::shed::ShedOptions;
+use crate::soak::SoakStep;
+use crate::spill::SpillOptions;
+#[cfg(feature = "registry")]
+use crate::stats::StatsOptions;
+#[cfg(feature = "push")]
+use crate::push::PushOptions;
+#[cfg(feature = "ffmpeg")]
+use crate::srt::SrtIngest;
+use crate::throttle::TrackRate;
+use crate::token::{SigningKey, TokenIssuer};
+#[cfg(feature = "ffmpeg")]
+use crate::thumbnail::ThumbnailOptions;
+use crate::timestamp::RebaseMode;
+use crate::upstream::{UpstreamKind, UpstreamQuirk, UpstreamQuirks};
+
+#[derive(Parser, Clone, Debug)]
+#[command(name = "cloudflare-adapter")]
+#[command(about = "Bridges moq-lite relay with CloudFlare Draft 14 network")]
+pub struct AdapterConfig {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+
+    /// Read options from this TOML file, or YAML for `.yaml` and `.yml`; the environment and command line override it
+    #[arg(long, env = "CONFIG_FILE")]
+    pub config: Option<PathBuf>,
+
+    /// Also apply this `[profiles.<name>]` table of the config file, e.g. dev, staging or prod
+    #[arg(long, requires = "config", env = "PROFILE")]
+    pub profile: Option<String>,
+
+    /// Run only this `[tenants.<name>]` table of the config file, rather than every tenant
+    #[arg(long, requires = "config", env = "TENANT")]
+    pub tenant: Option<String>,
+
+    /// How much the adapter and the moq libraries log: error, warn, info, debug or trace
+    #[arg(long, default_value = "info", env = "LOG_LEVEL")]
+    pub log_level: String,
+
+    /// Check the config file for changes to stream rules this often (seconds)
+    #[arg(long, requires = "config", env = "RELOAD_INTERVAL", value_parser = clap::value_parser!(u64).range(1..))]
+    pub reload_interval: Option<u64>,
+
+    /// On reload, stop running bridges that no longer follow the stream rules
+    #[arg(long, requires = "reload_interval", env = "RELOAD_TEARDOWN")]
+    pub reload_teardown: bool,
+
+    /// Your moq-lite relay URL (e.g., https://us-central.earthseed.live)
+    #[arg(long, env = "EARTHSEED_RELAY_URL")]
+    pub relay_url: String,
+
+    /// CloudFlare relay URL
+    #[arg(long, env = "CLOUDFLARE_RELAY_URL", default_value = "https://relay-next.cloudflare.mediaoverquic.com")]
+    pub cloudflare_url: String,
+
+    /// What kind of Draft 14 relay `--cloudflare-url` is: `cloudflare`, or `draft14` (alias `moq-rs`,
+    /// `moxygen`) for relays that announce their broadcasts
+    #[arg(long, value_enum, default_value_t, env = "UPSTREAM")]
+    pub upstream: UpstreamKind,
+
+    /// Upstream quirks to set over the ones `--upstream` picks, as `name=true|false`
+    /// (announce-remote, redirects)
+    #[arg(long = "upstream-quirk", env = "UPSTREAM_QUIRKS", value_delimiter = ',')]
+    pub upstream_quirks: Vec<UpstreamQuirk>,
+
+    /// First delay before reconnecting to the relay or CloudFlare, or re-polling a failing registry (milliseconds)
+    #[arg(long, default_value = "1000", env = "BACKOFF_INITIAL")]
+    pub backoff_initial: u64,
+
+    /// How much each consecutive failure multiplies the retry delay by
+    #[arg(long, default_value = "2", env = "BACKOFF_MULTIPLIER")]
+    pub backoff_multiplier: f64,
+
+    /// Cap on the retry delay (milliseconds)
+    #[arg(long, default_value = "60000", env = "BACKOFF_MAX")]
+    pub backoff_max: u64,
+
+    /// Random spread applied to each retry delay (percent)
+    #[arg(long, default_value = "20", env = "BACKOFF_JITTER")]
+    pub backoff_jitter: f64,
+
+    /// The CF namespace of each stream; `{stream_id}` and other `{field}`s of its registry entry are filled in
+    #[arg(long, default_value = "earthseed.live/{stream_id}", env = "CF_NAMESPACE_TEMPLATE")]
+    pub cf_namespace_template: String,
+
+    /// What a `/` in a field filled into a CF namespace does: `split` it into more tuple elements,
+    /// `escape` it as `%2F`, or `reject` the stream
+    #[arg(long, value_enum, default_value_t, env = "NAMESPACE_ENCODING")]
+    pub namespace_encoding: NamespaceEncoding,
+
+    /// Sessions to keep open to CloudFlare, spreading bridges across them
+    #[arg(long, default_value = "1", env = "CF_SESSIONS")]
+    pub cf_sessions: usize,
+
+    /// Streams one CF session carries well, for the autoscaling signals' utilization and capacity
+    #[arg(long, default_value = "30", env = "SESSION_CAPACITY")]
+    pub session_capacity: usize,
+
+    /// QUIC transport settings for the relay connection, as `key=value` (congestion, stream-window,
+    /// window, send-window, idle-timeout, keep-alive, dscp, priority)
+    #[arg(long = "relay-quic", env = "RELAY_QUIC", value_delimiter = ',')]
+    pub relay_quic: Vec<QuicSetting>,
+
+    /// QUIC transport settings for the CloudFlare sessions, like `--relay-quic`
+    #[arg(long = "cf-quic", env = "CF_QUIC", value_delimiter = ',')]
+    pub cf_quic: Vec<QuicSetting>,
+
+    /// SOCKS5 proxy to reach the relay through, as `socks5://[user:password@]host:port`; it has to
+    /// support UDP ASSOCIATE
+    #[arg(long, env = "RELAY_PROXY")]
+    pub relay_proxy: Option<Proxy>,
+
+    /// SOCKS5 proxy to reach CloudFlare through, like `--relay-proxy`
+    #[arg(long, env = "CF_PROXY")]
+    pub cf_proxy: Option<Proxy>,
+
+    /// Address family to reach the relay over; `any` races IPv4 and IPv6 when it has both
+    #[arg(long, value_enum, default_value = "any", env = "RELAY_IP_FAMILY")]
+    pub relay_ip_family: IpFamily,
+
+    /// Address family to reach CloudFlare over, like `--relay-ip-family`
+    #[arg(long, value_enum, default_value = "any", env = "CF_IP_FAMILY")]
+    pub cf_ip_family: IpFamily,
+
+    /// How to reach the relay: `auto` goes by the URL's scheme, WebTransport for https:// and raw
+    /// QUIC for moql:// and moqt://
+    #[arg(long, value_enum, default_value = "auto", env = "RELAY_TRANSPORT")]
+    pub relay_transport: TransportKind,
+
+    /// ALPN to offer the relay, in place of the transport's (h3, or moql for raw QUIC)
+    #[arg(long, env = "RELAY_ALPN")]
+    pub relay_alpn: Option<String>,
+
+    /// How to reach CloudFlare, like `--relay-transport`
+    #[arg(long, value_enum, default_value = "auto", env = "CF_TRANSPORT")]
+    pub cf_transport: TransportKind,
+
+    /// ALPN to offer CloudFlare, in place of the transport's (h3, or moqt for raw QUIC)
+    #[arg(long, env = "CF_ALPN")]
+    pub cf_alpn: Option<String>,
+
+    /// Send the session setup to the relay as 0-RTT data when reconnecting, if it allows it
+    #[arg(long, env = "RELAY_ZERO_RTT")]
+    pub relay_zero_rtt: bool,
+
+    /// Send the session setup to CloudFlare as 0-RTT data when reconnecting, like `--relay-zero-rtt`
+    #[arg(long, env = "CF_ZERO_RTT")]
+    pub cf_zero_rtt: bool,
+
+    /// Redirects to follow when CloudFlare sends a session elsewhere; 0 fails the connection instead
+    #[arg(long, default_value = "3", env = "CF_MAX_REDIRECTS")]
+    pub cf_max_redirects: usize,
+
+    /// Only use CF connections whose certificate key is one of these, as `sha256//<base64 SPKI digest>`
+    #[arg(long = "cf-pin", env = "CF_PINS", value_delimiter = ',')]
+    pub cf_pins: Vec<SpkiPin>,
+
+    /// How often to check whether the routes to the relay and CloudFlare changed, to migrate
+    /// their QUIC connections to the new path (seconds, 0 to disable)
+    #[arg(long, default_value = "2", env = "NETWORK_WATCH")]
+    pub network_watch: u64,
+
+    /// How long to wait for a CF broadcast to appear after announcing it (milliseconds)
+    #[arg(long, default_value = "2000", env = "ANNOUNCE_TIMEOUT")]
+    pub announce_timeout: u64,
+
+    /// How many CF broadcasts to announce at once, e.g. when bridging everything after a restart
+    #[arg(long, default_value = "32", env = "ANNOUNCE_CONCURRENCY")]
+    pub announce_concurrency: usize,
+
+    /// How many times to announce a CF broadcast before giving up on the bridge
+    #[arg(long, default_value = "3", env = "ANNOUNCE_ATTEMPTS")]
+    pub announce_attempts: u32,
+
+    /// How long a bridge whose CF session dropped waits to move to another one before ending (seconds)
+    #[arg(long, default_value = "30", env = "CF_MOVE_TIMEOUT")]
+    pub cf_move_timeout: u64,
+
+    /// How long a bridge whose broadcast ended, e.g. while its publisher reconnects, keeps its
+    /// relay side up waiting for the stream to come back before ending (seconds)
+    #[arg(long, env = "FLAP_GRACE")]
+    pub flap_grace: Option<u64>,
+
+    /// Where the streams to bridge come from: http polls the registry, webhook takes its pushes
+    #[arg(long, value_enum, default_value = "http", env = "DISCOVERY", requires_if("webhook", "http_listen"))]
+    pub discovery: Discovery,
+
+    /// Your stream registry API (e.g., https://earthseed.live/api/stats/greet)
+    #[arg(long, required_if_eq("discovery", "http"), env = "STREAM_REGISTRY_URL")]
+    pub registry_url: Option<String>,
+
+    /// Require this bearer token on stream lists pushed to POST /discovery
+    #[arg(long, env = "WEBHOOK_TOKEN")]
+    pub webhook_token: Option<String>,
+
+    /// The stream IDs to bridge with `--discovery static`
+    #[arg(long = "static-stream", required_if_eq("discovery", "static"), env = "STATIC_STREAMS", value_delimiter = ',')]
+    pub static_streams: Vec<String>,
+
+    /// The `--record-registry` file to list streams from with `--discovery replay`
+    #[arg(long, required_if_eq("discovery", "replay"), env = "REPLAY_REGISTRY")]
+    pub replay_registry: Option<PathBuf>,
+
+    /// Record every stream list to this file, one JSON line each, to replay later with
+    /// `--discovery replay`
+    #[arg(long, env = "RECORD_REGISTRY")]
+    pub record_registry: Option<PathBuf>,
+
+    /// Also bridge every broadcast CF announces under these namespace prefixes, e.g. `earthseed.live/*`,
+    /// as stream IDs of the rest of their path
+    #[arg(long = "bridge-prefix", env = "BRIDGE_PREFIX", value_delimiter = ',')]
+    pub bridge_prefixes: Vec<String>,
+
+    /// Bridge registry streams with any of these `origin` labels
+    #[arg(long = "origin", default_value = "cloudflare", env = "REGISTRY_ORIGINS", value_delimiter = ',')]
+    pub origins: Vec<String>,
+
+    /// Only bridge registry streams whose ID matches one of these globs, or `re:` regexes (default: all)
+    #[arg(long = "include-stream", env = "INCLUDE_STREAMS", value_delimiter = ',')]
+    pub include_streams: Vec<StreamPattern>,
+
+    /// Never bridge registry streams whose ID matches one of these globs, or `re:` regexes
+    #[arg(long = "exclude-stream", env = "EXCLUDE_STREAMS", value_delimiter = ',')]
+    pub exclude_streams: Vec<StreamPattern>,
+
+    /// Only bridge this share of the streams (percent), picked by stream ID, to canary a new version
+    #[arg(long, env = "CANARY")]
+    pub canary: Option<f64>,
+
+    /// Bridge the streams `--canary` leaves out instead, for the instances it's compared against
+    #[arg(long, env = "CANARY_REST")]
+    pub canary_rest: bool,
+
+    /// Consecutive registry failures before polling is suspended, keeping running bridges as they are
+    #[arg(long, default_value = "5", env = "REGISTRY_FAILURE_THRESHOLD")]
+    pub registry_failure_threshold: u32,
+
+    /// How often to probe the registry while polling is suspended (seconds)
+    #[arg(long, default_value = "60", env = "REGISTRY_PROBE_INTERVAL")]
+    pub registry_probe_interval: u64,
+
+    /// JWT token for connecting to your relay as a cluster node
+    #[arg(long, env = "RELAY_TOKEN")]
+    pub relay_token: Option<String>,
+
+    /// JWK with an HMAC key to mint each bridge a token that only lets it publish its own
+    /// broadcast, publishing it over a relay session of its own
+    #[arg(long, env = "RELAY_TOKEN_KEY", conflicts_with = "relay_token_url")]
+    pub relay_token_key: Option<String>,
+
+    /// Token service to get each bridge's token from, like `--relay-token-key`
+    #[arg(long, env = "RELAY_TOKEN_URL")]
+    pub relay_token_url: Option<String>,
+
+    /// Other relays a stream's registry entry can name in its `relay` field, as `name=url`
+    #[arg(long = "relay-target", env = "RELAY_TARGETS", value_delimiter = ',')]
+    pub relay_targets: Vec<NamedRelay>,
+
+    /// Share the listed streams with other replicas through leases in this Redis, so each
+    /// is bridged by exactly one of them
+    #[arg(long, env = "LEASE_REDIS_URL")]
+    pub lease_redis_url: Option<String>,
+
+    /// How long a replica's leases outlive it, and so how soon its streams move (seconds)
+    #[arg(long, default_value = "10", env = "LEASE_TTL")]
+    pub lease_ttl: u64,
+
+    /// Prefix of the lease keys; replicas sharing streams must use the same one
+    #[arg(long, default_value = "moq-adapter:lease:", env = "LEASE_PREFIX")]
+    pub lease_prefix: String,
+
+    /// How often to list the streams to bridge, even if the discovery didn't say they changed (seconds)
+    #[arg(long, default_value = "5", env = "POLL_INTERVAL")]
+    pub poll_interval: u64,
+
+    /// Restart bridges, or exit if it's anything else, that make no progress for this long (seconds)
+    #[arg(long, env = "WATCHDOG_TIMEOUT", value_parser = clap::value_parser!(u64).range(1..))]
+    pub watchdog_timeout: Option<u64>,
+
+    /// Exit with an error unless the relay and a CF session connect this soon after starting,
+    /// instead of retrying forever (seconds, 30 if given without a value)
+    #[arg(long, num_args = 0..=1, default_missing_value = "30", env = "REQUIRE_CONNECTIONS_ON_START")]
+    pub require_connections_on_start: Option<u64>,
+
+    /// Push metrics to the Prometheus Pushgateway at this URL, for networks nothing can scrape
+    #[arg(long, env = "METRICS_PUSH_URL")]
+    pub metrics_push_url: Option<String>,
+
+    /// How often to push metrics (seconds)
+    #[arg(long, default_value = "15", env = "METRICS_PUSH_INTERVAL")]
+    pub metrics_push_interval: u64,
+
+    /// The Pushgateway job to push metrics under
+    #[arg(long, default_value = "cloudflare-adapter", env = "METRICS_PUSH_JOB")]
+    pub metrics_push_job: String,
+
+    /// POST what each bridge did (throughput, drops, stalls) to this stats API URL every interval
+    #[arg(long, env = "STATS_URL")]
+    pub stats_url: Option<String>,
+
+    /// How often to post bridge stats (seconds)
+    #[arg(long, default_value = "10", env = "STATS_INTERVAL")]
+    pub stats_interval: u64,
+
+    /// Report panics and bridge failures to Sentry at this DSN
+    #[arg(long, env = "SENTRY_DSN")]
+    pub sentry_dsn: Option<String>,
+
+    /// The Sentry environment to report under, e.g. `production`
+    #[arg(long, requires = "sentry_dsn", env = "SENTRY_ENVIRONMENT")]
+    pub sentry_environment: Option<String>,
+
+    /// Save the running bridges to this file, and set them up again from it on startup
+    /// before the first poll
+    #[arg(long, env = "STATE_FILE")]
+    pub state_file: Option<PathBuf>,
+
+    /// Keep a history of bridge sessions in this SQLite database, served at /history with --admin
+    #[arg(long, env = "HISTORY_DB")]
+    pub history_db: Option<PathBuf>,
+
+    /// How long to keep sessions in the history after they ended (days)
+    #[arg(long, default_value = "30", requires = "history_db", env = "HISTORY_RETENTION")]
+    pub history_retention: u64,
+
+    /// The most sessions to keep in the history, dropping the oldest
+    #[arg(long, default_value = "100000", requires = "history_db", env = "HISTORY_MAX_SESSIONS")]
+    pub history_max_sessions: u64,
+
+    /// Soak scenario steps to inject on a schedule, never in production: `kill-session every <interval>`,
+    /// `stall-registry <length> every <interval>`, `fail-registry <length> every <interval>`
+    #[arg(long = "soak", env = "SOAK", value_delimiter = ',')]
+    pub soak: Vec<SoakStep>,
+
+    /// End the soak after this long, draining and exiting as on SIGTERM (seconds)
+    #[arg(long, requires = "soak", env = "SOAK_DURATION")]
+    pub soak_duration: Option<u64>,
+
+    /// Also write the soak report to this file, as JSON
+    #[arg(long, requires = "soak", env = "SOAK_REPORT")]
+    pub soak_report: Option<PathBuf>,
+
+    /// Time a frame of every bridge this often, from CF through the adapter and the relay
+    /// and back, exported as `bridge_latency_us` (seconds)
+    #[arg(long, env = "LATENCY_PROBE")]
+    pub latency_probe: Option<u64>,
+
+    /// Time media frames from their capture, for encoders whose timestamps are wall-clock
+    /// microseconds, exported as `bridge_capture_latency_us`
+    #[arg(long, env = "CAPTURE_LATENCY")]
+    pub capture_latency: bool,
+
+    /// Correct our clock for capture latency by this NTP server's, as `host[:port]`, rather
+    /// than trusting the system clock
+    #[arg(long, env = "NTP_SERVER")]
+    pub ntp_server: Option<String>,
+
+    /// How often to sync with --ntp-server (seconds)
+    #[arg(long, default_value = "64", env = "NTP_INTERVAL")]
+    pub ntp_interval: u64,
+
+    /// Inject faults to test recovery, never in production: `delay-objects:<ms>[@<percent>]`,
+    /// `drop-groups:<percent>`, `kill-session:<secs>`, `registry-errors:<percent>`
+    #[arg(long = "chaos", env = "CHAOS", value_delimiter = ',')]
+    pub chaos: Vec<ChaosFault>,
+
+    /// How long to spend stopping bridges and flushing them to the relay on SIGTERM before
+    /// exiting anyway, with status 3 rather than 0 (seconds)
+    #[arg(long, default_value = "30", env = "DRAIN_TIMEOUT")]
+    pub drain_timeout: u64,
+
+    /// Tear down bridges whose upstream produced nothing for this long, and retry them after as long again (seconds)
+    #[arg(long, env = "IDLE_TIMEOUT")]
+    pub idle_timeout: Option<u64>,
+
+    /// Only take streams from CF while the relay is subscribed to them, for a viewer, going back
+    /// to standby this long after the last one leaves (seconds)
+    #[arg(long, env = "ON_DEMAND")]
+    pub on_demand: Option<u64>,
+
+    /// Restart bridges whose upstream went silent for this long after producing media (seconds)
+    #[arg(long, env = "STALL_TIMEOUT")]
+    pub stall_timeout: Option<u64>,
+
+    /// Consecutive failures after which a stream is quarantined until it leaves the registry
+    #[arg(long, default_value = "10", env = "MAX_BRIDGE_ATTEMPTS")]
+    pub max_bridge_attempts: u32,
+
+    /// Evict bridges whose share of failed groups over a window exceeds this (percent)
+    #[arg(long, env = "EVICT_ERROR_RATE")]
+    pub evict_error_rate: Option<f64>,
+
+    /// The window error rates are measured over (seconds)
+    #[arg(long, default_value = "30", env = "EVICT_WINDOW", value_parser = clap::value_parser!(u64).range(1..))]
+    pub evict_window: u64,
+
+    /// Don't bridge an evicted stream again for this long (seconds)
+    #[arg(long, env = "EVICT_COOLDOWN")]
+    pub evict_cooldown: Option<u64>,
+
+    /// Bridge at most this many streams at once; the rest wait in a queue
+    #[arg(long, env = "MAX_BRIDGES")]
+    pub max_bridges: Option<usize>,
+
+    /// Run bridges on this many dedicated tokio runtimes, apart from registry and connection work
+    #[arg(long, env = "BRIDGE_RUNTIMES")]
+    pub bridge_runtimes: Option<usize>,
+
+    /// Priority classes as stream patterns, most important first; a `priority` from the registry wins
+    #[arg(long = "bridge-priority", env = "BRIDGE_PRIORITY", value_delimiter = ',')]
+    pub bridge_priority: Vec<String>,
+
+    /// Streams to keep announced on CloudFlare ahead of go-live, whether listed or not, and
+    /// bridge the moment they're live, like those the registry flags `starting_soon`
+    #[arg(long = "prewarm", env = "PREWARM", value_delimiter = ',')]
+    pub prewarm: Vec<String>,
+
+    /// At the bridge limit, stop the least important bridge for a queued stream of a more important class
+    #[arg(long, requires = "max_bridges", env = "PREEMPT")]
+    pub preempt: bool,
+
+    /// Only bridge matching tracks, as `[stream_id=]pattern` (`*` wildcard, leading `!` excludes)
+    #[arg(long = "track-filter", env = "TRACK_FILTERS", value_delimiter = ',')]
+    pub track_filters: Vec<String>,
+
+    /// Publish a stream under another relay path, as `stream_id=path`; wins over a `publish_path` from the registry
+    #[arg(long = "stream-path", env = "STREAM_PATHS", value_delimiter = ',')]
+    pub stream_paths: Vec<Scoped<String>>,
+
+    /// Prefix for the relay paths of streams without a `publish_path` from the registry, e.g.
+    /// `secondary/` or `cf/{region}`; `{field}` placeholders come from the registry entry
+    #[arg(long, default_value = "", env = "PUBLISH_ROOT")]
+    pub publish_root: String,
+
+    /// What to do when a stream's publish path is already published on the relay
+    #[arg(long, value_enum, default_value = "refuse", env = "PATH_COLLISION")]
+    pub path_collision: Collision,
+
+    /// What to do when another broadcast turns up at a bridged stream's relay path, e.g. its
+    /// publisher moving from CloudFlare to the relay
+    #[arg(long, value_enum, default_value = "ignore", env = "DUPLICATES")]
+    pub duplicates: DuplicatePolicy,
+
+    /// Rename tracks when republishing, as `[stream_id=]upstream->downstream`
+    #[arg(long = "track-alias", env = "TRACK_ALIASES", value_delimiter = ',')]
+    pub track_aliases: Vec<String>,
+
+    /// Rewrite media timestamps when republishing to the relay
+    #[arg(long, value_enum, default_value = "none", env = "REBASE_TIMESTAMPS")]
+    pub rebase_timestamps: RebaseMode,
+
+    /// Drop video renditions taller than this many pixels, as `[stream_id=]height`
+    #[arg(long = "max-video-height", env = "MAX_VIDEO_HEIGHT", value_delimiter = ',')]
+    pub max_video_height: Vec<Scoped<u64>>,
+
+    /// Drop video renditions above this catalog bitrate (bits/s), as `[stream_id=]bitrate`
+    #[arg(long = "max-video-bitrate", env = "MAX_VIDEO_BITRATE", value_delimiter = ',')]
+    pub max_video_bitrate: Vec<Scoped<u64>>,
+
+    /// Registry fields to serve on each bridge's `.metadata` track, e.g. `title,publisher,region,tags`
+    #[arg(long = "stream-metadata", env = "STREAM_METADATA", value_delimiter = ',')]
+    pub stream_metadata: Vec<String>,
+
+    /// Cap what each bridge publishes to the relay (kbit/s), as `[stream_id=]rate`
+    #[arg(long = "bridge-rate-limit", env = "BRIDGE_RATE_LIMIT", value_delimiter = ',')]
+    pub bridge_rate_limit: Vec<Scoped<u64>>,
+
+    /// Cap each track of a bridge matching the pattern (kbit/s), as `pattern=rate`
+    #[arg(long = "track-rate-limit", env = "TRACK_RATE_LIMIT", value_delimiter = ',')]
+    pub track_rate_limit: Vec<TrackRate>,
+
+    /// Priority for video tracks in place of the publisher's, higher being more important
+    #[arg(long, env = "VIDEO_PRIORITY")]
+    pub video_priority: Option<u8>,
+
+    /// Priority for audio tracks in place of the publisher's, higher being more important
+    #[arg(long, env = "AUDIO_PRIORITY")]
+    pub audio_priority: Option<u8>,
+
+    /// Priority for other tracks in place of the publisher's, higher being more important
+    #[arg(long, env = "DATA_PRIORITY")]
+    pub data_priority: Option<u8>,
+
+    /// Priority for tracks matching the pattern, ahead of the kind's, as `pattern=priority`
+    #[arg(long = "track-priority", env = "TRACK_PRIORITY", value_delimiter = ',')]
+    pub track_priority: Vec<TrackPriority>,
+
+    /// Cap everything published to a relay (kbit/s), as `[relay=]rate` with `main` for `--relay-url`
+    #[arg(long = "relay-egress-limit", env = "RELAY_EGRESS_LIMIT", value_delimiter = ',')]
+    pub relay_egress_limit: Vec<Scoped<u64>>,
+
+    /// Record bridged streams as fMP4 segments under this directory
+    #[arg(long, env = "RECORD_DIR")]
+    pub record_dir: Option<PathBuf>,
+
+    /// Only record streams matching these patterns (default: all bridged streams)
+    #[arg(long = "record-stream", env = "RECORD_STREAMS", value_delimiter = ',')]
+    pub record_streams: Vec<String>,
+
+    /// How long to keep recorded segments (seconds)
+    #[arg(long, default_value = "3600", env = "RECORD_RETENTION")]
+    pub record_retention: u64,
+
+    /// Pipe media frames through this command (run with `sh -c`, one process per track)
+    #[arg(long, env = "TRANSFORM_COMMAND")]
+    pub transform_command: Option<String>,
+
+    /// Only transform matching tracks, as `[stream_id=]pattern` (default: all media tracks)
+    #[arg(long = "transform-track", env = "TRANSFORM_TRACKS", value_delimiter = ',')]
+    pub transform_tracks: Vec<String>,
+
+    /// Forward matching data tracks byte-for-byte, without timestamp rebasing or transforms
+    #[arg(long = "passthrough-track", env = "PASSTHROUGH_TRACKS", value_delimiter = ',')]
+    pub passthrough_tracks: Vec<String>,
+
+    /// Streams are end-to-end encrypted: forward every track but the catalog byte-exact, and
+    /// refuse options that would change or read frames
+    #[arg(long, env = "E2EE")]
+    pub e2ee: bool,
+
+    /// Run frames through these built-in interceptors, in order, as `name[:arg][@track-pattern]`
+    /// (count-keyframes, keyframes-only, drop-empty, `strip-prefix:<hex>`)
+    #[arg(long = "interceptor", env = "INTERCEPTORS", value_delimiter = ',')]
+    pub interceptors: Vec<BuiltinInterceptor>,
+
+    /// Upstream data track carrying SCTE-35 ad markers (always passed through)
+    #[arg(long, env = "AD_MARKER_TRACK")]
+    pub ad_marker_track: Option<String>,
+
+    /// Also re-emit ad markers as JSON on this relay-side track
+    #[arg(long, env = "AD_MARKER_OUTPUT")]
+    pub ad_marker_output: Option<String>,
+
+    /// Accept extra tracks for bridged streams at POST /inject/{stream_id}/{track}
+    #[arg(long, requires = "http_listen", env = "INJECT")]
+    pub inject: bool,
+
+    /// Require this bearer token on injection requests
+    #[arg(long, env = "INJECT_TOKEN")]
+    pub inject_token: Option<String>,
+
+    /// Serve the admin API at /bridges, to list, stop, pause and resume bridges
+    #[arg(long, requires = "http_listen", env = "ADMIN")]
+    pub admin: bool,
+
+    /// Require this bearer token on admin requests
+    #[arg(long, env = "ADMIN_TOKEN")]
+    pub admin_token: Option<String>,
+
+    /// Under sustained relay congestion, stop forwarding tracks matching these patterns, first to last
+    #[arg(long = "shed-order", env = "SHED_ORDER", value_delimiter = ',')]
+    pub shed_order: Vec<String>,
+
+    /// Packet loss that counts as relay congestion (percent)
+    #[arg(long, default_value = "2", env = "SHED_LOSS")]
+    pub shed_loss: f64,
+
+    /// RTT increase over the minimum that counts as relay congestion (milliseconds)
+    #[arg(long, default_value = "150", env = "SHED_DELAY")]
+    pub shed_delay: u64,
+
+    /// What video tracks do with new groups while the relay is congested
+    #[arg(long, value_enum, default_value = "latest", env = "VIDEO_BACKPRESSURE")]
+    pub video_backpressure: DropPolicy,
+
+    /// What audio tracks do with new groups while the relay is congested
+    #[arg(long, value_enum, default_value = "latest", env = "AUDIO_BACKPRESSURE")]
+    pub audio_backpressure: DropPolicy,
+
+    /// What other tracks do with new groups while the relay is congested
+    #[arg(long, value_enum, default_value = "latest", env = "DATA_BACKPRESSURE")]
+    pub data_backpressure: DropPolicy,
+
+    /// Cut a group short at a frame that took longer than this from CloudFlare to the relay (milliseconds)
+    #[arg(long, env = "DELIVERY_TIMEOUT")]
+    pub delivery_timeout: Option<u64>,
+
+    /// Cap on media buffered for the relay by each bridge, dropping the oldest groups beyond it (MB)
+    #[arg(long, env = "BRIDGE_BUFFER_LIMIT")]
+    pub bridge_buffer_limit: Option<usize>,
+
+    /// Cap on media buffered for the relay across all bridges (MB)
+    #[arg(long, env = "BUFFER_LIMIT")]
+    pub buffer_limit: Option<usize>,
+
+    /// Keep this many recent groups of each bridged track, for relay subscribers that join late
+    #[arg(long, default_value = "0", env = "CACHE_GROUPS")]
+    pub cache_groups: usize,
+
+    /// How many recent groups of video tracks to keep instead, as groups or a duration like 1s or 500ms
+    #[arg(long, env = "VIDEO_RETENTION")]
+    pub video_retention: Option<Retention>,
+
+    /// How many recent groups of audio tracks to keep instead, as groups or a duration like 1s or 500ms
+    #[arg(long, env = "AUDIO_RETENTION")]
+    pub audio_retention: Option<Retention>,
+
+    /// How many recent groups of other tracks to keep instead, as groups or a duration like 1s or 500ms
+    #[arg(long, env = "DATA_RETENTION")]
+    pub data_retention: Option<Retention>,
+
+    /// Spill bridged groups under this directory while the relay is down, and replay them on reconnect
+    #[arg(long, env = "SPILL_DIR")]
+    pub spill_dir: Option<PathBuf>,
+
+    /// How much each bridge may spill (MB)
+    #[arg(long, default_value = "64", env = "SPILL_LIMIT")]
+    pub spill_limit: u64,
+
+    /// Serve HTTP egress (LL-HLS, DASH) on this address, e.g. [::]:8080
+    #[arg(long, env = "HTTP_LISTEN")]
+    pub http_listen: Option<SocketAddr>,
+
+    /// Serve bridged streams as LL-HLS under /hls/{stream_id}/master.m3u8
+    #[arg(long, requires = "http_listen", env = "HLS")]
+    pub hls: bool,
+
+    /// Only serve streams matching these patterns over HLS (default: all bridged streams)
+    #[arg(long = "hls-stream", env = "HLS_STREAMS", value_delimiter = ',')]
+    pub hls_streams: Vec<String>,
+
+    /// Serve bridged streams as MPEG-DASH under /dash/{stream_id}/manifest.mpd
+    #[arg(long, requires = "http_listen", env = "DASH")]
+    pub dash: bool,
+
+    /// Only serve streams matching these patterns over DASH (default: all bridged streams)
+    #[arg(long = "dash-stream", env = "DASH_STREAMS", value_delimiter = ',')]
+    pub dash_streams: Vec<String>,
+
+    /// Also send bridged streams as MPEG-TS to `udp://host:port` or `rtp://host:port`, as `[stream_id=]url`
+    #[arg(long = "udp-output", env = "UDP_OUTPUT", value_delimiter = ',')]
+    pub udp_output: Vec<Scoped<Url>>,
+
+    /// Target HLS/DASH segment duration (seconds)
+    #[arg(long, default_value = "2", env = "SEGMENT_DURATION")]
+    pub segment_duration: u64,
+
+    /// Target LL-HLS part duration (milliseconds)
+    #[arg(long, default_value = "250", env = "PART_DURATION")]
+    pub part_duration: u64,
+
+    /// How many segments each HLS playlist and DASH manifest keeps
+    #[arg(long, default_value = "6", env = "SEGMENT_WINDOW")]
+    pub segment_window: usize,
+
+    /// POST a JPEG thumbnail of each bridged stream here; `{stream_id}` is substituted
+    #[cfg(feature = "ffmpeg")]
+    #[arg(long, env = "THUMBNAIL_URL")]
+    pub thumbnail_url: Option<String>,
+
+    /// How often to capture thumbnails (seconds)
+    #[cfg(feature = "ffmpeg")]
+    #[arg(long, default_value = "30", env = "THUMBNAIL_INTERVAL", value_parser = clap::value_parser!(u64).range(1..))]
+    pub thumbnail_interval: u64,
+
+    /// Thumbnail width in pixels
+    #[cfg(feature = "ffmpeg")]
+    #[arg(long, default_value = "320", env = "THUMBNAIL_WIDTH")]
+    pub thumbnail_width: u32,
+
+    /// Listen for an SRT contribution feed and publish it to the relay, as `stream_id=host:port`
+    #[cfg(feature = "ffmpeg")]
+    #[arg(long = "srt-ingest", env = "SRT_INGEST", value_delimiter = ',')]
+    pub srt_ingest: Vec<SrtIngest>,
+
+    /// Passphrase SRT callers must use
+    #[cfg(feature = "ffmpeg")]
+    #[arg(long, env = "SRT_PASSPHRASE")]
+    pub srt_passphrase: Option<String>,
+
+    /// Play bridged streams to WebRTC players at `/whep/{stream_id}`
+    #[cfg(feature = "webrtc")]
+    #[arg(long, requires = "http_listen", requires = "webrtc_address", env = "WHEP")]
+    pub whep: bool,
+
+    /// Publish feeds from WebRTC encoders at `/whip/{stream_id}` to the relay
+    #[cfg(feature = "webrtc")]
+    #[arg(long, requires = "http_listen", requires = "webrtc_address", env = "WHIP")]
+    pub whip: bool,
+
+    /// Bearer token WHIP encoders must send
+    #[cfg(feature = "webrtc")]
+    #[arg(long, requires = "whip", env = "WHIP_TOKEN")]
+    pub whip_token: Option<String>,
+
+    /// Address WebRTC sessions get their UDP ports on, which peers must be able to reach
+    #[cfg(feature = "webrtc")]
+    #[arg(long, env = "WEBRTC_ADDRESS")]
+    pub webrtc_address: Option<IpAddr>,
+}
+
+#[derive(clap::Subcommand, Clone, Debug)]
+pub enum Command {
+    /// Check the configuration without running, exiting non-zero if anything is wrong
+    ValidateConfig,
+    /// Ask the instance running with this configuration whether it's ready, through
+    /// the admin API, exiting non-zero if it isn't or can't be reached
+    Healthcheck {
+        /// Give up on the instance after this long (seconds)
+        #[arg(long, default_value = "3")]
+        timeout: u64,
+    },
+    /// Print the objects a bridge of the instance running with this configuration forwards,
+    /// as they arrive, through the admin API
+    Tap {
+        stream_id: String,
+        /// Only this track, rather than the catalog and every rendition it lists
+        track: Option<String>,
+        /// Show this many bytes of each payload, in hex, after any timestamp
+        #[arg(long, default_value = "0")]
+        payload: usize,
+        /// Stop after this many objects
+        #[arg(long)]
+        count: Option<u64>,
+        /// Print the JSON lines served by the admin API instead
+        #[arg(long)]
+        json: bool,
+        /// Give up on the instance if it doesn't answer within this long (seconds)
+        #[arg(long, default_value = "3")]
+        timeout: u64,
+    },
+    /// Bridge a generated test pattern and check it arrives intact on the relay side,
+    /// exiting non-zero if it doesn't
+    SelfTest {
+        /// Bridge through local stand-ins for CloudFlare and the relay, or the configured ones
+        #[arg(long, value_enum, default_value_t)]
+        endpoints: Endpoints,
+        /// How many frames have to arrive
+        #[arg(long, default_value = "90")]
+        frames: usize,
+        /// Fail if they haven't arrived this long after starting (seconds)
+        #[arg(long, default_value = "30")]
+        timeout: u64,
+        /// Bridge with --e2ee and check every frame arrives byte-exact, by its SHA-256
+        #[arg(long)]
+        checksum: bool,
+    },
+    /// Bridge synthetic streams for a while and report their latency and drops, to see how
+    /// many an instance can take
+    Loadgen {
+        /// Bridge through local stand-ins for CloudFlare and the relay, or the configured ones
+        #[arg(long, value_enum, default_value_t)]
+        endpoints: Endpoints,
+        /// How many streams to bridge at once
+        #[arg(long, default_value = "10")]
+        streams: usize,
+        /// The bitrate of each stream (kbit/s)
+        #[arg(long, default_value = "2500")]
+        bitrate: u64,
+        /// How long to run for (seconds)
+        #[arg(long, default_value = "60")]
+        duration: u64,
+    },
+    /// Check the Draft 14 control messages of captures from CloudFlare sessions against
+    /// our codecs, exiting non-zero if any don't conform
+    Conformance {
+        /// The captures to check, one JSON line per message
+        #[arg(required = true)]
+        captures: Vec<PathBuf>,
+        /// Instead, write the control messages traced in this adapter log to the capture
+        #[arg(long)]
+        record: Option<PathBuf>,
+    },
+}
+
+impl AdapterConfig {
+    /// Whether this instance bridges `stream_id`, per `--include-stream`, `--exclude-stream` and `--canary`
+    pub(crate) fn bridges(&self, stream_id: &str) -> bool {
+        let included = self.include_streams.is_empty() || self.include_streams.iter().any(|p| p.matches(stream_id));
+        let sampled = self.canary.is_none_or(|percent| canary::sampled(stream_id, percent) != self.canary_rest);
+        included && sampled && !self.exclude_streams.iter().any(|p| p.matches(stream_id))
+    }
+
+    /// The recording settings for `stream_id`, if it should be recorded
+    pub(crate) fn record_options(&self, stream_id: &str) -> Option<RecordOptions> {
+        let dir = self.record_dir.clone()?;
+        let selected = self.record_streams.is_empty() || self.record_streams.iter().any(|p| glob_match(p, stream_id));
+
+        selected.then(|| RecordOptions {
+            dir,
+            retention: Duration::from_secs(self.record_retention),
+        })
+    }
+
+    /// The HTTP formats `stream_id` should be served in
+    #[cfg(feature = "http")]
+    pub(crate) fn formats(&self, stream_id: &str) -> Formats {
+        let selected = |patterns: &[String]| patterns.is_empty() || patterns.iter().any(|p| glob_match(p, stream_id));
+
+        Formats {
+            hls: self.hls && selected(&self.hls_streams),
+            dash: self.dash && selected(&self.dash_streams),
+        }
+    }
+
+    #[cfg(feature = "ffmpeg")]
+    pub(crate) fn thumbnail_options(&self) -> Option<ThumbnailOptions> {
+        Some(ThumbnailOptions {
+            url: self.thumbnail_url.clone()?,
+            interval: Duration::from_secs(self.thumbnail_interval),
+            width: self.thumbnail_width,
+        })
+    }
+
+    /// Tracks forwarded without touching their payload
+    pub(crate) fn passthrough(&self) -> Vec<String> {
+        let mut tracks = self.passthrough_tracks.clone();
+        tracks.extend(self.ad_marker_track.clone());
+        tracks
+    }
+
+    /// The track filter for a stream: its own `--track-filter` rules, then the registry's, then the global ones
+    pub(crate) fn track_filter(&self, stream: &StreamInfo) -> TrackFilter {
+        let scoped = |rule: &String| rule.split_once('=').is_some_and(|(id, _)| id == stream.stream_id);
+        let scoped = self.track_filters.iter().any(scoped);
+        match &stream.track_filter {
+            Some(patterns) if !scoped => TrackFilter::new(patterns.iter().map(String::as_str)),
+            _ => TrackFilter::for_stream(&self.track_filters, &stream.stream_id),
+        }
+    }
+
+    /// The relay path a stream is published under
+    ///
+    /// A `publish_path` from the registry is taken as it is, other paths go under `--publish-root`.
+    pub(crate) fn publish_path(&self, stream: &StreamInfo) -> anyhow::Result<String> {
+        let mapped = self.stream_paths.iter().rev().find(|p| p.stream_id.as_deref() == Some(stream.stream_id.as_str()));
+        let path = match (mapped, &stream.publish_path) {
+            (Some(mapped), _) => &mapped.value,
+            (None, Some(path)) => return Ok(path.clone()),
+            (None, None) => &stream.stream_id,
+        };
+
+        let root = namespace::render(&self.publish_root, |name| stream.field(name)).context("can't build publish root")?;
+        match root.trim_end_matches('/') {
+            "" => Ok(path.clone()),
+            root => Ok(format!("{root}/{path}")),
+        }
+    }
+
+    pub(crate) fn ad_marker_options(&self) -> Option<AdMarkerOptions> {
+        Some(AdMarkerOptions {
+            source: self.ad_marker_track.clone()?,
+            output: self.ad_marker_output.clone()?,
+        })
+    }
+
+    pub(crate) fn evict_options(&self) -> Option<EvictOptions> {
+        Some(EvictOptions {
+            rate: self.evict_error_rate? / 100.0,
+            window: Duration::from_secs(self.evict_window),
+            cooldown: self.evict_cooldown.map(Duration::from_secs),
+        })
+    }
+
+    pub(crate) fn breaker_options(&self) -> BreakerOptions {
+        BreakerOptions {
+            threshold: self.registry_failure_threshold,
+            probe_interval: Duration::from_secs(self.registry_probe_interval),
+        }
+    }
+
+    pub(crate) fn backoff(&self) -> BackoffPolicy {
+        BackoffPolicy {
+            initial: Duration::from_millis(self.backoff_initial),
+            multiplier: self.backoff_multiplier,
+            max: Duration::from_millis(self.backoff_max),
+            jitter: self.backoff_jitter / 100.0,
+        }
+    }
+
+    /// Where per-broadcast relay tokens come from, if bridges get them
+    pub(crate) fn token_issuer(&self) -> anyhow::Result<Option<TokenIssuer>> {
+        if let Some(path) = &self.relay_token_key {
+            return Ok(Some(TokenIssuer::Key(SigningKey::load(path)?)));
+        }
+        let Some(url) = &self.relay_token_url else {
+            return Ok(None);
+        };
+        #[cfg(feature = "token-service")]
+        return Ok(Some(TokenIssuer::Service {
+            url: url.clone(),
+            client: reqwest::Client::new(),
+        }));
+        #[cfg(not(feature = "token-service"))]
+        anyhow::bail!("built without the token-service feature, drop --relay-token-url {url}")
+    }
+
+    pub(crate) fn relay_connect_options(&self) -> ConnectOptions {
+        ConnectOptions {
+            family: self.relay_ip_family,
+            transport: self.relay_transport,
+            alpn: self.relay_alpn.clone(),
+            quic_alpn: moq_lite::lite::ALPN,
+            zero_rtt: self.relay_zero_rtt,
+            pins: Vec::new(),
+        }
+    }
+
+    pub(crate) fn cf_connect_options(&self) -> ConnectOptions {
+        ConnectOptions {
+            family: self.cf_ip_family,
+            transport: self.cf_transport,
+            alpn: self.cf_alpn.clone(),
+            quic_alpn: moq_lite::ietf::ALPN,
+            zero_rtt: self.cf_zero_rtt,
+            pins: self.cf_pins.clone(),
+        }
+    }
+
+    pub(crate) fn announce_options(&self) -> AnnounceOptions {
+        AnnounceOptions {
+            timeout: Duration::from_millis(self.announce_timeout),
+            attempts: self.announce_attempts,
+            announce_remote: self.quirks().announce_remote,
+        }
+    }
+
+    /// How the upstream deviates from the draft, per `--upstream` and `--upstream-quirk`
+    pub(crate) fn quirks(&self) -> UpstreamQuirks {
+        self.upstream.quirks().with(&self.upstream_quirks)
+    }
+
+    #[cfg(feature = "redis")]
+    pub(crate) fn lease_options(&self) -> Option<LeaseOptions> {
+        Some(LeaseOptions {
+            url: self.lease_redis_url.clone()?,
+            ttl: Duration::from_secs(self.lease_ttl.max(1)),
+            prefix: self.lease_prefix.clone(),
+        })
+    }
+
+    #[cfg(feature = "history")]
+    pub(crate) fn history_options(&self) -> Option<HistoryOptions> {
+        Some(HistoryOptions {
+            path: self.history_db.clone()?,
+            retention: Duration::from_secs(self.history_retention.saturating_mul(24 * 60 * 60)),
+            max_sessions: self.history_max_sessions,
+        })
+    }
+
+    #[cfg(feature = "push")]
+    pub(crate) fn push_options(&self) -> Option<PushOptions> {
+        Some(PushOptions {
+            url: self.metrics_push_url.clone()?,
+            interval: Duration::from_secs(self.metrics_push_interval.max(1)),
+            job: self.metrics_push_job.clone(),
+        })
+    }
+
+    #[cfg(feature = "registry")]
+    pub(crate) fn stats_options(&self) -> Option<StatsOptions> {
+        Some(StatsOptions {
+            url: self.stats_url.clone()?,
+            interval: Duration::from_secs(self.stats_interval.max(1)),
+        })
+    }
+
+    pub(crate) fn spill_options(&self) -> Option<SpillOptions> {
+        Some(SpillOptions {
+            dir: self.spill_dir.clone()?,
+            limit: self.spill_limit << 20,
+        })
+    }
+
+    pub(crate) fn backpressure(&self) -> Backpressure {
+        Backpressure {
+            video: self.video_backpressure,
+            audio: self.audio_backpressure,
+            data: self.data_backpressure,
+        }
+    }
+
+    /// The group cache's retention for each kind of track, `--cache-groups` where not set
+    pub(crate) fn cache_retention(&self) -> CacheRetention {
+        let retention = |kind: Option<Retention>| kind.unwrap_or(Retention::Groups(self.cache_groups));
+        CacheRetention {
+            video: retention(self.video_retention),
+            audio: retention(self.audio_retention),
+            data: retention(self.data_retention),
+        }
+    }
+
+    pub(crate) fn priority_overrides(&self) -> PriorityOverrides {
+        PriorityOverrides {
+            video: self.video_priority,
+            audio: self.audio_priority,
+            data: self.data_priority,
+            tracks: self.track_priority.clone(),
+        }
+    }
+
+    pub(crate) fn shed_options(&self) -> ShedOptions {
+        ShedOptions {
+            order: self.shed_order.clone(),
+            loss: self.shed_loss / 100.0,
+            delay: Duration::from_millis(self.shed_delay),
+        }
+    }
+
+    #[cfg(feature = "http")]
+    pub(crate) fn package_options(&self) -> PackageOptions {
+        PackageOptions {
+            segment_duration: Duration::from_secs(self.segment_duration),
+            part_duration: Duration::from_millis(self.part_duration),
+            window: self.segment_window,
+        }
+    }
+}
+
@@ -0,0 +1,319 @@
+//! `conformance`
+//!
+//! Checks the Draft 14 messages of golden captures from CloudFlare sessions against
+//! moq-lite's codecs, so that a moq-lite upgrade, or a change in what we ask of it, that
+//! would break interop with CF fails in CI instead of in production. A capture is a JSON
+//! line per message, in the order they crossed the control stream:
+//!
+//! `{"direction": "sent", "hex": "0300..."}`
+//!
+//! `hex` is the whole message as on the wire (type, length and payload), and
+//! `direction` is `sent` for what we sent CF or `received` for what CF sent us. CF
+//! accepted what we sent, so those messages have to decode and encode back to exactly
+//! the same bytes; what CF sent has to decode with nothing left over. Unknown message
+//! types fail either way.
+//!
+//! With `"stream": "subgroup"`, `hex` is a whole subgroup data stream instead: its header,
+//! which decodes (and if we sent it, encodes back) like a control message's payload, then
+//! its objects, each of which has to be framed the way moq-lite reads them.
+//!
+//! `--record <log>` makes a capture instead, from the log of an adapter run against CF
+//! with `--log-level trace` and `--cf-sessions 1`: moq-lite traces every control
+//! message it sends and receives. Record one with a release known to work with CF,
+//! check it in, and check every later build against it. Only control messages are
+//! recorded, as moq-lite doesn't trace data streams; `cargo test` checks hand-written
+//! messages of both kinds from the draft's wire formats.
+
+use std::path::{Path, PathBuf};
+
+use anyhow::Context;
+use moq_lite::coding::{Decode, Encode};
+use moq_lite::ietf::{self, Message};
+
+use crate::mp4::decode_hex;
+
+const DRAFT: ietf::Version = ietf::Version::Draft14;
+
+/// Who sent a captured message
+#[derive(Clone, Copy, Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Direction {
+    /// We sent it to CF
+    Sent,
+    /// CF sent it to us
+    Received,
+}
+
+/// Which stream a captured message crossed
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Stream {
+    /// The control stream
+    #[default]
+    Control,
+    /// A subgroup's data stream, header and objects
+    Subgroup,
+}
+
+/// One message of a capture
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct Captured {
+    pub direction: Direction,
+    #[serde(default, skip_serializing_if = "Stream::is_control")]
+    pub stream: Stream,
+    /// Type, length and payload, as on the wire
+    pub hex: String,
+}
+
+/// Check every message of `captures`, or with `record`, write the capture in that log to the one capture
+pub fn run(captures: &[PathBuf], record: Option<&Path>) -> anyhow::Result<()> {
+    if let Some(log) = record {
+        let [capture] = captures else {
+            anyhow::bail!("--record writes a single capture");
+        };
+        let recorded = self::record(log, capture)?;
+        tracing::info!(messages = recorded, capture = %capture.display(), "recorded capture");
+        return Ok(());
+    }
+
+    anyhow::ensure!(!captures.is_empty(), "no captures to check");
+    let mut failures = 0;
+    for capture in captures {
+        let messages = load(capture)?;
+        anyhow::ensure!(!messages.is_empty(), "{} has no messages", capture.display());
+
+        let mut failed = 0;
+        for (line, message) in &messages {
+            if let Err(problem) = check(message) {
+                tracing::error!("{}:{line}: {problem}", capture.display());
+                failed += 1;
+            }
+        }
+        match failed {
+            0 => tracing::info!(capture = %capture.display(), messages = messages.len(), "capture conforms"),
+            _ => tracing::warn!(capture = %capture.display(), messages = messages.len(), failed, "capture doesn't conform"),
+        }
+        failures += failed;
+    }
+
+    anyhow::ensure!(failures == 0, "{failures} message(s) don't conform");
+    Ok(())
+}
+
+/// The messages of a capture, with their line numbers
+fn load(capture: &Path) -> anyhow::Result<Vec<(usize, Captured)>> {
+    let contents = std::fs::read_to_string(capture).with_context(|| format!("can't read {}", capture.display()))?;
+    contents
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty())
+        .map(|(i, line)| {
+            let message = serde_json::from_str(line).with_context(|| format!("{}:{}", capture.display(), i + 1))?;
+            Ok((i + 1, message))
+        })
+        .collect()
+}
+
+impl Stream {
+    fn is_control(&self) -> bool {
+        *self == Self::Control
+    }
+}
+
+/// Check one captured message against our codecs, saying what's wrong with it if it doesn't conform
+pub fn check(message: &Captured) -> Result<(), String> {
+    let wire = decode_hex(&message.hex).map_err(|err| format!("invalid hex: {err:#}"))?;
+    let sent = message.direction == Direction::Sent;
+    match message.stream {
+        Stream::Control => control(&wire, sent),
+        Stream::Subgroup => subgroup(&wire, sent),
+    }
+}
+
+/// Check a control message: type, length and a payload that decodes as that type
+fn control(wire: &[u8], sent: bool) -> Result<(), String> {
+    let mut buf = wire;
+    let id = u64::decode(&mut buf, DRAFT).map_err(|err| format!("no message type: {err}"))?;
+    let size = u16::decode(&mut buf, DRAFT).map_err(|err| format!("no message length: {err}"))?;
+    if buf.len() != size as usize {
+        return Err(format!("message 0x{id:x} is {} bytes, but its length says {size}", buf.len()));
+    }
+
+    match id {
+        ietf::ClientSetup::ID => conforms::<ietf::ClientSetup>("CLIENT_SETUP", buf, sent),
+        ietf::ServerSetup::ID => conforms::<ietf::ServerSetup>("SERVER_SETUP", buf, sent),
+        ietf::Subscribe::ID => conforms::<ietf::Subscribe>("SUBSCRIBE", buf, sent),
+        ietf::SubscribeOk::ID => conforms::<ietf::SubscribeOk>("SUBSCRIBE_OK", buf, sent),
+        ietf::SubscribeError::ID => conforms::<ietf::SubscribeError>("SUBSCRIBE_ERROR", buf, sent),
+        ietf::SubscribeUpdate::ID => conforms::<ietf::SubscribeUpdate>("SUBSCRIBE_UPDATE", buf, sent),
+        ietf::Unsubscribe::ID => conforms::<ietf::Unsubscribe>("UNSUBSCRIBE", buf, sent),
+        ietf::PublishNamespace::ID => conforms::<ietf::PublishNamespace>("PUBLISH_NAMESPACE", buf, sent),
+        ietf::PublishNamespaceOk::ID => conforms::<ietf::PublishNamespaceOk>("PUBLISH_NAMESPACE_OK", buf, sent),
+        ietf::PublishNamespaceError::ID => {
+            conforms::<ietf::PublishNamespaceError>("PUBLISH_NAMESPACE_ERROR", buf, sent)
+        }
+        ietf::PublishNamespaceDone::ID => conforms::<ietf::PublishNamespaceDone>("PUBLISH_NAMESPACE_DONE", buf, sent),
+        ietf::PublishNamespaceCancel::ID => {
+            conforms::<ietf::PublishNamespaceCancel>("PUBLISH_NAMESPACE_CANCEL", buf, sent)
+        }
+        ietf::SubscribeNamespace::ID => conforms::<ietf::SubscribeNamespace>("SUBSCRIBE_NAMESPACE", buf, sent),
+        ietf::SubscribeNamespaceOk::ID => conforms::<ietf::SubscribeNamespaceOk>("SUBSCRIBE_NAMESPACE_OK", buf, sent),
+        ietf::SubscribeNamespaceError::ID => {
+            conforms::<ietf::SubscribeNamespaceError>("SUBSCRIBE_NAMESPACE_ERROR", buf, sent)
+        }
+        ietf::Publish::ID => conforms::<ietf::Publish>("PUBLISH", buf, sent),
+        ietf::PublishOk::ID => conforms::<ietf::PublishOk>("PUBLISH_OK", buf, sent),
+        ietf::PublishError::ID => conforms::<ietf::PublishError>("PUBLISH_ERROR", buf, sent),
+        ietf::PublishDone::ID => conforms::<ietf::PublishDone>("PUBLISH_DONE", buf, sent),
+        ietf::Fetch::ID => conforms::<ietf::Fetch>("FETCH", buf, sent),
+        ietf::FetchOk::ID => conforms::<ietf::FetchOk>("FETCH_OK", buf, sent),
+        ietf::FetchError::ID => conforms::<ietf::FetchError>("FETCH_ERROR", buf, sent),
+        ietf::FetchCancel::ID => conforms::<ietf::FetchCancel>("FETCH_CANCEL", buf, sent),
+        ietf::GoAway::ID => conforms::<ietf::GoAway>("GOAWAY", buf, sent),
+        ietf::MaxRequestId::ID => conforms::<ietf::MaxRequestId>("MAX_REQUEST_ID", buf, sent),
+        ietf::RequestsBlocked::ID => conforms::<ietf::RequestsBlocked>("REQUESTS_BLOCKED", buf, sent),
+        _ => Err(format!("unknown message type 0x{id:x}")),
+    }
+}
+
+/// Decode `payload` as a `T`, and if we sent it, check we'd encode it the same way again
+fn conforms<T: Message>(name: &str, payload: &[u8], sent: bool) -> Result<(), String> {
+    let mut buf = payload;
+    let message = T::decode_msg(&mut buf, DRAFT).map_err(|err| format!("{name} doesn't decode: {err}"))?;
+    if !buf.is_empty() {
+        return Err(format!("{name} has {} byte(s) left over after decoding", buf.len()));
+    }
+    if !sent {
+        return Ok(());
+    }
+
+    let mut encoded = Vec::new();
+    message.encode_msg(&mut encoded, DRAFT);
+    match encoded.iter().zip(payload).position(|(a, b)| a != b) {
+        None if encoded.len() == payload.len() => Ok(()),
+        at => Err(format!(
+            "{name} encodes differently from payload byte {}: captured {}, now {}",
+            at.unwrap_or(encoded.len().min(payload.len())),
+            hex(payload),
+            hex(&encoded)
+        )),
+    }
+}
+
+/// Check a subgroup stream: its header, then objects up to the end of the stream or of the group
+fn subgroup(wire: &[u8], sent: bool) -> Result<(), String> {
+    let mut buf = wire;
+    let header =
+        ietf::GroupHeader::decode(&mut buf, DRAFT).map_err(|err| format!("SUBGROUP_HEADER doesn't decode: {err}"))?;
+    if sent {
+        let captured = &wire[..wire.len() - buf.len()];
+        let mut encoded = Vec::new();
+        header.encode(&mut encoded, DRAFT);
+        if encoded != captured {
+            return Err(format!(
+                "SUBGROUP_HEADER encodes differently: captured {}, now {}",
+                hex(captured),
+                hex(&encoded)
+            ));
+        }
+    }
+
+    let mut object = 0;
+    while !buf.is_empty() {
+        let field = |name: &str, err| format!("object {object} has no {name}: {err}");
+        u64::decode(&mut buf, DRAFT).map_err(|err| field("object ID delta", err))?;
+        if header.flags.has_extensions {
+            let size = usize::decode(&mut buf, DRAFT).map_err(|err| field("extension headers length", err))?;
+            buf = buf.get(size..).ok_or_else(|| format!("object {object} has fewer extension bytes than {size}"))?;
+        }
+
+        let size = usize::decode(&mut buf, DRAFT).map_err(|err| field("payload length", err))?;
+        if size > 0 {
+            buf = buf.get(size..).ok_or_else(|| format!("object {object} has fewer payload bytes than {size}"))?;
+        } else {
+            match u64::decode(&mut buf, DRAFT).map_err(|err| field("status", err))? {
+                // An empty object
+                0 => {}
+                // END_OF_GROUP, unless the header already says the stream ends with the group
+                3 if !header.flags.has_end && buf.is_empty() => {}
+                3 if !header.flags.has_end => return Err(format!("{} byte(s) after END_OF_GROUP", buf.len())),
+                status => return Err(format!("object {object} has status 0x{status:x}, which moq-lite doesn't read")),
+            }
+        }
+        object += 1;
+    }
+    Ok(())
+}
+
+/// Write the control messages traced in `log` to `capture`, returning how many there were
+fn record(log: &Path, capture: &Path) -> anyhow::Result<usize> {
+    let contents = std::fs::read_to_string(log).with_context(|| format!("can't read {}", log.display()))?;
+    let mut messages = Vec::new();
+    // The type of the message being received, traced just before its payload
+    let mut receiving = None;
+
+    for line in contents.lines().map(strip_ansi) {
+        if line.contains("encoded control message") {
+            if let Some(hex) = field(&line, "hex") {
+                messages.push(Captured {
+                    direction: Direction::Sent,
+                    stream: Stream::Control,
+                    hex: hex.to_string(),
+                });
+            }
+        } else if line.contains("reading control message") {
+            receiving = field(&line, "id").and_then(|id| id.parse::<u64>().ok());
+        } else if line.contains("decoding control message") {
+            let (Some(id), Some(payload)) = (receiving.take(), field(&line, "hex")) else {
+                continue;
+            };
+            let payload = decode_hex(payload).with_context(|| format!("invalid hex in {}", log.display()))?;
+            let mut wire = Vec::new();
+            id.encode(&mut wire, DRAFT);
+            (payload.len() as u16).encode(&mut wire, DRAFT);
+            wire.extend_from_slice(&payload);
+            messages.push(Captured {
+                direction: Direction::Received,
+                stream: Stream::Control,
+                hex: hex(&wire),
+            });
+        }
+    }
+    anyhow::ensure!(
+        !messages.is_empty(),
+        "no control messages in {}, was it logged with --log-level trace?",
+        log.display()
+    );
+
+    let mut lines = String::new();
+    for message in &messages {
+        lines.push_str(&serde_json::to_string(message)?);
+        lines.push('\n');
+    }
+    std::fs::write(capture, lines).with_context(|| format!("can't write {}", capture.display()))?;
+    Ok(messages.len())
+}
+
+/// The value of `name=value` in a log line
+fn field<'a>(line: &'a str, name: &str) -> Option<&'a str> {
+    line.split_whitespace().find_map(|word| word.strip_prefix(name)?.strip_prefix('='))
+}
+
+/// `line` without the colors of a terminal log
+fn strip_ansi(line: &str) -> String {
+    let mut stripped = String::with_capacity(line.len());
+    let mut chars = line.chars();
+    while let Some(c) = chars.next() {
+        if c == '\x1b' {
+            // Up to and including the final letter of the escape sequence
+            chars.by_ref().find(|c| c.is_ascii_alphabetic());
+        } else {
+            stripped.push(c);
+        }
+    }
+    stripped
+}
+
+fn hex(data: &[u8]) -> String {
+    data.iter().map(|b| format!("{b:02x}")).collect()
+}
@@ -0,0 +1,296 @@
+//! MoQ connection setup that keeps hold of the QUIC connection
+//!
+//! `moq_native::Client::connect` hides the QUIC connection behind the MoQ session,
+//! but we want its stats. This mirrors moq-native's WebTransport and raw QUIC paths;
+//! other schemes (`http://` certificate fingerprints) still go through moq-native.
+//!
+//! When a hostname has both IPv4 and IPv6 addresses they're raced, Happy Eyeballs style:
+//! addresses are tried alternating between the families, each new attempt starting
+//! [ATTEMPT_DELAY] after the last one or as soon as it fails, and the first handshake to
+//! complete wins. The family that won is tried first the next time, so a host with a
+//! broken path for one family only pays for it once. `--relay-ip-family` and
+//! `--cf-ip-family` restrict the connections to one family instead.
+//!
+//! The URL's scheme picks the transport: WebTransport for `https://`, raw QUIC for
+//! `moql://` (moq-lite) and `moqt://` (Draft 14). `--relay-transport` and `--cf-transport`
+//! choose one whatever the scheme, and `--relay-alpn` and `--cf-alpn` override the ALPN
+//! it's offered with.
+//!
+//! TLS sessions are resumed on reconnect, and with `--relay-zero-rtt` and `--cf-zero-rtt`
+//! the session setup is sent as 0-RTT data when the server allows it, saving a round trip.
+//! 0-RTT data can be replayed by an attacker on the path, and the setup is only the
+//! WebTransport CONNECT and MoQ's SETUP, so replays can't publish or subscribe to anything
+//! new; but with a `--relay-token` they do repeat the token. A 0-RTT attempt that's
+//! rejected, or that doesn't set the session up within [EARLY_TIMEOUT], falls back to a
+//! full handshake, racing addresses as usual. `zero_rtt_connections_total` counts how
+//! they went.
+
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, LazyLock, Mutex};
+use std::time::Duration;
+
+use anyhow::Context;
+use moq_lite::{OriginConsumer, OriginProducer, Session};
+use moq_native::web_transport_quinn::{self, quinn};
+use tokio::task::JoinSet;
+use url::Url;
+
+use crate::metrics::Counter;
+use crate::pin::{self, SpkiPin};
+
+/// How long an attempt gets before the next address is tried alongside it
+const ATTEMPT_DELAY: Duration = Duration::from_millis(250);
+
+/// How long a 0-RTT attempt gets to set the session up
+const EARLY_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Whether IPv6 won the last race to each host
+static PREFER_IPV6: LazyLock<Mutex<HashMap<String, bool>>> = LazyLock::new(Default::default);
+
+/// The addresses to connect to
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum IpFamily {
+    /// Race IPv4 and IPv6
+    #[default]
+    Any,
+    /// IPv4 only
+    V4,
+    /// IPv6 only
+    V6,
+}
+
+/// How to carry a MoQ session
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum TransportKind {
+    /// Whatever the URL's scheme says
+    #[default]
+    Auto,
+    /// WebTransport over HTTP/3
+    #[value(name = "webtransport")]
+    WebTransport,
+    /// Raw QUIC, with MoQ's own ALPN
+    Quic,
+}
+
+/// How to connect to one endpoint
+#[derive(Clone, Debug)]
+pub struct ConnectOptions {
+    pub family: IpFamily,
+    pub transport: TransportKind,
+    /// In place of the transport's own ALPN
+    pub alpn: Option<String>,
+    /// The ALPN for raw QUIC when the scheme doesn't say which MoQ it is
+    pub quic_alpn: &'static str,
+    /// Send the session setup as 0-RTT data when resuming
+    pub zero_rtt: bool,
+    /// Keys the server's certificate has to have one of, if any
+    pub pins: Vec<SpkiPin>,
+}
+
+/// A MoQ session and, when we set it up ourselves, the QUIC connection under it
+pub struct Connection {
+    pub session: Session,
+    pub quic: Option<quinn::Connection>,
+}
+
+/// Connect to `url` with the same TLS and transport settings as `client`, as `options` say
+pub async fn connect(
+    client: &moq_native::Client,
+    mut url: Url,
+    options: &ConnectOptions,
+    publish: Option<OriginConsumer>,
+    subscribe: Option<OriginProducer>,
+) -> anyhow::Result<Connection> {
+    let (webtransport, alpn) = match (options.transport, url.scheme()) {
+        (TransportKind::Auto, "https") | (TransportKind::WebTransport, "https" | "moql" | "moqt") => {
+            (true, web_transport_quinn::ALPN)
+        }
+        (TransportKind::Auto | TransportKind::Quic, "moql") => (false, moq_lite::lite::ALPN),
+        (TransportKind::Auto | TransportKind::Quic, "moqt") => (false, moq_lite::ietf::ALPN),
+        (TransportKind::Quic, "https") => (false, options.quic_alpn),
+        (TransportKind::Auto, scheme) if !options.pins.is_empty() => {
+            anyhow::bail!("can't pin the key of a {scheme}:// URL")
+        }
+        (TransportKind::Auto, _) => {
+            let session = client.connect(url, publish, subscribe).await?;
+            return Ok(Connection { session, quic: None });
+        }
+        (transport, scheme) => anyhow::bail!("can't connect with {transport:?} to a {scheme}:// URL"),
+    };
+    let alpn = options.alpn.as_deref().unwrap_or(alpn);
+    if webtransport && url.scheme() != "https" {
+        // Only the scheme differs, so this can't fail
+        url = Url::parse(&format!("https{}", &url.as_str()[url.scheme().len()..]))?;
+    }
+    let family = options.family;
+
+    let host = url.host_str().context("missing hostname")?;
+    let host = host.trim_start_matches('[').trim_end_matches(']').to_string();
+    let port = url.port().unwrap_or(443);
+
+    let addrs: Vec<SocketAddr> = tokio::net::lookup_host((host.as_str(), port))
+        .await
+        .context("failed DNS lookup")?
+        .filter(|addr| match family {
+            IpFamily::Any => true,
+            IpFamily::V4 => addr.is_ipv4(),
+            IpFamily::V6 => addr.is_ipv6(),
+        })
+        .collect();
+    anyhow::ensure!(!addrs.is_empty(), "no DNS entries for {host} with {family:?}");
+
+    let mut tls = client.tls.clone();
+    tls.alpn_protocols = vec![alpn.as_bytes().to_vec()];
+    tls.enable_early_data = options.zero_rtt;
+
+    let tls: quinn::crypto::rustls::QuicClientConfig = tls.try_into()?;
+    let mut config = quinn::ClientConfig::new(Arc::new(tls));
+    config.transport_config(client.transport.clone());
+
+    let addrs = interleave(&host, addrs);
+    if options.zero_rtt {
+        // To the address that connected last time, since we're resuming its session
+        let early = client.quic.connect_with(config.clone(), addrs[0], &host)?.into_0rtt();
+        if let Ok((quic, accepted)) = early {
+            tracing::debug!(%url, addr = %addrs[0], %alpn, "connecting with 0-RTT");
+            let setup = establish_early(quic, accepted, webtransport, url.clone(), publish.clone(), subscribe.clone());
+            let setup = async {
+                let connection = setup.await?;
+                if let Some(connection) = &connection {
+                    pin::check(connection.quic.as_ref().expect("we set it up"), &host, &options.pins)?;
+                }
+                anyhow::Ok(connection)
+            };
+            let (connected, result) = match tokio::time::timeout(EARLY_TIMEOUT, setup).await {
+                Ok(Ok(Some(connection))) => (Ok(connection), "accepted"),
+                Ok(Ok(None)) => (Err(anyhow::anyhow!("0-RTT rejected")), "rejected"),
+                Ok(Err(err)) => (Err(err), "failed"),
+                Err(elapsed) => (Err(elapsed.into()), "failed"),
+            };
+            zero_rtt_counter(&host, result).inc();
+            match connected {
+                Ok(connection) => return Ok(connection),
+                Err(err) => tracing::debug!(%host, %err, result, "0-RTT didn't connect, falling back to a full handshake"),
+            }
+        }
+    }
+
+    tracing::debug!(%url, ?addrs, %alpn, "connecting");
+    let quic = race(&client.quic, config, addrs, &host).await?;
+    pin::check(&quic, &host, &options.pins)?;
+    establish(quic, webtransport, url, publish, subscribe).await
+}
+
+/// Set a MoQ session up on a 0-RTT connection, or None if the server rejected the 0-RTT data
+///
+/// web-transport-quinn panics on reading a stream whose 0-RTT data was rejected, so the
+/// setup is dropped as soon as the handshake says so, before it's polled again.
+async fn establish_early(
+    quic: quinn::Connection,
+    mut accepted: quinn::ZeroRttAccepted,
+    webtransport: bool,
+    url: Url,
+    publish: Option<OriginConsumer>,
+    subscribe: Option<OriginProducer>,
+) -> anyhow::Result<Option<Connection>> {
+    let setup = establish(quic, webtransport, url, publish, subscribe);
+    tokio::pin!(setup);
+    let mut confirmed = false;
+    loop {
+        tokio::select! {
+            biased;
+            accepted = &mut accepted, if !confirmed => match accepted {
+                true => confirmed = true,
+                false => return Ok(None),
+            },
+            connected = &mut setup => return connected.map(Some),
+        }
+    }
+}
+
+/// Set a MoQ session up on `quic`
+async fn establish(
+    quic: quinn::Connection,
+    webtransport: bool,
+    url: Url,
+    publish: Option<OriginConsumer>,
+    subscribe: Option<OriginProducer>,
+) -> anyhow::Result<Connection> {
+    let transport = match webtransport {
+        true => web_transport_quinn::Session::connect(quic.clone(), url).await?,
+        false => web_transport_quinn::Session::raw(quic.clone(), url),
+    };
+
+    let session = Session::connect(transport, publish, subscribe).await?;
+    Ok(Connection {
+        session,
+        quic: Some(quic),
+    })
+}
+
+fn zero_rtt_counter(host: &str, result: &str) -> Counter {
+    Counter::new(
+        "zero_rtt_connections_total",
+        "Connections attempted with 0-RTT, by how it went",
+        &[("host", host), ("result", result)],
+    )
+}
+
+/// `addrs` alternating between the families, starting with the one that won last time
+fn interleave(host: &str, addrs: Vec<SocketAddr>) -> Vec<SocketAddr> {
+    // The resolver has already sorted them by preference
+    let first_ipv6 = addrs[0].is_ipv6();
+    let prefer_ipv6 = PREFER_IPV6.lock().unwrap().get(host).copied().unwrap_or(first_ipv6);
+    let (mut preferred, mut other): (Vec<_>, Vec<_>) = addrs.into_iter().partition(|addr| addr.is_ipv6() == prefer_ipv6);
+    preferred.reverse();
+    other.reverse();
+
+    let mut interleaved = Vec::with_capacity(preferred.len() + other.len());
+    while let Some(addr) = preferred.pop().or_else(|| other.pop()) {
+        interleaved.push(addr);
+        interleaved.extend(other.pop());
+    }
+    interleaved
+}
+
+/// Connect to the first of `addrs` to complete a handshake
+async fn race(
+    endpoint: &quinn::Endpoint,
+    config: quinn::ClientConfig,
+    addrs: Vec<SocketAddr>,
+    host: &str,
+) -> anyhow::Result<quinn::Connection> {
+    let mut addrs = addrs.into_iter();
+    // Dropped attempts are abandoned
+    let mut attempts = JoinSet::new();
+    let mut failure = None;
+
+    loop {
+        if let Some(addr) = addrs.next() {
+            let (endpoint, config, host) = (endpoint.clone(), config.clone(), host.to_string());
+            attempts.spawn(async move {
+                let connected = async { anyhow::Ok(endpoint.connect_with(config, addr, &host)?.await?) };
+                (addr, connected.await)
+            });
+        }
+
+        let next = tokio::time::sleep(ATTEMPT_DELAY);
+        tokio::select! {
+            Some(attempt) = attempts.join_next() => match attempt? {
+                (addr, Ok(connection)) => {
+                    tracing::debug!(%host, %addr, "connected");
+                    PREFER_IPV6.lock().unwrap().insert(host.to_string(), addr.is_ipv6());
+                    return Ok(connection);
+                }
+                (addr, Err(err)) => {
+                    tracing::debug!(%host, %addr, %err, "connection attempt failed");
+                    failure = Some(err);
+                }
+            },
+            _ = next, if addrs.len() > 0 => {}
+            else => break,
+        }
+    }
+    Err(failure.unwrap_or_else(|| anyhow::anyhow!("no addresses to connect to")))
+}
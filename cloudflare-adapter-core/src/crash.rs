@@ -0,0 +1,78 @@
+//! Crash and error reporting
+//!
+//! With the `sentry` feature and `--sentry-dsn`, panics are reported to Sentry along
+//! with the bridge failures that point at a bug rather than the network: bridges that
+//! got stuck or whose task failed, and the error the adapter as a whole stopped with.
+//! Events carry the adapter version as their release, and those of a bridge are tagged
+//! with its stream, namespace and relay path. A panic in a bridge is reported by the
+//! panic handler with the same tags.
+//!
+//! Failures that are part of normal operation (a stream that isn't live on CF, a lost
+//! session) aren't reported; the lifecycle callbacks and the event stream see those.
+
+use std::future::Future;
+
+use sentry::{Hub, SentryFutureExt};
+
+use crate::error::{AdapterError, BridgeError};
+use crate::lifecycle::BridgeContext;
+use crate::AdapterConfig;
+
+/// Reporting for the life of the process, flushing what's queued when dropped
+pub struct CrashReporting {
+    _guard: sentry::ClientInitGuard,
+}
+
+/// Start reporting to `--sentry-dsn`, None if it isn't set
+pub fn init(config: &AdapterConfig) -> Option<CrashReporting> {
+    let dsn = config.sentry_dsn.as_deref()?;
+    let options = sentry::ClientOptions {
+        release: Some(env!("CARGO_PKG_VERSION").into()),
+        environment: config.sentry_environment.clone().map(Into::into),
+        ..Default::default()
+    };
+
+    let guard = sentry::init((dsn, options));
+    if !guard.is_enabled() {
+        tracing::warn!("invalid sentry dsn, not reporting errors");
+        return None;
+    }
+    tracing::info!("reporting errors to sentry");
+    Some(CrashReporting { _guard: guard })
+}
+
+/// Run a bridge with its context on whatever it reports, panics included
+pub(crate) fn bind<F: Future>(context: &BridgeContext, bridge: F) -> impl Future<Output = F::Output> {
+    let hub = Hub::new_from_top(Hub::current());
+    hub.configure_scope(|scope| {
+        scope.set_tag("stream_id", &context.stream.stream_id);
+        scope.set_tag("namespace", &context.namespace);
+        scope.set_tag("path", &context.path);
+        if let Some(relay) = &context.relay {
+            scope.set_tag("relay", relay);
+        }
+    });
+    bridge.bind_hub(hub)
+}
+
+/// Report a bridge failure, if it's one worth waking someone for
+pub(crate) fn bridge_failed(context: &BridgeContext, err: &BridgeError) {
+    // Panics went to the panic handler already
+    if !matches!(err, BridgeError::Stuck | BridgeError::Task(_)) {
+        return;
+    }
+
+    sentry::with_scope(
+        |scope| {
+            scope.set_tag("stream_id", &context.stream.stream_id);
+            scope.set_tag("namespace", &context.namespace);
+            scope.set_tag("path", &context.path);
+        },
+        || sentry::capture_error(err),
+    );
+}
+
+/// Report the error the adapter stopped with
+pub fn adapter_failed(err: &AdapterError) {
+    sentry::capture_error(err);
+}
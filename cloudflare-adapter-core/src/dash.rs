@@ -0,0 +1,159 @@
+//! MPEG-DASH egress
+//!
+//! A dynamic MPD over the same segments the packager cuts for HLS, for players that
+//! only speak DASH. Segments are addressed by number with a `SegmentTimeline`, and
+//! media time is anchored to the wall clock when the stream's first part was cut.
+//!
+//! Everything lives under `/dash/{stream_id}/`: `manifest.mpd`, plus `init.mp4` and
+//! `{msn}.m4s` for each track.
+
+use std::fmt::Write as _;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use axum::extract::{Path, State};
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use axum::routing::get;
+use axum::Router;
+
+use crate::catalog::MediaKind;
+use crate::mp4::TIMESCALE;
+use crate::package::{self, respond, PackagedStream, PackagedTrack, Packager};
+
+/// The DASH routes, to be merged into the embedded HTTP server
+pub fn routes(packager: Arc<Packager>) -> Router {
+    Router::new()
+        .route("/dash/{stream_id}/manifest.mpd", get(serve_manifest))
+        .route("/dash/{stream_id}/{track}/{file}", get(serve_track))
+        .with_state(packager)
+}
+
+/// Look up a stream that's packaged for DASH
+fn stream(packager: &Packager, stream_id: &str) -> Option<Arc<PackagedStream>> {
+    packager.stream(stream_id).filter(|s| s.formats.dash)
+}
+
+async fn serve_manifest(State(packager): State<Arc<Packager>>, Path(stream_id): Path<String>) -> Response {
+    let Some(stream) = stream(&packager, &stream_id) else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+
+    // Nothing can be addressed until the first part anchors media time
+    let Some(epoch) = stream.epoch() else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+
+    let options = &packager.options;
+    let segment = options.segment_duration.as_secs_f64();
+    let depth = segment * options.window as f64;
+
+    let mut out = String::new();
+    let _ = writeln!(out, r#"<?xml version="1.0" encoding="UTF-8"?>"#);
+    let _ = writeln!(
+        out,
+        r#"<MPD xmlns="urn:mpeg:dash:schema:mpd:2011" profiles="urn:mpeg:dash:profile:isoff-live:2011" type="dynamic" availabilityStartTime="{}" publishTime="{}" minimumUpdatePeriod="PT{segment:.3}S" minBufferTime="PT{segment:.3}S" timeShiftBufferDepth="PT{depth:.3}S" suggestedPresentationDelay="PT{:.3}S">"#,
+        iso8601(epoch),
+        iso8601(SystemTime::now()),
+        segment * 2.0,
+    );
+    let _ = writeln!(out, r#"  <Period id="0" start="PT0S">"#);
+
+    let tracks = stream.tracks();
+    for (id, (kind, content, mime)) in [(MediaKind::Video, "video", "video/mp4"), (MediaKind::Audio, "audio", "audio/mp4")]
+        .into_iter()
+        .enumerate()
+    {
+        let tracks: Vec<_> = tracks.iter().filter(|t| t.rendition.kind == kind).collect();
+        if tracks.is_empty() {
+            continue;
+        }
+
+        let _ = writeln!(
+            out,
+            r#"    <AdaptationSet id="{id}" contentType="{content}" mimeType="{mime}" startWithSAP="1">"#
+        );
+        for track in tracks {
+            write_representation(&mut out, track);
+        }
+        let _ = writeln!(out, "    </AdaptationSet>");
+    }
+
+    let _ = writeln!(out, "  </Period>");
+    let _ = writeln!(out, "</MPD>");
+
+    respond("application/dash+xml", out)
+}
+
+fn write_representation(out: &mut String, track: &PackagedTrack) {
+    let _ = write!(
+        out,
+        r#"      <Representation id="{}" codecs="{}" bandwidth="{}""#,
+        escape(&track.path),
+        escape(track.codec().unwrap_or_default()),
+        track.bitrate()
+    );
+    if let Some((width, height)) = track.dimensions() {
+        let _ = write!(out, r#" width="{width}" height="{height}""#);
+    }
+    let _ = writeln!(out, ">");
+
+    let window = track.window.borrow();
+    let segments: Vec<_> = window.segments.iter().filter(|s| s.complete).collect();
+    let first = segments.first().map_or(0, |s| s.msn);
+
+    let _ = writeln!(
+        out,
+        r#"        <SegmentTemplate timescale="{TIMESCALE}" initialization="{path}/init.mp4" media="{path}/$Number$.m4s" startNumber="{first}">"#,
+        path = escape(&track.path),
+    );
+    let _ = writeln!(out, "          <SegmentTimeline>");
+    for segment in segments {
+        let _ = writeln!(out, r#"            <S t="{}" d="{}"/>"#, segment.start, segment.duration());
+    }
+    let _ = writeln!(out, "          </SegmentTimeline>");
+    let _ = writeln!(out, "        </SegmentTemplate>");
+    let _ = writeln!(out, "      </Representation>");
+}
+
+async fn serve_track(
+    State(packager): State<Arc<Packager>>,
+    Path((stream_id, track, file)): Path<(String, String, String)>,
+) -> Response {
+    match stream(&packager, &stream_id).and_then(|s| s.track(&track)) {
+        Some(track) => package::serve_fragment(&track, &file),
+        None => StatusCode::NOT_FOUND.into_response(),
+    }
+}
+
+/// Escape a value for use in an XML attribute
+fn escape(value: &str) -> String {
+    value
+        .replace('&', "&amp;")
+        .replace('"', "&quot;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+/// Format a time as an ISO 8601 UTC timestamp with millisecond precision
+fn iso8601(time: SystemTime) -> String {
+    let since = time.duration_since(SystemTime::UNIX_EPOCH).unwrap_or(Duration::ZERO);
+    let secs = since.as_secs();
+    let (hour, minute, second) = (secs / 3600 % 24, secs / 60 % 60, secs % 60);
+
+    // Civil date from days since 1970-01-01 (Howard Hinnant's algorithm)
+    let days = (secs / 86_400) as i64 + 719_468;
+    let era = days.div_euclid(146_097);
+    let doe = days.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+
+    format!(
+        "{year:04}-{month:02}-{day:02}T{hour:02}:{minute:02}:{second:02}.{:03}Z",
+        since.subsec_millis()
+    )
+}
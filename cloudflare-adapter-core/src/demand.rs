@@ -0,0 +1,68 @@
+//! On-demand bridging
+//!
+//! Most of a large registry's streams are long tail, with nobody watching most of the
+//! time, yet a bridge holds its CF broadcast and session for as long as the stream is
+//! listed. With `--on-demand <secs>` a bridge starts out on standby instead: its broadcast
+//! is announced to the relay as usual, so players can find it, but nothing is taken from
+//! CF until the relay subscribes to one of its tracks, which it only does for a viewer.
+//! The bridge then announces the stream at CF and forwards as usual, and once the relay
+//! has unsubscribed from every track and stayed away for `secs`, it lets go of the CF
+//! broadcast and its session and goes back to standby.
+//!
+//! Viewers arriving at a standby bridge wait for the CF announcement, like they do for a
+//! bridge moving sessions. Local outputs (recording, HLS/DASH, UDP, thumbnails) subscribe
+//! like viewers do, so a bridge with any of them is always watched.
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::sync::watch;
+
+/// The relay's subscriptions to one bridge's tracks
+pub struct Demand {
+    watchers: watch::Sender<usize>,
+    /// How long the bridge stays up without any
+    idle: Duration,
+}
+
+impl Demand {
+    pub fn new(idle: Duration) -> Arc<Self> {
+        Arc::new(Self {
+            watchers: watch::Sender::new(0),
+            idle,
+        })
+    }
+
+    /// Count a subscription to one of the bridge's tracks, until the guard is dropped
+    pub fn watch(self: &Arc<Self>) -> Watching {
+        self.watchers.send_modify(|watchers| *watchers += 1);
+        Watching(self.clone())
+    }
+
+    /// Resolve once anything subscribes
+    pub async fn watched(&self) {
+        let mut watchers = self.watchers.subscribe();
+        let _ = watchers.wait_for(|watchers| *watchers > 0).await;
+    }
+
+    /// Resolve once nothing has been subscribed for the idle period
+    pub async fn unwatched(&self) {
+        let mut watchers = self.watchers.subscribe();
+        loop {
+            let _ = watchers.wait_for(|watchers| *watchers == 0).await;
+            let watched = watchers.wait_for(|watchers| *watchers > 0);
+            if tokio::time::timeout(self.idle, watched).await.is_err() {
+                return;
+            }
+        }
+    }
+}
+
+/// One subscription, counted until dropped
+pub struct Watching(Arc<Demand>);
+
+impl Drop for Watching {
+    fn drop(&mut self) {
+        self.0.watchers.send_modify(|watchers| *watchers -= 1);
+    }
+}
@@ -0,0 +1,488 @@
+//! Minimal fragmented MP4 demuxer
+//!
+//! The counterpart of `mp4`: reads the `moov` and `moof`+`mdat` boxes that ffmpeg
+//! writes with `-movflags empty_moov+default_base_moof`, and turns them into hang
+//! catalog entries and timestamped samples. Only what ffmpeg produces for a stream
+//! copy of H.264, H.265, AV1, AAC and Opus is supported.
+
+use std::collections::HashMap;
+
+use anyhow::Context;
+use bytes::Bytes;
+use serde_json::{json, Value};
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+use crate::catalog::MediaKind;
+
+/// Set in the sample flags of anything that isn't a sync sample
+const NON_SYNC: u32 = 0x0001_0000;
+
+/// A track described by the init segment
+#[derive(Clone, Debug)]
+pub struct InitTrack {
+    pub id: u32,
+    pub kind: MediaKind,
+    /// The hang catalog config (`codec`, `description`, `codedWidth`, ...)
+    pub config: Value,
+    timescale: u32,
+    defaults: SampleDefaults,
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+struct SampleDefaults {
+    duration: u32,
+    size: u32,
+    flags: u32,
+}
+
+/// One sample of a fragment, with its presentation timestamp in microseconds
+pub struct DemuxedSample {
+    pub track_id: u32,
+    pub timestamp: u64,
+    pub keyframe: bool,
+    pub data: Bytes,
+}
+
+/// Read the next top-level box, header included, or None at the end of the stream
+pub async fn read_box<R: AsyncRead + Unpin>(reader: &mut R) -> anyhow::Result<Option<([u8; 4], Bytes)>> {
+    let mut header = [0u8; 8];
+    match reader.read_exact(&mut header).await {
+        Ok(_) => {}
+        Err(err) if err.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(err) => return Err(err.into()),
+    }
+
+    let kind: [u8; 4] = header[4..].try_into().unwrap();
+    let mut buf = header.to_vec();
+    let size = match u32::from_be_bytes(header[..4].try_into().unwrap()) {
+        1 => {
+            let mut large = [0u8; 8];
+            reader.read_exact(&mut large).await?;
+            buf.extend_from_slice(&large);
+            u64::from_be_bytes(large)
+        }
+        0 => anyhow::bail!("unbounded {} box", fourcc(&kind)),
+        size => size as u64,
+    };
+
+    let size = usize::try_from(size).ok().filter(|&s| s >= buf.len()).context("invalid box size")?;
+    let header_len = buf.len();
+    buf.resize(size, 0);
+    reader.read_exact(&mut buf[header_len..]).await?;
+
+    Ok(Some((kind, Bytes::from(buf))))
+}
+
+/// Describe every supported track in a `moov` box (including its header)
+pub fn parse_init(moov: &[u8]) -> anyhow::Result<Vec<InitTrack>> {
+    let moov = body(moov)?;
+    let mut tracks = Vec::new();
+    let mut defaults = HashMap::new();
+
+    for (kind, data) in boxes(moov) {
+        match &kind {
+            b"trak" => match parse_trak(data) {
+                Ok(Some(track)) => tracks.push(track),
+                Ok(None) => {}
+                Err(err) => tracing::warn!(%err, "skipping unreadable track"),
+            },
+            b"mvex" => {
+                for (_, trex) in boxes(data).filter(|(kind, _)| kind == b"trex") {
+                    let mut r = Reader::full(trex)?;
+                    let id = r.u32()?;
+                    r.skip(4)?; // default_sample_description_index
+                    let sample = SampleDefaults {
+                        duration: r.u32()?,
+                        size: r.u32()?,
+                        flags: r.u32()?,
+                    };
+                    defaults.insert(id, sample);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    for track in &mut tracks {
+        track.defaults = defaults.get(&track.id).copied().unwrap_or_default();
+    }
+
+    Ok(tracks)
+}
+
+fn parse_trak(trak: &[u8]) -> anyhow::Result<Option<InitTrack>> {
+    let tkhd = find(trak, b"tkhd").context("missing tkhd")?;
+    let mut r = Reader::new(tkhd);
+    let version = r.u8()?;
+    r.skip(3)?;
+    r.skip(if version == 1 { 16 } else { 8 })?; // creation and modification times
+    let id = r.u32()?;
+
+    let mdia = find(trak, b"mdia").context("missing mdia")?;
+    let mut r = Reader::new(find(mdia, b"mdhd").context("missing mdhd")?);
+    let version = r.u8()?;
+    r.skip(3)?;
+    r.skip(if version == 1 { 16 } else { 8 })?;
+    let timescale = r.u32()?;
+    anyhow::ensure!(timescale > 0, "zero timescale");
+
+    let mut r = Reader::full(find(mdia, b"hdlr").context("missing hdlr")?)?;
+    r.skip(4)?; // pre_defined
+    let kind = match &r.fourcc()? {
+        b"vide" => MediaKind::Video,
+        b"soun" => MediaKind::Audio,
+        _ => return Ok(None),
+    };
+
+    let stsd = find(mdia, b"minf")
+        .and_then(|minf| find(minf, b"stbl"))
+        .and_then(|stbl| find(stbl, b"stsd"))
+        .context("missing stsd")?;
+    let mut r = Reader::full(stsd)?;
+    r.skip(4)?; // entry_count
+    let (entry, data) = boxes(r.rest()).next().context("empty stsd")?;
+
+    let config = match kind {
+        MediaKind::Video => video_config(&entry, data)?,
+        MediaKind::Audio => audio_config(&entry, data)?,
+    };
+
+    Ok(Some(InitTrack {
+        id,
+        kind,
+        config,
+        timescale,
+        defaults: SampleDefaults::default(),
+    }))
+}
+
+fn video_config(entry: &[u8; 4], data: &[u8]) -> anyhow::Result<Value> {
+    let mut r = Reader::new(data);
+    r.skip(24)?; // reserved, data_reference_index, pre_defined
+    let width = r.u16()?;
+    let height = r.u16()?;
+    r.skip(50)?; // resolution, frame_count, compressorname, depth
+
+    let children = r.rest();
+    let (codec, description) = match entry {
+        b"avc1" | b"avc3" => {
+            let avcc = find(children, b"avcC").context("missing avcC")?;
+            anyhow::ensure!(avcc.len() >= 4, "short avcC");
+            (format!("avc1.{:02x}{:02x}{:02x}", avcc[1], avcc[2], avcc[3]), avcc)
+        }
+        b"hvc1" | b"hev1" => {
+            let hvcc = find(children, b"hvcC").context("missing hvcC")?;
+            (hevc_codec(fourcc(entry), hvcc)?, hvcc)
+        }
+        b"av01" => {
+            let av1c = find(children, b"av1C").context("missing av1C")?;
+            (av1_codec(av1c)?, av1c)
+        }
+        _ => anyhow::bail!("unsupported video codec: {}", fourcc(entry)),
+    };
+
+    let mut config = json!({
+        "codec": codec,
+        "description": encode_hex(description),
+        "codedWidth": width,
+        "codedHeight": height,
+    });
+    if let Some(bitrate) = find(children, b"btrt").and_then(avg_bitrate) {
+        config["bitrate"] = json!(bitrate);
+    }
+
+    Ok(config)
+}
+
+fn audio_config(entry: &[u8; 4], data: &[u8]) -> anyhow::Result<Value> {
+    let mut r = Reader::new(data);
+    r.skip(16)?; // reserved, data_reference_index, reserved
+    let channels = r.u16()?;
+    r.skip(6)?; // samplesize, pre_defined, reserved
+    let sample_rate = r.u32()? >> 16;
+
+    let children = r.rest();
+    let mut config = match entry {
+        b"mp4a" => {
+            let asc = find(children, b"esds").map(audio_specific_config).transpose()?.flatten();
+            let object_type = asc.as_ref().and_then(|asc| asc.first()).map_or(2, |b| b >> 3);
+
+            let mut config = json!({ "codec": format!("mp4a.40.{object_type}") });
+            if let Some(asc) = asc {
+                config["description"] = json!(encode_hex(&asc));
+            }
+            config
+        }
+        b"Opus" => json!({ "codec": "opus" }),
+        _ => anyhow::bail!("unsupported audio codec: {}", fourcc(entry)),
+    };
+
+    config["sampleRate"] = json!(sample_rate);
+    config["numberOfChannels"] = json!(channels);
+    if let Some(bitrate) = find(children, b"btrt").and_then(avg_bitrate) {
+        config["bitrate"] = json!(bitrate);
+    }
+
+    Ok(config)
+}
+
+/// The WebCodecs codec string for an `hvcC` record
+fn hevc_codec(entry: &str, hvcc: &[u8]) -> anyhow::Result<String> {
+    anyhow::ensure!(hvcc.len() >= 13, "short hvcC");
+
+    let space = ["", "A", "B", "C"][(hvcc[1] >> 6) as usize];
+    let tier = if hvcc[1] & 0x20 != 0 { 'H' } else { 'L' };
+    let profile = hvcc[1] & 0x1f;
+    // The compatibility flags are written in reverse bit order
+    let compat = u32::from_be_bytes(hvcc[2..6].try_into().unwrap()).reverse_bits();
+    let level = hvcc[12];
+
+    let mut codec = format!("{entry}.{space}{profile}.{compat:x}.{tier}{level}");
+    let constraints = &hvcc[6..12];
+    let used = constraints.iter().rposition(|&b| b != 0).map_or(0, |i| i + 1);
+    for byte in &constraints[..used] {
+        codec.push_str(&format!(".{byte:x}"));
+    }
+
+    Ok(codec)
+}
+
+/// The WebCodecs codec string for an `av1C` record
+fn av1_codec(av1c: &[u8]) -> anyhow::Result<String> {
+    anyhow::ensure!(av1c.len() >= 3, "short av1C");
+
+    let profile = av1c[1] >> 5;
+    let level = av1c[1] & 0x1f;
+    let tier = if av1c[2] & 0x80 != 0 { 'H' } else { 'M' };
+    let depth = match (av1c[2] & 0x40 != 0, av1c[2] & 0x20 != 0) {
+        (true, true) => 12,
+        (true, false) => 10,
+        _ => 8,
+    };
+
+    Ok(format!("av01.{profile}.{level:02}{tier}.{depth:02}"))
+}
+
+/// Dig the AudioSpecificConfig out of an `esds` box
+fn audio_specific_config(esds: &[u8]) -> anyhow::Result<Option<Vec<u8>>> {
+    let mut r = Reader::full(esds)?;
+
+    anyhow::ensure!(r.u8()? == 0x03, "missing ES_Descriptor");
+    r.descriptor_len()?;
+    r.skip(2)?; // ES_ID
+    let flags = r.u8()?;
+    if flags & 0x80 != 0 {
+        r.skip(2)?; // dependsOn_ES_ID
+    }
+    if flags & 0x40 != 0 {
+        let len = r.u8()?;
+        r.skip(len as usize)?; // URL
+    }
+    if flags & 0x20 != 0 {
+        r.skip(2)?; // OCR_ES_Id
+    }
+
+    anyhow::ensure!(r.u8()? == 0x04, "missing DecoderConfigDescriptor");
+    r.descriptor_len()?;
+    r.skip(13)?; // objectTypeIndication, streamType, bufferSizeDB, bitrates
+
+    if r.u8().ok() != Some(0x05) {
+        return Ok(None);
+    }
+    let len = r.descriptor_len()?;
+    Ok(Some(r.bytes(len)?.to_vec()))
+}
+
+fn avg_bitrate(btrt: &[u8]) -> Option<u32> {
+    let mut r = Reader::new(btrt);
+    r.skip(8).ok()?; // bufferSizeDB, maxBitrate
+    r.u32().ok().filter(|&b| b > 0)
+}
+
+/// Split a `moof` box and the `mdat` box after it into samples
+pub fn parse_fragment(tracks: &[InitTrack], moof: &Bytes, mdat: &Bytes) -> anyhow::Result<Vec<DemuxedSample>> {
+    // With default-base-is-moof, data offsets count from the start of the moof
+    let payload_start = moof.len() + (mdat.len() - body(mdat)?.len());
+    let mut samples = Vec::new();
+
+    for (_, traf) in boxes(body(moof)?).filter(|(kind, _)| kind == b"traf") {
+        let mut r = Reader::new(find(traf, b"tfhd").context("missing tfhd")?);
+        r.skip(1)?; // version
+        let flags = r.flags()?;
+        let id = r.u32()?;
+        let Some(track) = tracks.iter().find(|t| t.id == id) else {
+            continue;
+        };
+
+        anyhow::ensure!(flags & 0x01 == 0, "explicit base data offsets aren't supported");
+        let mut defaults = track.defaults;
+        if flags & 0x02 != 0 {
+            r.skip(4)?; // sample_description_index
+        }
+        if flags & 0x08 != 0 {
+            defaults.duration = r.u32()?;
+        }
+        if flags & 0x10 != 0 {
+            defaults.size = r.u32()?;
+        }
+        if flags & 0x20 != 0 {
+            defaults.flags = r.u32()?;
+        }
+
+        let mut decode_time = match find(traf, b"tfdt") {
+            Some(tfdt) => {
+                let mut r = Reader::new(tfdt);
+                let version = r.u8()?;
+                r.skip(3)?;
+                if version == 1 { r.u64()? } else { r.u32()? as u64 }
+            }
+            None => 0,
+        };
+
+        let mut offset = 0;
+        for (_, trun) in boxes(traf).filter(|(kind, _)| kind == b"trun") {
+            let mut r = Reader::new(trun);
+            let version = r.u8()?;
+            let flags = r.flags()?;
+            let count = r.u32()?;
+
+            if flags & 0x01 != 0 {
+                offset = usize::try_from(r.u32()? as i32).context("negative data offset")?;
+            }
+            let first_flags = if flags & 0x04 != 0 { Some(r.u32()?) } else { None };
+
+            for i in 0..count {
+                let duration = if flags & 0x100 != 0 { r.u32()? } else { defaults.duration };
+                let size = if flags & 0x200 != 0 { r.u32()? } else { defaults.size } as usize;
+                let sample_flags = match (flags & 0x400 != 0, first_flags) {
+                    (true, _) => r.u32()?,
+                    (false, Some(first)) if i == 0 => first,
+                    _ => defaults.flags,
+                };
+                let composition = match flags & 0x800 != 0 {
+                    true if version == 1 => r.u32()? as i32 as i64,
+                    true => r.u32()? as i64,
+                    false => 0,
+                };
+
+                anyhow::ensure!(offset >= payload_start, "sample outside mdat");
+                let start = offset - moof.len();
+                let data = mdat.get(start..start + size).context("sample outside mdat")?;
+                let pts = (decode_time as i64 + composition).max(0) as u64;
+
+                samples.push(DemuxedSample {
+                    track_id: id,
+                    timestamp: (pts as u128 * 1_000_000 / track.timescale as u128) as u64,
+                    keyframe: track.kind == MediaKind::Audio || sample_flags & NON_SYNC == 0,
+                    data: mdat.slice_ref(data),
+                });
+
+                offset += size;
+                decode_time += duration as u64;
+            }
+        }
+    }
+
+    Ok(samples)
+}
+
+/// The payload of a box, skipping its header
+fn body(data: &[u8]) -> anyhow::Result<&[u8]> {
+    let large = data.get(..4) == Some(&[0, 0, 0, 1]);
+    data.get(if large { 16 } else { 8 }..).context("short box")
+}
+
+/// Iterate over the child boxes in `data` as (type, payload) pairs
+fn boxes(mut data: &[u8]) -> impl Iterator<Item = ([u8; 4], &[u8])> {
+    std::iter::from_fn(move || {
+        let size = u32::from_be_bytes(data.get(..4)?.try_into().unwrap()) as usize;
+        let kind: [u8; 4] = data.get(4..8)?.try_into().unwrap();
+        let payload = data.get(8..size)?;
+        data = &data[size..];
+        Some((kind, payload))
+    })
+}
+
+fn find<'a>(data: &'a [u8], kind: &[u8; 4]) -> Option<&'a [u8]> {
+    boxes(data).find(|(k, _)| k == kind).map(|(_, payload)| payload)
+}
+
+fn fourcc(kind: &[u8; 4]) -> &str {
+    std::str::from_utf8(kind).unwrap_or("????")
+}
+
+fn encode_hex(data: &[u8]) -> String {
+    data.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+/// Reads big-endian fields from a box payload
+struct Reader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        Self { data }
+    }
+
+    /// Skip the version and flags of a full box
+    fn full(data: &'a [u8]) -> anyhow::Result<Self> {
+        let mut r = Self::new(data);
+        r.skip(4)?;
+        Ok(r)
+    }
+
+    fn bytes(&mut self, len: usize) -> anyhow::Result<&'a [u8]> {
+        anyhow::ensure!(self.data.len() >= len, "truncated box");
+        let (head, rest) = self.data.split_at(len);
+        self.data = rest;
+        Ok(head)
+    }
+
+    fn skip(&mut self, len: usize) -> anyhow::Result<()> {
+        self.bytes(len).map(|_| ())
+    }
+
+    fn rest(&mut self) -> &'a [u8] {
+        std::mem::take(&mut self.data)
+    }
+
+    fn u8(&mut self) -> anyhow::Result<u8> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    /// The 24-bit flags of a full box
+    fn flags(&mut self) -> anyhow::Result<u32> {
+        let flags = self.bytes(3)?;
+        Ok(u32::from_be_bytes([0, flags[0], flags[1], flags[2]]))
+    }
+
+    fn u16(&mut self) -> anyhow::Result<u16> {
+        Ok(u16::from_be_bytes(self.bytes(2)?.try_into().unwrap()))
+    }
+
+    fn u32(&mut self) -> anyhow::Result<u32> {
+        Ok(u32::from_be_bytes(self.bytes(4)?.try_into().unwrap()))
+    }
+
+    fn u64(&mut self) -> anyhow::Result<u64> {
+        Ok(u64::from_be_bytes(self.bytes(8)?.try_into().unwrap()))
+    }
+
+    fn fourcc(&mut self) -> anyhow::Result<[u8; 4]> {
+        Ok(self.bytes(4)?.try_into().unwrap())
+    }
+
+    /// An MPEG-4 descriptor length: up to four bytes of 7 bits each
+    fn descriptor_len(&mut self) -> anyhow::Result<usize> {
+        let mut len = 0;
+        for _ in 0..4 {
+            let byte = self.u8()?;
+            len = (len << 7) | (byte & 0x7f) as usize;
+            if byte & 0x80 == 0 {
+                break;
+            }
+        }
+        Ok(len)
+    }
+}
@@ -0,0 +1,216 @@
+//! Stream discovery
+//!
+//! The bridge manager asks a [StreamDiscovery] which streams to bridge. It lists them
+//! every `--poll-interval`, and straight away whenever the discovery says the list
+//! changed. Three are built in, picked with `--discovery`:
+//!
+//! - `http` polls `--registry-url` (the default)
+//! - `webhook` waits for the registry to `POST /discovery` to the embedded HTTP server,
+//!   with the same `{"broadcasts": [...]}` body the registry API serves
+//! - `static` bridges the `--static-stream` ids and nothing else
+//! - `replay` lists what `--record-registry` recorded, at the pace it was recorded; see
+//!   [replay](crate::replay)
+//!
+//! With `--bridge-prefix`, the broadcasts CF announces under the prefixes are listed on top
+//! of what the discovery lists, as they come and go.
+//!
+//! Embedders keeping the list elsewhere (a database, say) implement the trait and hand
+//! it to [BridgeManager::with_discovery](crate::BridgeManager::with_discovery).
+//!
+//! `http` needs the `registry` feature, and the webhook endpoint the `http` feature;
+//! without it lists can still be pushed to a [Webhook] in code.
+
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+
+use anyhow::Context;
+#[cfg(feature = "http")]
+use axum::extract::State;
+#[cfg(feature = "http")]
+use axum::http::{header, HeaderMap, StatusCode};
+#[cfg(feature = "http")]
+use axum::response::{IntoResponse, Response};
+#[cfg(feature = "http")]
+use axum::routing::post;
+#[cfg(feature = "http")]
+use axum::{Json, Router};
+use tokio::sync::{watch, Mutex};
+
+use crate::config::AdapterConfig;
+#[cfg(feature = "registry")]
+use crate::registry::fetch_cloudflare_streams;
+#[cfg(feature = "http")]
+use crate::registry::RegistryResponse;
+use crate::registry::StreamInfo;
+use crate::replay::Replay;
+
+pub type ListFuture<'a> = Pin<Box<dyn Future<Output = anyhow::Result<Vec<StreamInfo>>> + Send + 'a>>;
+pub type ChangeFuture<'a> = Pin<Box<dyn Future<Output = ()> + Send + 'a>>;
+
+/// Tells the bridge manager which streams to bridge
+pub trait StreamDiscovery: Send + Sync {
+    /// The streams to bridge right now; on error the running bridges are left alone
+    fn list(&self) -> ListFuture<'_>;
+
+    /// Resolve once the list may have changed, to list again before the next poll
+    fn next_change(&self) -> ChangeFuture<'_> {
+        Box::pin(std::future::pending())
+    }
+}
+
+/// The built-in discoveries
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum Discovery {
+    /// Poll the registry API
+    #[default]
+    Http,
+    /// Take the lists the registry pushes
+    Webhook,
+    /// Bridge a fixed list of streams
+    Static,
+    /// List what a `--record-registry` file recorded
+    Replay,
+}
+
+/// Polls the registry API for streams with one of our origins
+#[cfg(feature = "registry")]
+pub struct HttpPolling {
+    client: reqwest::Client,
+    url: String,
+    origins: Vec<String>,
+}
+
+#[cfg(feature = "registry")]
+impl HttpPolling {
+    pub fn new(url: String, origins: Vec<String>) -> Self {
+        Self {
+            client: reqwest::Client::new(),
+            url,
+            origins,
+        }
+    }
+}
+
+#[cfg(feature = "registry")]
+impl StreamDiscovery for HttpPolling {
+    fn list(&self) -> ListFuture<'_> {
+        Box::pin(async move { Ok(fetch_cloudflare_streams(&self.client, &self.url, &self.origins).await?) })
+    }
+}
+
+/// Holds the last stream list the registry pushed, empty until the first push
+pub struct Webhook {
+    streams: watch::Sender<Vec<StreamInfo>>,
+    // Marks the list the manager has seen, so a push while it's busy isn't missed
+    seen: Mutex<watch::Receiver<Vec<StreamInfo>>>,
+    origins: Vec<String>,
+    /// Bearer token required on pushes, if any
+    #[cfg_attr(not(feature = "http"), allow(dead_code))]
+    token: Option<String>,
+}
+
+impl Webhook {
+    pub fn new(origins: Vec<String>, token: Option<String>) -> Arc<Self> {
+        let (streams, seen) = watch::channel(Vec::new());
+        Arc::new(Self {
+            streams,
+            seen: Mutex::new(seen),
+            origins,
+            token,
+        })
+    }
+
+    /// Replace the stream list, keeping streams with one of our origins
+    pub fn push(&self, mut streams: Vec<StreamInfo>) {
+        streams.retain(|s| self.origins.contains(&s.origin));
+        tracing::debug!(streams = streams.len(), "stream list pushed");
+        self.streams.send_replace(streams);
+    }
+}
+
+impl StreamDiscovery for Webhook {
+    fn list(&self) -> ListFuture<'_> {
+        Box::pin(async move { Ok(self.seen.lock().await.borrow_and_update().clone()) })
+    }
+
+    fn next_change(&self) -> ChangeFuture<'_> {
+        Box::pin(async move {
+            // The sender lives as long as we do, so this can't fail
+            let _ = self.seen.lock().await.changed().await;
+        })
+    }
+}
+
+/// The webhook route, to be merged into the embedded HTTP server
+#[cfg(feature = "http")]
+pub fn routes(webhook: Arc<Webhook>) -> Router {
+    Router::new()
+        .route("/discovery", post(push_streams))
+        .with_state(webhook)
+}
+
+#[cfg(feature = "http")]
+async fn push_streams(
+    State(webhook): State<Arc<Webhook>>,
+    headers: HeaderMap,
+    Json(response): Json<RegistryResponse>,
+) -> Response {
+    if let Some(token) = &webhook.token {
+        let bearer = headers
+            .get(header::AUTHORIZATION)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.strip_prefix("Bearer "));
+        if bearer != Some(token.as_str()) {
+            return StatusCode::UNAUTHORIZED.into_response();
+        }
+    }
+
+    webhook.push(response.broadcasts);
+    StatusCode::NO_CONTENT.into_response()
+}
+
+/// Always the same streams
+pub struct StaticStreams {
+    streams: Vec<StreamInfo>,
+}
+
+impl StaticStreams {
+    pub fn new(streams: Vec<StreamInfo>) -> Self {
+        Self { streams }
+    }
+}
+
+impl StreamDiscovery for StaticStreams {
+    fn list(&self) -> ListFuture<'_> {
+        Box::pin(async move { Ok(self.streams.clone()) })
+    }
+}
+
+/// A discovery, with the webhook to serve if it's that one
+pub(crate) type Chosen = (Arc<dyn StreamDiscovery>, Option<Arc<Webhook>>);
+
+/// The built-in discovery `config` picks
+pub(crate) fn from_config(config: &AdapterConfig) -> anyhow::Result<Chosen> {
+    Ok(match config.discovery {
+        #[cfg(feature = "registry")]
+        Discovery::Http => {
+            let url = config.registry_url.clone().context("--registry-url is required")?;
+            (Arc::new(HttpPolling::new(url, config.origins.clone())), None)
+        }
+        #[cfg(not(feature = "registry"))]
+        Discovery::Http => anyhow::bail!("built without the registry feature, pick another --discovery"),
+        Discovery::Webhook => {
+            let webhook = Webhook::new(config.origins.clone(), config.webhook_token.clone());
+            (webhook.clone(), Some(webhook))
+        }
+        Discovery::Static => {
+            let streams = config.static_streams.iter().map(|id| StreamInfo::new(id.clone())).collect();
+            (Arc::new(StaticStreams::new(streams)), None)
+        }
+        Discovery::Replay => {
+            let path = config.replay_registry.as_deref().context("--replay-registry is required")?;
+            (Arc::new(Replay::load(path)?), None)
+        }
+    })
+}
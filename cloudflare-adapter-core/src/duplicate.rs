@@ -0,0 +1,139 @@
+//! Duplicate suppression
+//!
+//! A publisher roaming from CloudFlare to the relay, or publishing to both, can leave the
+//! relay with two broadcasts at one path: its own and our bridged copy. The relay serves
+//! whichever was published last and keeps the other as a backup, so viewers switch between
+//! them whenever either flaps. Path claims catch a broadcast that was there before the
+//! bridge; this catches one that turns up while it runs.
+//!
+//! With `--duplicates warn` or `suppress`, every bridge serves a `.origin` track holding an
+//! ID of its own, and whenever the relay announces its path, reads the track back through
+//! the relay. If what the relay serves there isn't ours, someone else has the path: `warn`
+//! logs it and counts it in `duplicate_broadcasts_total`, and `suppress` also stops the
+//! bridge, which isn't bridged again until the relay stops announcing the path.
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use bytes::Bytes;
+use moq_lite::{BroadcastConsumer, OriginConsumer, OriginProducer, Path, Track, TrackProducer};
+
+use crate::inject::Injector;
+use crate::metrics::Counter;
+
+/// The relay-side track a bridge's ID goes out on
+pub const ORIGIN_TRACK: &str = ".origin";
+
+/// How long the relay gets to hand our ID back
+const CHECK_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// What to do about another publisher at a bridge's path
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum DuplicatePolicy {
+    /// Don't look for them
+    #[default]
+    Ignore,
+    /// Log and count them
+    Warn,
+    /// Stop the bridge while the other broadcast is there
+    Suppress,
+}
+
+/// Watches the relay for another broadcast at one bridge's path
+pub struct DuplicateWatch {
+    stream_id: Arc<str>,
+    policy: DuplicatePolicy,
+    /// What the relay announces to us
+    relay: OriginConsumer,
+    path: String,
+    id: u64,
+}
+
+impl DuplicateWatch {
+    /// Watch `path` for the bridge of `stream_id`, or None if the policy is to ignore duplicates
+    pub fn new(policy: DuplicatePolicy, stream_id: &str, relay: OriginConsumer, path: &str) -> Option<Self> {
+        if policy == DuplicatePolicy::Ignore {
+            return None;
+        }
+        Some(Self {
+            stream_id: stream_id.into(),
+            policy,
+            relay,
+            path: path.to_string(),
+            id: rand::random(),
+        })
+    }
+
+    /// Resolve once another broadcast has the path, if the policy is to suppress the bridge
+    pub async fn run(self, injector: Injector) {
+        let (Some(mut marker), Some(mut announced)) =
+            (injector.track(ORIGIN_TRACK), self.relay.consume_only(&[Path::new(&self.path)]))
+        else {
+            return std::future::pending().await;
+        };
+        self.mark(&mut marker);
+
+        let mut reported = false;
+        while let Some((path, broadcast)) = announced.announced().await {
+            let Some(broadcast) = broadcast.filter(|_| path.as_str() == self.path) else {
+                continue;
+            };
+            // Twice, so a relay slow to pass our track on doesn't look like someone else
+            if self.ours(&broadcast, &mut marker).await || self.ours(&broadcast, &mut marker).await {
+                reported = false;
+                continue;
+            }
+
+            if !std::mem::replace(&mut reported, true) {
+                let policy = self.policy;
+                tracing::warn!(stream_id = %self.stream_id, path = self.path, ?policy, "another broadcast has the path");
+                let labels = [("stream_id", &*self.stream_id)];
+                Counter::new("duplicate_broadcasts_total", "Other broadcasts found at a bridge's path", &labels).inc();
+            }
+            if self.policy == DuplicatePolicy::Suppress {
+                return;
+            }
+        }
+        std::future::pending().await
+    }
+
+    /// Whether the relay's broadcast at our path hands our ID back
+    async fn ours(&self, broadcast: &BroadcastConsumer, marker: &mut TrackProducer) -> bool {
+        let mut track = broadcast.subscribe_track(&Track::new(ORIGIN_TRACK));
+        // A fresh one, in case the relay only passes on groups from after the subscription
+        self.mark(marker);
+
+        let read = async {
+            let mut group = track.next_group().await.ok()??;
+            group.read_frame().await.ok()?
+        };
+        let id = tokio::time::timeout(CHECK_TIMEOUT, read).await.ok().flatten();
+        id.is_some_and(|id| id.as_ref() == self.id.to_be_bytes())
+    }
+
+    fn mark(&self, marker: &mut TrackProducer) {
+        let mut group = marker.append_group();
+        group.write_frame(Bytes::copy_from_slice(&self.id.to_be_bytes()));
+        group.close();
+    }
+}
+
+/// A stream stopped for a duplicate, held back while the relay announces its path
+pub struct Suppressed {
+    relay: OriginProducer,
+    path: String,
+}
+
+impl Suppressed {
+    pub fn new(relay: &OriginProducer, path: &str) -> Self {
+        Self {
+            relay: relay.clone(),
+            path: path.to_string(),
+        }
+    }
+
+    /// Whether the other broadcast is still there
+    pub fn held(&self) -> bool {
+        self.relay.consume().consume_broadcast(&self.path).is_some()
+    }
+}
@@ -0,0 +1,80 @@
+//! End-to-end encrypted streams
+//!
+//! Publishers encrypting media for their viewers (with SFrame, say) rely on every hop
+//! handing on each frame exactly as it was written: a rewritten timestamp prefix or a
+//! transformed payload fails authentication at the player, which drops the frame. With
+//! `--e2ee` the adapter guarantees that for every track but the catalog:
+//!
+//! - frames are forwarded byte-exact, as `--passthrough-track` ones are, whatever the
+//!   track or its kind, with no timestamp rebasing, hooks or interceptors, and groups keep
+//!   their frames in order, never merged or split
+//! - options that change frames, or need to read them, are refused at startup (and by
+//!   `validate-config`), along with interceptors added in code, rather than left to
+//!   break decryption
+//!
+//! What the adapter may still do is leave out whole tracks, groups or the rest of a group,
+//! which players already cope with. The catalog stays readable to the adapter, as SFrame
+//! leaves it, so filters, aliases and priorities still apply.
+//!
+//! moq-lite hands us object payloads only: draft 14 object extension headers from
+//! CloudFlare aren't carried over, so any key ids or SFrame metadata have to travel in the
+//! payload, where SFrame puts its header anyway.
+//!
+//! `self-test --checksum` checks the guarantee end to end, comparing a SHA-256 of every
+//! frame of the test pattern as published with the one read back from the relay.
+
+use crate::AdapterConfig;
+
+/// The options given in `config` that `--e2ee` doesn't allow
+pub(crate) fn conflicts(config: &AdapterConfig) -> Vec<&'static str> {
+    let mut conflicts = Vec::new();
+    for (option, given) in [
+        ("transform-command", config.transform_command.is_some()),
+        ("interceptor", !config.interceptors.is_empty()),
+        ("rebase-timestamps", config.rebase_timestamps != Default::default()),
+        ("hls", config.hls),
+        ("dash", config.dash),
+        ("udp-output", !config.udp_output.is_empty()),
+        ("record-dir", config.record_dir.is_some()),
+        ("capture-latency", config.capture_latency),
+        ("ad-marker-track", config.ad_marker_track.is_some()),
+    ] {
+        if given {
+            conflicts.push(option);
+        }
+    }
+    #[cfg(feature = "ffmpeg")]
+    if config.thumbnail_url.is_some() {
+        conflicts.push("thumbnail-url");
+    }
+    #[cfg(feature = "webrtc")]
+    if config.whep {
+        conflicts.push("whep");
+    }
+    conflicts
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::Parser;
+
+    use super::*;
+
+    fn conflicts_of(args: &[&str]) -> Vec<&'static str> {
+        let base = ["cloudflare-adapter", "--relay-url", "https://relay.example", "--e2ee"];
+        conflicts(&AdapterConfig::parse_from(base.iter().chain(args)))
+    }
+
+    #[test]
+    fn refuses_options_that_read_frames() {
+        assert_eq!(conflicts_of(&[]), Vec::<&str>::new());
+        assert_eq!(conflicts_of(&["--passthrough-track", "*", "--latency-probe", "5"]), Vec::<&str>::new());
+        assert_eq!(conflicts_of(&["--capture-latency"]), ["capture-latency"]);
+        assert_eq!(conflicts_of(&["--ad-marker-track", "scte35"]), ["ad-marker-track"]);
+        assert_eq!(conflicts_of(&["--record-dir", "/var/lib/recordings"]), ["record-dir"]);
+        let conflicting = conflicts_of(&["--udp-output", "udp://239.0.0.1:5000", "--rebase-timestamps", "common-epoch"]);
+        assert_eq!(conflicting, ["rebase-timestamps", "udp-output"]);
+        #[cfg(feature = "webrtc")]
+        assert_eq!(conflicts_of(&["--http-listen", "[::]:8080", "--webrtc-address", "10.0.0.1", "--whep"]), ["whep"]);
+    }
+}
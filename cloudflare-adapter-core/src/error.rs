@@ -0,0 +1,79 @@
+//! Typed errors
+//!
+//! The adapter as a whole fails with an [AdapterError], and a single bridge with a
+//! [BridgeError], so embedders and the retry policy can tell the classes apart. The
+//! causes underneath (moq, QUIC, HTTP) stay [anyhow::Error]s and are part of the
+//! message, so `{err:#}` logs the whole chain as before.
+
+/// Why the adapter stopped
+#[derive(Debug, thiserror::Error)]
+pub enum AdapterError {
+    /// The options can't be used, e.g. a missing `--registry-url` or bad QUIC settings
+    #[error("invalid config: {0:#}")]
+    Config(anyhow::Error),
+    /// The connections to a relay, to CF or to the lease Redis gave up; `target` is
+    /// `relay`, `cloudflare` or `redis`
+    #[error("{target} connection failed: {err:#}")]
+    Connect { target: &'static str, err: anyhow::Error },
+    /// Listing the registry failed; the bridge manager retries it, so this is only
+    /// seen by discoveries and in events
+    #[error("registry request failed: {0:#}")]
+    Registry(anyhow::Error),
+    #[error("http server failed: {0:#}")]
+    Http(anyhow::Error),
+    #[error("config reload failed: {0:#}")]
+    Reload(anyhow::Error),
+    /// A loop made no progress for `--watchdog-timeout`
+    #[error("watchdog fired: {0:#}")]
+    Watchdog(anyhow::Error),
+    /// The shutdown future failed, or the signal handlers couldn't be installed
+    #[error("shutdown failed: {0:#}")]
+    Shutdown(anyhow::Error),
+    /// The task [BridgeManager::spawn](crate::BridgeManager::spawn) started panicked
+    #[error("adapter task failed: {0}")]
+    Task(String),
+    /// Shutdown gave up on bridges still running after `--drain-timeout`
+    #[error("drain timed out with {active} bridges still running")]
+    DrainTimeout { active: usize },
+}
+
+impl AdapterError {
+    /// The process exit status for this error: 3 when the drain was cut short, 1 for
+    /// anything else, so a forced shutdown is told apart from a clean one (0)
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            Self::DrainTimeout { .. } => 3,
+            _ => 1,
+        }
+    }
+}
+
+/// Why a bridge failed
+#[derive(Debug, thiserror::Error)]
+pub enum BridgeError {
+    /// No CF session was connected to subscribe with
+    #[error("cloudflare session not connected")]
+    NotConnected,
+    /// CF refused the announcement, or the session went away during it
+    #[error("failed to announce remote: {0:#}")]
+    Announce(anyhow::Error),
+    /// CF never published the broadcast, so the stream isn't live there
+    #[error("broadcast {namespace} not announced after {attempts} attempts")]
+    NotFound { namespace: String, attempts: u32 },
+    /// Nothing was forwarded for `--watchdog-timeout`
+    #[error("bridge made no progress")]
+    Stuck,
+    #[error("bridge panicked: {0}")]
+    Panicked(String),
+    #[error("bridge task failed: {0}")]
+    Task(String),
+}
+
+impl BridgeError {
+    /// Whether the failure says something about the stream, rather than our connections
+    ///
+    /// Only these count towards `--max-bridge-attempts`.
+    pub fn is_stream_fault(&self) -> bool {
+        !matches!(self, Self::NotConnected)
+    }
+}
@@ -0,0 +1,99 @@
+//! Adapter events for embedders
+//!
+//! [BridgeManager::events](crate::BridgeManager::events) streams what the adapter does:
+//! connections going up and down, bridges starting and ending, and the result of every
+//! discovery poll. It's a lower-level alternative to the lifecycle callbacks, for
+//! dashboards and tests.
+//!
+//! Each subscriber buffers up to [BUFFER] events. One that falls further behind misses
+//! events, counted in `events_dropped_total`, rather than holding the adapter up.
+
+use std::pin::Pin;
+use std::sync::Mutex;
+use std::task::{Context, Poll};
+
+use tokio::sync::{mpsc, watch};
+
+use crate::bridge::BridgeEnd;
+use crate::lifecycle::BridgeContext;
+use crate::metrics::Counter;
+
+/// How many events a subscriber can fall behind
+pub const BUFFER: usize = 1024;
+
+/// Something the adapter did
+#[derive(Clone, Debug)]
+pub enum AdapterEvent {
+    /// A relay connection came up or went down; `relay` is None for the main relay
+    Relay { relay: Option<String>, up: bool },
+    /// The number of connected CF sessions changed
+    Cloudflare { connected: usize, sessions: usize },
+    /// The discovery listed these stream IDs, before filters, or failed
+    Polled(Result<Vec<String>, String>),
+    BridgeStarted(BridgeContext),
+    /// The bridge wrote its first frame to the relay
+    BridgeActive(BridgeContext),
+    BridgeFailed(BridgeContext, String),
+    BridgeClosed(BridgeContext, BridgeEnd),
+}
+
+/// Hands events to every subscriber
+#[derive(Default)]
+pub(crate) struct EventBus {
+    subscribers: Mutex<Vec<mpsc::Sender<AdapterEvent>>>,
+}
+
+impl EventBus {
+    pub(crate) fn subscribe(&self) -> AdapterEvents {
+        let (events, received) = mpsc::channel(BUFFER);
+        self.subscribers.lock().unwrap().push(events);
+        AdapterEvents { received }
+    }
+
+    pub(crate) fn publish(&self, event: AdapterEvent) {
+        self.subscribers.lock().unwrap().retain(|subscriber| match subscriber.try_send(event.clone()) {
+            Ok(()) => true,
+            Err(mpsc::error::TrySendError::Full(_)) => {
+                Counter::new("events_dropped_total", "Events a slow subscriber missed", &[]).inc();
+                true
+            }
+            Err(mpsc::error::TrySendError::Closed(_)) => false,
+        });
+    }
+}
+
+/// A subscription to the adapter's events, ending once the adapter is dropped
+pub struct AdapterEvents {
+    received: mpsc::Receiver<AdapterEvent>,
+}
+
+impl AdapterEvents {
+    /// The next event, for callers not using [Stream](futures_core::Stream)
+    pub async fn next(&mut self) -> Option<AdapterEvent> {
+        self.received.recv().await
+    }
+}
+
+impl futures_core::Stream for AdapterEvents {
+    type Item = AdapterEvent;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<AdapterEvent>> {
+        self.received.poll_recv(cx)
+    }
+}
+
+/// Publish a relay's connection state as it changes, until the relay is dropped
+pub(crate) async fn follow_relay(events: &EventBus, relay: Option<String>, mut up: watch::Receiver<bool>) {
+    while up.changed().await.is_ok() {
+        let up = *up.borrow_and_update();
+        events.publish(AdapterEvent::Relay { relay: relay.clone(), up });
+    }
+}
+
+/// Publish how many CF sessions are connected as it changes, until the pool is dropped
+pub(crate) async fn follow_cloudflare(events: &EventBus, sessions: usize, mut connected: watch::Receiver<usize>) {
+    while connected.changed().await.is_ok() {
+        let connected = *connected.borrow_and_update();
+        events.publish(AdapterEvent::Cloudflare { connected, sessions });
+    }
+}
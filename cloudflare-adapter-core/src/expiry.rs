@@ -0,0 +1,176 @@
+//! Stream expiry
+//!
+//! Registry entries can bound how long a stream is bridged, for pay-per-view windows and
+//! time-boxed events: `expires_at`, in Unix seconds or RFC 3339, ends the bridge at that
+//! time, and `ttl` that many seconds after the poll that listed it. Every poll listing the
+//! stream again moves the deadline to what its entry says then, so a registry extending
+//! the window moves `expires_at`, and one that keeps listing the stream refreshes its
+//! `ttl`. With both, the earlier one counts.
+//!
+//! Bridges otherwise run until their broadcast ends, listed or not, so `ttl` is also how a
+//! registry makes dropping a stream end its bridge, and how bridges end when the registry
+//! can't be polled. A bridge past its deadline stops with
+//! [BridgeEnd::Expired](crate::BridgeEnd::Expired), and a stream listed past it isn't
+//! bridged, however long its entry lingers.
+//!
+//! A value that can't be read, like a negative `ttl` or a timestamp past what the clock
+//! can hold, is ignored with a warning, so it costs its stream the deadline and not the
+//! poll the other streams came in.
+
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use serde::Deserialize;
+use tokio::sync::watch;
+
+use crate::registry::StreamInfo;
+
+/// When `stream` expires, going by the entry a poll at `listed` returned
+pub(crate) fn deadline(stream: &StreamInfo, listed: SystemTime) -> Option<SystemTime> {
+    // Too far out to represent is as good as never
+    let expires_at = stream.expires_at.and_then(|secs| UNIX_EPOCH.checked_add(Duration::from_secs(secs)));
+    let ttl = stream.ttl.and_then(|secs| listed.checked_add(Duration::from_secs(secs)));
+    match (expires_at, ttl) {
+        (Some(expires_at), Some(ttl)) => Some(expires_at.min(ttl)),
+        (deadline, None) | (None, deadline) => deadline,
+    }
+}
+
+/// Whether `stream` shouldn't be bridged now that a poll at `listed` returned it
+pub(crate) fn expired(stream: &StreamInfo, listed: SystemTime) -> bool {
+    deadline(stream, listed).is_some_and(|deadline| deadline <= listed)
+}
+
+/// When a running bridge's stream expires, as of the last poll that listed it
+pub(crate) struct Expiry(watch::Sender<Option<SystemTime>>);
+
+impl Expiry {
+    pub(crate) fn new(deadline: Option<SystemTime>) -> Self {
+        Self(watch::Sender::new(deadline))
+    }
+
+    /// Move the deadline to what the latest entry says
+    pub(crate) fn refresh(&self, deadline: Option<SystemTime>) {
+        self.0.send_if_modified(|current| std::mem::replace(current, deadline) != deadline);
+    }
+
+    /// What the bridge waits on, see [wait]
+    pub(crate) fn subscribe(&self) -> watch::Receiver<Option<SystemTime>> {
+        self.0.subscribe()
+    }
+}
+
+/// Wait until the deadline passes, following it as polls move it
+///
+/// Never returns for a stream without one. Once the manager stops tracking the bridge, the
+/// last deadline stands.
+pub(crate) async fn wait(mut deadline: watch::Receiver<Option<SystemTime>>) {
+    loop {
+        let until = *deadline.borrow_and_update();
+        let passed = async {
+            match until {
+                Some(until) => tokio::time::sleep(until.duration_since(SystemTime::now()).unwrap_or_default()).await,
+                None => std::future::pending().await,
+            }
+        };
+        tokio::select! {
+            _ = passed => return,
+            Ok(()) = deadline.changed() => {}
+        }
+    }
+}
+
+/// Deserialize `expires_at` from Unix seconds or an RFC 3339 timestamp, to Unix seconds
+///
+/// Never fails, see the module docs.
+pub(crate) fn unix_seconds<'de, D: serde::Deserializer<'de>>(deserializer: D) -> Result<Option<u64>, D::Error> {
+    let secs = match Option::<serde_json::Value>::deserialize(deserializer)? {
+        None | Some(serde_json::Value::Null) => return Ok(None),
+        Some(serde_json::Value::Number(secs)) => secs.as_i64().or_else(|| secs.as_f64().and_then(whole_seconds)),
+        Some(serde_json::Value::String(text)) => text.parse().ok().or_else(|| rfc3339(&text)),
+        Some(_) => None,
+    };
+    match secs {
+        // Anything before the epoch has expired all the same
+        Some(secs) => Ok(Some(secs.max(0) as u64)),
+        None => Ok(invalid("expires_at", "Unix seconds or an RFC 3339 timestamp")),
+    }
+}
+
+/// Deserialize `ttl` from a number of seconds, dropping any fraction
+///
+/// Never fails, like [unix_seconds].
+pub(crate) fn seconds<'de, D: serde::Deserializer<'de>>(deserializer: D) -> Result<Option<u64>, D::Error> {
+    let secs = match Option::<serde_json::Value>::deserialize(deserializer)? {
+        None | Some(serde_json::Value::Null) => return Ok(None),
+        Some(serde_json::Value::Number(secs)) => secs.as_u64().or_else(|| {
+            let secs = whole_seconds(secs.as_f64()?)?;
+            u64::try_from(secs).ok()
+        }),
+        Some(_) => None,
+    };
+    Ok(secs.or_else(|| invalid("ttl", "a number of seconds, at least 0")))
+}
+
+/// A float that fits in whole seconds
+fn whole_seconds(secs: f64) -> Option<i64> {
+    let secs = secs.trunc();
+    (secs.is_finite() && secs >= i64::MIN as f64 && secs < i64::MAX as f64).then_some(secs as i64)
+}
+
+fn invalid(field: &str, expected: &str) -> Option<u64> {
+    tracing::warn!(field, expected, "ignoring invalid registry field");
+    None
+}
+
+/// Unix seconds of an RFC 3339 timestamp like `2026-10-14T20:00:00Z` or `2026-10-14T22:00:00.5+02:00`
+fn rfc3339(text: &str) -> Option<i64> {
+    let (date, time) = text.split_once(['T', 't', ' '])?;
+    let mut date = date.splitn(3, '-');
+    let year = date.next()?;
+    // RFC 3339 years have four digits, which keeps the days in range
+    if year.len() != 4 {
+        return None;
+    }
+    let year: i64 = year.parse().ok()?;
+    let month: i64 = date.next()?.parse().ok()?;
+    let day: i64 = date.next()?.parse().ok()?;
+
+    let (time, offset) = match time.strip_suffix(['Z', 'z']) {
+        Some(time) => (time, 0),
+        None => {
+            let (time, offset) = time.split_at(time.rfind(['+', '-'])?);
+            let sign = if offset.starts_with('-') { -1 } else { 1 };
+            let (hours, minutes) = offset[1..].split_once(':')?;
+            let (hours, minutes): (i64, i64) = (hours.parse().ok()?, minutes.parse().ok()?);
+            if hours >= 24 || minutes >= 60 {
+                return None;
+            }
+            (time, sign * (hours * 3600 + minutes * 60))
+        }
+    };
+    let mut time = time.splitn(3, ':');
+    let hour: i64 = time.next()?.parse().ok()?;
+    let minute: i64 = time.next()?.parse().ok()?;
+    // Fractions of a second don't matter here
+    let second: i64 = time.next()?.split('.').next()?.parse().ok()?;
+
+    let valid = (1..=12).contains(&month) && (1..=31).contains(&day) && hour < 24 && minute < 60 && second <= 60;
+    if !valid {
+        return None;
+    }
+    days_from_civil(year, month, day)?
+        .checked_mul(86400)?
+        .checked_add(hour * 3600 + minute * 60 + second)?
+        .checked_sub(offset)
+}
+
+/// Days from the epoch to a date in the proleptic Gregorian calendar
+fn days_from_civil(year: i64, month: i64, day: i64) -> Option<i64> {
+    // Years start in March, so the leap day ends them
+    let year = if month <= 2 { year.checked_sub(1)? } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year.rem_euclid(400);
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era.checked_mul(146_097)?.checked_add(day_of_era - 719_468)
+}
@@ -0,0 +1,279 @@
+//! Track selection rules
+//!
+//! Rules are written as `[stream_id=]pattern`, where the pattern supports `*` and `?`
+//! wildcards and a leading `!` turns it into an exclude. Rules scoped to a stream
+//! replace the global rules for that stream.
+//!
+//! Stream ID patterns, for `--include-stream` and `--exclude-stream`, can also be regular
+//! expressions, written `re:<regex>`.
+
+use std::fmt;
+use std::str::FromStr;
+
+use regex::Regex;
+
+/// Which tracks of a single broadcast should be forwarded to the relay
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct TrackFilter {
+    include: Vec<String>,
+    exclude: Vec<String>,
+}
+
+impl TrackFilter {
+    /// Build the filter for `stream_id` from the configured `--track-filter` rules
+    pub fn for_stream(rules: &[String], stream_id: &str) -> Self {
+        Self::new(scoped_rules(rules, stream_id))
+    }
+
+    /// Build a filter from unscoped patterns
+    pub fn new<'a>(patterns: impl IntoIterator<Item = &'a str>) -> Self {
+        let mut filter = Self::default();
+        for pattern in patterns {
+            match pattern.strip_prefix('!') {
+                Some(pattern) => filter.exclude.push(pattern.to_string()),
+                None => filter.include.push(pattern.to_string()),
+            }
+        }
+
+        filter
+    }
+
+    /// Returns true if the track should be bridged
+    pub fn allows(&self, track: &str) -> bool {
+        let included = self.include.is_empty() || self.include.iter().any(|p| glob_match(p, track));
+        included && !self.exclude.iter().any(|p| glob_match(p, track))
+    }
+}
+
+/// Select the rules that apply to `stream_id`; per-stream rules win over the global ones
+pub fn scoped_rules<'a>(rules: &'a [String], stream_id: &str) -> Vec<&'a str> {
+    let scoped: Vec<&str> = rules
+        .iter()
+        .filter_map(|rule| match rule.split_once('=') {
+            Some((stream, pattern)) if stream == stream_id => Some(pattern),
+            _ => None,
+        })
+        .collect();
+
+    if scoped.is_empty() {
+        rules.iter().map(String::as_str).filter(|rule| !rule.contains('=')).collect()
+    } else {
+        scoped
+    }
+}
+
+/// A single value that may be scoped to one stream, written as `[stream_id=]value`
+#[derive(Clone, Debug)]
+pub struct Scoped<T> {
+    pub stream_id: Option<String>,
+    pub value: T,
+}
+
+impl<T: FromStr> FromStr for Scoped<T>
+where
+    T::Err: std::fmt::Display,
+{
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (stream_id, value) = match s.split_once('=') {
+            Some((stream, value)) => (Some(stream.to_string()), value),
+            None => (None, s),
+        };
+
+        let value = value.parse().map_err(|err| format!("invalid value {value:?}: {err}"))?;
+        Ok(Self { stream_id, value })
+    }
+}
+
+impl<T: Clone> Scoped<T> {
+    /// Pick the value for `stream_id`, falling back to the last global value
+    pub fn resolve(values: &[Self], stream_id: &str) -> Option<T> {
+        values
+            .iter()
+            .rev()
+            .find(|v| v.stream_id.as_deref() == Some(stream_id))
+            .or_else(|| values.iter().rev().find(|v| v.stream_id.is_none()))
+            .map(|v| v.value.clone())
+    }
+}
+
+/// A stream ID pattern: a glob, or with `re:`, a regular expression
+///
+/// Regular expressions match anywhere in the ID unless anchored, as in `re:^live/[a-z]+$`,
+/// while globs match all of it. They're compiled once, as the pattern is parsed.
+#[derive(Clone, Debug)]
+pub enum StreamPattern {
+    Glob(String),
+    Regex(Regex),
+}
+
+impl StreamPattern {
+    pub fn matches(&self, stream_id: &str) -> bool {
+        match self {
+            Self::Glob(pattern) => glob_match(pattern, stream_id),
+            Self::Regex(regex) => regex.is_match(stream_id),
+        }
+    }
+}
+
+impl FromStr for StreamPattern {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.strip_prefix("re:") {
+            Some(regex) => Regex::new(regex).map(Self::Regex).map_err(|err| format!("invalid regex {regex:?}: {err}")),
+            None => Ok(Self::Glob(s.to_string())),
+        }
+    }
+}
+
+impl fmt::Display for StreamPattern {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Glob(pattern) => f.write_str(pattern),
+            Self::Regex(regex) => write!(f, "re:{regex}"),
+        }
+    }
+}
+
+/// Match `text` against a pattern where `*` matches any run and `?` any single character
+pub fn glob_match(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+
+    let (mut p, mut t) = (0, 0);
+    // Position of the last `*` and the text index it was tried against
+    let mut backtrack: Option<(usize, usize)> = None;
+
+    while t < text.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                backtrack = Some((p, t));
+                p += 1;
+            }
+            Some(&c) if c == '?' || c == text[t] => {
+                p += 1;
+                t += 1;
+            }
+            _ => match backtrack {
+                // Let the last `*` swallow one more character and retry
+                Some((star, start)) => {
+                    p = star + 1;
+                    t = start + 1;
+                    backtrack = Some((star, start + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rules(rules: &[&str]) -> Vec<String> {
+        rules.iter().map(|rule| rule.to_string()).collect()
+    }
+
+    #[test]
+    fn glob_wildcards() {
+        assert!(glob_match("video", "video"));
+        assert!(!glob_match("video", "video2"));
+        assert!(!glob_match("video2", "video"));
+        assert!(glob_match("*", ""));
+        assert!(glob_match("*", "anything"));
+        assert!(!glob_match("?", ""));
+        assert!(glob_match("?", "a"));
+        assert!(!glob_match("?", "ab"));
+        assert!(glob_match("video_???p", "video_720p"));
+        assert!(!glob_match("video_???p", "video_1080p"));
+        assert!(glob_match("*1080p*", "video_1080p60"));
+        assert!(glob_match("a**b", "ab"));
+        assert!(glob_match("*é", "café"));
+    }
+
+    #[test]
+    fn glob_backtracks() {
+        // The first `b` the `*` stops at isn't the one that matches
+        assert!(glob_match("a*bc", "abbbc"));
+        assert!(glob_match("*a*b", "xaxbxb"));
+        assert!(glob_match("a*b*c", "aXbbYbc"));
+        assert!(!glob_match("a*b*c", "aXbbYb"));
+        assert!(glob_match("*ab", "aaab"));
+        assert!(!glob_match("*ab", "aaba"));
+        assert!(glob_match("*?x", "yyx"));
+        assert!(!glob_match("*?x", "x"));
+    }
+
+    #[test]
+    fn includes_and_excludes() {
+        let all = TrackFilter::new([]);
+        assert!(all.allows("video") && all.allows("audio"));
+
+        let filter = TrackFilter::new(["video*", "audio", "!*_low"]);
+        assert!(filter.allows("video_hd"));
+        assert!(filter.allows("audio"));
+        assert!(!filter.allows("video_low"));
+        assert!(!filter.allows("captions"));
+
+        let excludes = TrackFilter::new(["!captions"]);
+        assert!(excludes.allows("video"));
+        assert!(!excludes.allows("captions"));
+    }
+
+    #[test]
+    fn stream_rules_replace_global_ones() {
+        let rules = rules(&["video*", "!*_low", "live/main=audio", "live/main=!audio_low", "other=*"]);
+        assert_eq!(scoped_rules(&rules, "live/main"), ["audio", "!audio_low"]);
+        assert_eq!(scoped_rules(&rules, "other"), ["*"]);
+        assert_eq!(scoped_rules(&rules, "live/else"), ["video*", "!*_low"]);
+
+        let main = TrackFilter::for_stream(&rules, "live/main");
+        assert!(main.allows("audio"));
+        assert!(!main.allows("video_hd"));
+        let other = TrackFilter::for_stream(&rules, "live/else");
+        assert!(other.allows("video_hd"));
+        assert!(!other.allows("video_low"));
+        assert!(!other.allows("audio"));
+    }
+
+    #[test]
+    fn stream_patterns() {
+        let glob: StreamPattern = "live/*".parse().unwrap();
+        assert!(glob.matches("live/main"));
+        assert!(!glob.matches("vod/live/main"));
+
+        let regex: StreamPattern = "re:^live/[a-z]+$".parse().unwrap();
+        assert!(regex.matches("live/main"));
+        assert!(!regex.matches("live/main2"));
+        assert_eq!(regex.to_string(), "re:^live/[a-z]+$");
+        // Unanchored, anywhere in the ID
+        let anywhere: StreamPattern = "re:[0-9]{3}".parse().unwrap();
+        assert!(anywhere.matches("event-2026-100-b"));
+        assert!(!anywhere.matches("event-26"));
+
+        // Only the prefix makes it a regular expression
+        let literal: StreamPattern = "live.*".parse().unwrap();
+        assert!(literal.matches("live.main"));
+        assert!(!literal.matches("live/main"));
+
+        let err = "re:live/(".parse::<StreamPattern>().unwrap_err();
+        assert!(err.starts_with(r#"invalid regex "live/(""#), "{err}");
+    }
+
+    #[test]
+    fn scoped_values_fall_back_to_the_last_global_one() {
+        let values: Vec<Scoped<u32>> = ["720", "live/main=1080", "480", "live/main=360"]
+            .iter()
+            .map(|value| value.parse().unwrap())
+            .collect();
+        assert_eq!(Scoped::resolve(&values, "live/main"), Some(360));
+        assert_eq!(Scoped::resolve(&values, "other"), Some(480));
+        assert_eq!(Scoped::resolve(&values[1..2], "other"), None);
+        assert!("live/main=tall".parse::<Scoped<u32>>().is_err());
+    }
+}
@@ -0,0 +1,532 @@
+//! Track-by-track forwarding of a CloudFlare broadcast
+//!
+//! Instead of handing the CF broadcast straight to the relay, we publish our own
+//! broadcast and serve each track the relay requests by subscribing upstream and
+//! copying groups and frames across. This gives us a place to apply per-track rules.
+//!
+//! The upstream broadcast is followed through a watch channel, so a bridge can move
+//! to a new CF session without the relay noticing: while it's None the bridge is
+//! moving, and once it's replaced each forwarded track resubscribes upstream and
+//! carries on into the same relay-side track. Group sequences are assumed to carry on
+//! across the move; groups the relay already has are skipped.
+
+use std::future::Future;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use bytes::Bytes;
+use moq_lite::{
+    Broadcast, BroadcastConsumer, BroadcastProducer, GroupConsumer, Track, TrackConsumer, TrackProducer,
+};
+use tokio::sync::{mpsc, oneshot, watch};
+
+use crate::alias::TrackAliases;
+use crate::backpressure::{Backpressure, TrackPolicy};
+use crate::buffer::{BridgeBuffers, BufferedGroup};
+use crate::cache::{GroupCache, TrackCache};
+use crate::capture::CaptureMeter;
+use crate::catalog::{CatalogFilter, LayerLimits, CATALOG_TRACK};
+use crate::demand::Demand;
+use crate::chaos::Chaos;
+use crate::filter::{glob_match, TrackFilter};
+use crate::health::BridgeHealth;
+use crate::hook::FrameHook;
+use crate::inject::Injector;
+use crate::interceptor::{Interceptor, TrackInterceptors};
+use crate::loss::TrackLoss;
+use crate::media::MediaFrame;
+use crate::priority::PriorityOverrides;
+use crate::probe::LatencyProbe;
+use crate::resume::StreamResume;
+use crate::shed::{Shedder, TrackShed};
+use crate::spill::Spill;
+use crate::throttle::{BridgeThrottle, TrackThrottle};
+use crate::tasks;
+use crate::timestamp::{Rebaser, TrackRebaser};
+
+/// How long after an upstream track ends we wait to hear that the bridge is moving
+const MOVE_GRACE: Duration = Duration::from_secs(1);
+
+/// The current upstream broadcast of a bridge, or None while it moves to a new CF session
+pub type Upstream = watch::Receiver<Option<BroadcastConsumer>>;
+
+/// Per-bridge forwarding rules
+pub struct ForwardOptions {
+    pub filter: TrackFilter,
+    pub limits: LayerLimits,
+    pub aliases: TrackAliases,
+    /// Track priorities in place of the publisher's
+    pub priorities: PriorityOverrides,
+    pub rebaser: Arc<Rebaser>,
+    pub hook: Option<Arc<dyn FrameHook>>,
+    /// The upstream tracks `hook` applies to
+    pub hook_tracks: TrackFilter,
+    pub shedder: Arc<Shedder>,
+    /// Upstream tracks forwarded byte-for-byte, without rebasing or hooks
+    pub passthrough: Vec<String>,
+    /// Every track but the catalog forwarded byte-for-byte, without interceptors either, see `--e2ee`
+    pub e2ee: bool,
+    /// Run on every frame last, in order
+    pub interceptors: Arc<[Arc<dyn Interceptor>]>,
+    pub buffers: Arc<BridgeBuffers>,
+    pub backpressure: Backpressure,
+    /// Groups spilled to disk while the relay was away, replayed when it subscribes again
+    pub spill: Option<Arc<Spill>>,
+    /// Recent groups, served first when the relay subscribes to a track again
+    pub cache: Option<Arc<GroupCache>>,
+    /// Where earlier bridges for the stream left off
+    pub resume: StreamResume,
+    /// Counts forwarded and failed groups, for eviction
+    pub health: Arc<BridgeHealth>,
+    /// Set while the bridge is paused, see [BridgeHandle::pause](crate::BridgeHandle::pause)
+    pub paused: watch::Receiver<bool>,
+    /// Faults to inject, see `--chaos`
+    pub chaos: Arc<Chaos>,
+    /// Times frames on their way through, see `--latency-probe`
+    pub probe: Option<Arc<LatencyProbe>>,
+    /// Rate limits toward the relay, see `--bridge-rate-limit`
+    pub throttle: Arc<BridgeThrottle>,
+    /// See `--delivery-timeout`
+    pub delivery_timeout: Option<Duration>,
+    /// Times media frames from their capture, see `--capture-latency`
+    pub capture: Option<Arc<CaptureMeter>>,
+    /// Counts the relay's subscriptions, see `--on-demand`
+    pub demand: Option<Arc<Demand>>,
+}
+
+/// A forwarded broadcast
+///
+/// It closes when this is dropped.
+pub struct Forwarded {
+    pub broadcast: BroadcastConsumer,
+    /// Adds supplemental tracks to the broadcast
+    pub injector: Injector,
+    _stop: oneshot::Sender<()>,
+}
+
+/// Republish `upstream` as a new broadcast, applying `options` to every track
+pub fn forward_broadcast(stream_id: &str, upstream: Upstream, options: ForwardOptions) -> Forwarded {
+    let broadcast = Broadcast::produce();
+    let (injector, injected) = Injector::new();
+    let (stop, stopped) = oneshot::channel();
+    tasks::spawn(run_broadcast(stream_id.to_string(), broadcast.producer, upstream, options, injected, stopped));
+
+    Forwarded {
+        broadcast: broadcast.consumer,
+        injector,
+        _stop: stop,
+    }
+}
+
+/// A frame hook bound to one track
+#[derive(Clone)]
+struct TrackHook {
+    hook: Arc<dyn FrameHook>,
+    stream_id: Arc<str>,
+    track: Arc<str>,
+}
+
+/// What happens to each frame of a track on the way through
+#[derive(Clone)]
+enum Transform {
+    Catalog(Arc<CatalogFilter>),
+    Media(TrackRebaser, Option<TrackHook>, Option<TrackInterceptors>),
+    // Data tracks that don't use the hang timestamp prefix
+    Raw(Option<TrackInterceptors>),
+}
+
+impl Transform {
+    /// Returns None if the frame should be dropped
+    async fn apply(&self, frame: Bytes, keyframe: bool) -> anyhow::Result<Option<Bytes>> {
+        let (rebaser, hook, interceptors) = match self {
+            Self::Catalog(catalog) => return Ok(Some(catalog.rewrite(frame))),
+            Self::Raw(interceptors) => {
+                return Ok(match interceptors {
+                    Some(interceptors) => interceptors.apply(frame, keyframe, false),
+                    None => Some(frame),
+                })
+            }
+            Self::Media(rebaser, hook, interceptors) => (rebaser, hook, interceptors),
+        };
+
+        let frame = rebaser.rebase(frame);
+        let frame = match (hook, MediaFrame::decode(&frame)) {
+            (Some(hook), Some(decoded)) => {
+                match hook.hook.transform(&hook.stream_id, &hook.track, decoded, keyframe).await? {
+                    Some(output) => output.encode(),
+                    None => return Ok(None),
+                }
+            }
+            _ => frame,
+        };
+
+        Ok(match interceptors {
+            Some(interceptors) => interceptors.apply(frame, keyframe, true),
+            None => Some(frame),
+        })
+    }
+}
+
+/// Serve track requests from the relay until the bridge is over
+async fn run_broadcast(
+    stream_id: String,
+    mut downstream: BroadcastProducer,
+    upstream: Upstream,
+    options: ForwardOptions,
+    mut injected: mpsc::UnboundedReceiver<TrackConsumer>,
+    mut stopped: oneshot::Receiver<()>,
+) {
+    let catalog = Arc::new(CatalogFilter::new(options.filter, options.limits, options.aliases, options.priorities));
+
+    loop {
+        tokio::select! {
+            Some(track) = downstream.requested_track() => {
+                let name = track.info.name.clone();
+
+                let Some(source_name) = catalog.resolve(&name) else {
+                    tracing::debug!(stream_id, track = %name, "track excluded by filter");
+                    track.abort(moq_lite::Error::NotFound);
+                    continue;
+                };
+
+                let hook = options.hook.clone().filter(|_| options.hook_tracks.allows(&source_name)).map(|hook| TrackHook {
+                    hook,
+                    stream_id: stream_id.as_str().into(),
+                    track: source_name.as_str().into(),
+                });
+
+                let data = options.passthrough.iter().any(|p| glob_match(p, &source_name));
+                let raw = options.e2ee || data;
+                let interceptors = TrackInterceptors::new(&options.interceptors, &stream_id, &source_name)
+                    .filter(|_| !options.e2ee);
+                let congestion = options.shedder.congestion();
+                let kind = catalog.kind(&source_name);
+                let loss = options.health.loss().track(&stream_id, &source_name);
+                let policy = match name.as_str() {
+                    // Players can't do anything without the catalog, so it's never held back
+                    CATALOG_TRACK => Backpressure::default().track(None, congestion, options.health.clone(), loss.clone()),
+                    _ => options.backpressure.track(kind, congestion, options.health.clone(), loss.clone()),
+                };
+                let cache = options.cache.as_ref().map(|cache| cache.track(&source_name, kind));
+                let (transform, shed) = match name.as_str() {
+                    CATALOG_TRACK => (Transform::Catalog(catalog.clone()), None),
+                    _ if raw => (Transform::Raw(interceptors), options.shedder.track(&source_name)),
+                    _ => {
+                        let transform = Transform::Media(options.rebaser.track(), hook.clone(), interceptors);
+                        (transform, options.shedder.track(&source_name))
+                    }
+                };
+
+                tracing::debug!(stream_id, track = %name, upstream = %source_name, "forwarding track");
+                let source = Track {
+                    priority: catalog.priority(&source_name).unwrap_or(track.info.priority),
+                    name: source_name,
+                };
+                let spill = options.spill.clone().filter(|_| name != CATALOG_TRACK);
+                let forward = GroupForward {
+                    transform,
+                    health: options.health.clone(),
+                    loss,
+                    chaos: options.chaos.clone(),
+                    probe: options.probe.clone().filter(|_| name != CATALOG_TRACK),
+                    throttle: options.throttle.track(&source.name).filter(|_| name != CATALOG_TRACK),
+                    expiry: options.delivery_timeout,
+                    // Only hang frames carry timestamps, and encrypted ones can't be read
+                    capture: options.capture.clone().filter(|_| !raw && name != CATALOG_TRACK),
+                };
+                let groups = TrackGroups {
+                    upstream: upstream.clone(),
+                    buffers: options.buffers.clone(),
+                    cache,
+                    resume: options.resume.clone(),
+                    paused: options.paused.clone(),
+                    forward,
+                };
+                let watching = options.demand.as_ref().map(Demand::watch);
+                tasks::spawn(async move {
+                    let _watching = watching;
+                    let mut track = track;
+                    let mut earlier = match spill {
+                        Some(spill) => spill.take(&source.name).await,
+                        None => Vec::new(),
+                    };
+                    if let Some(cache) = &groups.cache {
+                        earlier.extend(cache.groups());
+                    }
+                    replay(earlier, &mut track, &groups);
+
+                    forward_track(source, track, policy, shed, groups).await;
+                    if let Some(hook) = hook {
+                        hook.hook.close(&hook.stream_id, &hook.track);
+                    }
+                });
+            }
+            Some(track) = injected.recv() => {
+                downstream.insert_track(track);
+            }
+            _ = &mut stopped => break,
+            else => break,
+        }
+    }
+
+    downstream.close();
+}
+
+/// Bridge-wide state a track's groups go through
+struct TrackGroups {
+    upstream: Upstream,
+    buffers: Arc<BridgeBuffers>,
+    cache: Option<TrackCache>,
+    resume: StreamResume,
+    paused: watch::Receiver<bool>,
+    forward: GroupForward,
+}
+
+/// What each group of a track goes through on its way to the relay
+#[derive(Clone)]
+struct GroupForward {
+    transform: Transform,
+    health: Arc<BridgeHealth>,
+    loss: Arc<TrackLoss>,
+    chaos: Arc<Chaos>,
+    probe: Option<Arc<LatencyProbe>>,
+    throttle: Option<TrackThrottle>,
+    /// How long a frame may take from CF to the relay, see `--delivery-timeout`
+    expiry: Option<Duration>,
+    /// For media tracks, with `--capture-latency`
+    capture: Option<Arc<CaptureMeter>>,
+}
+
+/// Copy groups from an upstream track until either side goes away
+async fn forward_track(
+    source: Track,
+    mut downstream: TrackProducer,
+    mut policy: TrackPolicy,
+    shed: Option<TrackShed>,
+    groups: TrackGroups,
+) {
+    let TrackGroups { mut upstream, buffers, cache, resume, paused, forward } = groups;
+    let GroupForward { health, loss, chaos, throttle, .. } = &forward;
+
+    let Some(broadcast) = until_unused(&downstream, current(&mut upstream)).await.flatten() else {
+        return;
+    };
+    let mut upstream_track = broadcast.subscribe_track(&source);
+    // The last group CF sent, to tell the ones that never came
+    let mut last: Option<u64> = None;
+
+    loop {
+        let group = tokio::select! {
+            res = upstream_track.next_group() => match res {
+                Ok(Some(group)) => group,
+                end => {
+                    // Pick the track up again if the bridge is moving to a new session
+                    match until_unused(&downstream, moved(&mut upstream)).await {
+                        Some(Some(broadcast)) => {
+                            upstream_track = broadcast.subscribe_track(&source);
+                            continue;
+                        }
+                        Some(None) => {}
+                        None => return,
+                    }
+
+                    match end {
+                        Ok(_) => return downstream.close(),
+                        Err(err) => {
+                            health.failed();
+                            return downstream.abort(err);
+                        }
+                    }
+                }
+            },
+            // Nobody on the relay side wants this track anymore
+            _ = downstream.unused() => return,
+        };
+
+        if let Some(cache) = &cache {
+            cache.push(&group);
+        }
+
+        let sequence = group.info.sequence;
+        if let Some(gap) = last.and_then(|last| sequence.checked_sub(last + 1)).filter(|gap| *gap > 0) {
+            loss.skipped_groups(gap);
+        }
+        last = last.max(Some(sequence));
+
+        if !resume.admit(&source.name, group.info.sequence) {
+            policy.dropped("duplicate");
+            continue;
+        }
+
+        // Skip whole groups while shed or paused, so we resume on a keyframe
+        if *paused.borrow() {
+            policy.dropped("paused");
+            continue;
+        }
+        if shed.as_ref().is_some_and(TrackShed::active) {
+            policy.dropped("shed");
+            continue;
+        }
+        if source.name != CATALOG_TRACK && chaos.drop_group() {
+            policy.dropped("chaos");
+            continue;
+        }
+        if let Some(reason) = throttle.as_ref().and_then(TrackThrottle::refused) {
+            policy.dropped(reason);
+            continue;
+        }
+
+        let admitted = tokio::select! {
+            admitted = policy.admit() => admitted,
+            _ = downstream.unused() => return,
+        };
+        if !admitted {
+            continue;
+        }
+
+        // Returns None if the relay already has a newer group
+        match downstream.create_group(group.info.clone()) {
+            Some(output) => {
+                tasks::spawn(forward_group(group, buffers.open(output), forward.clone()));
+            }
+            None => policy.dropped("superseded"),
+        }
+    }
+}
+
+/// The current upstream broadcast, waiting while the bridge moves; None once it's over
+async fn current(upstream: &mut Upstream) -> Option<BroadcastConsumer> {
+    upstream.wait_for(Option::is_some).await.ok()?.clone()
+}
+
+/// The broadcast the bridge moved to, after an upstream track ended
+///
+/// Returns None if the bridge isn't moving, or is over.
+async fn moved(upstream: &mut Upstream) -> Option<BroadcastConsumer> {
+    // The bridge hears that its session is gone a moment after the tracks do
+    match tokio::time::timeout(MOVE_GRACE, upstream.changed()).await {
+        Ok(Ok(())) => current(upstream).await,
+        _ => None,
+    }
+}
+
+/// Run `fut` unless the relay stops wanting `downstream` first, which returns None
+async fn until_unused<T>(downstream: &TrackProducer, fut: impl Future<Output = T>) -> Option<T> {
+    tokio::select! {
+        output = fut => Some(output),
+        _ = downstream.unused() => None,
+    }
+}
+
+/// Run `task` against each upstream broadcast of a bridge in turn, until the bridge is over
+pub async fn follow<F, Fut>(mut upstream: Upstream, task: F)
+where
+    F: Fn(BroadcastConsumer) -> Fut,
+    Fut: Future<Output = ()>,
+{
+    loop {
+        let current = upstream.borrow_and_update().clone();
+        if let Some(broadcast) = current {
+            tokio::select! {
+                _ = task(broadcast) => {}
+                res = upstream.changed() => match res {
+                    Ok(()) => continue,
+                    Err(_) => return,
+                },
+            }
+        }
+
+        if upstream.changed().await.is_err() {
+            return;
+        }
+    }
+}
+
+/// Forward spilled or cached groups for a track before any live ones
+fn replay(mut earlier: Vec<GroupConsumer>, downstream: &mut TrackProducer, groups: &TrackGroups) {
+    earlier.sort_by_key(|group| group.info.sequence);
+    for group in earlier {
+        // Skips groups we already replayed, from the cache or the spill
+        if let Some(output) = downstream.create_group(group.info.clone()) {
+            // Nothing to time, these were read from CF a while ago
+            let forward = GroupForward {
+                probe: None,
+                ..groups.forward.clone()
+            };
+            tasks::spawn(forward_group(group, groups.buffers.open(output), forward));
+        }
+    }
+}
+
+/// Read out the frames of a group that's no longer forwarded, returning how many there were
+async fn remaining(mut upstream: GroupConsumer) -> u64 {
+    let mut frames = 0;
+    while let Ok(Some(_)) = upstream.read_frame().await {
+        frames += 1;
+    }
+    frames
+}
+
+/// Copy the frames of a single group
+async fn forward_group(mut upstream: GroupConsumer, downstream: BufferedGroup, forward: GroupForward) {
+    let GroupForward { transform, health, loss, chaos, probe, throttle, expiry, capture } = forward;
+    // The first frame of every group is a keyframe
+    let mut keyframe = true;
+
+    loop {
+        let frame = match upstream.read_frame().await {
+            Ok(Some(frame)) => frame,
+            Ok(None) => {
+                health.forwarded();
+                return downstream.close();
+            }
+            Err(err) => {
+                health.failed();
+                return downstream.abort(err);
+            }
+        };
+
+        // The CF-consume point, for the latency probe
+        let read = probe.as_ref().and_then(|probe| probe.mark());
+        let received = Instant::now();
+        let captured = capture.as_ref().and_then(|capture| capture.read(&frame));
+
+        if let Some(delay) = chaos.object_delay() {
+            tokio::time::sleep(delay).await;
+        }
+
+        match transform.apply(frame, keyframe).await {
+            Ok(Some(frame)) => {
+                if let Some(throttle) = &throttle {
+                    throttle.pace(frame.len()).await;
+                }
+                // Later frames of the group depend on this one, so they go too
+                if expiry.is_some_and(|expiry| received.elapsed() > expiry) {
+                    loss.expired_group(1 + remaining(upstream).await);
+                    return downstream.abort(moq_lite::Error::Timeout);
+                }
+                // Dropped to stay under the buffer caps
+                let size = frame.len();
+                if !downstream.write_frame(frame) {
+                    loss.dropped_objects(1 + remaining(upstream).await);
+                    return health.failed();
+                }
+                health.wrote_frame(size);
+                if let (Some(probe), Some(read)) = (&probe, read) {
+                    probe.forwarded(read);
+                }
+                if let (Some(capture), Some(captured)) = (&capture, captured) {
+                    capture.republished(captured);
+                }
+            }
+            Ok(None) => {}
+            Err(err) => {
+                tracing::warn!(%err, "frame hook failed");
+                health.failed();
+                return downstream.abort(moq_lite::Error::Cancel);
+            }
+        }
+
+        keyframe = false;
+    }
+}
@@ -0,0 +1,182 @@
+//! Handles on running bridges
+//!
+//! The bridge manager keeps a [BridgeHandle] for every bridge from the moment it's
+//! admitted until it ends. Embedders get them from
+//! [BridgeManager::bridges](crate::BridgeManager::bridges); with `--admin` the same
+//! controls are served over HTTP under `/bridges`.
+
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
+use moq_lite::BroadcastConsumer;
+use tokio::sync::{oneshot, watch};
+
+use crate::bridge::BridgeEnd;
+use crate::health::BridgeHealth;
+use crate::loss::TrackLosses;
+use crate::capture::CaptureLatency;
+use crate::probe::ProbeLatency;
+
+/// How a bridge ended: why it stopped, or why it failed
+pub type BridgeResult = Result<BridgeEnd, String>;
+
+/// Controls one bridge
+#[derive(Clone)]
+pub struct BridgeHandle {
+    stream_id: Arc<str>,
+    shared: Arc<Shared>,
+}
+
+struct Shared {
+    /// Taken once the bridge is told to end, so it's only told once
+    stop: Mutex<Option<oneshot::Sender<BridgeEnd>>>,
+    paused: watch::Sender<bool>,
+    health: Arc<BridgeHealth>,
+    admitted: SystemTime,
+    closed: watch::Sender<Option<BridgeResult>>,
+    /// What the bridge publishes to the relay, once it does
+    forwarded: Mutex<Option<BroadcastConsumer>>,
+}
+
+/// A snapshot of what a bridge has done
+#[derive(Clone, Debug)]
+pub struct BridgeStats {
+    pub stream_id: String,
+    /// When the bridge was admitted
+    pub started: SystemTime,
+    pub uptime: Duration,
+    /// Whether it wrote a frame to the relay yet
+    pub active: bool,
+    pub paused: bool,
+    /// Groups forwarded in full
+    pub groups: u64,
+    /// Groups that failed on our side, see `--evict-error-rate`
+    pub failed_groups: u64,
+    /// Groups left out on purpose: for congestion, shedding, pausing and the like
+    pub dropped_groups: u64,
+    pub frames: u64,
+    /// Media bytes written to the relay
+    pub bytes: u64,
+    /// Times the upstream went away and the bridge waited for it to come back, moving
+    /// sessions or with `--flap-grace`
+    pub stalls: u64,
+    /// The last latency probe, with `--latency-probe`
+    pub latency: Option<ProbeLatency>,
+    /// The last frame timed from its capture, with `--capture-latency`
+    pub capture_latency: Option<CaptureLatency>,
+    /// What each track lost on the way to the relay
+    pub tracks: Vec<TrackLosses>,
+}
+
+impl BridgeHandle {
+    /// A handle for a newly admitted bridge, and what tells the bridge to end
+    pub(crate) fn new(stream_id: &str) -> (Self, oneshot::Receiver<BridgeEnd>) {
+        let (stop, stopped) = oneshot::channel();
+        let shared = Shared {
+            stop: Mutex::new(Some(stop)),
+            paused: watch::Sender::new(false),
+            health: BridgeHealth::new(),
+            admitted: SystemTime::now(),
+            closed: watch::Sender::new(None),
+            forwarded: Mutex::new(None),
+        };
+        let handle = Self {
+            stream_id: stream_id.into(),
+            shared: Arc::new(shared),
+        };
+        (handle, stopped)
+    }
+
+    pub fn stream_id(&self) -> &str {
+        &self.stream_id
+    }
+
+    /// Stop the bridge, returning false if it's already stopping
+    ///
+    /// The stream is bridged again on a later poll if the registry still lists it.
+    pub fn stop(&self) -> bool {
+        self.end(BridgeEnd::Stopped)
+    }
+
+    /// Stop forwarding new groups to the relay, returning false if already paused
+    ///
+    /// The bridge stays up and keeps its CF subscription, so [resume](Self::resume)
+    /// carries on from the next keyframe.
+    pub fn pause(&self) -> bool {
+        !self.shared.paused.send_replace(true)
+    }
+
+    /// Forward groups again, returning false if the bridge wasn't paused
+    pub fn resume(&self) -> bool {
+        self.shared.paused.send_replace(false)
+    }
+
+    pub fn stats(&self) -> BridgeStats {
+        let totals = self.shared.health.totals();
+        BridgeStats {
+            stream_id: self.stream_id.to_string(),
+            started: self.shared.admitted,
+            uptime: self.shared.admitted.elapsed().unwrap_or_default(),
+            active: self.shared.health.is_active(),
+            paused: *self.shared.paused.borrow(),
+            groups: totals.groups,
+            failed_groups: totals.failed_groups,
+            dropped_groups: totals.dropped_groups,
+            frames: totals.frames,
+            bytes: totals.bytes,
+            stalls: totals.stalls,
+            latency: self.shared.health.latency(),
+            capture_latency: self.shared.health.capture_latency(),
+            tracks: self.shared.health.loss().tracks(),
+        }
+    }
+
+    /// The broadcast the bridge publishes to the relay, to read what it forwards
+    ///
+    /// None until the bridge is set up. Subscribing counts as watching with `--on-demand`.
+    pub fn forwarded(&self) -> Option<BroadcastConsumer> {
+        self.shared.forwarded.lock().unwrap().clone()
+    }
+
+    /// Wait for the bridge to end, however it does
+    pub async fn await_closed(&self) -> BridgeResult {
+        let mut closed = self.shared.closed.subscribe();
+        loop {
+            if let Some(result) = closed.borrow_and_update().clone() {
+                return result;
+            }
+            // The sender lives as long as we do, so this can't fail
+            let _ = closed.changed().await;
+        }
+    }
+
+    /// Tell the bridge to end with `end`, returning false if it was already told
+    pub(crate) fn end(&self, end: BridgeEnd) -> bool {
+        match self.shared.stop.lock().unwrap().take() {
+            Some(stop) => stop.send(end).is_ok(),
+            None => false,
+        }
+    }
+
+    /// Whether the bridge was told to end
+    pub(crate) fn stopping(&self) -> bool {
+        self.shared.stop.lock().unwrap().is_none()
+    }
+
+    pub(crate) fn health(&self) -> Arc<BridgeHealth> {
+        self.shared.health.clone()
+    }
+
+    pub(crate) fn paused(&self) -> watch::Receiver<bool> {
+        self.shared.paused.subscribe()
+    }
+
+    pub(crate) fn forwarding(&self, broadcast: &BroadcastConsumer) {
+        *self.shared.forwarded.lock().unwrap() = Some(broadcast.clone());
+    }
+
+    /// Record how the bridge ended, for [await_closed](Self::await_closed)
+    pub(crate) fn closed(&self, result: BridgeResult) {
+        self.shared.closed.send_replace(Some(result));
+    }
+}
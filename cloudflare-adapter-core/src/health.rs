@@ -0,0 +1,170 @@
+//! Error-rate eviction
+//!
+//! Each bridge counts the groups it forwards and the ones that go wrong on its side:
+//! upstream errors, frame hook failures and groups cut short by its buffer cap. With
+//! `--evict-error-rate`, a bridge whose share of failed groups over a `--evict-window`
+//! exceeds the rate is evicted, so one pathological stream can't keep loading the
+//! shared CF and relay sessions. With `--evict-cooldown` the stream isn't bridged
+//! again for that long.
+//!
+//! Drops caused by relay congestion or shedding aren't the stream's fault and don't
+//! count.
+
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use tokio::sync::Notify;
+
+use crate::capture::CaptureLatency;
+use crate::loss::BridgeLoss;
+use crate::probe::ProbeLatency;
+
+/// Windows with fewer groups than this never evict, so a quiet track can't trip it
+const MIN_GROUPS: u64 = 10;
+
+/// When to evict a bridge, and how long to hold its stream back
+#[derive(Clone, Copy, Debug)]
+pub struct EvictOptions {
+    /// The fraction of failed groups that evicts
+    pub rate: f64,
+    pub window: Duration,
+    pub cooldown: Option<Duration>,
+}
+
+/// The group outcomes of one bridge, since the last window
+#[derive(Debug, Default)]
+pub struct BridgeHealth {
+    forwarded: AtomicU64,
+    failed: AtomicU64,
+    /// Since the bridge started, for [BridgeStats](crate::BridgeStats)
+    total_forwarded: AtomicU64,
+    total_failed: AtomicU64,
+    frames: AtomicU64,
+    bytes: AtomicU64,
+    /// Groups left out on purpose (congestion, shedding, pausing, ...), which don't count as failed
+    dropped: AtomicU64,
+    /// Times the upstream went away and the bridge waited for it, see [BridgeStats](crate::BridgeStats)
+    stalls: AtomicU64,
+    /// Set once the bridge wrote its first frame to the relay, for the lifecycle callbacks
+    active: AtomicBool,
+    activated: Notify,
+    /// The last latency probe, see `--latency-probe`
+    latency: Mutex<Option<ProbeLatency>>,
+    /// The last frame timed from its capture, see `--capture-latency`
+    capture: Mutex<Option<CaptureLatency>>,
+    /// What each track lost on the way
+    loss: BridgeLoss,
+}
+
+impl BridgeHealth {
+    pub fn new() -> Arc<Self> {
+        Default::default()
+    }
+
+    pub fn forwarded(&self) {
+        self.forwarded.fetch_add(1, Ordering::Relaxed);
+        self.total_forwarded.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn failed(&self) {
+        self.failed.fetch_add(1, Ordering::Relaxed);
+        self.total_failed.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn wrote_frame(&self, size: usize) {
+        self.frames.fetch_add(1, Ordering::Relaxed);
+        self.bytes.fetch_add(size as u64, Ordering::Relaxed);
+        if !self.active.load(Ordering::Relaxed) && !self.active.swap(true, Ordering::Relaxed) {
+            self.activated.notify_waiters();
+        }
+    }
+
+    pub fn dropped(&self) {
+        self.dropped.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn stalled(&self) {
+        self.stalls.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.active.load(Ordering::Relaxed)
+    }
+
+    pub fn probed(&self, latency: ProbeLatency) {
+        *self.latency.lock().unwrap() = Some(latency);
+    }
+
+    pub fn latency(&self) -> Option<ProbeLatency> {
+        *self.latency.lock().unwrap()
+    }
+
+    pub fn captured(&self, latency: CaptureLatency) {
+        *self.capture.lock().unwrap() = Some(latency);
+    }
+
+    pub fn capture_latency(&self) -> Option<CaptureLatency> {
+        *self.capture.lock().unwrap()
+    }
+
+    pub fn loss(&self) -> &BridgeLoss {
+        &self.loss
+    }
+
+    /// What the bridge did since it started
+    pub fn totals(&self) -> HealthTotals {
+        HealthTotals {
+            groups: self.total_forwarded.load(Ordering::Relaxed),
+            failed_groups: self.total_failed.load(Ordering::Relaxed),
+            dropped_groups: self.dropped.load(Ordering::Relaxed),
+            frames: self.frames.load(Ordering::Relaxed),
+            bytes: self.bytes.load(Ordering::Relaxed),
+            stalls: self.stalls.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Resolve once the first frame was written
+    pub async fn wait_active(&self) {
+        loop {
+            let activated = self.activated.notified();
+            if self.active.load(Ordering::Relaxed) {
+                return;
+            }
+            activated.await;
+        }
+    }
+}
+
+/// A [BridgeHealth]'s counts since the bridge started
+#[derive(Clone, Copy, Debug)]
+pub struct HealthTotals {
+    pub groups: u64,
+    pub failed_groups: u64,
+    pub dropped_groups: u64,
+    pub frames: u64,
+    pub bytes: u64,
+    pub stalls: u64,
+}
+
+/// Resolve with the failure rate once a window exceeds `options.rate`
+pub async fn wait_unhealthy(health: &BridgeHealth, options: &EvictOptions) -> f64 {
+    let mut interval = tokio::time::interval(options.window);
+    interval.tick().await;
+
+    loop {
+        interval.tick().await;
+
+        let forwarded = health.forwarded.swap(0, Ordering::Relaxed);
+        let failed = health.failed.swap(0, Ordering::Relaxed);
+        let total = forwarded + failed;
+        if total < MIN_GROUPS {
+            continue;
+        }
+
+        let rate = failed as f64 / total as f64;
+        if rate > options.rate {
+            return rate;
+        }
+    }
+}
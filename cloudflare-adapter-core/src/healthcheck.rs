@@ -0,0 +1,59 @@
+//! `healthcheck`
+//!
+//! Asks the adapter running with the same configuration for `GET /ready` on its admin
+//! API and exits non-zero unless it answers 200, so a container image can use the
+//! binary itself as an exec healthcheck without curl. The request is plain HTTP/1.1
+//! to `--http-listen`, on loopback when that's a wildcard address.
+
+use std::io::{Read, Write};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpStream};
+use std::time::Duration;
+
+use anyhow::Context;
+
+use crate::AdapterConfig;
+
+/// Check the running instance is ready, failing if it isn't
+pub fn run(config: &AdapterConfig, timeout: Duration) -> anyhow::Result<()> {
+    let Some(listen) = config.http_listen else {
+        anyhow::bail!("healthcheck needs --http-listen and --admin");
+    };
+    anyhow::ensure!(config.admin, "healthcheck needs --admin");
+
+    let (status, body) = get_ready(local(listen), timeout)?;
+    anyhow::ensure!(status == 200, "not ready ({status}): {body}");
+
+    tracing::info!(%body, "ready");
+    Ok(())
+}
+
+/// Where to reach a server listening on `listen` from the same host
+pub(crate) fn local(listen: SocketAddr) -> SocketAddr {
+    let ip = match listen.ip() {
+        IpAddr::V4(ip) if ip.is_unspecified() => IpAddr::V4(Ipv4Addr::LOCALHOST),
+        IpAddr::V6(ip) if ip.is_unspecified() => IpAddr::V6(Ipv6Addr::LOCALHOST),
+        ip => ip,
+    };
+    SocketAddr::new(ip, listen.port())
+}
+
+/// `GET /ready` from `addr`, returning the status code and body
+fn get_ready(addr: SocketAddr, timeout: Duration) -> anyhow::Result<(u16, String)> {
+    let mut stream = TcpStream::connect_timeout(&addr, timeout).with_context(|| format!("failed to connect to {addr}"))?;
+    stream.set_read_timeout(Some(timeout))?;
+    stream.set_write_timeout(Some(timeout))?;
+
+    let request = format!("GET /ready HTTP/1.1\r\nHost: {addr}\r\nConnection: close\r\n\r\n");
+    stream.write_all(request.as_bytes()).context("failed to send request")?;
+
+    let mut response = String::new();
+    stream.read_to_string(&mut response).context("failed to read response")?;
+
+    let (head, body) = response.split_once("\r\n\r\n").unwrap_or((&response, ""));
+    let status = head
+        .split_whitespace()
+        .nth(1)
+        .and_then(|code| code.parse().ok())
+        .context("malformed response")?;
+    Ok((status, body.trim().to_string()))
+}
@@ -0,0 +1,215 @@
+//! Bridge session history
+//!
+//! With `--history-db`, every bridge that ends is written to a SQLite database: the
+//! stream, when it started and ended, what it forwarded and why it stopped, with the
+//! error when it failed. That answers "was this stream bridged on Tuesday, and why did
+//! it stop" long after the logs are gone. With `--admin`, `GET /history` lists the
+//! sessions, newest first, filtered by `stream_id`, `reason` and a `since`/`until`
+//! window in Unix seconds, which matches the sessions running at any point in it.
+//!
+//! Sessions that ended over `--history-retention` days ago are deleted, as are the oldest
+//! past `--history-max-sessions`. Writes happen off the runtime, as each bridge ends, so
+//! a drain waits for them. Embedders without the admin API can open the same database
+//! with [SessionHistory::open] and query it with [SessionHistory::sessions].
+
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::Context;
+use rusqlite::{params, Connection};
+
+/// The most sessions a query returns
+pub const MAX_LIMIT: usize = 1000;
+
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS sessions (
+    id INTEGER PRIMARY KEY,
+    stream_id TEXT NOT NULL,
+    namespace TEXT NOT NULL,
+    relay TEXT,
+    path TEXT NOT NULL,
+    started INTEGER NOT NULL,
+    ended INTEGER NOT NULL,
+    bytes INTEGER NOT NULL,
+    groups INTEGER NOT NULL,
+    frames INTEGER NOT NULL,
+    reason TEXT NOT NULL,
+    error TEXT
+);
+CREATE INDEX IF NOT EXISTS sessions_by_stream ON sessions (stream_id, started);
+CREATE INDEX IF NOT EXISTS sessions_by_end ON sessions (ended);
+";
+
+#[derive(Clone, Debug)]
+pub struct HistoryOptions {
+    pub path: PathBuf,
+    /// How long to keep a session after it ended
+    pub retention: Duration,
+    pub max_sessions: u64,
+}
+
+/// One bridge, from start to end
+#[derive(Clone, Debug, serde::Serialize)]
+pub struct Session {
+    pub stream_id: String,
+    pub namespace: String,
+    /// The `--relay-target` it published to, None for the main relay
+    pub relay: Option<String>,
+    pub path: String,
+    /// Unix seconds
+    pub started: u64,
+    pub ended: u64,
+    /// Media bytes written to the relay
+    pub bytes: u64,
+    pub groups: u64,
+    pub frames: u64,
+    /// How it ended, as in [BridgeEnd::name](crate::BridgeEnd::name), or `failed`
+    pub reason: String,
+    /// Why it failed
+    pub error: Option<String>,
+}
+
+/// Which sessions to list, all of them by default
+#[derive(Clone, Debug, Default, serde::Deserialize)]
+pub struct HistoryQuery {
+    pub stream_id: Option<String>,
+    pub reason: Option<String>,
+    /// Only sessions still running at or after this, in Unix seconds
+    pub since: Option<u64>,
+    /// Only sessions already running at or before this
+    pub until: Option<u64>,
+    /// 100 by default, at most [MAX_LIMIT]
+    pub limit: Option<usize>,
+}
+
+/// The session database
+pub struct SessionHistory {
+    conn: Arc<Mutex<Connection>>,
+    retention: Duration,
+    max_sessions: u64,
+}
+
+impl SessionHistory {
+    pub async fn open(options: &HistoryOptions) -> anyhow::Result<Arc<Self>> {
+        let path = options.path.clone();
+        let conn = blocking(move || open(&path)).await?;
+        let history = Arc::new(Self {
+            conn: Arc::new(Mutex::new(conn)),
+            retention: options.retention,
+            max_sessions: options.max_sessions,
+        });
+        history.prune().await?;
+
+        tracing::info!(path = %options.path.display(), retention = ?options.retention, "keeping bridge session history");
+        Ok(history)
+    }
+
+    /// Add a session that just ended, dropping what's past the retention limits
+    pub async fn record(&self, session: Session) {
+        let conn = self.conn.clone();
+        let stream_id = session.stream_id.clone();
+        let written = blocking(move || {
+            conn.lock().unwrap().execute(
+                "INSERT INTO sessions
+                    (stream_id, namespace, relay, path, started, ended, bytes, groups, frames, reason, error)
+                VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11)",
+                params![
+                    session.stream_id,
+                    session.namespace,
+                    session.relay,
+                    session.path,
+                    session.started,
+                    session.ended,
+                    session.bytes,
+                    session.groups,
+                    session.frames,
+                    session.reason,
+                    session.error,
+                ],
+            )?;
+            Ok(())
+        })
+        .await;
+
+        if let Err(err) = written.and(self.prune().await) {
+            tracing::warn!(err = format!("{err:#}"), stream_id, "failed to record bridge session");
+        }
+    }
+
+    /// The sessions matching `query`, newest first
+    pub async fn sessions(&self, query: HistoryQuery) -> anyhow::Result<Vec<Session>> {
+        let conn = self.conn.clone();
+        let limit = query.limit.unwrap_or(100).min(MAX_LIMIT);
+        blocking(move || {
+            let conn = conn.lock().unwrap();
+            let mut select = conn.prepare_cached(
+                "SELECT stream_id, namespace, relay, path, started, ended, bytes, groups, frames, reason, error
+                FROM sessions
+                WHERE (?1 IS NULL OR stream_id = ?1)
+                    AND (?2 IS NULL OR reason = ?2)
+                    AND (?3 IS NULL OR ended >= ?3)
+                    AND (?4 IS NULL OR started <= ?4)
+                ORDER BY started DESC, id DESC
+                LIMIT ?5",
+            )?;
+            let rows = select.query_map(
+                params![query.stream_id, query.reason, query.since, query.until, limit],
+                |row| {
+                    Ok(Session {
+                        stream_id: row.get(0)?,
+                        namespace: row.get(1)?,
+                        relay: row.get(2)?,
+                        path: row.get(3)?,
+                        started: row.get(4)?,
+                        ended: row.get(5)?,
+                        bytes: row.get(6)?,
+                        groups: row.get(7)?,
+                        frames: row.get(8)?,
+                        reason: row.get(9)?,
+                        error: row.get(10)?,
+                    })
+                },
+            )?;
+            Ok(rows.collect::<Result<_, _>>()?)
+        })
+        .await
+    }
+
+    /// Delete the sessions past `--history-retention` and `--history-max-sessions`
+    async fn prune(&self) -> anyhow::Result<()> {
+        let conn = self.conn.clone();
+        let cutoff = unix(SystemTime::now()).saturating_sub(self.retention.as_secs());
+        let max_sessions = self.max_sessions;
+        blocking(move || {
+            let conn = conn.lock().unwrap();
+            conn.execute("DELETE FROM sessions WHERE ended < ?1", params![cutoff])?;
+            conn.execute(
+                "DELETE FROM sessions WHERE id <= (SELECT id FROM sessions ORDER BY id DESC LIMIT 1 OFFSET ?1)",
+                params![max_sessions],
+            )?;
+            Ok(())
+        })
+        .await
+    }
+}
+
+fn open(path: &Path) -> anyhow::Result<Connection> {
+    let conn = Connection::open(path).with_context(|| format!("failed to open {}", path.display()))?;
+    // Readers don't hold up the bridges writing as they end
+    conn.pragma_update(None, "journal_mode", "WAL")?;
+    conn.execute_batch(SCHEMA).context("failed to create the sessions table")?;
+    Ok(conn)
+}
+
+/// Run a query on the blocking pool
+async fn blocking<T: Send + 'static>(
+    query: impl FnOnce() -> anyhow::Result<T> + Send + 'static,
+) -> anyhow::Result<T> {
+    tokio::task::spawn_blocking(query).await.context("history query panicked")?
+}
+
+/// Seconds since the Unix epoch
+pub fn unix(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs()
+}
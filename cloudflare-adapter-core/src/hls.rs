@@ -0,0 +1,208 @@
+//! Low-latency HLS egress
+//!
+//! Bridged broadcasts can be watched by players that don't speak MoQ. Playlists are
+//! rendered from the packager's segment windows and support blocking reloads
+//! (`_HLS_msn`/`_HLS_part`) and preload hints, so LL-HLS players can stay within a
+//! part or two of the live edge.
+//!
+//! Everything lives under `/hls/{stream_id}/`: `master.m3u8`, plus `index.m3u8`,
+//! `init.mp4`, `{msn}.m4s` and `{msn}.{part}.m4s` for each track.
+
+use std::fmt::Write as _;
+use std::sync::Arc;
+
+use axum::extract::{Path, Query, State};
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use axum::routing::get;
+use axum::Router;
+
+use crate::catalog::MediaKind;
+use crate::package::{self, respond, PackageOptions, PackagedStream, Packager, Window};
+
+/// The HLS routes, to be merged into the embedded HTTP server
+pub fn routes(packager: Arc<Packager>) -> Router {
+    Router::new()
+        .route("/hls/{stream_id}/master.m3u8", get(serve_master))
+        .route("/hls/{stream_id}/{track}/{file}", get(serve_track))
+        .with_state(packager)
+}
+
+/// Look up a stream that's packaged for HLS
+fn stream(packager: &Packager, stream_id: &str) -> Option<Arc<PackagedStream>> {
+    packager.stream(stream_id).filter(|s| s.formats.hls)
+}
+
+fn render(window: &Window, options: &PackageOptions) -> String {
+    let part_target = options.part_duration.as_secs_f64();
+    let target = options.segment_duration.as_micros().max(window.longest() as u128).div_ceil(1_000_000);
+    let first = window.segments.front().map_or(0, |s| s.msn);
+    let (next_msn, next_part) = window.next_part();
+
+    let mut out = String::new();
+    let _ = writeln!(out, "#EXTM3U");
+    let _ = writeln!(out, "#EXT-X-VERSION:9");
+    let _ = writeln!(out, "#EXT-X-TARGETDURATION:{target}");
+    let _ = writeln!(out, "#EXT-X-SERVER-CONTROL:CAN-BLOCK-RELOAD=YES,PART-HOLD-BACK={:.3}", part_target * 3.0);
+    let _ = writeln!(out, "#EXT-X-PART-INF:PART-TARGET={part_target:.3}");
+    let _ = writeln!(out, "#EXT-X-MEDIA-SEQUENCE:{first}");
+    let _ = writeln!(out, "#EXT-X-MAP:URI=\"init.mp4\"");
+
+    for segment in &window.segments {
+        // Parts are only listed near the live edge
+        if segment.msn + 3 > next_msn {
+            for (index, part) in segment.parts.iter().enumerate() {
+                let _ = write!(
+                    out,
+                    "#EXT-X-PART:DURATION={:.5},URI=\"{}.{index}.m4s\"",
+                    part.duration as f64 / 1e6,
+                    segment.msn
+                );
+                let _ = writeln!(out, "{}", if part.independent { ",INDEPENDENT=YES" } else { "" });
+            }
+        }
+
+        if segment.complete {
+            let _ = writeln!(out, "#EXTINF:{:.5},", segment.duration() as f64 / 1e6);
+            let _ = writeln!(out, "{}.m4s", segment.msn);
+        }
+    }
+
+    if window.ended {
+        let _ = writeln!(out, "#EXT-X-ENDLIST");
+    } else {
+        let _ = writeln!(out, "#EXT-X-PRELOAD-HINT:TYPE=PART,URI=\"{next_msn}.{next_part}.m4s\"");
+    }
+
+    out
+}
+
+/// LL-HLS blocking reload parameters
+#[derive(serde::Deserialize)]
+struct Reload {
+    #[serde(rename = "_HLS_msn")]
+    msn: Option<u64>,
+    #[serde(rename = "_HLS_part")]
+    part: Option<usize>,
+}
+
+async fn serve_master(State(packager): State<Arc<Packager>>, Path(stream_id): Path<String>) -> Response {
+    let Some(stream) = stream(&packager, &stream_id) else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+
+    let tracks = stream.tracks();
+    if tracks.is_empty() {
+        return StatusCode::NOT_FOUND.into_response();
+    }
+
+    let (video, audio): (Vec<_>, Vec<_>) = tracks.iter().partition(|t| t.rendition.kind == MediaKind::Video);
+
+    let mut out = String::new();
+    let _ = writeln!(out, "#EXTM3U");
+    let _ = writeln!(out, "#EXT-X-VERSION:9");
+    let _ = writeln!(out, "#EXT-X-INDEPENDENT-SEGMENTS");
+
+    for (index, track) in audio.iter().enumerate() {
+        let _ = writeln!(
+            out,
+            "#EXT-X-MEDIA:TYPE=AUDIO,GROUP-ID=\"audio\",NAME=\"{}\",DEFAULT={},AUTOSELECT=YES,URI=\"{}/index.m3u8\"",
+            track.rendition.track,
+            if index == 0 { "YES" } else { "NO" },
+            track.path
+        );
+    }
+
+    let audio_bitrate = audio.iter().map(|t| t.bitrate()).max().unwrap_or(0);
+    let audio_codec = audio.first().and_then(|t| t.codec());
+
+    if video.is_empty() {
+        // Audio-only broadcasts still need a variant to point at
+        for track in &audio {
+            let codec = track.codec().unwrap_or_default();
+            let _ = writeln!(out, "#EXT-X-STREAM-INF:BANDWIDTH={},CODECS=\"{codec}\"", track.bitrate());
+            let _ = writeln!(out, "{}/index.m3u8", track.path);
+        }
+    }
+
+    for track in &video {
+        let mut codecs: Vec<&str> = track.codec().into_iter().collect();
+        codecs.extend(audio_codec);
+
+        let bandwidth = track.bitrate() + audio_bitrate;
+        let _ = write!(out, "#EXT-X-STREAM-INF:BANDWIDTH={bandwidth},CODECS=\"{}\"", codecs.join(","));
+        if let Some((width, height)) = track.dimensions() {
+            let _ = write!(out, ",RESOLUTION={width}x{height}");
+        }
+        if !audio.is_empty() {
+            let _ = write!(out, ",AUDIO=\"audio\"");
+        }
+        let _ = writeln!(out);
+        let _ = writeln!(out, "{}/index.m3u8", track.path);
+    }
+
+    respond("application/vnd.apple.mpegurl", out)
+}
+
+async fn serve_track(
+    State(packager): State<Arc<Packager>>,
+    Path((stream_id, track, file)): Path<(String, String, String)>,
+    Query(reload): Query<Reload>,
+) -> Response {
+    let Some(track) = stream(&packager, &stream_id).and_then(|s| s.track(&track)) else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+
+    // Players give up on blocking requests after three target durations, so we do too
+    let timeout = packager.options.segment_duration * 3;
+
+    if file == "index.m3u8" {
+        let mut window = track.window.subscribe();
+
+        if let Some(msn) = reload.msn {
+            // Requests too far ahead can never be satisfied in time
+            if msn > window.borrow().next_part().0 + 2 {
+                return StatusCode::BAD_REQUEST.into_response();
+            }
+
+            let ready = window.wait_for(|w| w.ended || w.has(msn, reload.part));
+            if !matches!(tokio::time::timeout(timeout, ready).await, Ok(Ok(_))) {
+                return StatusCode::SERVICE_UNAVAILABLE.into_response();
+            }
+        }
+
+        let body = render(&window.borrow(), &packager.options);
+        return respond("application/vnd.apple.mpegurl", body);
+    }
+
+    let Some((msn, part)) = parse_part(&file) else {
+        return package::serve_fragment(&track, &file);
+    };
+
+    let mut window = track.window.subscribe();
+
+    // Hold requests for the preloaded part until it's ready
+    if !window.borrow().has(msn, Some(part)) {
+        let (next_msn, next_part) = window.borrow().next_part();
+        if (msn, part) != (next_msn, next_part) && (msn, part) != (next_msn + 1, 0) {
+            return StatusCode::NOT_FOUND.into_response();
+        }
+
+        let ready = window.wait_for(|w| w.ended || w.has(msn, Some(part)));
+        if !matches!(tokio::time::timeout(timeout, ready).await, Ok(Ok(_))) {
+            return StatusCode::SERVICE_UNAVAILABLE.into_response();
+        }
+    }
+
+    let window = window.borrow();
+    match window.segment(msn).and_then(|s| s.parts.get(part)) {
+        Some(part) => respond("video/mp4", part.data.clone()),
+        None => StatusCode::NOT_FOUND.into_response(),
+    }
+}
+
+/// Parse a `{msn}.{part}.m4s` part name
+fn parse_part(file: &str) -> Option<(u64, usize)> {
+    let (msn, part) = file.strip_suffix(".m4s")?.split_once('.')?;
+    Some((msn.parse().ok()?, part.parse().ok()?))
+}
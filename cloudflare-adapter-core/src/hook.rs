@@ -0,0 +1,151 @@
+//! Per-track frame hooks
+//!
+//! A hook sees every media frame of the tracks it's enabled for, after timestamp
+//! rebasing and before the frame reaches the relay. It can rewrite the payload
+//! (watermarking, transcoding) or drop the frame (content scanning).
+//!
+//! The adapter ships one hook that pipes frames through an external command. Each
+//! bridged track gets its own long-running process, which reads and writes frames on
+//! stdin/stdout as a 13-byte header followed by the payload:
+//!
+//! - flags (u8): bit 0 is set for keyframes
+//! - timestamp (u64, big endian): microseconds
+//! - length (u32, big endian): payload size, or `0xFFFFFFFF` in a reply to drop the frame
+//!
+//! The process must answer every frame, in order. `MOQ_STREAM_ID` and `MOQ_TRACK`
+//! are set in its environment.
+
+use std::collections::HashMap;
+use std::future::Future;
+use std::pin::Pin;
+use std::process::Stdio;
+use std::sync::{Arc, Mutex};
+
+use anyhow::Context;
+use bytes::Bytes;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::process::{Child, ChildStdin, ChildStdout, Command};
+
+use crate::media::MediaFrame;
+
+/// The length that marks a dropped frame in a reply
+const DROP: u32 = u32::MAX;
+
+/// Processes are shared between a track's groups, which are forwarded concurrently
+type SharedProcess = Arc<tokio::sync::Mutex<Process>>;
+
+pub type HookFuture<'a> = Pin<Box<dyn Future<Output = anyhow::Result<Option<MediaFrame>>> + Send + 'a>>;
+
+/// Transforms media frames on their way to the relay
+pub trait FrameHook: Send + Sync {
+    /// Transform one frame of `track`, or return None to drop it
+    fn transform<'a>(&'a self, stream_id: &'a str, track: &'a str, frame: MediaFrame, keyframe: bool) -> HookFuture<'a>;
+
+    /// Called once a hooked track stops being forwarded
+    fn close(&self, _stream_id: &str, _track: &str) {}
+}
+
+/// A hook that pipes frames through one external process per track
+pub struct CommandHook {
+    command: String,
+    processes: Mutex<HashMap<(String, String), SharedProcess>>,
+}
+
+impl CommandHook {
+    /// Run `command` with `sh -c` for every hooked track
+    pub fn new(command: String) -> Arc<Self> {
+        Arc::new(Self {
+            command,
+            processes: Default::default(),
+        })
+    }
+
+    fn process(&self, stream_id: &str, track: &str) -> anyhow::Result<SharedProcess> {
+        let key = (stream_id.to_string(), track.to_string());
+        let mut processes = self.processes.lock().unwrap();
+        if let Some(process) = processes.get(&key) {
+            return Ok(process.clone());
+        }
+
+        tracing::info!(stream_id, track, command = %self.command, "starting transform process");
+        let process = Arc::new(tokio::sync::Mutex::new(Process::spawn(&self.command, stream_id, track)?));
+        processes.insert(key, process.clone());
+        Ok(process)
+    }
+
+    /// Forget a process that failed, so the next frame starts a new one
+    fn discard(&self, stream_id: &str, track: &str) {
+        self.processes.lock().unwrap().remove(&(stream_id.to_string(), track.to_string()));
+    }
+}
+
+impl FrameHook for CommandHook {
+    fn transform<'a>(&'a self, stream_id: &'a str, track: &'a str, frame: MediaFrame, keyframe: bool) -> HookFuture<'a> {
+        Box::pin(async move {
+            let process = self.process(stream_id, track)?;
+            let res = process.lock().await.exchange(frame, keyframe).await;
+            if res.is_err() {
+                self.discard(stream_id, track);
+            }
+            res
+        })
+    }
+
+    fn close(&self, stream_id: &str, track: &str) {
+        self.discard(stream_id, track);
+    }
+}
+
+struct Process {
+    // Kept so the process is killed when we drop it
+    _child: Child,
+    stdin: ChildStdin,
+    stdout: ChildStdout,
+}
+
+impl Process {
+    fn spawn(command: &str, stream_id: &str, track: &str) -> anyhow::Result<Self> {
+        let mut child = Command::new("sh")
+            .args(["-c", command])
+            .env("MOQ_STREAM_ID", stream_id)
+            .env("MOQ_TRACK", track)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .kill_on_drop(true)
+            .spawn()
+            .context("failed to start transform command")?;
+
+        Ok(Self {
+            stdin: child.stdin.take().context("no stdin")?,
+            stdout: child.stdout.take().context("no stdout")?,
+            _child: child,
+        })
+    }
+
+    /// Send one frame and wait for the reply
+    async fn exchange(&mut self, frame: MediaFrame, keyframe: bool) -> anyhow::Result<Option<MediaFrame>> {
+        let mut header = [0u8; 13];
+        header[0] = keyframe as u8;
+        header[1..9].copy_from_slice(&frame.timestamp.to_be_bytes());
+        header[9..].copy_from_slice(&(frame.payload.len() as u32).to_be_bytes());
+
+        self.stdin.write_all(&header).await?;
+        self.stdin.write_all(&frame.payload).await?;
+        self.stdin.flush().await?;
+
+        self.stdout.read_exact(&mut header).await.context("transform command closed stdout")?;
+        let timestamp = u64::from_be_bytes(header[1..9].try_into().unwrap());
+        let size = u32::from_be_bytes(header[9..].try_into().unwrap());
+        if size == DROP {
+            return Ok(None);
+        }
+
+        let mut payload = vec![0u8; size as usize];
+        self.stdout.read_exact(&mut payload).await?;
+
+        Ok(Some(MediaFrame {
+            timestamp,
+            payload: Bytes::from(payload),
+        }))
+    }
+}
@@ -0,0 +1,65 @@
+//! Embedded HTTP server
+//!
+//! Serves egress for players that can't reach the relay over MoQ, and lets
+//! supplemental processes inject tracks into bridged broadcasts, the registry push
+//! its stream list or operators control bridges, and with the `webrtc` feature carries
+//! WHIP and WHEP sessions. Metrics are always served at `/metrics`,
+//! and the autoscaling signals at `/autoscale`.
+
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use anyhow::Context;
+use axum::Router;
+
+use crate::admin::Admin;
+use crate::autoscale::Autoscale;
+use crate::discovery::Webhook;
+use crate::inject::Injectors;
+use crate::package::Packager;
+#[cfg(feature = "webrtc")]
+use crate::webrtc::{self, Gateway};
+use crate::{admin, autoscale, dash, discovery, hls, inject, metrics};
+
+/// Serve HTTP on `listen` until the listener fails
+///
+/// The injection, discovery, admin and WebRTC endpoints are only mounted when `injectors`,
+/// `webhook`, `admin` and `gateway` are given.
+pub async fn run_http_server(
+    listen: SocketAddr,
+    packager: Arc<Packager>,
+    injectors: Option<Arc<Injectors>>,
+    webhook: Option<Arc<Webhook>>,
+    admin: Option<Arc<Admin>>,
+    autoscale: Arc<Autoscale>,
+    #[cfg(feature = "webrtc")] gateway: Option<Arc<Gateway>>,
+) -> anyhow::Result<()> {
+    let mut app = Router::new()
+        .merge(hls::routes(packager.clone()))
+        .merge(dash::routes(packager))
+        .merge(metrics::routes())
+        .merge(autoscale::routes(autoscale));
+
+    if let Some(injectors) = injectors {
+        app = app.merge(inject::routes(injectors));
+    }
+    if let Some(webhook) = webhook {
+        app = app.merge(discovery::routes(webhook));
+    }
+    if let Some(admin) = admin {
+        app = app.merge(admin::routes(admin));
+    }
+    #[cfg(feature = "webrtc")]
+    if let Some(gateway) = gateway {
+        app = app.merge(webrtc::routes(gateway));
+    }
+
+    let listener = tokio::net::TcpListener::bind(listen)
+        .await
+        .context("failed to bind http listener")?;
+
+    tracing::info!(%listen, "http server listening");
+    axum::serve(listener, app).await?;
+
+    Ok(())
+}
@@ -0,0 +1,88 @@
+//! Idle bridge detection
+//!
+//! CF streams can show up in the registry well before (or without ever) going live,
+//! and a live stream can go silent without its broadcast closing. Either way the
+//! bridge holds a CF subscription for nothing. We watch the catalog plus the cheapest
+//! rendition and call the bridge idle once neither has produced anything for the
+//! configured timeout. Relay subscribers share the same upstream subscriptions, so
+//! this costs at most one low-bitrate track per bridge.
+//!
+//! A stall is a stream that was live and went silent, which usually means the CF
+//! subscription is wedged rather than the stream being over.
+
+use std::time::Duration;
+
+use moq_lite::{BroadcastConsumer, Track, TrackConsumer};
+use serde_json::Value;
+
+use crate::catalog::{self, MediaKind, Rendition, CATALOG_TRACK};
+
+/// Resolve once `broadcast` has produced no objects for `timeout`
+pub async fn wait_idle(broadcast: &BroadcastConsumer, timeout: Duration) {
+    let mut watch = Watch::new(broadcast);
+    while tokio::time::timeout(timeout, watch.activity()).await.is_ok() {}
+}
+
+/// Resolve once `broadcast` has produced media and then nothing at all for `timeout`
+pub async fn wait_stall(broadcast: &BroadcastConsumer, timeout: Duration) {
+    let mut watch = Watch::new(broadcast);
+    while !watch.activity().await {}
+    while tokio::time::timeout(timeout, watch.activity()).await.is_ok() {}
+}
+
+/// The catalog and cheapest rendition of a broadcast
+struct Watch<'a> {
+    broadcast: &'a BroadcastConsumer,
+    catalog: TrackConsumer,
+    media: Option<TrackConsumer>,
+}
+
+impl<'a> Watch<'a> {
+    fn new(broadcast: &'a BroadcastConsumer) -> Self {
+        Self {
+            broadcast,
+            catalog: broadcast.subscribe_track(&Track::new(CATALOG_TRACK)),
+            media: None,
+        }
+    }
+
+    /// Wait for the next object, returning true if it was media rather than the catalog
+    async fn activity(&mut self) -> bool {
+        tokio::select! {
+            group = self.catalog.next_group() => {
+                let Ok(Some(mut group)) = group else {
+                    // The broadcast is going away; closing is handled elsewhere
+                    return std::future::pending().await;
+                };
+                if let Ok(Some(frame)) = group.read_frame().await {
+                    let rendition = catalog::renditions(&frame).and_then(|r| cheapest(&r));
+                    self.media = rendition.map(|r| self.broadcast.subscribe_track(&Track::new(&r.track)));
+                }
+                false
+            }
+            Some(()) = next_frame(&mut self.media) => true,
+        }
+    }
+}
+
+/// Wait for the next frame of the media track, if we're watching one
+async fn next_frame(media: &mut Option<TrackConsumer>) -> Option<()> {
+    let track = media.as_mut()?;
+    match track.next_group().await {
+        Ok(Some(mut group)) => group.read_frame().await.ok().flatten().map(|_| ()),
+        // No more groups, so only the catalog can prove the broadcast is alive
+        _ => {
+            *media = None;
+            std::future::pending().await
+        }
+    }
+}
+
+/// The rendition that costs the least to keep an eye on: audio, else the smallest video
+fn cheapest(renditions: &[Rendition]) -> Option<Rendition> {
+    let cost = |r: &&Rendition| {
+        let height = r.config.get("codedHeight").and_then(Value::as_u64).unwrap_or(u64::MAX);
+        (r.kind == MediaKind::Video, height)
+    };
+    renditions.iter().min_by_key(cost).cloned()
+}
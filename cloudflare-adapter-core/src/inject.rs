@@ -0,0 +1,159 @@
+//! Supplemental tracks injected into bridged broadcasts
+//!
+//! Every bridge exposes an [`Injector`], which publishes extra tracks (captions from
+//! an external service, timed metadata events, ...) alongside the forwarded ones. Out
+//! of process writers can use `POST /inject/{stream_id}/{track}` on the embedded HTTP
+//! server: each request body becomes a single-frame group. With `?timestamp=` (in
+//! microseconds) the frame gets a hang timestamp prefix like any media frame.
+//!
+//! Injected tracks aren't added to the catalog, so subscribers need to know the name.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+#[cfg(feature = "http")]
+use axum::extract::{Path, Query, State};
+#[cfg(feature = "http")]
+use axum::http::{header, HeaderMap, StatusCode};
+#[cfg(feature = "http")]
+use axum::response::{IntoResponse, Response};
+#[cfg(feature = "http")]
+use axum::routing::post;
+#[cfg(feature = "http")]
+use axum::Router;
+#[cfg(feature = "http")]
+use bytes::Bytes;
+use moq_lite::{Track, TrackConsumer, TrackProducer};
+use tokio::sync::mpsc;
+
+use crate::catalog::CATALOG_TRACK;
+#[cfg(feature = "http")]
+use crate::media::MediaFrame;
+
+/// Adds tracks to one bridged broadcast
+#[derive(Clone)]
+pub struct Injector {
+    // Picked up by the forwarding task, which owns the broadcast producer
+    publish: mpsc::UnboundedSender<TrackConsumer>,
+    tracks: Arc<Mutex<HashMap<String, TrackProducer>>>,
+}
+
+impl Injector {
+    /// Create an injector and the receiver the forwarding task publishes from
+    pub fn new() -> (Self, mpsc::UnboundedReceiver<TrackConsumer>) {
+        let (publish, published) = mpsc::unbounded_channel();
+        let injector = Self {
+            publish,
+            tracks: Default::default(),
+        };
+
+        (injector, published)
+    }
+
+    /// The producer for injected track `name`, creating it on first use
+    ///
+    /// Returns None for reserved names or once the broadcast has ended.
+    pub fn track(&self, name: &str) -> Option<TrackProducer> {
+        if name == CATALOG_TRACK {
+            return None;
+        }
+
+        let mut tracks = self.tracks.lock().unwrap();
+        if let Some(track) = tracks.get(name) {
+            return Some(track.clone());
+        }
+
+        let track = Track::new(name).produce();
+        self.publish.send(track.consumer).ok()?;
+        tracks.insert(name.to_string(), track.producer.clone());
+
+        tracing::info!(track = name, "injecting track");
+        Some(track.producer)
+    }
+
+    fn is_same(&self, other: &Self) -> bool {
+        self.publish.same_channel(&other.publish)
+    }
+}
+
+/// The injectors of every active bridge
+pub struct Injectors {
+    /// Bearer token required by the HTTP endpoint, if any
+    #[cfg_attr(not(feature = "http"), allow(dead_code))]
+    token: Option<String>,
+    bridges: Mutex<HashMap<String, Injector>>,
+}
+
+impl Injectors {
+    pub fn new(token: Option<String>) -> Arc<Self> {
+        Arc::new(Self {
+            token,
+            bridges: Default::default(),
+        })
+    }
+
+    pub fn insert(&self, stream_id: &str, injector: Injector) {
+        self.bridges.lock().unwrap().insert(stream_id.to_string(), injector);
+    }
+
+    /// Remove a bridge's injector, unless a newer bridge already replaced it
+    pub fn remove(&self, stream_id: &str, injector: &Injector) {
+        let mut bridges = self.bridges.lock().unwrap();
+        if bridges.get(stream_id).is_some_and(|i| i.is_same(injector)) {
+            bridges.remove(stream_id);
+        }
+    }
+
+    pub fn get(&self, stream_id: &str) -> Option<Injector> {
+        self.bridges.lock().unwrap().get(stream_id).cloned()
+    }
+}
+
+/// The injection routes, to be merged into the embedded HTTP server
+#[cfg(feature = "http")]
+pub fn routes(injectors: Arc<Injectors>) -> Router {
+    Router::new()
+        .route("/inject/{stream_id}/{track}", post(inject_frame))
+        .with_state(injectors)
+}
+
+#[cfg(feature = "http")]
+#[derive(serde::Deserialize)]
+struct InjectQuery {
+    timestamp: Option<u64>,
+}
+
+#[cfg(feature = "http")]
+async fn inject_frame(
+    State(injectors): State<Arc<Injectors>>,
+    Path((stream_id, track)): Path<(String, String)>,
+    Query(query): Query<InjectQuery>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Response {
+    if let Some(token) = &injectors.token {
+        let bearer = headers
+            .get(header::AUTHORIZATION)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.strip_prefix("Bearer "));
+        if bearer != Some(token.as_str()) {
+            return StatusCode::UNAUTHORIZED.into_response();
+        }
+    }
+
+    let Some(mut track) = injectors.get(&stream_id).and_then(|i| i.track(&track)) else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+
+    let frame = match query.timestamp {
+        Some(timestamp) => MediaFrame {
+            timestamp,
+            payload: body,
+        }
+        .encode(),
+        None => body,
+    };
+
+    track.write_frame(frame);
+    StatusCode::NO_CONTENT.into_response()
+}
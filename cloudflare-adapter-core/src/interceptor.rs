@@ -0,0 +1,182 @@
+//! Frame interceptors
+//!
+//! Interceptors see every frame of the tracks they apply to, in order, as the last step
+//! before the relay: after timestamp rebasing and the frame hook. Each can inspect the
+//! frame, rewrite it or drop it, which skips the rest of the chain. Unlike the frame
+//! hook they're synchronous and in-process, so they're meant to be cheap. The catalog
+//! is never intercepted.
+//!
+//! Embedders add their own with
+//! [BridgeManager::with_interceptor](crate::BridgeManager::with_interceptor). A few are
+//! built in, enabled with `--interceptor name[:arg][@track-pattern]` and run first, in
+//! the order given:
+//!
+//! - `count-keyframes` counts keyframes per track in `keyframes_total`
+//! - `keyframes-only` drops every frame but keyframes
+//! - `drop-empty` drops frames with an empty payload
+//! - `strip-prefix:<hex>` removes a leading byte sequence from payloads that start with it
+
+use std::str::FromStr;
+use std::sync::Arc;
+
+use bytes::Bytes;
+
+use crate::filter::glob_match;
+use crate::media::MediaFrame;
+use crate::metrics::Counter;
+
+/// One frame on its way to the relay
+#[derive(Clone, Debug)]
+pub struct Frame {
+    /// Presentation timestamp in microseconds, or None on tracks without the hang prefix
+    pub timestamp: Option<u64>,
+    pub payload: Bytes,
+    pub keyframe: bool,
+}
+
+/// Inspects, rewrites or drops the frames of the tracks it applies to
+pub trait Interceptor: Send + Sync {
+    /// Whether to intercept upstream `track`, asked once each time it starts being forwarded
+    fn applies(&self, _stream_id: &str, _track: &str) -> bool {
+        true
+    }
+
+    /// Look at or change a frame, returning false to drop it
+    fn intercept(&self, stream_id: &str, track: &str, frame: &mut Frame) -> bool;
+}
+
+/// The interceptors that apply to one track
+#[derive(Clone)]
+pub(crate) struct TrackInterceptors {
+    chain: Arc<[Arc<dyn Interceptor>]>,
+    stream_id: Arc<str>,
+    track: Arc<str>,
+}
+
+impl TrackInterceptors {
+    /// The part of `chain` for `track`, or None if none of it applies
+    pub(crate) fn new(chain: &[Arc<dyn Interceptor>], stream_id: &str, track: &str) -> Option<Self> {
+        let chain: Arc<[_]> = chain.iter().filter(|i| i.applies(stream_id, track)).cloned().collect();
+        (!chain.is_empty()).then(|| Self {
+            chain,
+            stream_id: stream_id.into(),
+            track: track.into(),
+        })
+    }
+
+    /// Run an encoded frame through the chain, returning None if it's dropped
+    ///
+    /// Media frames are decoded first, so interceptors see the payload without its prefix.
+    pub(crate) fn apply(&self, frame: Bytes, keyframe: bool, media: bool) -> Option<Bytes> {
+        let mut frame = match media.then(|| MediaFrame::decode(&frame)).flatten() {
+            Some(decoded) => Frame {
+                timestamp: Some(decoded.timestamp),
+                payload: decoded.payload,
+                keyframe,
+            },
+            None => Frame {
+                timestamp: None,
+                payload: frame,
+                keyframe,
+            },
+        };
+
+        for interceptor in self.chain.iter() {
+            if !interceptor.intercept(&self.stream_id, &self.track, &mut frame) {
+                return None;
+            }
+        }
+
+        Some(match frame.timestamp {
+            Some(timestamp) => MediaFrame {
+                timestamp,
+                payload: frame.payload,
+            }
+            .encode(),
+            None => frame.payload,
+        })
+    }
+}
+
+/// What a built-in interceptor does
+#[derive(Clone, Debug)]
+enum Builtin {
+    CountKeyframes,
+    KeyframesOnly,
+    DropEmpty,
+    StripPrefix(Bytes),
+}
+
+/// A built-in interceptor, written as `name[:arg][@track-pattern]`
+#[derive(Clone, Debug)]
+pub struct BuiltinInterceptor {
+    builtin: Builtin,
+    /// Only these upstream tracks, or all of them
+    tracks: Option<String>,
+}
+
+impl FromStr for BuiltinInterceptor {
+    type Err = String;
+
+    fn from_str(spec: &str) -> Result<Self, Self::Err> {
+        let (spec, tracks) = match spec.split_once('@') {
+            Some((spec, tracks)) => (spec, Some(tracks.trim().to_string())),
+            None => (spec, None),
+        };
+        let (name, arg) = match spec.split_once(':') {
+            Some((name, arg)) => (name.trim(), Some(arg.trim())),
+            None => (spec.trim(), None),
+        };
+
+        let builtin = match (name, arg) {
+            ("count-keyframes", None) => Builtin::CountKeyframes,
+            ("keyframes-only", None) => Builtin::KeyframesOnly,
+            ("drop-empty", None) => Builtin::DropEmpty,
+            ("strip-prefix", Some(hex)) => Builtin::StripPrefix(decode_hex(hex)?),
+            ("strip-prefix", None) => return Err("strip-prefix needs the prefix, as strip-prefix:<hex>".to_string()),
+            ("count-keyframes" | "keyframes-only" | "drop-empty", Some(_)) => {
+                return Err(format!("{name} takes no argument"))
+            }
+            _ => return Err(format!("unknown interceptor {name:?}")),
+        };
+        Ok(Self { builtin, tracks })
+    }
+}
+
+impl Interceptor for BuiltinInterceptor {
+    fn applies(&self, _stream_id: &str, track: &str) -> bool {
+        self.tracks.as_deref().is_none_or(|pattern| glob_match(pattern, track))
+    }
+
+    fn intercept(&self, _stream_id: &str, track: &str, frame: &mut Frame) -> bool {
+        match &self.builtin {
+            Builtin::CountKeyframes => {
+                if frame.keyframe {
+                    Counter::new("keyframes_total", "Keyframes forwarded to the relay", &[("track", track)]).inc();
+                }
+                true
+            }
+            Builtin::KeyframesOnly => frame.keyframe,
+            Builtin::DropEmpty => !frame.payload.is_empty(),
+            Builtin::StripPrefix(prefix) => {
+                if frame.payload.starts_with(prefix) {
+                    frame.payload = frame.payload.slice(prefix.len()..);
+                }
+                true
+            }
+        }
+    }
+}
+
+fn decode_hex(hex: &str) -> Result<Bytes, String> {
+    if hex.is_empty() || !hex.len().is_multiple_of(2) {
+        return Err(format!("{hex:?} isn't an even number of hex digits"));
+    }
+
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2).unwrap_or_default(), 16))
+        .collect::<Result<Vec<_>, _>>()
+        .map(Bytes::from)
+        .map_err(|_| format!("{hex:?} isn't hex"))
+}
@@ -0,0 +1,167 @@
+//! Bridge ownership across replicas
+//!
+//! With `--lease-redis-url`, replicas polling the same registry take a lease on a
+//! stream before bridging it, a Redis key under `--lease-prefix` holding the owner's
+//! token that expires after `--lease-ttl`. The owner renews it while the bridge runs
+//! and deletes it when the bridge ends. The others skip the stream until then, and
+//! pick it up on a later poll once a replica that died lets its leases expire.
+//!
+//! A bridge whose lease is lost, to an expiry while Redis was unreachable or to
+//! another replica, stops with [BridgeEnd::LeaseLost](crate::BridgeEnd::LeaseLost).
+//! Without Redis, no new bridges start, since we couldn't tell who owns them.
+
+use std::collections::HashSet;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::Context;
+use redis::aio::ConnectionManager;
+use redis::Script;
+use tokio::time::Instant;
+
+/// Take the lease, or extend it if it's already ours
+const ACQUIRE: &str = r"
+if redis.call('GET', KEYS[1]) == ARGV[1] then
+    return redis.call('PEXPIRE', KEYS[1], ARGV[2])
+end
+if redis.call('SET', KEYS[1], ARGV[1], 'NX', 'PX', ARGV[2]) then
+    return 1
+end
+return 0
+";
+
+/// Extend the lease if it's still ours
+const RENEW: &str = r"
+if redis.call('GET', KEYS[1]) == ARGV[1] then
+    return redis.call('PEXPIRE', KEYS[1], ARGV[2])
+end
+return 0
+";
+
+/// Drop the lease if it's still ours
+const RELEASE: &str = r"
+if redis.call('GET', KEYS[1]) == ARGV[1] then
+    return redis.call('DEL', KEYS[1])
+end
+return 0
+";
+
+#[derive(Clone, Debug)]
+pub struct LeaseOptions {
+    pub url: String,
+    pub ttl: Duration,
+    pub prefix: String,
+}
+
+/// Leases held by this replica
+pub struct Leases {
+    conn: ConnectionManager,
+    /// What our leases hold, so we can tell them from other replicas'
+    token: String,
+    ttl: Duration,
+    prefix: String,
+    acquire: Script,
+    renew: Script,
+    release: Script,
+}
+
+impl Leases {
+    pub async fn connect(options: &LeaseOptions) -> anyhow::Result<Arc<Self>> {
+        let client = redis::Client::open(options.url.as_str()).context("invalid redis url")?;
+        let conn = client.get_connection_manager().await.context("failed to connect to redis")?;
+
+        let host = std::env::var("HOSTNAME").unwrap_or_else(|_| "adapter".to_string());
+        let token = format!("{host}-{}-{:08x}", std::process::id(), rand::random::<u32>());
+        tracing::info!(token, ttl = ?options.ttl, "sharing streams through redis leases");
+
+        Ok(Arc::new(Self {
+            conn,
+            token,
+            ttl: options.ttl,
+            prefix: options.prefix.clone(),
+            acquire: Script::new(ACQUIRE),
+            renew: Script::new(RENEW),
+            release: Script::new(RELEASE),
+        }))
+    }
+
+    /// Which of `stream_ids` other replicas hold leases on
+    pub async fn held_elsewhere<'a>(&self, stream_ids: impl Iterator<Item = &'a str>) -> anyhow::Result<HashSet<String>> {
+        let stream_ids: Vec<_> = stream_ids.collect();
+        if stream_ids.is_empty() {
+            return Ok(HashSet::new());
+        }
+
+        let keys: Vec<_> = stream_ids.iter().map(|id| format!("{}{id}", self.prefix)).collect();
+        let holders: Vec<Option<String>> = redis::cmd("MGET").arg(&keys).query_async(&mut self.conn.clone()).await?;
+        let held = stream_ids
+            .into_iter()
+            .zip(holders)
+            .filter(|(_, holder)| holder.as_ref().is_some_and(|holder| *holder != self.token))
+            .map(|(stream_id, _)| stream_id.to_string())
+            .collect();
+        Ok(held)
+    }
+
+    /// Take the lease on `stream_id`, None if another replica holds it
+    pub async fn acquire(self: &Arc<Self>, stream_id: &str) -> anyhow::Result<Option<Lease>> {
+        let key = format!("{}{stream_id}", self.prefix);
+        if !self.call(&self.acquire, &key).await? {
+            return Ok(None);
+        }
+
+        Ok(Some(Lease {
+            leases: self.clone(),
+            key,
+        }))
+    }
+
+    /// Run `script` on `key` with our token and TTL, returning whether it changed anything
+    async fn call(&self, script: &Script, key: &str) -> anyhow::Result<bool> {
+        let ttl = self.ttl.as_millis() as u64;
+        let mut conn = self.conn.clone();
+        let done: i64 = script.key(key).arg(&self.token).arg(ttl).invoke_async(&mut conn).await?;
+        Ok(done == 1)
+    }
+}
+
+/// Our lease on one stream
+pub struct Lease {
+    leases: Arc<Leases>,
+    key: String,
+}
+
+impl Lease {
+    /// Renew the lease until it's lost, and resolve then
+    ///
+    /// Failed renewals are retried, but once a full TTL passed since the last one that
+    /// worked, another replica may have taken over, so the lease counts as lost.
+    pub async fn keep(&self) {
+        let mut ticks = tokio::time::interval(self.leases.ttl / 3);
+        ticks.tick().await;
+        let mut renewed = Instant::now();
+
+        loop {
+            ticks.tick().await;
+            match self.leases.call(&self.leases.renew, &self.key).await {
+                Ok(true) => renewed = Instant::now(),
+                Ok(false) => {
+                    tracing::warn!(key = self.key, "lease taken by another replica");
+                    return;
+                }
+                Err(err) if renewed.elapsed() >= self.leases.ttl => {
+                    tracing::warn!(%err, key = self.key, "lease expired while redis was unreachable");
+                    return;
+                }
+                Err(err) => tracing::debug!(%err, key = self.key, "failed to renew lease"),
+            }
+        }
+    }
+
+    /// Let another replica take the stream
+    pub async fn release(self) {
+        if let Err(err) = self.leases.call(&self.leases.release, &self.key).await {
+            tracing::debug!(%err, key = self.key, "failed to release lease, leaving it to expire");
+        }
+    }
+}
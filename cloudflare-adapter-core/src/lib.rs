@@ -0,0 +1,153 @@
+//! CloudFlare Adapter
+//!
+//! Bridges moq-lite relay with CloudFlare's Draft 14 MoQ network.
+//! - Connects to your moq-lite relay as a cluster node
+//! - Connects to CloudFlare as a subscriber
+//! - Polls your stream registry for CloudFlare-origin streams
+//! - Bridges streams by subscribing to CloudFlare and republishing to your relay
+//!
+//! The `cloudflare-adapter` binary is a thin wrapper: it parses an [AdapterConfig] and
+//! hands it to a [BridgeManager]. Other services can embed the adapter the same way, or
+//! [spawn](BridgeManager::spawn) one from [Adapter::builder] into their own runtime
+//! and control it through the [AdapterHandle]. The manager starts a [Bridge] for every
+//! stream it admits and runs it until it ends, keeping a [BridgeHandle] to control it
+//! with. It fails with an [AdapterError], and each bridge with a [BridgeError].
+//!
+//! Registry polling and posting bridge stats (the `registry` feature), the embedded HTTP server with its
+//! egress, webhook and admin endpoints (`http`), pushing metrics to a Pushgateway
+//! (`push`), per-broadcast relay tokens from a token service (`token-service`), following
+//! CloudFlare's redirects (`redirects`) and the [selftest] and [loadgen] subcommands
+//! (`self-test`) are default features.
+//! The [conformance] subcommand checks our Draft 14 messages against captures from CF.
+//! Embedders that bring their own [discovery] and control plane can turn them off to
+//! drop reqwest and axum, and serve [render_metrics] themselves.
+
+mod admarker;
+#[cfg(feature = "http")]
+mod admin;
+mod alias;
+mod announce;
+mod autoscale;
+mod backoff;
+mod backpressure;
+mod breaker;
+mod bridge;
+mod buffer;
+mod cache;
+mod canary;
+mod capture;
+mod catalog;
+mod chaos;
+mod clock;
+mod config;
+pub mod conformance;
+mod connect;
+#[cfg(feature = "sentry")]
+pub mod crash;
+#[cfg(feature = "http")]
+mod dash;
+mod demand;
+#[cfg(feature = "ffmpeg")]
+mod demux;
+pub mod discovery;
+mod duplicate;
+mod e2ee;
+mod error;
+mod expiry;
+pub mod events;
+mod filter;
+mod forward;
+mod handle;
+mod health;
+pub mod healthcheck;
+#[cfg(feature = "http")]
+mod hls;
+#[cfg(feature = "history")]
+pub mod history;
+mod hook;
+#[cfg(feature = "http")]
+mod http;
+mod idle;
+mod inject;
+pub mod interceptor;
+#[cfg(feature = "redis")]
+mod lease;
+pub mod lifecycle;
+pub mod loadgen;
+mod loss;
+mod manager;
+mod media;
+mod metadata;
+mod metrics;
+mod migrate;
+mod mp4;
+mod mpegts;
+mod namespace;
+#[cfg(feature = "http")]
+mod package;
+mod paths;
+mod pin;
+mod pool;
+mod prewarm;
+mod priority;
+mod probe;
+mod proxy;
+#[cfg(feature = "push")]
+mod push;
+mod qos;
+mod queue;
+mod quic;
+mod record;
+mod redirect;
+mod registry;
+mod relay;
+pub mod reload;
+pub mod replay;
+mod resume;
+mod retry;
+mod runner;
+pub mod selftest;
+pub mod settings;
+mod shard;
+mod shed;
+mod shutdown;
+pub mod sink;
+pub mod soak;
+mod spill;
+mod statefile;
+#[cfg(feature = "registry")]
+mod stats;
+#[cfg(feature = "ffmpeg")]
+mod srt;
+mod supervise;
+pub mod systemd;
+#[cfg(feature = "http")]
+pub mod tap;
+mod tasks;
+pub mod tenant;
+#[cfg(feature = "test-util")]
+pub mod testing;
+mod throttle;
+#[cfg(feature = "ffmpeg")]
+mod thumbnail;
+mod timestamp;
+mod token;
+mod udp;
+mod upstream;
+pub mod validate;
+mod watchdog;
+#[cfg(feature = "webrtc")]
+mod webrtc;
+mod wildcard;
+
+pub use bridge::BridgeEnd;
+pub use capture::CaptureLatency;
+pub use config::{AdapterConfig, Command};
+pub use error::{AdapterError, BridgeError};
+pub use handle::{BridgeHandle, BridgeResult, BridgeStats};
+pub use loss::TrackLosses;
+pub use manager::{Adapter, AdapterHandle, BridgeManager};
+pub use metrics::render as render_metrics;
+pub use probe::ProbeLatency;
+pub use registry::StreamInfo;
+pub use runner::Bridge;
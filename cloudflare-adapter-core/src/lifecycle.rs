@@ -0,0 +1,179 @@
+//! Bridge lifecycle callbacks
+//!
+//! Embedders register async callbacks on the [BridgeManager](crate::BridgeManager) for
+//! billing, alerting or analytics:
+//!
+//! - `on_bridge_start` once a stream is admitted and its relay path claimed
+//! - `on_bridge_active` once the bridge wrote its first frame to the relay
+//! - `on_bridge_error` when the bridge fails (and is retried per `--backoff-*`)
+//! - `on_bridge_close` when it ends any other way, with why
+//!
+//! Every started bridge gets exactly one of the last two. A bridge's events are
+//! delivered in order by a task of its own, never holding up the bridge, so a slow
+//! callback only delays the bridge's later callbacks.
+
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use tokio::sync::mpsc;
+
+use crate::bridge::BridgeEnd;
+use crate::error::BridgeError;
+use crate::events::{AdapterEvent, EventBus};
+use crate::registry::StreamInfo;
+
+pub type CallbackFuture = Pin<Box<dyn Future<Output = ()> + Send>>;
+
+type Callback<T> = Arc<dyn Fn(BridgeContext, T) -> CallbackFuture + Send + Sync>;
+
+/// What callbacks are told about a bridge
+#[derive(Clone, Debug)]
+pub struct BridgeContext {
+    /// The registry entry the bridge was started from
+    pub stream: StreamInfo,
+    /// The CF namespace it subscribes to
+    pub namespace: String,
+    /// The `--relay-target` it publishes to, None for the main relay
+    pub relay: Option<String>,
+    /// The relay path it publishes under
+    pub path: String,
+    pub started: SystemTime,
+}
+
+/// The callbacks registered for each event
+#[derive(Clone, Default)]
+pub(crate) struct Lifecycle {
+    start: Vec<Callback<()>>,
+    active: Vec<Callback<()>>,
+    error: Vec<Callback<String>>,
+    close: Vec<Callback<BridgeEnd>>,
+}
+
+impl Lifecycle {
+    pub(crate) fn on_start<F: Future<Output = ()> + Send + 'static>(
+        &mut self,
+        callback: impl Fn(BridgeContext) -> F + Send + Sync + 'static,
+    ) {
+        self.start.push(Arc::new(move |context, ()| Box::pin(callback(context))));
+    }
+
+    pub(crate) fn on_active<F: Future<Output = ()> + Send + 'static>(
+        &mut self,
+        callback: impl Fn(BridgeContext) -> F + Send + Sync + 'static,
+    ) {
+        self.active.push(Arc::new(move |context, ()| Box::pin(callback(context))));
+    }
+
+    pub(crate) fn on_error<F: Future<Output = ()> + Send + 'static>(
+        &mut self,
+        callback: impl Fn(BridgeContext, String) -> F + Send + Sync + 'static,
+    ) {
+        self.error.push(Arc::new(move |context, err| Box::pin(callback(context, err))));
+    }
+
+    pub(crate) fn on_close<F: Future<Output = ()> + Send + 'static>(
+        &mut self,
+        callback: impl Fn(BridgeContext, BridgeEnd) -> F + Send + Sync + 'static,
+    ) {
+        self.close.push(Arc::new(move |context, end| Box::pin(callback(context, end))));
+    }
+
+    fn is_empty(&self) -> bool {
+        self.start.is_empty() && self.active.is_empty() && self.error.is_empty() && self.close.is_empty()
+    }
+
+    /// Start delivering the events of one bridge, to its callbacks and the event stream
+    pub(crate) fn bridge(self: &Arc<Self>, context: BridgeContext, bus: Arc<EventBus>) -> BridgeEvents {
+        if self.is_empty() {
+            return BridgeEvents {
+                context,
+                callbacks: None,
+                bus,
+            };
+        }
+
+        let (callbacks, mut queued) = mpsc::unbounded_channel();
+        let lifecycle = self.clone();
+        tokio::spawn(async move {
+            while let Some(event) = queued.recv().await {
+                lifecycle.deliver(event).await;
+            }
+        });
+
+        BridgeEvents {
+            context,
+            callbacks: Some(callbacks),
+            bus,
+        }
+    }
+
+    async fn deliver(&self, event: AdapterEvent) {
+        match event {
+            AdapterEvent::BridgeStarted(context) => {
+                for callback in &self.start {
+                    callback(context.clone(), ()).await;
+                }
+            }
+            AdapterEvent::BridgeActive(context) => {
+                for callback in &self.active {
+                    callback(context.clone(), ()).await;
+                }
+            }
+            AdapterEvent::BridgeFailed(context, err) => {
+                for callback in &self.error {
+                    callback(context.clone(), err.clone()).await;
+                }
+            }
+            AdapterEvent::BridgeClosed(context, end) => {
+                for callback in &self.close {
+                    callback(context.clone(), end).await;
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Reports the events of one bridge
+#[derive(Clone)]
+pub(crate) struct BridgeEvents {
+    context: BridgeContext,
+    callbacks: Option<mpsc::UnboundedSender<AdapterEvent>>,
+    bus: Arc<EventBus>,
+}
+
+impl BridgeEvents {
+    fn send(&self, event: AdapterEvent) {
+        if let Some(callbacks) = &self.callbacks {
+            let _ = callbacks.send(event.clone());
+        }
+        self.bus.publish(event);
+    }
+
+    pub(crate) fn start(&self) {
+        self.send(AdapterEvent::BridgeStarted(self.context.clone()));
+    }
+
+    pub(crate) fn active(&self) {
+        self.send(AdapterEvent::BridgeActive(self.context.clone()));
+    }
+
+    pub(crate) fn context(&self) -> &BridgeContext {
+        &self.context
+    }
+
+    pub(crate) fn ended(&self, end: &Result<BridgeEnd, BridgeError>) {
+        #[cfg(feature = "sentry")]
+        if let Err(err) = end {
+            crate::crash::bridge_failed(&self.context, err);
+        }
+
+        let context = self.context.clone();
+        match end {
+            Ok(end) => self.send(AdapterEvent::BridgeClosed(context, *end)),
+            Err(err) => self.send(AdapterEvent::BridgeFailed(context, format!("{err:#}"))),
+        }
+    }
+}
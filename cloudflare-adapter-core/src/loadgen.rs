@@ -0,0 +1,212 @@
+//! `loadgen`
+//!
+//! Capacity planning: bridges `--streams` synthetic streams of `--bitrate` each with an
+//! adapter running in-process, the same way [selftest](crate::selftest) bridges one,
+//! and reports what arrived on the relay side after `--duration`. Each stream gets a
+//! line with its frames, the frames dropped on the way (groups skipped or cut short)
+//! and the latency from being written to being read back, then a summary for all of
+//! them. The first group of each stream isn't counted, since most of it was written
+//! before the stream was bridged. Streams are listed with static discovery, so no
+//! registry is involved.
+//!
+//! The adapter runs with the configured limits (`--max-bridges`, `--cf-sessions`,
+//! buffers and backpressure), so the numbers are for this configuration on this host.
+//! Frames are written and read back in this process, so the latencies include no
+//! clock skew, just whatever the endpoints, the adapter and the host add.
+
+use std::time::Duration;
+
+#[cfg(feature = "self-test")]
+use std::time::SystemTime;
+
+#[cfg(feature = "self-test")]
+use anyhow::Context;
+#[cfg(feature = "self-test")]
+use moq_lite::{BroadcastConsumer, Track};
+#[cfg(feature = "self-test")]
+use tokio::task::JoinSet;
+#[cfg(feature = "self-test")]
+use tokio::time::Instant;
+
+#[cfg(feature = "self-test")]
+use crate::registry::StreamInfo;
+#[cfg(feature = "self-test")]
+use crate::selftest::Harness;
+use crate::selftest::Endpoints;
+#[cfg(feature = "self-test")]
+use crate::testing::SyntheticOptions;
+use crate::AdapterConfig;
+
+/// How much load to generate
+#[derive(Clone, Debug)]
+pub struct LoadOptions {
+    pub streams: usize,
+    /// Per stream, in kbit/s
+    pub bitrate: u64,
+    pub duration: Duration,
+}
+
+/// Bridge the synthetic streams for the duration, then report how they did
+#[cfg(feature = "self-test")]
+pub async fn run(config: &AdapterConfig, endpoints: Endpoints, options: LoadOptions) -> anyhow::Result<()> {
+    let pattern = pattern(options.bitrate);
+    let run = rand::random::<u32>();
+    let streams: Vec<_> = (0..options.streams).map(|i| StreamInfo::new(format!("loadgen-{run:08x}-{i}"))).collect();
+    let end = Instant::now() + options.duration;
+
+    tracing::info!(
+        streams = options.streams,
+        bitrate = options.bitrate,
+        frame_size = pattern.frame_size,
+        duration = ?options.duration,
+        ?endpoints,
+        "generating load"
+    );
+    let mut harness = Harness::start(config, endpoints, &streams, &pattern, end).await?;
+
+    // Measure each stream from when it shows up on the relay until the end
+    let mut measuring = JoinSet::new();
+    let announced = async {
+        let mut pending: Vec<_> = streams.iter().map(|stream| stream.stream_id.as_str()).collect();
+        while !pending.is_empty() {
+            let (path, broadcast) = harness.announced().await?;
+            let Some(position) = pending.iter().position(|id| *id == path) else {
+                continue;
+            };
+            pending.swap_remove(position);
+
+            let pattern = pattern.clone();
+            measuring.spawn(async move {
+                let mut stats = StreamStats::default();
+                let res = tokio::time::timeout_at(end, measure(&broadcast, &pattern, &mut stats)).await;
+                if let Ok(Err(err)) = res {
+                    stats.error = Some(format!("{err:#}"));
+                }
+                (path, stats)
+            });
+        }
+        anyhow::Ok(())
+    };
+    match tokio::time::timeout_at(end, announced).await {
+        Ok(res) => res?,
+        Err(_) => tracing::warn!("not every stream was bridged in time"),
+    }
+
+    let mut results = Vec::with_capacity(options.streams);
+    while let Some(res) = measuring.join_next().await {
+        results.push(res.context("measuring task failed")?);
+    }
+    harness.stop().await;
+
+    report(&mut results, options.streams);
+    Ok(())
+}
+
+/// One second groups at 30 fps, with frames as large as `bitrate` needs
+#[cfg(feature = "self-test")]
+fn pattern(bitrate: u64) -> SyntheticOptions {
+    let defaults = SyntheticOptions::default();
+    let per_second = bitrate * 1000 / 8;
+    SyntheticOptions {
+        frames_per_group: 30,
+        frame_interval: Duration::from_secs(1) / 30,
+        frame_size: (per_second / 30) as usize,
+        ..defaults
+    }
+}
+
+/// What arrived of one stream
+#[cfg(feature = "self-test")]
+#[derive(Debug, Default)]
+struct StreamStats {
+    frames: u64,
+    dropped: u64,
+    /// Per frame, from writing it to reading it back
+    latencies: Vec<Duration>,
+    /// Why measuring stopped early
+    error: Option<String>,
+}
+
+/// Read the pattern's frames from `bridged` into `stats`, until it's over or fails
+#[cfg(feature = "self-test")]
+async fn measure(bridged: &BroadcastConsumer, pattern: &SyntheticOptions, stats: &mut StreamStats) -> anyhow::Result<()> {
+    let mut track = bridged.subscribe_track(&Track::new(&pattern.track));
+    let per_group = pattern.frames_per_group as u64;
+    // Most of the first group was written before the bridge started
+    let first = track.next_group().await?.context("track ended")?;
+    let mut last = first.info.sequence;
+
+    while let Some(mut group) = track.next_group().await? {
+        let sequence = group.info.sequence;
+        stats.dropped += sequence.saturating_sub(last + 1) * per_group;
+        last = sequence;
+
+        let mut index = 0;
+        while let Some(frame) = group.read_frame().await? {
+            let written = pattern.check(sequence, index, &frame)?;
+            stats.latencies.push(SystemTime::now().duration_since(written).unwrap_or_default());
+            stats.frames += 1;
+            index += 1;
+        }
+        stats.dropped += per_group.saturating_sub(index as u64);
+    }
+    anyhow::bail!("track ended")
+}
+
+/// Log a line per stream and a summary, slowest streams first
+#[cfg(feature = "self-test")]
+fn report(results: &mut [(String, StreamStats)], streams: usize) {
+    for (_, stats) in results.iter_mut() {
+        stats.latencies.sort();
+    }
+    results.sort_by_key(|(_, stats)| std::cmp::Reverse(percentile(&stats.latencies, 0.99)));
+
+    for (stream_id, stats) in results.iter() {
+        tracing::info!(
+            stream_id,
+            frames = stats.frames,
+            dropped = stats.dropped,
+            p50_ms = millis(percentile(&stats.latencies, 0.5)),
+            p99_ms = millis(percentile(&stats.latencies, 0.99)),
+            max_ms = millis(stats.latencies.last().copied().unwrap_or_default()),
+            error = stats.error,
+            "stream measured"
+        );
+    }
+
+    let mut latencies: Vec<_> = results.iter().flat_map(|(_, stats)| stats.latencies.iter().copied()).collect();
+    latencies.sort();
+    let (frames, dropped) = results.iter().fold((0, 0), |(f, d), (_, stats)| (f + stats.frames, d + stats.dropped));
+    let failed = results.iter().filter(|(_, stats)| stats.error.is_some()).count();
+    tracing::info!(
+        streams,
+        bridged = results.len(),
+        failed,
+        frames,
+        dropped,
+        drop_rate = dropped as f64 / (frames + dropped).max(1) as f64,
+        p50_ms = millis(percentile(&latencies, 0.5)),
+        p99_ms = millis(percentile(&latencies, 0.99)),
+        max_ms = millis(latencies.last().copied().unwrap_or_default()),
+        "load generated"
+    );
+}
+
+/// The `p` quantile of sorted `samples`
+#[cfg(feature = "self-test")]
+fn percentile(samples: &[Duration], p: f64) -> Duration {
+    match samples.len() {
+        0 => Duration::ZERO,
+        len => samples[((len - 1) as f64 * p).round() as usize],
+    }
+}
+
+#[cfg(feature = "self-test")]
+fn millis(duration: Duration) -> f64 {
+    (duration.as_secs_f64() * 10_000.0).round() / 10.0
+}
+
+#[cfg(not(feature = "self-test"))]
+pub async fn run(_config: &AdapterConfig, _endpoints: Endpoints, _options: LoadOptions) -> anyhow::Result<()> {
+    anyhow::bail!("built without the self-test feature")
+}
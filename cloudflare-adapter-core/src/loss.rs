@@ -0,0 +1,122 @@
+//! Media lost across the bridge
+//!
+//! Relay viewers see a stream degrade without anything saying why, so each bridge counts,
+//! per track, what of CloudFlare's media didn't make it to the relay:
+//!
+//! - dropped groups, left out on purpose: for congestion (see [backpressure](crate::backpressure)),
+//!   shedding, pausing, rate limits and the like
+//! - dropped objects, frames cut off by the buffer caps, along with the rest of their group
+//! - skipped groups, gaps in the group sequences CloudFlare delivered, which never got to us
+//! - expired groups, cut short at a frame that took longer than `--delivery-timeout` from
+//!   CloudFlare to the relay, held up by rate limits or slow hooks; the frames they had left
+//!   are counted as expired objects, since they depend on the late one
+//!
+//! Each is a `track_*_total` metric labelled with the stream and upstream track, and the
+//! admin API lists them by track under each bridge's `tracks`.
+
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+use crate::metrics::Counter;
+
+/// The losses of each track of one bridge
+#[derive(Debug, Default)]
+pub struct BridgeLoss {
+    tracks: Mutex<BTreeMap<String, Arc<TrackLoss>>>,
+}
+
+impl BridgeLoss {
+    /// The losses of upstream `track`, shared by every subscription to it
+    pub fn track(&self, stream_id: &str, track: &str) -> Arc<TrackLoss> {
+        let mut tracks = self.tracks.lock().unwrap();
+        let loss = tracks.entry(track.to_string()).or_insert_with(|| {
+            Arc::new(TrackLoss {
+                stream_id: stream_id.to_string(),
+                track: track.to_string(),
+                dropped_groups: AtomicU64::new(0),
+                skipped_groups: AtomicU64::new(0),
+                expired_groups: AtomicU64::new(0),
+                dropped_objects: AtomicU64::new(0),
+                expired_objects: AtomicU64::new(0),
+            })
+        });
+        loss.clone()
+    }
+
+    /// What each track lost so far
+    pub fn tracks(&self) -> Vec<TrackLosses> {
+        let tracks = self.tracks.lock().unwrap();
+        tracks.values().map(|loss| loss.losses()).collect()
+    }
+}
+
+/// What one track lost so far
+#[derive(Clone, Debug, Default)]
+pub struct TrackLosses {
+    /// The upstream track name
+    pub track: String,
+    pub dropped_groups: u64,
+    pub skipped_groups: u64,
+    pub expired_groups: u64,
+    pub dropped_objects: u64,
+    pub expired_objects: u64,
+}
+
+/// Counts the losses of one track
+#[derive(Debug)]
+pub struct TrackLoss {
+    stream_id: String,
+    track: String,
+    dropped_groups: AtomicU64,
+    skipped_groups: AtomicU64,
+    expired_groups: AtomicU64,
+    dropped_objects: AtomicU64,
+    expired_objects: AtomicU64,
+}
+
+impl TrackLoss {
+    /// A group left out on purpose, for `reason`
+    pub fn dropped_group(&self, reason: &str) {
+        self.dropped_groups.fetch_add(1, Ordering::Relaxed);
+        self.counter("track_dropped_groups_total", "Groups of a track left out on purpose", Some(reason)).inc();
+    }
+
+    /// `groups` that never arrived from CloudFlare, from a gap in the sequence
+    pub fn skipped_groups(&self, groups: u64) {
+        self.skipped_groups.fetch_add(groups, Ordering::Relaxed);
+        self.counter("track_skipped_groups_total", "Groups of a track missing from what CloudFlare sent", None)
+            .add(groups);
+    }
+
+    /// Frames of a group cut off by the buffer caps
+    pub fn dropped_objects(&self, objects: u64) {
+        self.dropped_objects.fetch_add(objects, Ordering::Relaxed);
+        self.counter("track_dropped_objects_total", "Frames of a track cut off by the buffer caps", None).add(objects);
+    }
+
+    /// A group cut short by the delivery timeout, with the frames it had left
+    pub fn expired_group(&self, objects: u64) {
+        self.expired_groups.fetch_add(1, Ordering::Relaxed);
+        self.expired_objects.fetch_add(objects, Ordering::Relaxed);
+        self.counter("track_expired_groups_total", "Groups of a track cut short by --delivery-timeout", None).inc();
+        self.counter("track_expired_objects_total", "Frames of a track past --delivery-timeout", None).add(objects);
+    }
+
+    fn losses(&self) -> TrackLosses {
+        TrackLosses {
+            track: self.track.clone(),
+            dropped_groups: self.dropped_groups.load(Ordering::Relaxed),
+            skipped_groups: self.skipped_groups.load(Ordering::Relaxed),
+            expired_groups: self.expired_groups.load(Ordering::Relaxed),
+            dropped_objects: self.dropped_objects.load(Ordering::Relaxed),
+            expired_objects: self.expired_objects.load(Ordering::Relaxed),
+        }
+    }
+
+    fn counter(&self, name: &'static str, help: &'static str, reason: Option<&str>) -> Counter {
+        let mut labels = vec![("stream_id", self.stream_id.as_str()), ("track", self.track.as_str())];
+        labels.extend(reason.map(|reason| ("reason", reason)));
+        Counter::new(name, help, &labels)
+    }
+}
@@ -0,0 +1,1427 @@
+//! Bridge management
+//!
+//! [BridgeManager] runs the whole adapter once its options are parsed: the relay and CF
+//! connections, the registry polls with a bridge per listed stream, the HTTP server and
+//! the watchdog, until told to shut down, when it drains the bridges.
+
+use std::collections::{HashMap, HashSet};
+use std::future::Future;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
+
+use anyhow::Context;
+use bytes::Bytes;
+use moq_lite::{Origin, OriginConsumer, OriginProducer};
+use moq_native::ClientConfig;
+use tokio::sync::{oneshot, watch, Notify, RwLock, Semaphore};
+use tokio::task::{JoinHandle, JoinSet};
+use url::Url;
+
+#[cfg(feature = "http")]
+use crate::admin::Admin;
+use crate::announce::AnnounceBatch;
+use crate::autoscale::Autoscale;
+use crate::breaker::CircuitBreaker;
+use crate::bridge::{BridgeEnd, CloudFlareSource};
+use crate::buffer::BufferBudget;
+use crate::chaos::Chaos;
+use crate::config::AdapterConfig;
+use crate::discovery::StreamDiscovery;
+use crate::duplicate::Suppressed;
+use crate::error::{AdapterError, BridgeError};
+use crate::events::{AdapterEvent, AdapterEvents, EventBus};
+use crate::expiry::{self, Expiry};
+use crate::filter::TrackFilter;
+use crate::handle::BridgeHandle;
+#[cfg(feature = "history")]
+use crate::history::SessionHistory;
+use crate::hook::{CommandHook, FrameHook};
+use crate::inject::Injectors;
+use crate::interceptor::Interceptor;
+#[cfg(feature = "redis")]
+use crate::lease::{Lease, Leases};
+use crate::lifecycle::{BridgeContext, Lifecycle};
+use crate::migrate::Migration;
+#[cfg(feature = "http")]
+use crate::package::Packager;
+use crate::pool::SessionPool;
+use crate::prewarm::Prewarmer;
+#[cfg(feature = "push")]
+use crate::push::Pusher;
+use crate::queue::BridgeQueue;
+use crate::registry::StreamInfo;
+use crate::relay::{Relay, Relays};
+use crate::reload::Reloader;
+use crate::replay::Recorder;
+use crate::resume::ResumePoints;
+use crate::retry::StreamRetries;
+use crate::runner::{Admitted, Bridge};
+use crate::shard::Shards;
+use crate::systemd::Notifier;
+use crate::sink::BridgeSink;
+use crate::soak::{Soak, SoakGuard};
+use crate::statefile::{SavedBridge, StateFile};
+#[cfg(feature = "registry")]
+use crate::stats::StatsReporter;
+use crate::token::BroadcastSessions;
+use crate::watchdog::{Heartbeat, Stuck, Watchdog};
+#[cfg(feature = "webrtc")]
+use crate::webrtc::Gateway;
+use crate::wildcard::PrefixDiscovery;
+use crate::{
+    clock, connect, discovery, e2ee, events, metadata, metrics, migrate, paths, pool, proxy, quic, redirect, shutdown,
+};
+#[cfg(feature = "http")]
+use crate::http;
+#[cfg(feature = "ffmpeg")]
+use crate::srt;
+
+/// How often the status reported to systemd is brought up to date
+const SYSTEMD_STATUS_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Embeds the adapter in an application that already runs tokio
+pub struct Adapter;
+
+impl Adapter {
+    /// Configure an adapter, then [spawn](BridgeManager::spawn) it for an [AdapterHandle]
+    pub fn builder(config: AdapterConfig) -> BridgeManager {
+        BridgeManager::new(config)
+    }
+}
+
+/// Bridges the streams the registry lists from CloudFlare to the relays
+pub struct BridgeManager {
+    config: AdapterConfig,
+    reloader: Option<Reloader>,
+    discovery: Option<Arc<dyn StreamDiscovery>>,
+    sinks: Vec<Arc<dyn BridgeSink>>,
+    interceptors: Vec<Arc<dyn Interceptor>>,
+    lifecycle: Lifecycle,
+    state: Arc<RwLock<BridgeState>>,
+    /// Makes the bridge manager list streams straight away
+    wake: Arc<Notify>,
+    events: Arc<EventBus>,
+    systemd: Option<Arc<Notifier>>,
+}
+
+impl BridgeManager {
+    pub fn new(config: AdapterConfig) -> Self {
+        if let Some(tenant) = &config.tenant {
+            metrics::set_tenant(tenant);
+        }
+        let state = Arc::new(RwLock::new(BridgeState {
+            bridges: HashMap::new(),
+            queue: BridgeQueue::new(config.max_bridges, config.bridge_priority.clone(), config.preempt),
+            idle: HashMap::new(),
+            evicted: HashMap::new(),
+            suppressed: HashMap::new(),
+            retries: StreamRetries::new(config.backoff(), config.max_bridge_attempts),
+            forced: HashSet::new(),
+        }));
+
+        Self {
+            config,
+            reloader: None,
+            discovery: None,
+            sinks: Vec::new(),
+            interceptors: Vec::new(),
+            lifecycle: Lifecycle::default(),
+            state,
+            wake: Arc::new(Notify::new()),
+            events: Arc::default(),
+            systemd: None,
+        }
+    }
+
+    /// Report readiness, status and watchdog keepalives to systemd, see [systemd](crate::systemd)
+    pub fn with_systemd(mut self, notifier: Notifier) -> Self {
+        self.systemd = Some(Arc::new(notifier));
+        self
+    }
+
+    /// Follow the stream rules of a config file as it changes, every `--reload-interval`
+    pub fn with_reloader(mut self, reloader: Reloader) -> Self {
+        self.reloader = Some(reloader);
+        self
+    }
+
+    /// Find the streams to bridge with `discovery`, instead of the one `--discovery` picks
+    pub fn with_discovery(mut self, discovery: Arc<dyn StreamDiscovery>) -> Self {
+        self.discovery = Some(discovery);
+        self
+    }
+
+    /// Also publish every bridged broadcast to `sink`, alongside the relay
+    pub fn with_sink(mut self, sink: Arc<dyn BridgeSink>) -> Self {
+        self.sinks.push(sink);
+        self
+    }
+
+    /// Run every forwarded frame through `interceptor`, after the `--interceptor` ones,
+    /// see [interceptor](crate::interceptor)
+    pub fn with_interceptor(mut self, interceptor: Arc<dyn Interceptor>) -> Self {
+        self.interceptors.push(interceptor);
+        self
+    }
+
+    /// Call `callback` as each bridge starts, see [lifecycle](crate::lifecycle)
+    pub fn on_bridge_start<F: Future<Output = ()> + Send + 'static>(
+        mut self,
+        callback: impl Fn(BridgeContext) -> F + Send + Sync + 'static,
+    ) -> Self {
+        self.lifecycle.on_start(callback);
+        self
+    }
+
+    /// Call `callback` once each bridge forwarded its first frame
+    pub fn on_bridge_active<F: Future<Output = ()> + Send + 'static>(
+        mut self,
+        callback: impl Fn(BridgeContext) -> F + Send + Sync + 'static,
+    ) -> Self {
+        self.lifecycle.on_active(callback);
+        self
+    }
+
+    /// Call `callback` with the error of each bridge that fails
+    pub fn on_bridge_error<F: Future<Output = ()> + Send + 'static>(
+        mut self,
+        callback: impl Fn(BridgeContext, String) -> F + Send + Sync + 'static,
+    ) -> Self {
+        self.lifecycle.on_error(callback);
+        self
+    }
+
+    /// Call `callback` with why each bridge that doesn't fail ended
+    pub fn on_bridge_close<F: Future<Output = ()> + Send + 'static>(
+        mut self,
+        callback: impl Fn(BridgeContext, BridgeEnd) -> F + Send + Sync + 'static,
+    ) -> Self {
+        self.lifecycle.on_close(callback);
+        self
+    }
+
+    /// Follow what the adapter does, see [events](crate::events)
+    pub fn events(&self) -> AdapterEvents {
+        self.events.subscribe()
+    }
+
+    /// The bridges running right now, by stream ID
+    pub async fn bridges(&self) -> Vec<BridgeHandle> {
+        BridgeLookup(self.state.clone()).all().await
+    }
+
+    /// The bridge of `stream_id`, if it's running
+    pub async fn bridge(&self, stream_id: &str) -> Option<BridgeHandle> {
+        BridgeLookup(self.state.clone()).get(stream_id).await
+    }
+
+    /// Bridge `stream_id` straight away, even if it isn't listed, filtered out or held back
+    ///
+    /// Returns false if it's already bridged. Once started it's like any other bridge,
+    /// so it isn't retried if it fails and the stream isn't listed.
+    pub async fn force_bridge(&self, stream_id: &str) -> bool {
+        let mut state = self.state.write().await;
+        if state.bridges.contains_key(stream_id) {
+            return false;
+        }
+
+        state.idle.remove(stream_id);
+        state.evicted.remove(stream_id);
+        state.suppressed.remove(stream_id);
+        state.retries.succeeded(stream_id);
+        state.forced.insert(stream_id.to_string());
+        self.wake.notify_one();
+        true
+    }
+
+    /// Run in the background of the current tokio runtime, until the handle shuts it down
+    pub fn spawn(self) -> AdapterHandle {
+        let manager = Arc::new(self);
+        let (shutdown, stopped) = oneshot::channel();
+        let task = tokio::spawn({
+            let manager = manager.clone();
+            async move {
+                let shutdown = async move {
+                    // Dropping the handle shuts down too
+                    let _ = stopped.await;
+                    Ok(())
+                };
+                manager.run_until(shutdown).await
+            }
+        });
+
+        AdapterHandle { manager, shutdown, task }
+    }
+
+    /// Run until SIGTERM or SIGINT, then drain the bridges
+    pub async fn run(&self) -> Result<(), AdapterError> {
+        self.run_until(async {
+            let signal = shutdown::signal().await.context("failed to listen for signals")?;
+            tracing::info!(signal, "received signal");
+            Ok(())
+        })
+        .await
+    }
+
+    /// Run until `shutdown` resolves, then drain the bridges
+    ///
+    /// An error from `shutdown` is returned straight away, without draining.
+    pub async fn run_until(&self, shutdown: impl Future<Output = anyhow::Result<()>>) -> Result<(), AdapterError> {
+        let config = &self.config;
+
+        tracing::info!(
+            relay_url = %config.relay_url,
+            cloudflare_url = %config.cloudflare_url,
+            upstream = ?config.upstream,
+            discovery = ?config.discovery,
+            registry_url = config.registry_url,
+            poll_interval = config.poll_interval,
+            "Starting CloudFlare adapter"
+        );
+
+        let client = ClientConfig::default().init().map_err(AdapterError::Config)?;
+        let relay_client = quic::client(&client, &config.relay_quic).map_err(AdapterError::Config)?;
+
+        // Origins for broadcasts we'll publish TO your relays, and what they announce
+        let relays = Relays::new(
+            &config.relay_url,
+            &config.relay_targets,
+            config.path_collision,
+            &config.shed_options(),
+            &config.relay_egress_limit,
+        );
+
+        // Origin for broadcasts we receive FROM CloudFlare
+        let from_cloudflare = Arc::new(Origin::produce());
+
+        // Where the streams to bridge come from, and the endpoint the registry pushes them to
+        #[cfg_attr(not(feature = "http"), allow(unused_variables))]
+        let (discovery, webhook) = match &self.discovery {
+            Some(discovery) => (discovery.clone(), None),
+            None => discovery::from_config(config).map_err(AdapterError::Config)?,
+        };
+
+        // Every broadcast CF announces under --bridge-prefix, on top of those listed
+        let prefixes = &config.bridge_prefixes;
+        let discovery = PrefixDiscovery::wrap(discovery, &from_cloudflare.consumer, prefixes, config.namespace_encoding);
+
+        // Faults injected with --chaos, starting with failing lists
+        let chaos = Chaos::new(&config.chaos);
+        let discovery = chaos.discovery(discovery);
+
+        // Faults on a schedule with --soak, reported however we stop
+        let soak = Soak::new(&config.soak, config.soak_duration.map(Duration::from_secs), config.soak_report.clone());
+        let _soak_report = SoakGuard(soak.clone());
+        let discovery = soak.discovery(discovery);
+
+        // Recording what the manager is given, errors and all
+        let discovery: Arc<dyn StreamDiscovery> = match &config.record_registry {
+            Some(path) => Recorder::new(discovery, path).map_err(AdapterError::Config)?,
+            None => discovery,
+        };
+
+        // Streams packaged for the embedded HTTP server
+        #[cfg(feature = "http")]
+        let packager = Packager::new(config.package_options());
+
+        // Supplemental tracks injected into bridged broadcasts
+        let injectors = Injectors::new(config.inject_token.clone());
+
+        // Bridges with tokens of their own publish over sessions of their own
+        let broadcast_sessions = config.token_issuer().map_err(AdapterError::Config)?.map(|issuer| {
+            Arc::new(BroadcastSessions {
+                issuer,
+                client: relay_client.clone(),
+                options: config.relay_connect_options(),
+                proxy: config.relay_proxy.clone(),
+                backoff: config.backoff(),
+            })
+        });
+
+        // The global cap on buffered media, shared by every bridge
+        let buffers = BufferBudget::new(config.buffer_limit.map(|mb| mb << 20));
+
+        // SRT feeds go straight to the relay, independently of CloudFlare
+        #[cfg(feature = "ffmpeg")]
+        for ingest in config.srt_ingest.clone() {
+            tokio::spawn(srt::run_ingest(ingest, config.srt_passphrase.clone(), relays.main.publish.producer.clone()));
+        }
+
+        // CloudFlare sessions, shared by every bridge
+        let cf_sessions = SessionPool::new(config.cf_sessions);
+        let autoscale = Autoscale::new(cf_sessions.clone(), config.max_bridges, config.session_capacity);
+
+        // Connection state for the event stream
+        for relay in relays.all() {
+            let (events, name, up) = (self.events.clone(), relay.name.clone(), relay.up.subscribe());
+            tokio::spawn(async move { events::follow_relay(&events, name, up).await });
+        }
+        let (events, sessions, connected) = (self.events.clone(), cf_sessions.len(), cf_sessions.connected());
+        tokio::spawn(async move { events::follow_cloudflare(&events, sessions, connected).await });
+
+        // The stream rules the bridge manager goes by, updated as the config file changes
+        let rules = match &self.reloader {
+            Some(reloader) => reloader.subscribe(),
+            None => watch::channel(Arc::new(config.clone())).1,
+        };
+
+        // Who bridges which stream, when replicas share them
+        #[cfg(feature = "redis")]
+        let leases = match config.lease_options() {
+            Some(options) => {
+                let leases = Leases::connect(&options).await;
+                Some(leases.map_err(|err| AdapterError::Connect { target: "redis", err })?)
+            }
+            None => None,
+        };
+        #[cfg(not(feature = "redis"))]
+        if config.lease_redis_url.is_some() {
+            let err = anyhow::anyhow!("built without the redis feature, drop --lease-redis-url");
+            return Err(AdapterError::Config(err));
+        }
+
+        #[cfg(feature = "history")]
+        let history = match config.history_options() {
+            Some(options) => Some(SessionHistory::open(&options).await.map_err(AdapterError::Config)?),
+            None => None,
+        };
+        #[cfg(not(feature = "history"))]
+        if config.history_db.is_some() {
+            let err = anyhow::anyhow!("built without the history feature, drop --history-db");
+            return Err(AdapterError::Config(err));
+        }
+
+        // Every loop and bridge reports its progress here
+        let watchdog = Watchdog::new(config.watchdog_timeout.map(Duration::from_secs));
+
+        // Set once bridges have drained on shutdown, to close the sessions
+        let (close, closing) = watch::channel(false);
+
+        // The connections outlive the rest of the service while we drain
+        let relay = run_relay_connections(
+            relay_client.clone(),
+            config,
+            relays.clone(),
+            closing.clone(),
+            watchdog.clone(),
+        );
+        let cloudflare = run_cloudflare_connections(
+            quic::client(&client, &config.cf_quic).map_err(AdapterError::Config)?,
+            config,
+            from_cloudflare.clone(),
+            cf_sessions.clone(),
+            closing,
+            watchdog.clone(),
+        );
+        tokio::pin!(relay, cloudflare);
+
+        // Tasks that stop with us, however we return
+        let mut background = JoinSet::new();
+        if let Some(notifier) = self.systemd.clone() {
+            let (up, connected) = (relays.main.up.subscribe(), cf_sessions.connected());
+            let (sessions, state) = (cf_sessions.len(), self.state.clone());
+            background.spawn(async move { notify_systemd(&notifier, up, connected, sessions, &state).await });
+        }
+        if let Some(server) = config.ntp_server.clone() {
+            background.spawn(clock::sync(server, Duration::from_secs(config.ntp_interval.max(1))));
+        }
+        let (killer, sessions) = (chaos.clone(), cf_sessions.clone());
+        background.spawn(async move { killer.kill_sessions(&sessions).await });
+        let (soaking, sessions, events) = (soak.clone(), cf_sessions.clone(), self.events());
+        background.spawn(async move { soaking.run(&sessions, events).await });
+        // Pushing stops before the pushed metrics are deleted, so it's on its own
+        #[cfg(feature = "push")]
+        let pusher = config.push_options().map(|options| Arc::new(Pusher::new(&options)));
+        #[cfg(feature = "push")]
+        let mut pushing = JoinSet::new();
+        #[cfg(feature = "push")]
+        if let Some(pusher) = pusher.clone() {
+            pushing.spawn(async move { pusher.run().await });
+        }
+        #[cfg(not(feature = "sentry"))]
+        if config.sentry_dsn.is_some() {
+            let err = anyhow::anyhow!("built without the sentry feature, drop --sentry-dsn");
+            return Err(AdapterError::Config(err));
+        }
+        #[cfg(feature = "registry")]
+        if let Some(options) = config.stats_options() {
+            let (reporter, events) = (StatsReporter::new(options, BridgeLookup(self.state.clone())), self.events());
+            background.spawn(async move { reporter.run(events).await });
+        }
+        #[cfg(not(feature = "registry"))]
+        if config.stats_url.is_some() {
+            let err = anyhow::anyhow!("built without the registry feature, drop --stats-url");
+            return Err(AdapterError::Config(err));
+        }
+        #[cfg(not(feature = "push"))]
+        if config.metrics_push_url.is_some() {
+            let err = anyhow::anyhow!("built without the push feature, drop --metrics-push-url");
+            return Err(AdapterError::Config(err));
+        }
+        if config.e2ee {
+            if let Some(option) = e2ee::conflicts(config).first() {
+                let err = anyhow::anyhow!("--{option} would change or read encrypted frames, drop it or --e2ee");
+                return Err(AdapterError::Config(err));
+            }
+            if !self.interceptors.is_empty() {
+                let err = anyhow::anyhow!("interceptors would change encrypted frames, drop them or --e2ee");
+                return Err(AdapterError::Config(err));
+            }
+        }
+
+        tokio::select! {
+            res = &mut relay => return res.map_err(|err| AdapterError::Connect { target: "relay", err }),
+            res = &mut cloudflare => return res.map_err(|err| AdapterError::Connect { target: "cloudflare", err }),
+            res = run_bridge_manager(
+                config,
+                self.state.clone(),
+                cf_sessions.clone(),
+                from_cloudflare.consumer.clone(),
+                rules,
+                discovery.clone(),
+                BridgeServices {
+                    #[cfg(feature = "http")]
+                    packager: packager.clone(),
+                    injectors: injectors.clone(),
+                    buffers: buffers.clone(),
+                    shards: Shards::new(config.bridge_runtimes).map_err(AdapterError::Config)?,
+                    resume: ResumePoints::new(),
+                    watchdog: watchdog.clone(),
+                    relays: relays.clone(),
+                    broadcast_sessions: broadcast_sessions.clone(),
+                    sinks: self.sinks.clone(),
+                    interceptors: config
+                        .interceptors
+                        .iter()
+                        .map(|builtin| Arc::new(builtin.clone()) as Arc<dyn Interceptor>)
+                        .chain(self.interceptors.iter().cloned())
+                        .collect(),
+                    lifecycle: Arc::new(self.lifecycle.clone()),
+                    wake: self.wake.clone(),
+                    events: self.events.clone(),
+                    #[cfg(feature = "redis")]
+                    leases: leases.clone(),
+                    state_file: config.state_file.clone().map(|path| Arc::new(StateFile::new(path))),
+                    #[cfg(feature = "history")]
+                    history: history.clone(),
+                    chaos: chaos.clone(),
+                    autoscale: autoscale.clone(),
+                }
+            ) => return res,
+            res = async {
+                match config.http_listen {
+                    #[cfg(feature = "http")]
+                    Some(listen) => {
+                        let injectors = config.inject.then(|| injectors.clone());
+                        let admin = config.admin.then(|| {
+                            let bridges = BridgeLookup(self.state.clone());
+                            let (up, connected) = (relays.main.up.subscribe(), cf_sessions.connected());
+                            let admin = Admin::new(bridges, config.admin_token.clone(), up, connected);
+                            #[cfg(feature = "history")]
+                            let admin = admin.with_history(history.clone());
+                            Arc::new(admin)
+                        });
+                        let (webhook, autoscale) = (webhook.clone(), autoscale.clone());
+                        #[cfg(feature = "webrtc")]
+                        let gateway = Gateway::new(config, BridgeLookup(self.state.clone()), relays.main.clone());
+                        http::run_http_server(
+                            listen,
+                            packager.clone(),
+                            injectors,
+                            webhook,
+                            admin,
+                            autoscale,
+                            #[cfg(feature = "webrtc")]
+                            gateway,
+                        )
+                        .await
+                    }
+                    #[cfg(not(feature = "http"))]
+                    Some(_) => anyhow::bail!("built without the http feature, drop --http-listen"),
+                    None => std::future::pending().await,
+                }
+            } => return res.map_err(AdapterError::Http),
+            res = async {
+                match (&self.reloader, config.reload_interval) {
+                    (Some(reloader), Some(interval)) => reloader.run(Duration::from_secs(interval)).await,
+                    _ => std::future::pending().await,
+                }
+            } => return res.map_err(AdapterError::Reload),
+            res = watchdog.run() => return res.map_err(AdapterError::Watchdog),
+            res = require_connections(
+                config.require_connections_on_start.map(Duration::from_secs),
+                relays.main.up.subscribe(),
+                cf_sessions.connected(),
+            ) => return res,
+            _ = soak.finished() => tracing::info!("soak finished"),
+            res = shutdown => res.map_err(AdapterError::Shutdown)?,
+        }
+
+        // The bridge manager is gone, so no new bridges start from here
+        let timeout = Duration::from_secs(config.drain_timeout);
+        let active = self.state.read().await.bridges.len();
+        tracing::info!(active, ?timeout, "shutting down, draining bridges");
+        if let Some(notifier) = &self.systemd {
+            notifier.notify(&format!("STOPPING=1\nSTATUS=draining {active} bridges"));
+        }
+
+        let drain = async {
+            drain_bridges(&self.state, &buffers).await;
+            close.send_replace(true);
+        };
+        match tokio::time::timeout(timeout, async { tokio::join!(drain, &mut relay, &mut cloudflare) }).await {
+            Ok(((), relay, cloudflare)) => {
+                relay.map_err(|err| AdapterError::Connect { target: "relay", err })?;
+                cloudflare.map_err(|err| AdapterError::Connect { target: "cloudflare", err })?;
+                #[cfg(feature = "push")]
+                if let Some(pusher) = &pusher {
+                    pushing.shutdown().await;
+                    pusher.delete().await;
+                }
+                tracing::info!("drained, exiting");
+            }
+            Err(_) => {
+                let active = self.state.read().await.bridges.len();
+                tracing::warn!(active, buffered = buffers.used(), "drain timed out, exiting anyway");
+                return Err(AdapterError::DrainTimeout { active });
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Finds running bridges, for the admin API and stats reports
+#[derive(Clone)]
+pub(crate) struct BridgeLookup(Arc<RwLock<BridgeState>>);
+
+impl BridgeLookup {
+    /// By stream ID
+    pub(crate) async fn all(&self) -> Vec<BridgeHandle> {
+        let mut bridges: Vec<_> = self.0.read().await.bridges.values().cloned().collect();
+        bridges.sort_by(|a, b| a.stream_id().cmp(b.stream_id()));
+        bridges
+    }
+
+    pub(crate) async fn get(&self, stream_id: &str) -> Option<BridgeHandle> {
+        self.0.read().await.bridges.get(stream_id).cloned()
+    }
+}
+
+/// Controls an adapter running in the background, see [BridgeManager::spawn]
+pub struct AdapterHandle {
+    manager: Arc<BridgeManager>,
+    shutdown: oneshot::Sender<()>,
+    task: JoinHandle<Result<(), AdapterError>>,
+}
+
+impl AdapterHandle {
+    /// See [BridgeManager::bridges]
+    pub async fn bridges(&self) -> Vec<BridgeHandle> {
+        self.manager.bridges().await
+    }
+
+    /// See [BridgeManager::bridge]
+    pub async fn bridge(&self, stream_id: &str) -> Option<BridgeHandle> {
+        self.manager.bridge(stream_id).await
+    }
+
+    /// See [BridgeManager::events]
+    pub fn events(&self) -> AdapterEvents {
+        self.manager.events()
+    }
+
+    /// See [BridgeManager::force_bridge]
+    pub async fn force_bridge(&self, stream_id: &str) -> bool {
+        self.manager.force_bridge(stream_id).await
+    }
+
+    /// Whether the adapter stopped by itself, because a connection or the watchdog failed
+    pub fn is_finished(&self) -> bool {
+        self.task.is_finished()
+    }
+
+    /// Drain the bridges and stop, returning why the adapter failed if it did
+    pub async fn shutdown(self) -> Result<(), AdapterError> {
+        let _ = self.shutdown.send(());
+        self.task.await.map_err(|err| AdapterError::Task(err.to_string()))?
+    }
+}
+
+/// Services shared by the bridge manager and every bridge
+#[derive(Clone)]
+pub(crate) struct BridgeServices {
+    #[cfg(feature = "http")]
+    pub(crate) packager: Arc<Packager>,
+    pub(crate) injectors: Arc<Injectors>,
+    pub(crate) buffers: Arc<BufferBudget>,
+    pub(crate) shards: Arc<Shards>,
+    pub(crate) resume: Arc<ResumePoints>,
+    pub(crate) watchdog: Arc<Watchdog>,
+    pub(crate) relays: Arc<Relays>,
+    /// With `--relay-token-key` or `--relay-token-url`
+    pub(crate) broadcast_sessions: Option<Arc<BroadcastSessions>>,
+    pub(crate) sinks: Vec<Arc<dyn BridgeSink>>,
+    /// The `--interceptor` ones, then those added in code
+    pub(crate) interceptors: Arc<[Arc<dyn Interceptor>]>,
+    pub(crate) lifecycle: Arc<Lifecycle>,
+    pub(crate) wake: Arc<Notify>,
+    pub(crate) events: Arc<EventBus>,
+    /// Shared with other replicas, with `--lease-redis-url`
+    #[cfg(feature = "redis")]
+    pub(crate) leases: Option<Arc<Leases>>,
+    pub(crate) state_file: Option<Arc<StateFile>>,
+    /// With `--history-db`
+    #[cfg(feature = "history")]
+    pub(crate) history: Option<Arc<SessionHistory>>,
+    pub(crate) chaos: Arc<Chaos>,
+    pub(crate) autoscale: Arc<Autoscale>,
+}
+
+/// Tracks which streams we're currently bridging
+struct BridgeState {
+    /// From admission until the bridge ends
+    bridges: HashMap<String, BridgeHandle>,
+    queue: BridgeQueue,
+    /// Streams torn down for being idle, and when they may be bridged again
+    idle: HashMap<String, Instant>,
+    /// Streams evicted for their error rate, and when they may be bridged again
+    evicted: HashMap<String, Instant>,
+    /// Streams stopped for another broadcast at their path, until it's gone
+    suppressed: HashMap<String, Suppressed>,
+    /// Streams whose bridges failed, and when they may be retried
+    retries: StreamRetries,
+    /// Streams to bridge whether listed or not, until they start, see [BridgeManager::force_bridge]
+    forced: HashSet<String>,
+}
+
+/// What a stopped bridge leaves behind in the bridge state, depending on how it ended
+struct Aftermath {
+    /// How long a stream torn down for being idle isn't bridged again
+    idle_timeout: Option<Duration>,
+    /// How long an evicted stream isn't bridged again
+    cooldown: Option<Duration>,
+    /// Holds the stream back while another broadcast is at its path
+    suppressed: Suppressed,
+}
+
+impl BridgeState {
+    /// Forget the bridge behind `handle`, which ended with `end`, and hold its stream back if it should be
+    fn finished(&mut self, handle: &BridgeHandle, end: Result<BridgeEnd, BridgeError>, aftermath: Aftermath) {
+        let stream_id = handle.stream_id().to_string();
+        self.bridges.remove(&stream_id);
+        metrics::remove_stream(&stream_id);
+        handle.closed(end.as_ref().copied().map_err(|err| format!("{err:#}")));
+        match &end {
+            Ok(_) => self.retries.succeeded(&stream_id),
+            Err(err) => self.retries.failed(&stream_id, err),
+        }
+        match (end, aftermath.idle_timeout, aftermath.cooldown) {
+            (Ok(BridgeEnd::Idle), Some(timeout), _) => {
+                self.idle.insert(stream_id, Instant::now() + timeout);
+            }
+            (Ok(BridgeEnd::Evicted), _, Some(cooldown)) => {
+                self.evicted.insert(stream_id, Instant::now() + cooldown);
+            }
+            (Ok(BridgeEnd::Duplicate), _, _) => {
+                self.suppressed.insert(stream_id, aftermath.suppressed);
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Stop every bridge, then wait for what they forwarded to reach the relay
+///
+/// Each bridge unpublishes its broadcast as it stops. Groups already being forwarded
+/// are still written to the end and released once the relay session is done with them.
+async fn drain_bridges(bridge_state: &RwLock<BridgeState>, buffers: &BufferBudget) {
+    let bridges: Vec<BridgeHandle> = bridge_state.read().await.bridges.values().cloned().collect();
+    for bridge in &bridges {
+        bridge.end(BridgeEnd::Shutdown);
+    }
+
+    // Each is closed as it's removed from the state, with the bridge manager gone no more are added
+    for bridge in &bridges {
+        let _ = bridge.await_closed().await;
+    }
+    buffers.drained().await;
+}
+
+/// Tell systemd we're ready once the main relay and a CF session are up, then keep our
+/// status and its watchdog current
+///
+/// Runs until dropped. Keepalives come from a task of their own, so they stop if the
+/// runtime wedges.
+async fn notify_systemd(
+    notifier: &Notifier,
+    relay_up: watch::Receiver<bool>,
+    connected: watch::Receiver<usize>,
+    sessions: usize,
+    state: &RwLock<BridgeState>,
+) {
+    let period = match notifier.watchdog() {
+        Some(timeout) => (timeout / 2).min(SYSTEMD_STATUS_INTERVAL),
+        None => SYSTEMD_STATUS_INTERVAL,
+    };
+    let mut ticks = tokio::time::interval(period);
+    let mut ready = false;
+    let mut reported = String::new();
+
+    loop {
+        ticks.tick().await;
+
+        let (up, connected) = (*relay_up.borrow(), *connected.borrow());
+        if !ready && up && connected > 0 {
+            tracing::info!("connected, notifying systemd");
+            notifier.notify("READY=1");
+            ready = true;
+        }
+
+        let bridges = state.read().await.bridges.len();
+        let relay = if up { "up" } else { "down" };
+        let status = format!("relay {relay}, {connected}/{sessions} cloudflare sessions, {bridges} bridges");
+        if status != reported {
+            notifier.notify(&format!("STATUS={status}"));
+            reported = status;
+        }
+
+        if notifier.watchdog().is_some() {
+            notifier.notify("WATCHDOG=1");
+        }
+    }
+}
+
+/// Fail if the main relay and a CF session aren't both connected within `timeout`
+async fn require_connections(
+    timeout: Option<Duration>,
+    mut relay_up: watch::Receiver<bool>,
+    mut connected: watch::Receiver<usize>,
+) -> Result<(), AdapterError> {
+    let Some(timeout) = timeout else {
+        return std::future::pending().await;
+    };
+
+    let both = async {
+        let _ = relay_up.wait_for(|up| *up).await;
+        let _ = connected.wait_for(|n| *n > 0).await;
+    };
+    if tokio::time::timeout(timeout, both).await.is_ok() {
+        tracing::debug!("connected on start");
+        return std::future::pending().await;
+    }
+
+    let target = if *relay_up.borrow() { "cloudflare" } else { "relay" };
+    let err = anyhow::anyhow!("not connected within {timeout:?} of starting");
+    Err(AdapterError::Connect { target, err })
+}
+
+/// Keep every relay we publish to connected
+async fn run_relay_connections(
+    client: moq_native::Client,
+    config: &AdapterConfig,
+    relays: Arc<Relays>,
+    closing: watch::Receiver<bool>,
+    watchdog: Arc<Watchdog>,
+) -> anyhow::Result<()> {
+    let mut connections = tokio::task::JoinSet::new();
+    for relay in relays.all() {
+        let (client, config, relay, closing) = (client.clone(), config.clone(), relay.clone(), closing.clone());
+        let heartbeat = match &relay.name {
+            Some(name) => watchdog.register(format!("relay connection {name}"), Stuck::Exit),
+            None => watchdog.register("relay connection", Stuck::Exit),
+        };
+        connections.spawn(async move { run_relay_connection(client, &config, relay, closing, heartbeat).await });
+    }
+
+    // Each connection only returns early on error; otherwise they all close on shutdown
+    while let Some(res) = connections.join_next().await {
+        res??;
+    }
+    Ok(())
+}
+
+/// Connect to YOUR relay as a cluster node
+/// Publishes CF streams into your relay's `secondary` origin
+async fn run_relay_connection(
+    client: moq_native::Client,
+    config: &AdapterConfig,
+    relay: Arc<Relay>,
+    mut closing: watch::Receiver<bool>,
+    heartbeat: Heartbeat,
+) -> anyhow::Result<()> {
+    let url = match &config.relay_token {
+        Some(token) => Url::parse(&format!("{}/?jwt={}", relay.url, token))?,
+        None => Url::parse(&relay.url)?,
+    };
+    let (shedder, relay_up) = (&relay.shedder, &relay.up);
+    let name = relay.name.as_deref().unwrap_or("main");
+    let options = config.relay_connect_options();
+    // A proxy's connections go out from the proxy, whatever our own network does
+    let migration = match config.relay_proxy {
+        Some(_) => None,
+        None => Migration::new("relay", &client, &config.relay_quic, config.network_watch),
+    };
+
+    let mut backoff = config.backoff().start();
+    loop {
+        heartbeat.beat();
+        tracing::info!(%url, relay = name, "connecting to earthseed relay");
+
+        // We publish TO the relay (CF streams we're bridging)
+        // We only take announcements FROM it (we get streams from CF directly)
+        let publish = Some(relay.publish.consumer.consume());
+        let subscribe = Some(relay.announced.clone());
+
+        let connected = match proxy::client(&client, config.relay_proxy.as_ref()).await {
+            Ok(client) => connect::connect(&client, url.clone(), &options, publish, subscribe).await,
+            Err(err) => Err(err),
+        };
+        match connected {
+            Ok(connection) => {
+                backoff.reset();
+                tracing::info!(relay = name, "connected to relay");
+                relay_up.send_replace(true);
+
+                // Watch the connection for congestion while it's up
+                let monitor = async {
+                    match connection.quic.clone() {
+                        Some(quic) => shedder.monitor(quic).await,
+                        None => std::future::pending().await,
+                    }
+                };
+                // And move it to a new path if the network changes
+                let following = migrate::follow(migration.as_ref(), connection.quic.as_ref());
+
+                let closed = heartbeat
+                    .pulse(async {
+                        tokio::select! {
+                            _ = connection.session.closed() => false,
+                            _ = monitor => false,
+                            _ = following => false,
+                            _ = shutdown::closing(&mut closing) => true,
+                        }
+                    })
+                    .await;
+                relay_up.send_replace(false);
+
+                if closed {
+                    connection.session.close(moq_lite::Error::Cancel);
+                    tracing::info!(relay = name, "closed relay connection");
+                    return Ok(());
+                }
+                tracing::warn!(relay = name, "relay connection closed");
+            }
+            Err(err) => {
+                tracing::error!(%err, relay = name, "failed to connect to relay");
+            }
+        }
+
+        let closed = heartbeat
+            .pulse(async {
+                tokio::select! {
+                    _ = backoff.wait() => false,
+                    _ = shutdown::closing(&mut closing) => true,
+                }
+            })
+            .await;
+        if closed {
+            return Ok(());
+        }
+    }
+}
+
+/// Keep every session of the pool connected to CloudFlare
+async fn run_cloudflare_connections(
+    client: moq_native::Client,
+    config: &AdapterConfig,
+    from_cloudflare: Arc<moq_lite::Produce<OriginProducer, OriginConsumer>>,
+    cf_sessions: Arc<SessionPool>,
+    closing: watch::Receiver<bool>,
+    watchdog: Arc<Watchdog>,
+) -> anyhow::Result<()> {
+    let mut connections = tokio::task::JoinSet::new();
+    for index in 0..cf_sessions.len() {
+        let (client, config) = (client.clone(), config.clone());
+        let (from_cloudflare, cf_sessions) = (from_cloudflare.clone(), cf_sessions.clone());
+        let (closing, watchdog) = (closing.clone(), watchdog.clone());
+        connections.spawn(async move {
+            run_cloudflare_connection(client, &config, from_cloudflare, cf_sessions, index, closing, watchdog).await
+        });
+    }
+
+    // Each connection only returns early on error; otherwise they all close on shutdown
+    while let Some(res) = connections.join_next().await {
+        res??;
+    }
+    Ok(())
+}
+
+/// Connect one session of the pool to CloudFlare as a subscriber
+/// Stores the session so bridge_stream can call announce_remote()
+async fn run_cloudflare_connection(
+    client: moq_native::Client,
+    config: &AdapterConfig,
+    from_cloudflare: Arc<moq_lite::Produce<OriginProducer, OriginConsumer>>,
+    cf_sessions: Arc<SessionPool>,
+    index: usize,
+    mut closing: watch::Receiver<bool>,
+    watchdog: Arc<Watchdog>,
+) -> anyhow::Result<()> {
+    let url = Url::parse(&config.cloudflare_url)?;
+    let heartbeat = watchdog.register(format!("cloudflare session {index}"), Stuck::Exit);
+    let options = config.cf_connect_options();
+    let migration = match config.cf_proxy {
+        Some(_) => None,
+        None => Migration::new("cloudflare", &client, &config.cf_quic, config.network_watch),
+    };
+
+    let max_redirects = if config.quirks().redirects { config.cf_max_redirects } else { 0 };
+    let mut backoff = config.backoff().start();
+    loop {
+        heartbeat.beat();
+        tracing::info!(%url, session = index, "connecting to cloudflare");
+
+        // We subscribe FROM CloudFlare
+        // We don't publish TO it (Safari streams go via your relay)
+        let publish: Option<OriginConsumer> = None;
+        let subscribe = Some(from_cloudflare.producer.clone());
+
+        let connected = match proxy::client(&client, config.cf_proxy.as_ref()).await {
+            Ok(client) => redirect::connect(&client, &url, &options, publish, subscribe, max_redirects).await,
+            Err(err) => Err(err),
+        };
+        match connected {
+            Ok(connection) => {
+                backoff.reset();
+                tracing::info!(session = index, "connected to cloudflare");
+
+                // Share the session with bridges (for announce_remote()) until it closes, moving it
+                // to a new path if the network changes
+                let following = migrate::follow(migration.as_ref(), connection.quic.as_ref());
+                let serving = cf_sessions.serve(index, connection.session, closing.clone());
+                let closed = heartbeat
+                    .pulse(async {
+                        tokio::select! {
+                            closed = serving => closed,
+                            _ = following => false,
+                        }
+                    })
+                    .await;
+                if closed {
+                    tracing::info!(session = index, "closed cloudflare connection");
+                    return Ok(());
+                }
+                tracing::warn!(session = index, "cloudflare connection closed");
+            }
+            Err(err) => {
+                tracing::error!(%err, session = index, "failed to connect to cloudflare");
+            }
+        }
+
+        let closed = heartbeat
+            .pulse(async {
+                tokio::select! {
+                    _ = backoff.wait() => false,
+                    _ = shutdown::closing(&mut closing) => true,
+                }
+            })
+            .await;
+        if closed {
+            return Ok(());
+        }
+    }
+}
+
+/// Polls your registry for CF streams and bridges them
+async fn run_bridge_manager(
+    config: &AdapterConfig,
+    bridge_state: Arc<RwLock<BridgeState>>,
+    cf_sessions: Arc<SessionPool>,
+    from_cloudflare: OriginConsumer,
+    mut rules: watch::Receiver<Arc<AdapterConfig>>,
+    discovery: Arc<dyn StreamDiscovery>,
+    services: BridgeServices,
+) -> Result<(), AdapterError> {
+    let hook: Option<Arc<dyn FrameHook>> = match &config.transform_command {
+        Some(command) => Some(CommandHook::new(command.clone())),
+        None => None,
+    };
+    let mut connected = cf_sessions.connected();
+    let announce_limit = Arc::new(Semaphore::new(config.announce_concurrency.max(1)));
+
+    // Streams whose namespace or path can't be built or claimed, so we only complain once
+    let mut unbridgeable = HashSet::new();
+
+    // What each running bridge started with, and whether the rules changed since
+    let mut running: HashMap<String, Running> = HashMap::new();
+    let mut reconfigured = false;
+
+    // The bridges the last run saved, set up again before the first poll
+    let mut restored = match &services.state_file {
+        Some(state_file) => restore(state_file, &services.resume).await,
+        None => None,
+    };
+
+    let mut prewarmer =
+        Prewarmer::new(cf_sessions.clone(), from_cloudflare.clone(), announce_limit.clone(), services.wake.clone());
+
+    let mut backoff = config.backoff().start();
+    let mut breaker = CircuitBreaker::new(config.breaker_options());
+    let heartbeat = services.watchdog.register("bridge manager", Stuck::Exit);
+    loop {
+        heartbeat.beat();
+        reconfigured |= rules.has_changed().unwrap_or(false);
+        let current = rules.borrow_and_update().clone();
+        let config = &*current;
+
+        let mut delay = Duration::from_secs(config.poll_interval);
+        let listed = match restored.take() {
+            Some(streams) => {
+                // Bridges need a CF session, so give one a poll interval to connect, then poll right after
+                let _ = tokio::time::timeout(delay, connected.wait_for(|n| *n > 0)).await;
+                tracing::info!(count = streams.len(), "restoring bridges from the state file");
+                delay = Duration::ZERO;
+                Ok(streams)
+            }
+            None => {
+                let listed = discovery.list().await;
+                services.events.publish(AdapterEvent::Polled(match &listed {
+                    Ok(streams) => Ok(streams.iter().map(|s| s.stream_id.clone()).collect()),
+                    Err(err) => Err(format!("{err:#}")),
+                }));
+                listed
+            }
+        };
+        match listed {
+            Ok(mut streams) => {
+                let listed_at = SystemTime::now();
+                backoff.reset();
+                breaker.success();
+
+                // Streams left to other instances are treated as unlisted, so they never get queued
+                streams.retain(|s| config.bridges(&s.stream_id));
+                for stream_id in bridge_state.read().await.forced.iter().chain(&config.prewarm) {
+                    if !streams.iter().any(|s| &s.stream_id == stream_id) {
+                        streams.push(StreamInfo::new(stream_id.clone()));
+                    }
+                }
+
+                // Streams another replica holds the lease on are left to it the same way
+                #[cfg(feature = "redis")]
+                if let Some(leases) = &services.leases {
+                    match leases.held_elsewhere(streams.iter().map(|s| s.stream_id.as_str())).await {
+                        Ok(held) => streams.retain(|s| !held.contains(&s.stream_id)),
+                        Err(err) => {
+                            tracing::warn!(err = format!("{err:#}"), "can't check leases, not bridging new streams");
+                            streams.retain(|s| running.contains_key(&s.stream_id));
+                        }
+                    }
+                }
+
+                // Entries move the deadlines of their bridges, and those past it aren't bridged again
+                for stream in &streams {
+                    if let Some(bridge) = running.get(&stream.stream_id) {
+                        bridge.expiry.refresh(expiry::deadline(stream, listed_at));
+                    }
+                }
+                streams.retain(|s| !expiry::expired(s, listed_at));
+
+                let mut plans = HashMap::new();
+                for stream in &streams {
+                    match plan(config, &services.relays, stream) {
+                        Ok(plan) => {
+                            plans.insert(stream.stream_id.clone(), plan);
+                        }
+                        Err(err) if unbridgeable.insert(stream.stream_id.clone()) => {
+                            tracing::warn!(err = format!("{err:#}"), stream_id = %stream.stream_id, "not bridging");
+                        }
+                        Err(_) => {}
+                    }
+                }
+                unbridgeable.retain(|stream_id| streams.iter().any(|s| &s.stream_id == stream_id));
+
+                // Streams about to go live are only announced until they are, then bridged like the rest
+                let warming: Vec<_> = {
+                    let state_guard = bridge_state.read().await;
+                    streams
+                        .iter()
+                        .filter(|s| s.starting_soon || config.prewarm.contains(&s.stream_id))
+                        .filter(|s| !state_guard.bridges.contains_key(&s.stream_id))
+                        .filter(|s| !state_guard.forced.contains(&s.stream_id))
+                        .filter_map(|s| Some((s.stream_id.clone(), plans.get(&s.stream_id)?.namespace.clone())))
+                        .collect()
+                };
+                prewarmer.sync(warming, config.announce_options());
+                streams.retain(|s| !prewarmer.holds(&s.stream_id));
+
+                // Bridges that don't follow reloaded rules stop, to be bridged again under them
+                if std::mem::take(&mut reconfigured) && config.reload_teardown {
+                    let state_guard = bridge_state.read().await;
+                    for (stream_id, bridge) in &running {
+                        let conforms = config.bridges(stream_id)
+                            && plans
+                                .get(stream_id)
+                                .is_none_or(|plan| plan.path == bridge.path && plan.filter == bridge.filter);
+                        if conforms {
+                            continue;
+                        }
+                        if state_guard.bridges.get(stream_id).is_some_and(|b| b.end(BridgeEnd::Reconfigured)) {
+                            tracing::info!(stream_id = %stream_id, "rules changed, restarting bridge");
+                        }
+                    }
+                }
+
+                // Registry metadata that changed goes out to the running bridges
+                for stream in &streams {
+                    let Some(bridge) = running.get_mut(&stream.stream_id) else {
+                        continue;
+                    };
+                    let metadata = metadata::frame(stream, &config.stream_metadata);
+                    if metadata != bridge.metadata {
+                        if let (Some(frame), Some(injector)) = (&metadata, services.injectors.get(&stream.stream_id)) {
+                            metadata::publish(&injector, frame.clone());
+                        }
+                        bridge.metadata = metadata;
+                    }
+                }
+
+                // Queue new streams, then start as many as the bridge limit allows
+                let ready = {
+                    let mut state_guard = bridge_state.write().await;
+                    let BridgeState { bridges, queue, idle, evicted, suppressed, retries, forced } = &mut *state_guard;
+                    idle.retain(|_, until| *until > Instant::now());
+                    evicted.retain(|_, until| *until > Instant::now());
+                    suppressed.retain(|_, suppressed| suppressed.held());
+                    running.retain(|stream_id, _| bridges.contains_key(stream_id));
+                    retries.retain_listed(&streams.iter().map(|s| s.stream_id.as_str()).collect());
+                    let listed = streams
+                        .iter()
+                        .filter(|s| plans.contains_key(&s.stream_id))
+                        .filter(|s| !idle.contains_key(&s.stream_id) && !evicted.contains_key(&s.stream_id))
+                        .filter(|s| !suppressed.contains_key(&s.stream_id))
+                        .filter(|s| retries.ready(&s.stream_id))
+                        .map(|s| (s.stream_id.as_str(), s.priority));
+                    queue.offer(listed, |stream_id| bridges.contains_key(stream_id));
+
+                    // Bridges already being stopped can't make room twice
+                    let preemptible =
+                        bridges.values().filter(|b| !b.stopping()).map(|b| b.stream_id().to_string()).collect();
+                    let admission = queue.take_ready(bridges.len(), &preemptible);
+                    for stream_id in &admission.preempt {
+                        if let Some(bridge) = bridges.get(stream_id) {
+                            bridge.end(BridgeEnd::Preempted);
+                        }
+                    }
+
+                    services.autoscale.update(queue.len(), bridges.len() + admission.start.len());
+                    forced.retain(|stream_id| plans.contains_key(stream_id) && !admission.start.contains(stream_id));
+                    admission
+                        .start
+                        .into_iter()
+                        .map(|stream_id| {
+                            let (handle, stopped) = BridgeHandle::new(&stream_id);
+                            bridges.insert(stream_id.clone(), handle.clone());
+                            (stream_id, handle, stopped)
+                        })
+                        .collect::<Vec<_>>()
+                };
+
+                let batch = AnnounceBatch::new(ready.len());
+                for (stream_id, handle, stopped) in ready {
+                    // Another replica may have taken the stream since we listed it
+                    #[cfg(feature = "redis")]
+                    let lease = match lease(&services.leases, &stream_id).await {
+                        Ok(lease) => lease,
+                        Err(reason) => {
+                            bridge_state.write().await.bridges.remove(&stream_id);
+                            handle.closed(Err(reason));
+                            continue;
+                        }
+                    };
+                    tracing::info!(stream_id = %stream_id, "bridging new CF stream");
+
+                    // Queued streams had a plan when they were offered, but the path may have been published since
+                    let claimed = match plans.remove(&stream_id) {
+                        Some(plan) => match plan.relay.claims.claim(&stream_id, &plan.path) {
+                            Ok(claim) => Some((plan, claim)),
+                            Err(err) => {
+                                if unbridgeable.insert(stream_id.clone()) {
+                                    tracing::warn!(%err, stream_id = %stream_id, "path collision, not bridging");
+                                }
+                                None
+                            }
+                        },
+                        None => None,
+                    };
+                    let Some((StreamPlan { namespace, relay, path, filter }, claim)) = claimed else {
+                        bridge_state.write().await.bridges.remove(&stream_id);
+                        handle.closed(Err("stream can't be bridged".to_string()));
+                        continue;
+                    };
+                    let stream = streams
+                        .iter()
+                        .find(|s| s.stream_id == stream_id)
+                        .cloned()
+                        .unwrap_or_else(|| StreamInfo::new(stream_id.clone()));
+                    let metadata = metadata::frame(&stream, &config.stream_metadata);
+                    let expiry = Expiry::new(expiry::deadline(&stream, listed_at));
+                    let suppressed = Suppressed::new(&relay.announced, claim.path());
+                    let admitted = Admitted {
+                        stream: stream.clone(),
+                        namespace: namespace.clone(),
+                        relay,
+                        path: path.clone(),
+                        filter: filter.clone(),
+                        claim,
+                        handle: handle.clone(),
+                        stopped,
+                        metadata: metadata.clone(),
+                        expires: expiry.subscribe(),
+                        #[cfg(feature = "redis")]
+                        lease,
+                    };
+                    running.insert(stream_id.clone(), Running { stream, namespace, path, filter, metadata, expiry });
+                    let source = CloudFlareSource {
+                        sessions: cf_sessions.clone(),
+                        origin: from_cloudflare.clone(),
+                        announce: config.announce_options(),
+                        announce_limit: announce_limit.clone(),
+                        batch: batch.clone(),
+                        move_timeout: Duration::from_secs(config.cf_move_timeout),
+                        flap_grace: config.flap_grace.map(Duration::from_secs),
+                    };
+                    let bridge = Bridge::start(admitted, source, hook.clone(), config, &services);
+                    let aftermath = Aftermath {
+                        idle_timeout: config.idle_timeout.map(Duration::from_secs),
+                        cooldown: config.evict_options().and_then(|evict| evict.cooldown),
+                        suppressed,
+                    };
+                    let bridge_state = bridge_state.clone();
+
+                    // Spawn a task to bridge this specific stream
+                    services.shards.spawn(async move {
+                        let end = bridge.run().await;
+                        // Remove from active bridges when done
+                        bridge_state.write().await.finished(&handle, end, aftermath);
+                    });
+                }
+            }
+            Err(err) => {
+                breaker.failure(&err);
+                delay = breaker.probe_interval().unwrap_or_else(|| delay.max(backoff.next()));
+            }
+        }
+
+        if let Some(state_file) = &services.state_file {
+            let state_guard = bridge_state.read().await;
+            let bridges = running
+                .iter()
+                .filter(|(stream_id, _)| state_guard.bridges.contains_key(*stream_id))
+                .map(|(stream_id, bridge)| SavedBridge {
+                    stream: bridge.stream.clone(),
+                    namespace: bridge.namespace.clone(),
+                    path: bridge.path.clone(),
+                    groups: services.resume.last_groups(stream_id),
+                })
+                .collect();
+            drop(state_guard);
+            state_file.save(bridges).await;
+        }
+
+        // List again early when a session (re)connects, so its streams come back quickly, or the list changes
+        heartbeat
+            .pulse(async {
+                tokio::select! {
+                    _ = tokio::time::sleep(delay) => {}
+                    _ = pool::next_connect(&mut connected) => {}
+                    _ = discovery.next_change() => {}
+                    _ = services.wake.notified() => {}
+                }
+            })
+            .await;
+    }
+}
+
+/// Our lease on `stream_id`, None when replicas don't share streams, or why we can't bridge it
+#[cfg(feature = "redis")]
+async fn lease(leases: &Option<Arc<Leases>>, stream_id: &str) -> Result<Option<Lease>, String> {
+    let Some(leases) = leases else {
+        return Ok(None);
+    };
+    match leases.acquire(stream_id).await {
+        Ok(Some(lease)) => Ok(Some(lease)),
+        Ok(None) => {
+            tracing::debug!(stream_id, "bridged by another replica");
+            Err("bridged by another replica".to_string())
+        }
+        Err(err) => {
+            tracing::warn!(err = format!("{err:#}"), stream_id, "failed to take lease, not bridging");
+            Err(format!("failed to take lease: {err:#}"))
+        }
+    }
+}
+
+/// What a running bridge started with
+struct Running {
+    stream: StreamInfo,
+    namespace: String,
+    path: String,
+    filter: TrackFilter,
+    /// The last metadata it served, see `--stream-metadata`
+    metadata: Option<Bytes>,
+    /// When its registry entry expires
+    expiry: Expiry,
+}
+
+/// The streams of the bridges `state_file` saved, as they were bridged, None if there are none
+async fn restore(state_file: &StateFile, resume: &ResumePoints) -> Option<Vec<StreamInfo>> {
+    let saved = state_file.load().await;
+    if saved.is_empty() {
+        return None;
+    }
+
+    let streams = saved
+        .into_iter()
+        .map(|bridge| {
+            resume.restore(&bridge.stream.stream_id, bridge.groups);
+            StreamInfo {
+                cf_namespace: Some(bridge.namespace),
+                publish_path: Some(bridge.path),
+                ..bridge.stream
+            }
+        })
+        .collect();
+    Some(streams)
+}
+
+/// How one listed stream is bridged, from its registry entry and our config
+struct StreamPlan {
+    namespace: String,
+    relay: Arc<Relay>,
+    path: String,
+    filter: TrackFilter,
+}
+
+/// Work out how `stream` is bridged, or why it can't be
+fn plan(config: &AdapterConfig, relays: &Relays, stream: &StreamInfo) -> anyhow::Result<StreamPlan> {
+    let namespace = match &stream.cf_namespace {
+        Some(namespace) => namespace.clone(),
+        None => {
+            let template = &config.cf_namespace_template;
+            config.namespace_encoding.render(template, |name| stream.field(name)).context("can't build namespace")?
+        }
+    };
+
+    let relay = relays
+        .get(stream.relay.as_deref())
+        .with_context(|| format!("unknown relay {}", stream.relay.as_deref().unwrap_or_default()))?;
+
+    Ok(StreamPlan {
+        namespace,
+        relay: relay.clone(),
+        path: paths::sanitize(&config.publish_path(stream)?).context("invalid publish path")?,
+        filter: config.track_filter(stream),
+    })
+}
+
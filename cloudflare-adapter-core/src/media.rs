@@ -0,0 +1,73 @@
+//! hang media frame layout
+//!
+//! Every hang media frame is a QUIC varint presentation timestamp in microseconds,
+//! followed by the codec payload. The first frame of each group is a keyframe.
+
+use bytes::{BufMut, Bytes, BytesMut};
+use moq_lite::GroupConsumer;
+
+/// A decoded hang media frame
+#[derive(Clone, Debug)]
+pub struct MediaFrame {
+    /// Presentation timestamp in microseconds
+    pub timestamp: u64,
+    pub payload: Bytes,
+}
+
+impl MediaFrame {
+    /// Split a frame into its timestamp and payload, or None if the prefix is malformed
+    pub fn decode(frame: &Bytes) -> Option<Self> {
+        let (timestamp, size) = decode_varint(frame)?;
+        Some(Self {
+            timestamp,
+            payload: frame.slice(size..),
+        })
+    }
+
+    pub fn encode(&self) -> Bytes {
+        let mut output = BytesMut::with_capacity(self.payload.len() + 8);
+        encode_varint(&mut output, self.timestamp);
+        output.put_slice(&self.payload);
+        output.freeze()
+    }
+}
+
+/// Read every frame of a group, skipping any without a valid timestamp
+pub async fn read_group(mut group: GroupConsumer) -> anyhow::Result<Vec<MediaFrame>> {
+    let mut frames = Vec::new();
+    while let Some(frame) = group.read_frame().await? {
+        if let Some(frame) = MediaFrame::decode(&frame) {
+            frames.push(frame);
+        }
+    }
+
+    Ok(frames)
+}
+
+/// Decode a QUIC varint, returning the value and its encoded length
+pub fn decode_varint(buf: &[u8]) -> Option<(u64, usize)> {
+    let first = *buf.first()?;
+    let size = 1usize << (first >> 6);
+    let bytes = buf.get(..size)?;
+
+    let mut value = (first & 0x3f) as u64;
+    for byte in &bytes[1..] {
+        value = (value << 8) | *byte as u64;
+    }
+
+    Some((value, size))
+}
+
+/// Encode a QUIC varint using the smallest possible length
+pub fn encode_varint(buf: &mut BytesMut, value: u64) {
+    if value < 1 << 6 {
+        buf.put_u8(value as u8);
+    } else if value < 1 << 14 {
+        buf.put_u16(0x4000 | value as u16);
+    } else if value < 1 << 30 {
+        buf.put_u32(0x8000_0000 | value as u32);
+    } else {
+        // Timestamps never come close to 2^62 microseconds
+        buf.put_u64(0xc000_0000_0000_0000 | value);
+    }
+}
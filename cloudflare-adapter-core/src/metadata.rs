@@ -0,0 +1,55 @@
+//! Registry metadata on bridged broadcasts
+//!
+//! Relay-side apps showing what's on (a title, who's publishing, tags) would otherwise
+//! have to look each broadcast up in the registry again. With `--stream-metadata
+//! title,publisher,region,tags`, every bridge serves a `.metadata` track alongside the
+//! forwarded ones, holding those fields of the stream's registry entry as one JSON object,
+//! with its `stream_id`:
+//!
+//! ```json
+//! {"stream_id": "s1", "title": "Town hall", "publisher": "ops", "tags": ["live"]}
+//! ```
+//!
+//! Fields the entry doesn't have are left out. Each version is a group of one frame,
+//! written when the bridge starts and again whenever a poll finds the fields changed, so
+//! subscribers get the latest first and every update after it.
+
+use bytes::Bytes;
+use serde_json::{Map, Value};
+
+use crate::inject::Injector;
+use crate::registry::StreamInfo;
+
+/// The track a bridge's metadata goes out on
+pub const METADATA_TRACK: &str = ".metadata";
+
+/// The metadata frame for `stream`, or None if no fields are carried
+pub fn frame(stream: &StreamInfo, fields: &[String]) -> Option<Bytes> {
+    if fields.is_empty() {
+        return None;
+    }
+
+    let mut metadata = Map::new();
+    metadata.insert("stream_id".to_string(), stream.stream_id.clone().into());
+    for name in fields {
+        let value = match name.as_str() {
+            "origin" => Some(stream.origin.clone().into()),
+            "priority" => stream.priority.map(Value::from),
+            "relay" => stream.relay.clone().map(Value::from),
+            _ => stream.fields.get(name).cloned(),
+        };
+        if let Some(value) = value {
+            metadata.insert(name.clone(), value);
+        }
+    }
+    serde_json::to_vec(&metadata).ok().map(Bytes::from)
+}
+
+/// Write a new version of a bridge's metadata
+pub fn publish(injector: &Injector, frame: Bytes) {
+    if let Some(mut track) = injector.track(METADATA_TRACK) {
+        let mut group = track.append_group();
+        group.write_frame(frame);
+        group.close();
+    }
+}
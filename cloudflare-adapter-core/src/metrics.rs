@@ -0,0 +1,150 @@
+//! Process-wide metrics
+//!
+//! Counters and gauges are registered on first use and live as long as the process, but
+//! for a bridge's, labelled with its `stream_id`, which go when the bridge ends.
+//! The embedded HTTP server renders them at `/metrics` in the Prometheus text format.
+//! A process running one [tenant](crate::tenant)'s adapter labels every series with it.
+
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::sync::atomic::{AtomicI64, Ordering};
+use std::sync::{Arc, LazyLock, Mutex, OnceLock};
+
+#[cfg(feature = "http")]
+use axum::http::header;
+#[cfg(feature = "http")]
+use axum::response::IntoResponse;
+#[cfg(feature = "http")]
+use axum::routing::get;
+#[cfg(feature = "http")]
+use axum::Router;
+
+/// Every metric prefix, so ours are easy to tell apart on a shared Prometheus
+const PREFIX: &str = "cf_adapter_";
+
+static REGISTRY: LazyLock<Mutex<BTreeMap<&'static str, Family>>> = LazyLock::new(Default::default);
+
+/// The rendered tenant label, if the process runs for one
+static TENANT: OnceLock<String> = OnceLock::new();
+
+/// All the series of one metric
+struct Family {
+    help: &'static str,
+    kind: &'static str,
+    // Keyed by the rendered label set
+    series: BTreeMap<String, Arc<AtomicI64>>,
+}
+
+/// Look up (or register) the series of `name` with `labels`
+fn series(name: &'static str, help: &'static str, kind: &'static str, labels: &[(&str, &str)]) -> Arc<AtomicI64> {
+    let mut rendered = String::new();
+    for (key, value) in labels {
+        let sep = if rendered.is_empty() { "" } else { "," };
+        let _ = write!(rendered, "{sep}{}", label(key, value));
+    }
+
+    let mut registry = REGISTRY.lock().unwrap();
+    let family = registry.entry(name).or_insert_with(|| Family {
+        help,
+        kind,
+        series: BTreeMap::new(),
+    });
+    family.series.entry(rendered).or_default().clone()
+}
+
+fn label(key: &str, value: &str) -> String {
+    let value = value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n");
+    format!("{key}=\"{value}\"")
+}
+
+/// Drop every series labelled with `stream_id`, once its bridge has ended
+pub(crate) fn remove_stream(stream_id: &str) {
+    let label = label("stream_id", stream_id);
+    // Quotes in values are escaped, so the label can only match as a whole
+    let labelled = |labels: &str| {
+        labels == label
+            || labels.starts_with(&format!("{label},"))
+            || labels.ends_with(&format!(",{label}"))
+            || labels.contains(&format!(",{label},"))
+    };
+
+    let mut registry = REGISTRY.lock().unwrap();
+    registry.retain(|_, family| {
+        family.series.retain(|labels, _| !labelled(labels));
+        !family.series.is_empty()
+    });
+}
+
+/// Label every series with `tenant` from now on
+pub(crate) fn set_tenant(tenant: &str) {
+    let _ = TENANT.set(label("tenant", tenant));
+}
+
+/// A value that only goes up
+#[derive(Clone)]
+pub struct Counter(Arc<AtomicI64>);
+
+impl Counter {
+    pub fn new(name: &'static str, help: &'static str, labels: &[(&str, &str)]) -> Self {
+        Self(series(name, help, "counter", labels))
+    }
+
+    pub fn inc(&self) {
+        self.add(1);
+    }
+
+    pub fn add(&self, n: u64) {
+        self.0.fetch_add(n as i64, Ordering::Relaxed);
+    }
+}
+
+/// A value that's set to the current state of something
+#[derive(Clone)]
+pub struct Gauge(Arc<AtomicI64>);
+
+impl Gauge {
+    pub fn new(name: &'static str, help: &'static str, labels: &[(&str, &str)]) -> Self {
+        Self(series(name, help, "gauge", labels))
+    }
+
+    pub fn set(&self, value: i64) {
+        self.0.store(value, Ordering::Relaxed);
+    }
+}
+
+/// Render every metric in the Prometheus text format
+pub fn render() -> String {
+    let registry = REGISTRY.lock().unwrap();
+    let mut out = String::new();
+
+    for (name, family) in registry.iter() {
+        let _ = writeln!(out, "# HELP {PREFIX}{name} {}", family.help);
+        let _ = writeln!(out, "# TYPE {PREFIX}{name} {}", family.kind);
+        for (labels, value) in &family.series {
+            let value = value.load(Ordering::Relaxed);
+            let labels = match (TENANT.get(), labels.is_empty()) {
+                (Some(tenant), true) => tenant.clone(),
+                (Some(tenant), false) => format!("{tenant},{labels}"),
+                (None, _) => labels.clone(),
+            };
+            if labels.is_empty() {
+                let _ = writeln!(out, "{PREFIX}{name} {value}");
+            } else {
+                let _ = writeln!(out, "{PREFIX}{name}{{{labels}}} {value}");
+            }
+        }
+    }
+
+    out
+}
+
+/// The metrics route, to be merged into the embedded HTTP server
+#[cfg(feature = "http")]
+pub fn routes() -> Router {
+    Router::new().route("/metrics", get(serve_metrics))
+}
+
+#[cfg(feature = "http")]
+async fn serve_metrics() -> impl IntoResponse {
+    ([(header::CONTENT_TYPE, "text/plain; version=0.0.4")], render())
+}
@@ -0,0 +1,141 @@
+//! Following network changes with QUIC connection migration
+//!
+//! When the host's route to a peer starts going out another interface (a NIC failing
+//! over, an address moving, a default route changing), the sessions to it would otherwise
+//! keep sending from a path that no longer works until they time out and reconnect, with
+//! every bridge on them. Every `--network-watch` seconds, each connection looks up which
+//! local address the route to its peer goes out from. When that changes, the endpoint is
+//! rebound to a fresh socket: QUIC migrates its connections to the new path, with new
+//! connection IDs and a probe right away, and the sessions and their bridges carry on.
+//! `quic_rebinds_total` counts the rebinds.
+//!
+//! Connections through a `--relay-proxy` or `--cf-proxy` aren't followed: their path is
+//! the proxy's.
+
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::{LazyLock, Mutex};
+use std::time::Duration;
+
+use anyhow::Context;
+use moq_native::web_transport_quinn::quinn;
+use tokio::time::Instant;
+
+use crate::metrics::Counter;
+use crate::qos;
+use crate::quic::{self, QuicSetting};
+
+/// When each endpoint was last rebound, by its address before and after, so the
+/// connections sharing it that notice the same change don't each rebind it again
+static REBOUND: LazyLock<Mutex<HashMap<SocketAddr, Instant>>> = LazyLock::new(Default::default);
+
+/// Rebinds the endpoint of one side's connections when their routes change
+pub struct Migration {
+    /// `relay` or `cloudflare`
+    side: &'static str,
+    endpoint: quinn::Endpoint,
+    dscp: Option<u8>,
+    priority: Option<u32>,
+    interval: Duration,
+}
+
+impl Migration {
+    /// Follow the connections of `client` every `secs`, or None if that's 0
+    pub fn new(side: &'static str, client: &moq_native::Client, settings: &[QuicSetting], secs: u64) -> Option<Self> {
+        if secs == 0 {
+            return None;
+        }
+        let (dscp, priority) = quic::marking(settings);
+        Some(Self {
+            side,
+            endpoint: client.quic.clone(),
+            dscp,
+            priority,
+            interval: Duration::from_secs(secs),
+        })
+    }
+
+    /// Rebind when the route to `quic`'s peer changes, until it closes
+    async fn watch(&self, quic: &quinn::Connection) {
+        let mut interval = tokio::time::interval(self.interval);
+        let mut sources = HashMap::new();
+        loop {
+            tokio::select! {
+                _ = interval.tick() => {},
+                _ = quic.closed() => return,
+            }
+
+            // The peer's address can change too, if it migrated
+            let peer = quic.remote_address();
+            let source = match route(peer) {
+                Ok(source) => source,
+                Err(err) => {
+                    // No route right now; see where it goes once there's one again
+                    tracing::debug!(%peer, %err, "no route to peer");
+                    continue;
+                }
+            };
+
+            let previous = sources.insert(peer, source);
+            if previous.is_none_or(|previous| previous == source) {
+                continue;
+            }
+
+            let mut rebound = REBOUND.lock().unwrap();
+            rebound.retain(|_, at| at.elapsed() < self.interval);
+            let Ok(bound) = self.endpoint.local_addr() else { continue };
+            if rebound.contains_key(&bound) {
+                continue;
+            }
+            match self.rebind(bound) {
+                Ok(local) => {
+                    rebound.extend([(bound, Instant::now()), (local, Instant::now())]);
+                    tracing::info!(side = self.side, %peer, ?previous, %source, %local, "network changed, migrating");
+                    let labels = [("side", self.side)];
+                    Counter::new("quic_rebinds_total", "QUIC endpoints rebound after a network change", &labels).inc();
+                }
+                Err(err) => tracing::warn!(side = self.side, %err, "failed to rebind QUIC endpoint"),
+            }
+        }
+    }
+
+    /// Swap the endpoint's socket for a new one on the same address, returning its address
+    fn rebind(&self, bound: SocketAddr) -> anyhow::Result<SocketAddr> {
+        let bind = SocketAddr::new(bound.ip(), 0);
+        if self.dscp.is_some() || self.priority.is_some() {
+            self.endpoint.rebind_abstract(qos::socket(bind, self.dscp, self.priority)?)?;
+        } else {
+            let domain = socket2::Domain::for_address(bind);
+            let socket = socket2::Socket::new(domain, socket2::Type::DGRAM, Some(socket2::Protocol::UDP))?;
+            if bind.is_ipv6() {
+                // Dual-stack, like moq-native's own socket
+                socket.set_only_v6(false)?;
+            }
+            socket.bind(&bind.into()).with_context(|| format!("failed to bind UDP socket to {bind}"))?;
+            self.endpoint.rebind(socket.into())?;
+        }
+        Ok(self.endpoint.local_addr()?)
+    }
+}
+
+/// Follow the network for `quic` while it's open, if there's a `migration`; never returns
+pub async fn follow(migration: Option<&Migration>, quic: Option<&quinn::Connection>) {
+    if let (Some(migration), Some(quic)) = (migration, quic) {
+        migration.watch(quic).await;
+    }
+    std::future::pending().await
+}
+
+/// The local address the route to `peer` goes out from
+fn route(peer: SocketAddr) -> std::io::Result<IpAddr> {
+    // A dual-stack socket sees IPv4 peers as mapped IPv6 addresses
+    let peer = SocketAddr::new(peer.ip().to_canonical(), peer.port());
+    let unspecified = match peer {
+        SocketAddr::V4(_) => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+        SocketAddr::V6(_) => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+    };
+    // Connecting a UDP socket only picks the route, nothing is sent
+    let socket = std::net::UdpSocket::bind(SocketAddr::new(unspecified, 0))?;
+    socket.connect(peer)?;
+    Ok(socket.local_addr()?.ip())
+}
@@ -0,0 +1,388 @@
+//! Minimal fragmented MP4 (CMAF) muxer
+//!
+//! Produces one init segment per track and one `moof`+`mdat` fragment per group.
+//! Only what's needed to package hang renditions: H.264, H.265 and AV1 video with
+//! a catalog `description`, plus Opus and AAC audio. Timestamps stay in microseconds.
+
+use anyhow::Context;
+use serde_json::Value;
+
+use crate::catalog::{MediaKind, Rendition};
+use crate::media::MediaFrame;
+
+/// All hang timestamps are in microseconds, so we use that as the timescale everywhere
+pub const TIMESCALE: u32 = 1_000_000;
+
+/// The codec configuration needed to write an init segment
+#[derive(Clone, Debug)]
+pub struct TrackConfig {
+    pub kind: MediaKind,
+    codec: Codec,
+    width: u16,
+    height: u16,
+    sample_rate: u32,
+    channels: u16,
+}
+
+#[derive(Clone, Debug)]
+enum Codec {
+    // The sample entry fourcc, plus the codec configuration box and its payload
+    Video([u8; 4], [u8; 4], Vec<u8>),
+    Opus,
+    Aac(Vec<u8>),
+}
+
+impl TrackConfig {
+    /// Build the config from a catalog rendition
+    pub fn from_rendition(rendition: &Rendition) -> anyhow::Result<Self> {
+        let config = &rendition.config;
+        let codec = config.get("codec").and_then(Value::as_str).context("missing codec")?;
+        let description = config
+            .get("description")
+            .and_then(Value::as_str)
+            .map(decode_hex)
+            .transpose()
+            .context("invalid description")?;
+        let field = |name: &str| config.get(name).and_then(Value::as_u64).unwrap_or(0);
+
+        let fourcc = codec.split('.').next().unwrap_or(codec);
+        let codec = match (rendition.kind, fourcc) {
+            (MediaKind::Video, "avc1" | "avc3") => Codec::Video(*b"avc1", *b"avcC", description.context("H.264 without description")?),
+            (MediaKind::Video, "hvc1" | "hev1") => Codec::Video(*b"hvc1", *b"hvcC", description.context("H.265 without description")?),
+            (MediaKind::Video, "av01") => Codec::Video(*b"av01", *b"av1C", description.context("AV1 without description")?),
+            (MediaKind::Audio, "opus") => Codec::Opus,
+            (MediaKind::Audio, "mp4a") => Codec::Aac(description.unwrap_or_default()),
+            _ => anyhow::bail!("unsupported codec: {codec}"),
+        };
+
+        let mut track = Self {
+            kind: rendition.kind,
+            codec,
+            width: field("codedWidth") as u16,
+            height: field("codedHeight") as u16,
+            sample_rate: field("sampleRate") as u32,
+            channels: field("numberOfChannels") as u16,
+        };
+
+        // Synthesize an AAC-LC AudioSpecificConfig when the publisher didn't send one
+        if let Codec::Aac(asc) = &mut track.codec {
+            if asc.is_empty() {
+                *asc = audio_specific_config(track.sample_rate, track.channels);
+            }
+        }
+
+        Ok(track)
+    }
+
+    /// The sample duration assumed when a group only has a single frame
+    pub fn default_duration(&self) -> u64 {
+        match self.kind {
+            MediaKind::Video => 33_333,
+            MediaKind::Audio => 20_000,
+        }
+    }
+}
+
+/// A sample ready to be packaged
+pub struct Sample<'a> {
+    /// Decode timestamp in microseconds
+    pub timestamp: u64,
+    pub duration: u64,
+    pub keyframe: bool,
+    pub data: &'a [u8],
+}
+
+/// Turn a group's frames into samples, deriving durations from the timestamp deltas
+pub fn samples(frames: &[MediaFrame], default_duration: u64) -> Vec<Sample<'_>> {
+    frames
+        .iter()
+        .enumerate()
+        .map(|(i, frame)| {
+            let duration = match frames.get(i + 1) {
+                Some(next) => next.timestamp.saturating_sub(frame.timestamp),
+                // Assume the last frame lasts as long as the one before it
+                None if i > 0 => frame.timestamp.saturating_sub(frames[i - 1].timestamp),
+                None => default_duration,
+            };
+
+            Sample {
+                timestamp: frame.timestamp,
+                duration,
+                keyframe: i == 0,
+                data: &frame.payload,
+            }
+        })
+        .collect()
+}
+
+/// Write the `ftyp` + `moov` init segment for a single track
+pub fn init_segment(track: &TrackConfig) -> Vec<u8> {
+    let mut buf = Vec::new();
+
+    write_box(&mut buf, b"ftyp", |b| {
+        b.extend_from_slice(b"iso6");
+        put_u32(b, 0);
+        for brand in [b"iso6", b"cmfc", b"isom", b"mp41"] {
+            b.extend_from_slice(brand);
+        }
+    });
+
+    write_box(&mut buf, b"moov", |b| {
+        write_full_box(b, b"mvhd", 0, 0, |b| {
+            put_u32(b, 0); // creation time
+            put_u32(b, 0); // modification time
+            put_u32(b, TIMESCALE);
+            put_u32(b, 0); // duration
+            put_u32(b, 0x0001_0000); // rate
+            put_u16(b, 0x0100); // volume
+            b.extend_from_slice(&[0; 10]);
+            put_matrix(b);
+            b.extend_from_slice(&[0; 24]);
+            put_u32(b, 2); // next track id
+        });
+
+        write_box(b, b"trak", |b| write_trak(b, track));
+
+        write_box(b, b"mvex", |b| {
+            write_full_box(b, b"trex", 0, 0, |b| {
+                put_u32(b, 1); // track id
+                put_u32(b, 1); // sample description index
+                put_u32(b, 0); // default duration
+                put_u32(b, 0); // default size
+                put_u32(b, 0); // default flags
+            });
+        });
+    });
+
+    buf
+}
+
+fn write_trak(b: &mut Vec<u8>, track: &TrackConfig) {
+    let video = track.kind == MediaKind::Video;
+
+    // Flags: enabled, in movie
+    write_full_box(b, b"tkhd", 0, 3, |b| {
+        put_u32(b, 0); // creation time
+        put_u32(b, 0); // modification time
+        put_u32(b, 1); // track id
+        put_u32(b, 0);
+        put_u32(b, 0); // duration
+        b.extend_from_slice(&[0; 8]);
+        put_u16(b, 0); // layer
+        put_u16(b, 0); // alternate group
+        put_u16(b, if video { 0 } else { 0x0100 });
+        put_u16(b, 0);
+        put_matrix(b);
+        put_u32(b, (track.width as u32) << 16);
+        put_u32(b, (track.height as u32) << 16);
+    });
+
+    write_box(b, b"mdia", |b| {
+        write_full_box(b, b"mdhd", 0, 0, |b| {
+            put_u32(b, 0);
+            put_u32(b, 0);
+            put_u32(b, TIMESCALE);
+            put_u32(b, 0);
+            put_u16(b, 0x55c4); // "und"
+            put_u16(b, 0);
+        });
+
+        write_full_box(b, b"hdlr", 0, 0, |b| {
+            put_u32(b, 0);
+            b.extend_from_slice(if video { b"vide" } else { b"soun" });
+            b.extend_from_slice(&[0; 12]);
+            b.extend_from_slice(if video { b"VideoHandler\0" } else { b"SoundHandler\0" });
+        });
+
+        write_box(b, b"minf", |b| {
+            match video {
+                true => write_full_box(b, b"vmhd", 0, 1, |b| b.extend_from_slice(&[0; 8])),
+                false => write_full_box(b, b"smhd", 0, 0, |b| b.extend_from_slice(&[0; 4])),
+            }
+
+            write_box(b, b"dinf", |b| {
+                write_full_box(b, b"dref", 0, 0, |b| {
+                    put_u32(b, 1);
+                    // Flag 1: media data is in the same file
+                    write_full_box(b, b"url ", 0, 1, |_| {});
+                });
+            });
+
+            write_box(b, b"stbl", |b| {
+                write_full_box(b, b"stsd", 0, 0, |b| {
+                    put_u32(b, 1);
+                    write_sample_entry(b, track);
+                });
+
+                // Empty sample tables; the samples all live in fragments
+                write_full_box(b, b"stts", 0, 0, |b| put_u32(b, 0));
+                write_full_box(b, b"stsc", 0, 0, |b| put_u32(b, 0));
+                write_full_box(b, b"stsz", 0, 0, |b| {
+                    put_u32(b, 0);
+                    put_u32(b, 0);
+                });
+                write_full_box(b, b"stco", 0, 0, |b| put_u32(b, 0));
+            });
+        });
+    });
+}
+
+fn write_sample_entry(b: &mut Vec<u8>, track: &TrackConfig) {
+    match &track.codec {
+        Codec::Video(fourcc, config_box, config) => write_box(b, fourcc, |b| {
+            b.extend_from_slice(&[0; 6]);
+            put_u16(b, 1); // data reference index
+            b.extend_from_slice(&[0; 16]);
+            put_u16(b, track.width);
+            put_u16(b, track.height);
+            put_u32(b, 0x0048_0000); // 72 dpi
+            put_u32(b, 0x0048_0000);
+            put_u32(b, 0);
+            put_u16(b, 1); // frame count
+            b.extend_from_slice(&[0; 32]); // compressor name
+            put_u16(b, 0x0018); // depth
+            put_u16(b, 0xffff); // pre-defined
+            write_box(b, config_box, |b| b.extend_from_slice(config));
+        }),
+        Codec::Opus => write_audio_entry(b, b"Opus", track, |b| {
+            write_box(b, b"dOps", |b| {
+                b.push(0); // version
+                b.push(track.channels as u8);
+                put_u16(b, 0); // pre-skip
+                put_u32(b, track.sample_rate);
+                put_u16(b, 0); // output gain
+                b.push(0); // channel mapping family
+            });
+        }),
+        Codec::Aac(asc) => write_audio_entry(b, b"mp4a", track, |b| {
+            write_full_box(b, b"esds", 0, 0, |b| {
+                let decoder_specific = descriptor(0x05, asc);
+
+                let mut decoder_config = vec![0x40, 0x15, 0, 0, 0];
+                decoder_config.extend_from_slice(&[0; 8]); // max and average bitrate
+                decoder_config.extend_from_slice(&decoder_specific);
+                let decoder_config = descriptor(0x04, &decoder_config);
+
+                let mut es = vec![0, 1, 0]; // ES id, flags
+                es.extend_from_slice(&decoder_config);
+                es.extend_from_slice(&descriptor(0x06, &[0x02]));
+                b.extend_from_slice(&descriptor(0x03, &es));
+            });
+        }),
+    }
+}
+
+fn write_audio_entry(b: &mut Vec<u8>, fourcc: &[u8; 4], track: &TrackConfig, config: impl FnOnce(&mut Vec<u8>)) {
+    write_box(b, fourcc, |b| {
+        b.extend_from_slice(&[0; 6]);
+        put_u16(b, 1); // data reference index
+        b.extend_from_slice(&[0; 8]);
+        put_u16(b, track.channels);
+        put_u16(b, 16); // sample size
+        put_u32(b, 0);
+        put_u32(b, track.sample_rate << 16);
+        config(b);
+    });
+}
+
+/// Write a `moof` + `mdat` fragment containing `samples`
+pub fn media_segment(sequence: u32, samples: &[Sample<'_>]) -> Vec<u8> {
+    // The trun data offset depends on the moof size, which doesn't depend on the offset itself
+    let moof_len = write_moof(sequence, samples, 0).len();
+    let mut buf = write_moof(sequence, samples, (moof_len + 8) as u32);
+
+    let mdat_len: usize = samples.iter().map(|s| s.data.len()).sum();
+    put_u32(&mut buf, (mdat_len + 8) as u32);
+    buf.extend_from_slice(b"mdat");
+    for sample in samples {
+        buf.extend_from_slice(sample.data);
+    }
+
+    buf
+}
+
+fn write_moof(sequence: u32, samples: &[Sample<'_>], data_offset: u32) -> Vec<u8> {
+    let mut buf = Vec::new();
+    let base_time = samples.first().map_or(0, |s| s.timestamp);
+
+    write_box(&mut buf, b"moof", |b| {
+        write_full_box(b, b"mfhd", 0, 0, |b| put_u32(b, sequence));
+
+        write_box(b, b"traf", |b| {
+            // Flags: default-base-is-moof
+            write_full_box(b, b"tfhd", 0, 0x02_0000, |b| put_u32(b, 1));
+            write_full_box(b, b"tfdt", 1, 0, |b| put_u64(b, base_time));
+
+            // Flags: data offset, sample duration, size and flags present
+            write_full_box(b, b"trun", 0, 0x0701, |b| {
+                put_u32(b, samples.len() as u32);
+                put_u32(b, data_offset);
+
+                for sample in samples {
+                    put_u32(b, sample.duration as u32);
+                    put_u32(b, sample.data.len() as u32);
+                    put_u32(b, if sample.keyframe { 0x0200_0000 } else { 0x0101_0000 });
+                }
+            });
+        });
+    });
+
+    buf
+}
+
+fn write_box(buf: &mut Vec<u8>, kind: &[u8; 4], body: impl FnOnce(&mut Vec<u8>)) {
+    let mut inner = Vec::new();
+    body(&mut inner);
+    put_u32(buf, (inner.len() + 8) as u32);
+    buf.extend_from_slice(kind);
+    buf.extend_from_slice(&inner);
+}
+
+fn write_full_box(buf: &mut Vec<u8>, kind: &[u8; 4], version: u8, flags: u32, body: impl FnOnce(&mut Vec<u8>)) {
+    write_box(buf, kind, |b| {
+        put_u32(b, (version as u32) << 24 | flags);
+        body(b);
+    });
+}
+
+/// An MPEG-4 descriptor with a single byte length
+fn descriptor(tag: u8, body: &[u8]) -> Vec<u8> {
+    let mut out = vec![tag, body.len() as u8];
+    out.extend_from_slice(body);
+    out
+}
+
+fn put_matrix(b: &mut Vec<u8>) {
+    for value in [0x0001_0000, 0, 0, 0, 0x0001_0000, 0, 0, 0, 0x4000_0000u32] {
+        put_u32(b, value);
+    }
+}
+
+fn put_u16(b: &mut Vec<u8>, v: u16) {
+    b.extend_from_slice(&v.to_be_bytes());
+}
+
+fn put_u32(b: &mut Vec<u8>, v: u32) {
+    b.extend_from_slice(&v.to_be_bytes());
+}
+
+fn put_u64(b: &mut Vec<u8>, v: u64) {
+    b.extend_from_slice(&v.to_be_bytes());
+}
+
+/// Build an AAC-LC AudioSpecificConfig from the sample rate and channel count
+pub fn audio_specific_config(sample_rate: u32, channels: u16) -> Vec<u8> {
+    const RATES: [u32; 13] = [96000, 88200, 64000, 48000, 44100, 32000, 24000, 22050, 16000, 12000, 11025, 8000, 7350];
+    let index = RATES.iter().position(|&r| r == sample_rate).unwrap_or(3) as u16;
+    let config = (2 << 11) | (index << 7) | ((channels & 0xf) << 3);
+    config.to_be_bytes().to_vec()
+}
+
+/// Decode a hex catalog field such as `description`
+pub fn decode_hex(hex: &str) -> anyhow::Result<Vec<u8>> {
+    anyhow::ensure!(hex.len().is_multiple_of(2), "odd length");
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).context("invalid hex"))
+        .collect()
+}
@@ -0,0 +1,376 @@
+//! Minimal MPEG-TS muxer
+//!
+//! Enough to feed confidence monitors and legacy broadcast gear: one H.264 or H.265
+//! video stream and one AAC stream, PES packets with a PTS, a PCR on the first
+//! stream and the PAT/PMT repeated at every random access point. Length-prefixed
+//! video is rewritten to Annex B with the catalog's parameter sets in front of each
+//! keyframe, and AAC gets ADTS headers.
+
+use anyhow::Context;
+use serde_json::Value;
+
+use crate::catalog::{MediaKind, Rendition};
+use crate::mp4;
+
+pub const PACKET_SIZE: usize = 188;
+
+const PAT_PID: u16 = 0x0000;
+const PMT_PID: u16 = 0x1000;
+const VIDEO_PID: u16 = 0x0100;
+const AUDIO_PID: u16 = 0x0101;
+
+/// Shift hang timestamps so the PCR, which runs a little ahead, never goes negative
+const PTS_OFFSET: u64 = 90_000;
+
+/// How far the PCR trails the PTS, in 90kHz ticks
+const PCR_DELAY: u64 = 63_000;
+
+/// Minimum PAT/PMT interval when there's no video keyframe to attach them to
+const AUDIO_TABLE_INTERVAL: u64 = 45_000;
+
+/// A rendition we can put in a transport stream
+#[derive(Clone, Debug)]
+pub enum Codec {
+    /// Parameter sets in Annex B form, and the NAL length size (None if frames are already Annex B)
+    H264(Vec<u8>, Option<usize>),
+    H265(Vec<u8>, Option<usize>),
+    /// The ADTS profile, sampling frequency index and channel configuration
+    Aac(u8, u8, u8),
+}
+
+impl Codec {
+    pub fn from_rendition(rendition: &Rendition) -> anyhow::Result<Self> {
+        let config = &rendition.config;
+        let codec = config.get("codec").and_then(Value::as_str).context("missing codec")?;
+        let description = config
+            .get("description")
+            .and_then(Value::as_str)
+            .map(mp4::decode_hex)
+            .transpose()
+            .context("invalid description")?;
+
+        let fourcc = codec.split('.').next().unwrap_or(codec);
+        match (rendition.kind, fourcc, description) {
+            (MediaKind::Video, "avc1" | "avc3", Some(avcc)) => {
+                let (params, size) = avcc_parameter_sets(&avcc).context("invalid avcC")?;
+                Ok(Self::H264(params, Some(size)))
+            }
+            (MediaKind::Video, "hvc1" | "hev1", Some(hvcc)) => {
+                let (params, size) = hvcc_parameter_sets(&hvcc).context("invalid hvcC")?;
+                Ok(Self::H265(params, Some(size)))
+            }
+            // Without a description the parameter sets are in band
+            (MediaKind::Video, "avc1" | "avc3", None) => Ok(Self::H264(Vec::new(), None)),
+            (MediaKind::Video, "hvc1" | "hev1", None) => Ok(Self::H265(Vec::new(), None)),
+            (MediaKind::Audio, "mp4a", asc) => {
+                let field = |name: &str| config.get(name).and_then(Value::as_u64).unwrap_or(0);
+                let asc = asc.unwrap_or_else(|| {
+                    mp4::audio_specific_config(field("sampleRate") as u32, field("numberOfChannels") as u16)
+                });
+                anyhow::ensure!(asc.len() >= 2, "short AudioSpecificConfig");
+
+                let object_type = asc[0] >> 3;
+                let frequency = ((asc[0] & 0x07) << 1) | (asc[1] >> 7);
+                let channels = (asc[1] >> 3) & 0x0f;
+                Ok(Self::Aac(object_type.clamp(1, 4) - 1, frequency, channels))
+            }
+            _ => anyhow::bail!("unsupported codec for MPEG-TS: {codec}"),
+        }
+    }
+
+    fn stream_type(&self) -> u8 {
+        match self {
+            Self::H264(..) => 0x1b,
+            Self::H265(..) => 0x24,
+            Self::Aac(..) => 0x0f,
+        }
+    }
+
+    /// The PES payload for one frame
+    fn payload(&self, data: &[u8], keyframe: bool) -> Vec<u8> {
+        match self {
+            Self::H264(params, size) => {
+                // An access unit delimiter, which a lot of hardware decoders insist on
+                let mut out = vec![0, 0, 0, 1, 0x09, 0xf0];
+                annex_b(&mut out, params, *size, data, keyframe);
+                out
+            }
+            Self::H265(params, size) => {
+                let mut out = vec![0, 0, 0, 1, 0x46, 0x01, 0x50];
+                annex_b(&mut out, params, *size, data, keyframe);
+                out
+            }
+            Self::Aac(profile, frequency, channels) => {
+                let len = data.len() + 7;
+                let mut out = vec![
+                    0xff,
+                    0xf1,
+                    (profile << 6) | (frequency << 2) | (channels >> 2),
+                    ((channels & 0x03) << 6) | (len >> 11) as u8,
+                    (len >> 3) as u8,
+                    ((len & 0x07) << 5) as u8 | 0x1f,
+                    0xfc,
+                ];
+                out.extend_from_slice(data);
+                out
+            }
+        }
+    }
+}
+
+/// Append a frame as Annex B, with the parameter sets in front of keyframes
+pub(crate) fn annex_b(out: &mut Vec<u8>, params: &[u8], size: Option<usize>, data: &[u8], keyframe: bool) {
+    if keyframe {
+        out.extend_from_slice(params);
+    }
+
+    let Some(size) = size else {
+        out.extend_from_slice(data);
+        return;
+    };
+
+    let mut data = data;
+    while data.len() >= size {
+        let len = data[..size].iter().fold(0usize, |len, &b| (len << 8) | b as usize);
+        let Some(nal) = data.get(size..size + len) else {
+            break;
+        };
+        out.extend_from_slice(&[0, 0, 0, 1]);
+        out.extend_from_slice(nal);
+        data = &data[size + len..];
+    }
+}
+
+/// The SPS and PPS of an `avcC` record as Annex B, plus its NAL length size
+fn avcc_parameter_sets(avcc: &[u8]) -> Option<(Vec<u8>, usize)> {
+    let size = (*avcc.get(4)? & 0x03) as usize + 1;
+    let mut out = Vec::new();
+    let mut data = avcc.get(5..)?;
+
+    // The SPS count shares its byte with reserved bits, the PPS count doesn't
+    for mask in [0x1f, 0xff] {
+        let (&count, rest) = data.split_first()?;
+        data = rest;
+        for _ in 0..(count & mask) {
+            data = copy_nal(&mut out, data)?;
+        }
+    }
+
+    Some((out, size))
+}
+
+/// The VPS, SPS and PPS of an `hvcC` record as Annex B, plus its NAL length size
+fn hvcc_parameter_sets(hvcc: &[u8]) -> Option<(Vec<u8>, usize)> {
+    let size = (*hvcc.get(21)? & 0x03) as usize + 1;
+    let mut out = Vec::new();
+    let arrays = *hvcc.get(22)?;
+    let mut data = hvcc.get(23..)?;
+
+    for _ in 0..arrays {
+        let count = u16::from_be_bytes(data.get(1..3)?.try_into().ok()?);
+        data = &data[3..];
+        for _ in 0..count {
+            data = copy_nal(&mut out, data)?;
+        }
+    }
+
+    Some((out, size))
+}
+
+/// Copy one 16-bit length-prefixed NAL unit with a start code, returning the rest
+fn copy_nal<'a>(out: &mut Vec<u8>, data: &'a [u8]) -> Option<&'a [u8]> {
+    let len = u16::from_be_bytes(data.get(..2)?.try_into().ok()?) as usize;
+    out.extend_from_slice(&[0, 0, 0, 1]);
+    out.extend_from_slice(data.get(2..2 + len)?);
+    Some(&data[2 + len..])
+}
+
+/// Muxes one video and one audio stream into 188-byte packets
+pub struct Muxer {
+    video: Option<Codec>,
+    audio: Option<Codec>,
+    // Continuity counters for the PAT, PMT, video and audio PIDs
+    counters: [u8; 4],
+    pcr: u64,
+    last_tables: Option<u64>,
+}
+
+impl Muxer {
+    pub fn new(video: Option<Codec>, audio: Option<Codec>) -> Self {
+        Self {
+            video,
+            audio,
+            counters: [0; 4],
+            pcr: 0,
+            last_tables: None,
+        }
+    }
+
+    /// Mux one frame with a timestamp in microseconds, returning whole TS packets
+    ///
+    /// Frames for a stream the muxer wasn't created with are ignored.
+    pub fn write(&mut self, kind: MediaKind, timestamp: u64, data: &[u8], keyframe: bool) -> Vec<u8> {
+        let codec = match kind {
+            MediaKind::Video => self.video.as_ref(),
+            MediaKind::Audio => self.audio.as_ref(),
+        };
+        let Some(codec) = codec else {
+            return Vec::new();
+        };
+
+        let pts = (timestamp * 9 / 100 + PTS_OFFSET) & ((1 << 33) - 1);
+        let payload = codec.payload(data, keyframe);
+        // The PCR rides on the video stream if there is one
+        let carries_pcr = kind == MediaKind::Video || self.video.is_none();
+
+        let mut out = Vec::new();
+        let tables_due = match (kind, self.last_tables) {
+            (_, None) => true,
+            (MediaKind::Video, _) => keyframe,
+            (MediaKind::Audio, Some(last)) => self.video.is_none() && pts >= last + AUDIO_TABLE_INTERVAL,
+        };
+        if tables_due {
+            self.write_tables(&mut out);
+            self.last_tables = Some(pts);
+        }
+
+        let pcr = carries_pcr.then(|| {
+            self.pcr = self.pcr.max(pts.saturating_sub(PCR_DELAY));
+            self.pcr
+        });
+
+        let (pid, stream_id, counter) = match kind {
+            MediaKind::Video => (VIDEO_PID, 0xe0, 2),
+            MediaKind::Audio => (AUDIO_PID, 0xc0, 3),
+        };
+
+        let mut pes = vec![0, 0, 1, stream_id];
+        // Video PES packets are usually too large for the length field, 0 means unbounded
+        let len = payload.len() + 8;
+        let len = if kind == MediaKind::Audio && len <= u16::MAX as usize { len as u16 } else { 0 };
+        pes.extend_from_slice(&len.to_be_bytes());
+        pes.extend_from_slice(&[0x80, 0x80, 5]);
+        pes.extend_from_slice(&encode_pts(pts));
+        pes.extend_from_slice(&payload);
+
+        self.write_pes(&mut out, pid, counter, &pes, pcr, keyframe);
+        out
+    }
+
+    fn write_tables(&mut self, out: &mut Vec<u8>) {
+        let mut pat = vec![0, 1, 0xc1, 0, 0, 0, 1];
+        pat.extend_from_slice(&(0xe000 | PMT_PID).to_be_bytes());
+        self.write_section(out, PAT_PID, 0, 0x00, &pat);
+
+        let pcr_pid = if self.video.is_some() { VIDEO_PID } else { AUDIO_PID };
+        let mut pmt = vec![0, 1, 0xc1, 0, 0];
+        pmt.extend_from_slice(&(0xe000 | pcr_pid).to_be_bytes());
+        pmt.extend_from_slice(&0xf000u16.to_be_bytes()); // no program descriptors
+        for (codec, pid) in [(&self.video, VIDEO_PID), (&self.audio, AUDIO_PID)] {
+            if let Some(codec) = codec {
+                pmt.push(codec.stream_type());
+                pmt.extend_from_slice(&(0xe000 | pid).to_be_bytes());
+                pmt.extend_from_slice(&0xf000u16.to_be_bytes());
+            }
+        }
+        self.write_section(out, PMT_PID, 1, 0x02, &pmt);
+    }
+
+    /// Write a PSI section that fits in a single packet
+    fn write_section(&mut self, out: &mut Vec<u8>, pid: u16, counter: usize, table_id: u8, body: &[u8]) {
+        let mut section = vec![table_id];
+        // Section syntax, plus the length of the body and the CRC
+        section.extend_from_slice(&(0xb000 | (body.len() as u16 + 4)).to_be_bytes());
+        section.extend_from_slice(body);
+        let crc = crc32(&section);
+        section.extend_from_slice(&crc.to_be_bytes());
+
+        let start = out.len();
+        out.extend_from_slice(&header(pid, true, self.next_counter(counter), false));
+        out.push(0); // pointer field
+        out.extend_from_slice(&section);
+        out.resize(start + PACKET_SIZE, 0xff);
+    }
+
+    fn write_pes(&mut self, out: &mut Vec<u8>, pid: u16, counter: usize, mut pes: &[u8], pcr: Option<u64>, keyframe: bool) {
+        let mut first = true;
+
+        while !pes.is_empty() {
+            // The first packet may carry the random access flag and the PCR
+            let mut adaptation = Vec::new();
+            if first && (keyframe || pcr.is_some()) {
+                adaptation.push(if keyframe { 0x40 } else { 0 } | if pcr.is_some() { 0x10 } else { 0 });
+                if let Some(pcr) = pcr {
+                    adaptation.extend_from_slice(&encode_pcr(pcr));
+                }
+            }
+
+            let overhead = if first && !adaptation.is_empty() { 1 + adaptation.len() } else { 0 };
+            let space = PACKET_SIZE - 4 - overhead;
+
+            // Pad the last packet with adaptation field stuffing
+            let stuffing = space.saturating_sub(pes.len());
+            let with_adaptation = overhead > 0 || stuffing > 0;
+            if stuffing > 0 {
+                let field = overhead + stuffing;
+                if adaptation.is_empty() && field > 1 {
+                    adaptation.push(0);
+                }
+                adaptation.resize(field - 1, 0xff);
+            }
+
+            let start = out.len();
+            out.extend_from_slice(&header(pid, first, self.next_counter(counter), with_adaptation));
+            if with_adaptation {
+                out.push(adaptation.len() as u8);
+                out.extend_from_slice(&adaptation);
+            }
+
+            let take = (start + PACKET_SIZE - out.len()).min(pes.len());
+            out.extend_from_slice(&pes[..take]);
+            pes = &pes[take..];
+            first = false;
+        }
+    }
+
+    fn next_counter(&mut self, index: usize) -> u8 {
+        let counter = self.counters[index];
+        self.counters[index] = (counter + 1) & 0x0f;
+        counter
+    }
+}
+
+fn header(pid: u16, start: bool, counter: u8, adaptation: bool) -> [u8; 4] {
+    [
+        0x47,
+        if start { 0x40 } else { 0 } | (pid >> 8) as u8,
+        pid as u8,
+        if adaptation { 0x30 } else { 0x10 } | counter,
+    ]
+}
+
+fn encode_pts(pts: u64) -> [u8; 5] {
+    [
+        0x21 | ((pts >> 29) & 0x0e) as u8,
+        (pts >> 22) as u8,
+        0x01 | ((pts >> 14) & 0xfe) as u8,
+        (pts >> 7) as u8,
+        0x01 | ((pts << 1) & 0xfe) as u8,
+    ]
+}
+
+fn encode_pcr(pcr: u64) -> [u8; 6] {
+    [(pcr >> 25) as u8, (pcr >> 17) as u8, (pcr >> 9) as u8, (pcr >> 1) as u8, ((pcr & 1) << 7) as u8 | 0x7e, 0]
+}
+
+/// The MPEG-2 CRC used by PSI sections
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = 0xffff_ffffu32;
+    for &byte in data {
+        crc ^= (byte as u32) << 24;
+        for _ in 0..8 {
+            crc = if crc & 0x8000_0000 != 0 { (crc << 1) ^ 0x04c1_1db7 } else { crc << 1 };
+        }
+    }
+    crc
+}
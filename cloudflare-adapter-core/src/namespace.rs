@@ -0,0 +1,169 @@
+//! CF namespaces for registry streams
+//!
+//! `--cf-namespace-template` builds the namespace each stream is announced under on
+//! CloudFlare. `{stream_id}` and any other `{field}` of the stream's registry entry
+//! are substituted, so deployments with other naming conventions can use fields like
+//! `{account}` or `{region}`. A stream missing a field in the template isn't bridged.
+//!
+//! Draft 14 namespaces are tuples of elements, while moq-lite paths are strings: moq-lite
+//! writes each `/`-separated part of a path as one element and joins elements back with
+//! `/`. So a field holding a `/`, like an `acme/eu` account, ends up as two elements.
+//! `--namespace-encoding` says what to do about it:
+//!
+//! - `split` leaves it, so the field becomes several elements (the default)
+//! - `escape` writes `/` and `%` in fields as `%2F` and `%25`, keeping each field one
+//!   element; CF publishers have to name their broadcasts the same way, and streams
+//!   found under `--bridge-prefix` get their IDs unescaped
+//! - `reject` doesn't bridge streams with a `/` in a field
+//!
+//! A registry entry's own `cf_namespace` is taken as already encoded.
+
+use anyhow::Context;
+
+/// How a `/` in a filled-in field is written into the namespace
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum NamespaceEncoding {
+    /// As is, separating elements like the template's own
+    #[default]
+    Split,
+    /// Percent-escaped, with `%`, so the field stays one element
+    Escape,
+    /// Not at all: the stream isn't bridged
+    Reject,
+}
+
+impl NamespaceEncoding {
+    /// Fill in `template` like [render], encoding each field
+    pub fn render(self, template: &str, field: impl Fn(&str) -> Option<String>) -> anyhow::Result<String> {
+        fill(template, |name| {
+            let value = field(name).with_context(|| format!("no `{name}` field"))?;
+            match self {
+                Self::Split => Ok(value),
+                Self::Escape => Ok(escape(&value)),
+                Self::Reject if value.contains('/') => anyhow::bail!("`{name}` field {value:?} has a `/`"),
+                Self::Reject => Ok(value),
+            }
+        })
+    }
+
+    /// The stream ID for the part of a namespace under a bridged prefix
+    pub fn stream_id(self, rest: &str) -> String {
+        match self {
+            Self::Escape => unescape(rest),
+            Self::Split | Self::Reject => rest.to_string(),
+        }
+    }
+}
+
+/// Fill in the `{field}` placeholders of `template` with `field(name)`
+pub fn render(template: &str, field: impl Fn(&str) -> Option<String>) -> anyhow::Result<String> {
+    fill(template, |name| field(name).with_context(|| format!("no `{name}` field")))
+}
+
+fn fill(template: &str, field: impl Fn(&str) -> anyhow::Result<String>) -> anyhow::Result<String> {
+    let mut namespace = String::with_capacity(template.len());
+    let mut rest = template;
+
+    while let Some(start) = rest.find('{') {
+        namespace.push_str(&rest[..start]);
+        let end = rest[start..].find('}').context("unclosed placeholder")? + start;
+        let name = &rest[start + 1..end];
+        namespace.push_str(&field(name)?);
+        rest = &rest[end + 1..];
+    }
+
+    namespace.push_str(rest);
+    Ok(namespace)
+}
+
+/// `value` with `%` and `/` percent-escaped
+fn escape(value: &str) -> String {
+    value.replace('%', "%25").replace('/', "%2F")
+}
+
+/// `value` with the escapes of [escape] undone, leaving any other `%` alone
+fn unescape(value: &str) -> String {
+    let mut unescaped = String::with_capacity(value.len());
+    let mut rest = value;
+    while let Some(start) = rest.find('%') {
+        unescaped.push_str(&rest[..start]);
+        let escaped = rest.get(start..start + 3).unwrap_or("");
+        let (char, len) = match escaped {
+            "%25" => ('%', 3),
+            "%2F" | "%2f" => ('/', 3),
+            _ => ('%', 1),
+        };
+        unescaped.push(char);
+        rest = &rest[start + len..];
+    }
+    unescaped.push_str(rest);
+    unescaped
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+
+    fn fields(fields: &[(&str, &str)]) -> impl Fn(&str) -> Option<String> {
+        let fields: HashMap<String, String> = fields.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect();
+        move |name| fields.get(name).cloned()
+    }
+
+    #[test]
+    fn renders_fields() {
+        let stream = fields(&[("stream_id", "demo"), ("account", "acme")]);
+        assert_eq!(render("earthseed.live/{stream_id}", &stream).unwrap(), "earthseed.live/demo");
+        assert_eq!(render("{account}/{stream_id}/", &stream).unwrap(), "acme/demo/");
+        assert_eq!(render("{account}{stream_id}", &stream).unwrap(), "acmedemo");
+        assert_eq!(render("no placeholders", &stream).unwrap(), "no placeholders");
+        assert_eq!(render("close } alone", &stream).unwrap(), "close } alone");
+    }
+
+    #[test]
+    fn render_errors() {
+        let stream = fields(&[("stream_id", "demo")]);
+        let err = render("live/{stream_id", &stream).unwrap_err();
+        assert_eq!(err.to_string(), "unclosed placeholder");
+        let err = render("{stream_id}/{region}", &stream).unwrap_err();
+        assert_eq!(err.to_string(), "no `region` field");
+        assert!(render("{}", &stream).is_err());
+    }
+
+    #[test]
+    fn encodings() {
+        let stream = fields(&[("account", "acme/eu"), ("stream_id", "100%")]);
+        let template = "{account}/{stream_id}";
+        assert_eq!(NamespaceEncoding::Split.render(template, &stream).unwrap(), "acme/eu/100%");
+        assert_eq!(NamespaceEncoding::Escape.render(template, &stream).unwrap(), "acme%2Feu/100%25");
+        let err = NamespaceEncoding::Reject.render(template, &stream).unwrap_err();
+        assert_eq!(err.to_string(), r#"`account` field "acme/eu" has a `/`"#);
+        assert_eq!(NamespaceEncoding::Reject.render("{stream_id}", &stream).unwrap(), "100%");
+    }
+
+    #[test]
+    fn escape_round_trips() {
+        for value in ["plain", "acme/eu", "100%", "%2F", "%25/%", "a//b", "%", "/", "", "café/%é"] {
+            let escaped = escape(value);
+            assert!(!escaped.contains('/'), "{escaped:?}");
+            assert_eq!(unescape(&escaped), value);
+            let stream = fields(&[("stream_id", value)]);
+            let rendered = NamespaceEncoding::Escape.render("{stream_id}", &stream).unwrap();
+            assert_eq!(NamespaceEncoding::Escape.stream_id(&rendered), value);
+        }
+    }
+
+    #[test]
+    fn unescape_leaves_other_percents() {
+        assert_eq!(unescape("acme%2feu"), "acme/eu");
+        assert_eq!(unescape("acme%2Feu"), "acme/eu");
+        assert_eq!(unescape("50%"), "50%");
+        assert_eq!(unescape("%2"), "%2");
+        assert_eq!(unescape("%20"), "%20");
+        assert_eq!(unescape("%%25"), "%%");
+        assert_eq!(unescape("%é"), "%é");
+        assert_eq!(NamespaceEncoding::Split.stream_id("acme%2Feu"), "acme%2Feu");
+        assert_eq!(NamespaceEncoding::Reject.stream_id("acme%2Feu"), "acme%2Feu");
+    }
+}
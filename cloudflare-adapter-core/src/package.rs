@@ -0,0 +1,463 @@
+//! Live CMAF packaging for HTTP egress
+//!
+//! Each rendition of a packaged broadcast is cut into segments and parts as frames
+//! arrive and kept in a short in-memory window. The HLS and DASH endpoints render
+//! their playlists and manifests from the same windows and serve the same fragments.
+
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{Duration, SystemTime};
+
+#[cfg(feature = "http")]
+use axum::http::{header, StatusCode};
+#[cfg(feature = "http")]
+use axum::response::{IntoResponse, Response};
+use bytes::{Bytes, BytesMut};
+use moq_lite::{BroadcastConsumer, GroupConsumer, Track, TrackConsumer};
+use serde_json::Value;
+use tokio::sync::watch;
+
+use crate::catalog::{self, MediaKind, Rendition, CATALOG_TRACK};
+use crate::media::MediaFrame;
+use crate::mp4::{self, Sample, TrackConfig};
+use crate::record::safe_name;
+
+/// Packaging settings shared by every stream
+#[derive(Clone, Debug)]
+pub struct PackageOptions {
+    /// Segments are cut at the first keyframe after this much media
+    pub segment_duration: Duration,
+    /// Parts never exceed this duration unless a single frame does
+    pub part_duration: Duration,
+    /// How many complete segments each track keeps
+    pub window: usize,
+}
+
+/// Which HTTP formats a stream is served in
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Formats {
+    pub hls: bool,
+    pub dash: bool,
+}
+
+impl Formats {
+    pub fn any(&self) -> bool {
+        self.hls || self.dash
+    }
+}
+
+/// Every stream currently packaged for HTTP egress
+pub struct Packager {
+    pub options: PackageOptions,
+    streams: Mutex<HashMap<String, Arc<PackagedStream>>>,
+}
+
+impl Packager {
+    pub fn new(options: PackageOptions) -> Arc<Self> {
+        Arc::new(Self {
+            options,
+            streams: Default::default(),
+        })
+    }
+
+    /// Package `broadcast` as `stream_id` until it closes
+    pub fn publish(self: &Arc<Self>, stream_id: &str, broadcast: BroadcastConsumer, formats: Formats) {
+        let stream = Arc::new(PackagedStream {
+            formats,
+            tracks: Default::default(),
+            epoch: Default::default(),
+        });
+        self.streams.lock().unwrap().insert(stream_id.to_string(), stream.clone());
+
+        let packager = self.clone();
+        let stream_id = stream_id.to_string();
+        tokio::spawn(async move {
+            if let Err(err) = packager.run_stream(&stream_id, &stream, broadcast).await {
+                tracing::warn!(%err, stream_id, "packaging failed");
+            }
+
+            // Don't remove a newer bridge of the same stream
+            let mut streams = packager.streams.lock().unwrap();
+            if streams.get(&stream_id).is_some_and(|s| Arc::ptr_eq(s, &stream)) {
+                streams.remove(&stream_id);
+            }
+        });
+    }
+
+    pub fn stream(&self, stream_id: &str) -> Option<Arc<PackagedStream>> {
+        self.streams.lock().unwrap().get(stream_id).cloned()
+    }
+
+    /// Follow the catalog and start packaging each new rendition
+    async fn run_stream(&self, stream_id: &str, stream: &PackagedStream, broadcast: BroadcastConsumer) -> anyhow::Result<()> {
+        let mut catalog = broadcast.subscribe_track(&Track::new(CATALOG_TRACK));
+        let mut packaging = HashSet::new();
+
+        tracing::info!(stream_id, hls = stream.formats.hls, dash = stream.formats.dash, "packaging bridge");
+
+        loop {
+            let mut group = tokio::select! {
+                res = catalog.next_group() => match res? {
+                    Some(group) => group,
+                    None => break,
+                },
+                _ = broadcast.closed() => break,
+            };
+
+            let Some(renditions) = group.read_frame().await?.and_then(|frame| catalog::renditions(&frame)) else {
+                continue;
+            };
+
+            for rendition in renditions {
+                if !packaging.insert(rendition.track.clone()) {
+                    continue;
+                }
+
+                let config = match TrackConfig::from_rendition(&rendition) {
+                    Ok(config) => config,
+                    Err(err) => {
+                        tracing::warn!(%err, stream_id, track = %rendition.track, "can't package track");
+                        continue;
+                    }
+                };
+
+                let track = Arc::new(PackagedTrack::new(rendition, &config));
+                stream.tracks.lock().unwrap().push(track.clone());
+
+                let consumer = broadcast.subscribe_track(&Track::new(&track.rendition.track));
+                let segmenter = Segmenter::new(track, &config, &self.options, stream.epoch.clone());
+                tokio::spawn(segmenter.run(consumer));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// The packaged renditions of one stream
+pub struct PackagedStream {
+    pub formats: Formats,
+    tracks: Mutex<Vec<Arc<PackagedTrack>>>,
+    /// The wall clock time of media timestamp zero, set when the first part is cut
+    epoch: Arc<OnceLock<SystemTime>>,
+}
+
+impl PackagedStream {
+    pub fn tracks(&self) -> Vec<Arc<PackagedTrack>> {
+        self.tracks.lock().unwrap().clone()
+    }
+
+    pub fn track(&self, path: &str) -> Option<Arc<PackagedTrack>> {
+        self.tracks.lock().unwrap().iter().find(|t| t.path == path).cloned()
+    }
+
+    pub fn epoch(&self) -> Option<SystemTime> {
+        self.epoch.get().copied()
+    }
+}
+
+/// One packaged rendition
+pub struct PackagedTrack {
+    pub rendition: Rendition,
+    /// The URL path component for this track
+    pub path: String,
+    pub init: Bytes,
+    pub window: watch::Sender<Window>,
+}
+
+impl PackagedTrack {
+    fn new(rendition: Rendition, config: &TrackConfig) -> Self {
+        Self {
+            path: safe_name(&rendition.track),
+            init: mp4::init_segment(config).into(),
+            window: watch::Sender::new(Window::default()),
+            rendition,
+        }
+    }
+
+    pub fn codec(&self) -> Option<&str> {
+        self.rendition.config.get("codec").and_then(Value::as_str)
+    }
+
+    pub fn bitrate(&self) -> u64 {
+        match (self.rendition.config.get("bitrate").and_then(Value::as_u64), self.rendition.kind) {
+            (Some(bitrate), _) => bitrate,
+            // Both formats require a bandwidth, so guess when the catalog doesn't say
+            (None, MediaKind::Video) => 2_000_000,
+            (None, MediaKind::Audio) => 128_000,
+        }
+    }
+
+    /// The coded width and height, for video renditions that list them
+    pub fn dimensions(&self) -> Option<(u64, u64)> {
+        let field = |name: &str| self.rendition.config.get(name).and_then(Value::as_u64);
+        Some((field("codedWidth")?, field("codedHeight")?))
+    }
+}
+
+/// The rolling window of segments for one track
+#[derive(Default)]
+pub struct Window {
+    /// Oldest first; only the last segment may be incomplete
+    pub segments: VecDeque<Segment>,
+    pub ended: bool,
+}
+
+pub struct Segment {
+    /// The media sequence number, counting from zero
+    pub msn: u64,
+    /// The timestamp of the first sample, in microseconds
+    pub start: u64,
+    pub parts: Vec<Part>,
+    pub complete: bool,
+}
+
+pub struct Part {
+    /// Microseconds
+    pub duration: u64,
+    pub independent: bool,
+    pub data: Bytes,
+}
+
+impl Segment {
+    pub fn duration(&self) -> u64 {
+        self.parts.iter().map(|p| p.duration).sum()
+    }
+
+    pub fn data(&self) -> Bytes {
+        let mut data = BytesMut::new();
+        for part in &self.parts {
+            data.extend_from_slice(&part.data);
+        }
+        data.freeze()
+    }
+}
+
+impl Window {
+    pub fn segment(&self, msn: u64) -> Option<&Segment> {
+        let first = self.segments.front()?.msn;
+        self.segments.get(msn.checked_sub(first)? as usize)
+    }
+
+    /// The msn and part index the next part will be published as
+    pub fn next_part(&self) -> (u64, usize) {
+        match self.segments.back() {
+            Some(last) if last.complete => (last.msn + 1, 0),
+            Some(last) => (last.msn, last.parts.len()),
+            None => (0, 0),
+        }
+    }
+
+    /// Returns true once the requested segment (or part of it) has been published
+    pub fn has(&self, msn: u64, part: Option<usize>) -> bool {
+        let (next_msn, next_part) = self.next_part();
+        match part {
+            Some(part) => (msn, part) < (next_msn, next_part),
+            None => msn < next_msn,
+        }
+    }
+
+    /// The longest complete segment, in microseconds
+    pub fn longest(&self) -> u64 {
+        self.segments.iter().filter(|s| s.complete).map(Segment::duration).max().unwrap_or(0)
+    }
+}
+
+/// Cuts a track's frames into parts and segments
+struct Segmenter {
+    track: Arc<PackagedTrack>,
+    epoch: Arc<OnceLock<SystemTime>>,
+    kind: MediaKind,
+    default_duration: u64,
+    segment_target: u64,
+    part_target: u64,
+    window: usize,
+    // The fragment sequence number of the next part
+    sequence: u32,
+    // The most recent frame, waiting for the next one to learn its duration
+    last: Option<(MediaFrame, bool)>,
+    // Frames for the next part, with their durations
+    pending: Vec<(MediaFrame, bool, u64)>,
+}
+
+impl Segmenter {
+    fn new(track: Arc<PackagedTrack>, config: &TrackConfig, options: &PackageOptions, epoch: Arc<OnceLock<SystemTime>>) -> Self {
+        Self {
+            track,
+            epoch,
+            kind: config.kind,
+            default_duration: config.default_duration(),
+            segment_target: options.segment_duration.as_micros() as u64,
+            part_target: options.part_duration.as_micros() as u64,
+            window: options.window.max(1),
+            sequence: 1,
+            last: None,
+            pending: Vec::new(),
+        }
+    }
+
+    /// Package frames until the track ends
+    async fn run(mut self, mut consumer: TrackConsumer) {
+        loop {
+            match consumer.next_group().await {
+                Ok(Some(group)) => {
+                    if let Err(err) = self.read_group(group).await {
+                        tracing::debug!(%err, track = %self.track.rendition.track, "packaging group failed");
+                    }
+                }
+                Ok(None) => break,
+                Err(err) => {
+                    tracing::debug!(%err, track = %self.track.rendition.track, "packaging track failed");
+                    break;
+                }
+            }
+        }
+
+        self.finish();
+    }
+
+    async fn read_group(&mut self, mut group: GroupConsumer) -> anyhow::Result<()> {
+        let mut keyframe = true;
+        while let Some(frame) = group.read_frame().await? {
+            let Some(frame) = MediaFrame::decode(&frame) else {
+                continue;
+            };
+
+            if let Some((last, last_keyframe)) = self.last.take() {
+                let duration = frame.timestamp.saturating_sub(last.timestamp);
+                self.push(last, last_keyframe, duration);
+            }
+
+            self.last = Some((frame, keyframe));
+            keyframe = false;
+        }
+
+        Ok(())
+    }
+
+    fn push(&mut self, frame: MediaFrame, keyframe: bool, duration: u64) {
+        // Video segments have to start on a keyframe, audio can be cut anywhere
+        let boundary = keyframe || self.kind == MediaKind::Audio;
+        if boundary && self.open_duration() >= self.segment_target {
+            self.flush_part();
+            self.close_segment();
+        }
+
+        let pending: u64 = self.pending.iter().map(|(_, _, d)| d).sum();
+        if !self.pending.is_empty() && pending + duration > self.part_target {
+            self.flush_part();
+        }
+
+        self.pending.push((frame, keyframe, duration));
+    }
+
+    /// The duration of the segment being built, including unflushed frames
+    fn open_duration(&self) -> u64 {
+        let pending: u64 = self.pending.iter().map(|(_, _, d)| d).sum();
+        let window = self.track.window.borrow();
+        let published = window.segments.back().filter(|s| !s.complete).map_or(0, Segment::duration);
+        published + pending
+    }
+
+    fn flush_part(&mut self) {
+        if self.pending.is_empty() {
+            return;
+        }
+
+        let samples: Vec<Sample<'_>> = self
+            .pending
+            .iter()
+            .map(|(frame, keyframe, duration)| Sample {
+                timestamp: frame.timestamp,
+                duration: *duration,
+                keyframe: *keyframe,
+                data: &frame.payload,
+            })
+            .collect();
+
+        let start = samples[0].timestamp;
+        let part = Part {
+            duration: samples.iter().map(|s| s.duration).sum(),
+            independent: self.kind == MediaKind::Audio || self.pending[0].1,
+            data: mp4::media_segment(self.sequence, &samples).into(),
+        };
+
+        self.sequence += 1;
+        self.pending.clear();
+
+        // Anchor media time to the wall clock the first time any track produces a part
+        self.epoch.get_or_init(|| {
+            let now = SystemTime::now();
+            now.checked_sub(Duration::from_micros(start)).unwrap_or(SystemTime::UNIX_EPOCH)
+        });
+
+        self.track.window.send_modify(|window| {
+            let (msn, _) = window.next_part();
+            match window.segments.back_mut() {
+                Some(segment) if !segment.complete => segment.parts.push(part),
+                _ => window.segments.push_back(Segment {
+                    msn,
+                    start,
+                    parts: vec![part],
+                    complete: false,
+                }),
+            }
+        });
+    }
+
+    fn close_segment(&mut self) {
+        let max = self.window;
+        self.track.window.send_modify(|window| {
+            if let Some(segment) = window.segments.back_mut() {
+                segment.complete = true;
+            }
+
+            while window.segments.len() > max {
+                window.segments.pop_front();
+            }
+        });
+    }
+
+    fn finish(mut self) {
+        if let Some((last, keyframe)) = self.last.take() {
+            let duration = self.default_duration;
+            self.push(last, keyframe, duration);
+        }
+
+        self.flush_part();
+        self.close_segment();
+        self.track.window.send_modify(|window| window.ended = true);
+    }
+}
+
+/// Serve `init.mp4` or a complete `{msn}.m4s` segment of a track
+#[cfg(feature = "http")]
+pub fn serve_fragment(track: &PackagedTrack, file: &str) -> Response {
+    if file == "init.mp4" {
+        return respond("video/mp4", track.init.clone());
+    }
+
+    let Some(msn) = file.strip_suffix(".m4s").and_then(|msn| msn.parse().ok()) else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+
+    let window = track.window.borrow();
+    match window.segment(msn).filter(|s| s.complete) {
+        Some(segment) => respond("video/mp4", segment.data()),
+        None => StatusCode::NOT_FOUND.into_response(),
+    }
+}
+
+#[cfg(feature = "http")]
+pub fn respond(content_type: &'static str, body: impl Into<axum::body::Body>) -> Response {
+    (
+        [
+            (header::CONTENT_TYPE, content_type),
+            (header::CACHE_CONTROL, "no-cache"),
+            // Web players are usually served from another origin
+            (header::ACCESS_CONTROL_ALLOW_ORIGIN, "*"),
+        ],
+        body.into(),
+    )
+        .into_response()
+}
@@ -0,0 +1,247 @@
+//! Relay publish paths
+//!
+//! Stream IDs and mapped paths come from the registry, so they're checked before any
+//! broadcast is published under them. Surrounding slashes are trimmed, and a path
+//! must be made of non-empty segments other than `.` and `..`, without control
+//! characters, in at most [MAX_LEN] bytes.
+//!
+//! A path can also collide with a broadcast that's already published, by another of
+//! our bridges, an SRT ingest, or another node as seen in the relay's announcements.
+//! `--path-collision` decides whether the stream is refused until the path frees up,
+//! or published under the first free `<path>-2`, `<path>-3`... Collisions are counted
+//! in the `path_collisions_total` metric.
+
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use moq_lite::{OriginConsumer, OriginProducer};
+
+use crate::metrics::Counter;
+
+/// The longest path we publish under
+pub const MAX_LEN: usize = 256;
+
+/// The most suffixes tried for a colliding path
+const MAX_SUFFIX: u32 = 16;
+
+/// How long the relay may keep announcing a broadcast after one of our bridges ended it
+const ECHO_WINDOW: Duration = Duration::from_secs(30);
+
+/// What to do when a stream's path is already published
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum Collision {
+    /// Don't bridge the stream until the path is free
+    #[default]
+    Refuse,
+    /// Publish under the path with the first free `-N` suffix
+    Suffix,
+}
+
+/// Check a registry-provided path, trimming its surrounding slashes
+pub fn sanitize(path: &str) -> anyhow::Result<String> {
+    let path = path.trim_matches('/');
+
+    anyhow::ensure!(!path.is_empty(), "empty path");
+    anyhow::ensure!(path.len() <= MAX_LEN, "path is longer than {MAX_LEN} bytes");
+    anyhow::ensure!(!path.chars().any(char::is_control), "path contains control characters");
+    anyhow::ensure!(
+        path.split('/').all(|segment| !matches!(segment, "" | "." | "..")),
+        "path has an empty, `.` or `..` segment"
+    );
+
+    Ok(path.to_string())
+}
+
+/// The paths our bridges publish under
+pub struct PathClaims {
+    policy: Collision,
+    /// What we publish to the relay
+    local: OriginProducer,
+    /// What the relay announces, including other nodes' broadcasts
+    remote: OriginProducer,
+    state: Arc<Mutex<ClaimState>>,
+}
+
+#[derive(Default)]
+struct ClaimState {
+    claimed: HashSet<String>,
+    /// Paths our bridges let go of, and when
+    released: HashMap<String, Instant>,
+}
+
+impl PathClaims {
+    pub fn new(policy: Collision, local: OriginProducer, remote: OriginProducer) -> Self {
+        Self {
+            policy,
+            local,
+            remote,
+            state: Default::default(),
+        }
+    }
+
+    /// Claim `path` for `stream_id`, or a suffixed one if that's the policy
+    pub fn claim(&self, stream_id: &str, path: &str) -> anyhow::Result<PathClaim> {
+        let mut state = self.state.lock().unwrap();
+        state.released.retain(|_, at| at.elapsed() < ECHO_WINDOW);
+
+        // Fresh consumers, so we don't keep announcements queued between claims
+        let published = [self.local.consume(), self.remote.consume()];
+        let taken = |path: &str| taken(&state, &published, path);
+
+        let chosen = if !taken(path) {
+            path.to_string()
+        } else {
+            Counter::new("path_collisions_total", "Streams whose publish path was already published", &[]).inc();
+            match self.policy {
+                Collision::Refuse => anyhow::bail!("{path} is already published"),
+                Collision::Suffix => (2..=MAX_SUFFIX)
+                    .map(|n| format!("{path}-{n}"))
+                    .find(|path| !taken(path))
+                    .ok_or_else(|| anyhow::anyhow!("{path} and its suffixes are already published"))?,
+            }
+        };
+
+        if chosen != path {
+            tracing::warn!(stream_id, path, chosen, "publish path already published, suffixing");
+        }
+
+        state.claimed.insert(chosen.clone());
+        Ok(PathClaim {
+            path: chosen,
+            state: self.state.clone(),
+        })
+    }
+}
+
+fn taken(state: &ClaimState, published: &[OriginConsumer], path: &str) -> bool {
+    if state.claimed.contains(path) {
+        return true;
+    }
+
+    // A broadcast one of our bridges just ended may still be on its way out
+    if state.released.contains_key(path) {
+        return false;
+    }
+
+    published.iter().any(|origin| origin.consume_broadcast(path).is_some())
+}
+
+/// A path held by one bridge, released when dropped
+pub struct PathClaim {
+    path: String,
+    state: Arc<Mutex<ClaimState>>,
+}
+
+impl PathClaim {
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+}
+
+impl Drop for PathClaim {
+    fn drop(&mut self) {
+        let mut state = self.state.lock().unwrap();
+        state.claimed.remove(&self.path);
+        state.released.insert(self.path.clone(), Instant::now());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use moq_lite::{Broadcast, BroadcastProducer, Origin};
+
+    use super::*;
+
+    fn claims(policy: Collision) -> (PathClaims, OriginProducer) {
+        let (local, remote) = (Origin::produce(), Origin::produce());
+        (PathClaims::new(policy, local.producer, remote.producer.clone()), remote.producer)
+    }
+
+    /// Publish a broadcast at `path` until the producer is dropped
+    fn publish(origin: &OriginProducer, path: &str) -> BroadcastProducer {
+        let broadcast = Broadcast::produce();
+        origin.publish_broadcast(path, broadcast.consumer);
+        broadcast.producer
+    }
+
+    #[test]
+    fn sanitizes() {
+        assert_eq!(sanitize("live/demo").unwrap(), "live/demo");
+        assert_eq!(sanitize("/live/demo/").unwrap(), "live/demo");
+        assert_eq!(sanitize("//demo//").unwrap(), "demo");
+        assert_eq!(sanitize("live/..demo/a.b").unwrap(), "live/..demo/a.b");
+        assert_eq!(sanitize("café").unwrap(), "café");
+
+        for path in ["", "/", "///", "live//demo", "./demo", "live/.", "live/../admin", ".."] {
+            assert!(sanitize(path).is_err(), "{path:?}");
+        }
+        for path in ["live/de\nmo", "live\0", "\u{7f}", "live/\u{85}"] {
+            let err = sanitize(path).unwrap_err();
+            assert_eq!(err.to_string(), "path contains control characters", "{path:?}");
+        }
+    }
+
+    #[test]
+    fn sanitize_limits_length() {
+        let longest = "a".repeat(MAX_LEN);
+        assert_eq!(sanitize(&longest).unwrap(), longest);
+        // The slashes are trimmed before measuring
+        assert_eq!(sanitize(&format!("/{longest}/")).unwrap(), longest);
+        assert!(sanitize(&"a".repeat(MAX_LEN + 1)).is_err());
+        // Bytes, not characters
+        assert!(sanitize(&"é".repeat(MAX_LEN / 2 + 1)).is_err());
+    }
+
+    #[test]
+    fn refuses_claimed_paths() {
+        let (claims, _) = claims(Collision::Refuse);
+        let claim = claims.claim("a", "live/demo").unwrap();
+        assert_eq!(claim.path(), "live/demo");
+        let err = claims.claim("b", "live/demo").err().unwrap();
+        assert_eq!(err.to_string(), "live/demo is already published");
+        assert_eq!(claims.claim("b", "live/other").unwrap().path(), "live/other");
+
+        drop(claim);
+        assert_eq!(claims.claim("b", "live/demo").unwrap().path(), "live/demo");
+    }
+
+    #[tokio::test]
+    async fn suffixes_taken_paths() {
+        let (claims, remote) = claims(Collision::Suffix);
+        let _other_node = publish(&remote, "live/demo");
+        let second = claims.claim("a", "live/demo").unwrap();
+        assert_eq!(second.path(), "live/demo-2");
+        let third = claims.claim("b", "live/demo").unwrap();
+        assert_eq!(third.path(), "live/demo-3");
+
+        // The first free suffix, not the next one
+        drop(second);
+        assert_eq!(claims.claim("c", "live/demo").unwrap().path(), "live/demo-2");
+    }
+
+    #[test]
+    fn gives_up_after_the_last_suffix() {
+        let (claims, _) = claims(Collision::Suffix);
+        let held: Vec<PathClaim> = (1..=MAX_SUFFIX).map(|n| claims.claim(&n.to_string(), "demo").unwrap()).collect();
+        assert_eq!(held.last().unwrap().path(), format!("demo-{MAX_SUFFIX}"));
+        let err = claims.claim("last", "demo").err().unwrap();
+        assert_eq!(err.to_string(), "demo and its suffixes are already published");
+    }
+
+    #[tokio::test]
+    async fn released_paths_ignore_their_echo() {
+        let (claims, remote) = claims(Collision::Refuse);
+        let claim = claims.claim("a", "live/demo").unwrap();
+        // The relay still announces the broadcast our bridge published
+        let _echo = publish(&remote, "live/demo");
+        drop(claim);
+        let claim = claims.claim("a", "live/demo").unwrap();
+        drop(claim);
+
+        // Past the window, it's someone else's
+        let past = Instant::now().checked_sub(ECHO_WINDOW + Duration::from_secs(1)).unwrap();
+        claims.state.lock().unwrap().released.insert("live/demo".to_string(), past);
+        assert!(claims.claim("a", "live/demo").is_err());
+    }
+}
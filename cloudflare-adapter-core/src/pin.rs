@@ -0,0 +1,191 @@
+//! Public key pinning
+//!
+//! Any CA the host trusts can issue a certificate for CloudFlare's hostname, so one
+//! compromised or coerced CA would let an impostor relay feed content into our network
+//! through the adapter. With `--cf-pin`, a CF connection is only used once the chain checks
+//! out as usual and its certificate's key is also one of the pins: the base64 SHA-256 of
+//! its SubjectPublicKeyInfo, as `sha256//...` like curl's `--pinnedpubkey`, the same value as
+//! HPKP's `pin-sha256`. Give a backup pin for the next key ahead of rotating it.
+//!
+//! The key is checked right after the handshake, before the session setup is sent, and for
+//! 0-RTT connections, which resume a session with a server that was checked, before the
+//! session is used. A mismatch fails the connection like any other TLS error, logging the
+//! key's own pin, and is counted in `pin_mismatches_total`.
+
+use std::fmt;
+use std::str::FromStr;
+
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+use moq_native::web_transport_quinn::quinn;
+use moq_native::web_transport_quinn::quinn::rustls::pki_types::CertificateDer;
+
+use crate::metrics::Counter;
+
+/// The SHA-256 of a SubjectPublicKeyInfo
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct SpkiPin([u8; 32]);
+
+impl FromStr for SpkiPin {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let encoded = s.strip_prefix("sha256//").or_else(|| s.strip_prefix("sha256/")).unwrap_or(s);
+        let digest = STANDARD.decode(encoded).map_err(|err| format!("{s:?} isn't base64: {err}"))?;
+        let digest = digest.try_into().map_err(|_| format!("{s:?} isn't a SHA-256 digest"))?;
+        Ok(Self(digest))
+    }
+}
+
+impl fmt::Display for SpkiPin {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "sha256//{}", STANDARD.encode(self.0))
+    }
+}
+
+impl fmt::Debug for SpkiPin {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(self, f)
+    }
+}
+
+/// Fail unless `quic`'s peer presented a key in `pins`, if there are any
+pub fn check(quic: &quinn::Connection, host: &str, pins: &[SpkiPin]) -> anyhow::Result<()> {
+    if pins.is_empty() {
+        return Ok(());
+    }
+
+    let certs = quic.peer_identity().and_then(|identity| identity.downcast::<Vec<CertificateDer<'static>>>().ok());
+    let cert = certs.as_deref().and_then(|certs| certs.first());
+    let Some(pin) = cert.and_then(|cert| pin(cert)) else {
+        anyhow::bail!("can't read the key of {host}'s certificate to check its pin");
+    };
+
+    if !pins.contains(&pin) {
+        Counter::new("pin_mismatches_total", "Connections whose key wasn't pinned", &[("host", host)]).inc();
+        quic.close(0u32.into(), b"unpinned key");
+        anyhow::bail!("{host}'s certificate key {pin} isn't pinned");
+    }
+    Ok(())
+}
+
+/// The pin of a DER certificate's key
+fn pin(cert: &[u8]) -> Option<SpkiPin> {
+    let digest = ring::digest::digest(&ring::digest::SHA256, spki(cert)?);
+    Some(SpkiPin(digest.as_ref().try_into().expect("SHA-256 digests are 32 bytes")))
+}
+
+/// The SubjectPublicKeyInfo of a DER certificate
+fn spki(cert: &[u8]) -> Option<&[u8]> {
+    let (_, cert, _) = element(cert)?;
+    let (_, tbs, _) = element(cert)?;
+
+    let mut fields = Vec::new();
+    let mut rest = tbs;
+    while let Some((tag, _, after)) = element(rest) {
+        fields.push((tag, &rest[..rest.len() - after.len()]));
+        rest = after;
+    }
+    // The version is optional, then the serial, signature, issuer, validity and subject
+    let skip = if fields.first()?.0 == 0xa0 { 6 } else { 5 };
+    fields.get(skip).map(|(_, spki)| *spki)
+}
+
+/// The DER element at the start of `der`: its tag, its contents and what follows it
+fn element(der: &[u8]) -> Option<(u8, &[u8], &[u8])> {
+    let (&tag, rest) = der.split_first()?;
+    let (&first, rest) = rest.split_first()?;
+    let (len, rest) = match first {
+        0..=0x7f => (first as usize, rest),
+        0x81..=0x84 => {
+            let (len, rest) = rest.split_at_checked((first & 0x7f) as usize)?;
+            (len.iter().fold(0, |len, &byte| len << 8 | byte as usize), rest)
+        }
+        _ => return None,
+    };
+    let (contents, after) = rest.split_at_checked(len)?;
+    Some((tag, contents, after))
+}
+
+#[cfg(test)]
+mod tests {
+    use rcgen::PublicKeyData;
+
+    use super::*;
+
+    /// A P-256 certificate for `relay.example` made with `openssl req -x509`
+    const CERT: &str = "\
+        MIIBgzCCASugAwIBAgIUJNBHDPdVal6gKvoyfiOQZH4y100wCgYIKoZIzj0EAwIwGDEWMBQGA1UEAwwNcmVsYXkuZXhhbXBsZTAeFw0y\
+        NjEwMTQxNTMzNDJaFw0zNjEwMTExNTMzNDJaMBgxFjAUBgNVBAMMDXJlbGF5LmV4YW1wbGUwWTATBgcqhkjOPQIBBggqhkjOPQMBBwNC\
+        AARVaZGwmaxu/we1PISpiOp22aMOazpAKv2iljBlqIEJCJlTx1LeGykubDPT5hKpufSWSadphfwH0Mwvhp9SodElo1MwUTAdBgNVHQ4E\
+        FgQUsQI4Dzf6thhqnGetsKQB+WP5xzcwHwYDVR0jBBgwFoAUsQI4Dzf6thhqnGetsKQB+WP5xzcwDwYDVR0TAQH/BAUwAwEB/zAKBggq\
+        hkjOPQQDAgNGADBDAh9PDJtKiWxuKyRTdiG9sKcJ8bMqRqs8ubcOydc7SX7sAiAfUmSJJJHNUg//J4gcKQVz8BLikSB4ZWyaRKtxeTvm\
+        7A==";
+
+    /// Its pin, from `openssl x509 -pubkey | openssl pkey -pubin -outform der | openssl dgst -sha256 -binary`
+    const PIN: &str = "sha256//KRekxbxcuc11yu4qKfW8I/hprNjP6VXoDVcDZdZO8eA=";
+
+    /// The same certificate without its optional version field, as a v1 certificate
+    const CERT_V1: &str = "\
+        MIIBfjCCASYCFCTQRwz3VWpeoCr6Mn4jkGR+MtdNMAoGCCqGSM49BAMCMBgxFjAUBgNVBAMMDXJlbGF5LmV4YW1wbGUwHhcNMjYxMDE0\
+        MTUzMzQyWhcNMzYxMDExMTUzMzQyWjAYMRYwFAYDVQQDDA1yZWxheS5leGFtcGxlMFkwEwYHKoZIzj0CAQYIKoZIzj0DAQcDQgAEVWmR\
+        sJmsbv8HtTyEqYjqdtmjDms6QCr9opYwZaiBCQiZU8dS3hspLmwz0+YSqbn0lkmnaYX8B9DML4afUqHRJaNTMFEwHQYDVR0OBBYEFLEC\
+        OA83+rYYapxnrbCkAflj+cc3MB8GA1UdIwQYMBaAFLECOA83+rYYapxnrbCkAflj+cc3MA8GA1UdEwEB/wQFMAMBAf8wCgYIKoZIzj0E\
+        AwIDRgAwQwIfTwybSolsbiskU3YhvbCnCfGzKkarPLm3DsnXO0l+7AIgH1JkiSSRzVIP/yeIHCkFc/AS4pEgeGVsmkSrcXk75uw=";
+
+    fn der(base64: &str) -> Vec<u8> {
+        STANDARD.decode(base64).unwrap()
+    }
+
+    #[test]
+    fn pins_a_known_certificate() {
+        let expected: SpkiPin = PIN.parse().unwrap();
+        assert_eq!(pin(&der(CERT)), Some(expected));
+        assert_eq!(pin(&der(CERT_V1)), Some(expected));
+        assert_eq!(expected.to_string(), PIN);
+    }
+
+    #[test]
+    fn finds_generated_keys() {
+        for _ in 0..4 {
+            let generated = rcgen::generate_simple_self_signed(vec!["relay.example".to_string()]).unwrap();
+            let key = generated.signing_key.subject_public_key_info();
+            assert_eq!(spki(generated.cert.der()), Some(key.as_slice()));
+        }
+    }
+
+    #[test]
+    fn rejects_truncated_certificates() {
+        let cert = der(CERT);
+        for len in 0..cert.len() {
+            assert_eq!(spki(&cert[..len]), None, "{len} bytes");
+        }
+        // The version, serial, signature, issuer, validity and subject, and then no key
+        let fields = [0xa0, 0, 0x02, 1, 1, 0x30, 0, 0x30, 0, 0x30, 0, 0x30, 0];
+        assert_eq!(spki(&[&[0x30, 15, 0x30, 13][..], &fields].concat()), None);
+        let with_key = [&[0x30, 17, 0x30, 15][..], &fields, &[0x30, 0]].concat();
+        assert_eq!(spki(&with_key), Some(&[0x30, 0][..]));
+    }
+
+    #[test]
+    fn reads_lengths() {
+        assert_eq!(element(&[0x04, 0x02, 1, 2, 3]), Some((0x04, &[1, 2][..], &[3][..])));
+        assert_eq!(element(&[0x04, 0x00]), Some((0x04, &[][..], &[][..])));
+
+        let long = [&[0x04, 0x81, 0x80][..], &[7; 0x80], &[9]].concat();
+        let (tag, contents, rest) = element(&long).unwrap();
+        assert_eq!((tag, contents.len(), rest), (0x04, 0x80, &[9][..]));
+        let longer = [&[0x04, 0x82, 0x01, 0x00][..], &[7; 0x100]].concat();
+        assert_eq!(element(&longer).map(|(_, contents, _)| contents.len()), Some(0x100));
+
+        // Longer than what's there
+        assert_eq!(element(&[0x04, 0x03, 1, 2]), None);
+        assert_eq!(element(&[0x04, 0x82, 0x01]), None);
+        assert_eq!(element(&[0x04, 0x84, 0xff, 0xff, 0xff, 0xff, 0]), None);
+        // Indefinite, and lengths of lengths past four bytes
+        assert_eq!(element(&[0x30, 0x80, 0, 0]), None);
+        assert_eq!(element(&[0x04, 0x85, 0, 0, 0, 0, 1, 0]), None);
+        assert_eq!(element(&[0x04]), None);
+        assert_eq!(element(&[]), None);
+    }
+}
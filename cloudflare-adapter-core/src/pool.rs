@@ -0,0 +1,177 @@
+//! CloudFlare session pool
+//!
+//! A single CF session runs into its per-connection flow-control limits at around 30
+//! active streams, so with `--cf-sessions` we keep several sessions open and spread
+//! bridges across them. Each new bridge goes to the connected session carrying the
+//! fewest bridges.
+//!
+//! When a session dies, the broadcasts it was serving close and their bridges end.
+//! The next registry poll bridges those streams again on whichever sessions are up,
+//! and a session that reconnects picks up new bridges first since it carries none.
+//!
+//! Each slot publishes its session on a watch channel, so bridges pick a session
+//! without taking a lock and the bridge manager hears as soon as one connects.
+//! Leases only hold on to their session while they use it, so the slot can close it
+//! whenever it likes.
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Weak};
+use std::time::Duration;
+
+use moq_lite::Session;
+use tokio::sync::{watch, Notify};
+
+use crate::shutdown;
+
+/// The CF sessions shared by every bridge
+pub struct SessionPool {
+    slots: Vec<Slot>,
+    // How many slots have a session
+    connected: watch::Sender<usize>,
+}
+
+struct Slot {
+    session: watch::Sender<Option<Arc<Session>>>,
+    bridges: AtomicUsize,
+    /// Closes the session, see [SessionPool::kill]
+    kill: Notify,
+}
+
+impl SessionPool {
+    pub fn new(size: usize) -> Arc<Self> {
+        let slots = (0..size.max(1))
+            .map(|_| Slot {
+                session: watch::Sender::new(None),
+                bridges: AtomicUsize::new(0),
+                kill: Notify::new(),
+            })
+            .collect();
+
+        Arc::new(Self {
+            slots,
+            connected: watch::Sender::new(0),
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.slots.len()
+    }
+
+    /// The bridges each slot carries, in slot order
+    pub fn loads(&self) -> Vec<usize> {
+        self.slots.iter().map(|slot| slot.bridges.load(Ordering::Relaxed)).collect()
+    }
+
+    /// Publish the session for one slot, and hold it there until it closes
+    ///
+    /// Returns true if we closed it ourselves, because `closing` was set.
+    pub async fn serve(&self, index: usize, session: Session, mut closing: watch::Receiver<bool>) -> bool {
+        let session = Arc::new(session);
+        self.slots[index].session.send_replace(Some(session.clone()));
+        self.connected.send_modify(|connected| *connected += 1);
+
+        let (close, closed) = tokio::select! {
+            _ = session.closed() => (false, false),
+            _ = self.slots[index].kill.notified() => (true, false),
+            _ = shutdown::closing(&mut closing) => (true, true),
+        };
+
+        self.slots[index].session.send_replace(None);
+        self.connected.send_modify(|connected| *connected -= 1);
+
+        if close {
+            close_session(session).await;
+        }
+        closed
+    }
+
+    /// Close one of the connected sessions at random, returning its index
+    ///
+    /// Its bridges see it drop like any other lost session, and it reconnects with backoff.
+    pub fn kill(&self) -> Option<usize> {
+        let connected: Vec<_> = (0..self.slots.len()).filter(|&i| self.slots[i].session.borrow().is_some()).collect();
+        let index = *connected.get(rand::random_range(0..connected.len().max(1)))?;
+        self.slots[index].kill.notify_waiters();
+        Some(index)
+    }
+
+    /// Follow how many sessions are connected
+    pub fn connected(&self) -> watch::Receiver<usize> {
+        self.connected.subscribe()
+    }
+
+    /// Assign a bridge to the least loaded connected session, or None if none are connected
+    ///
+    /// The bridge counts against the session until the lease is dropped.
+    pub fn lease(self: &Arc<Self>) -> Option<SessionLease> {
+        let mut best: Option<(usize, Arc<Session>)> = None;
+
+        for (index, slot) in self.slots.iter().enumerate() {
+            let Some(session) = slot.session.borrow().clone() else {
+                continue;
+            };
+
+            let load = slot.bridges.load(Ordering::Relaxed);
+            if best.as_ref().is_none_or(|(i, _)| load < self.slots[*i].bridges.load(Ordering::Relaxed)) {
+                best = Some((index, session));
+            }
+        }
+
+        let (index, session) = best?;
+        self.slots[index].bridges.fetch_add(1, Ordering::Relaxed);
+
+        Some(SessionLease {
+            pool: self.clone(),
+            index,
+            session: Arc::downgrade(&session),
+        })
+    }
+}
+
+/// Close a session once leases are done using it, which they only do for a moment
+async fn close_session(mut session: Arc<Session>) {
+    loop {
+        match Arc::try_unwrap(session) {
+            Ok(session) => return session.close(moq_lite::Error::Cancel),
+            Err(shared) => session = shared,
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+}
+
+/// Resolve the next time a session connects, given a receiver from [SessionPool::connected]
+pub async fn next_connect(connected: &mut watch::Receiver<usize>) {
+    loop {
+        let before = *connected.borrow_and_update();
+        if connected.changed().await.is_err() {
+            return std::future::pending().await;
+        }
+        if *connected.borrow() > before {
+            return;
+        }
+    }
+}
+
+/// A bridge's claim on one session of the pool
+pub struct SessionLease {
+    pool: Arc<SessionPool>,
+    index: usize,
+    session: Weak<Session>,
+}
+
+impl SessionLease {
+    /// The session, or None once it's gone
+    pub fn session(&self) -> Option<Arc<Session>> {
+        self.session.upgrade()
+    }
+
+    pub fn index(&self) -> usize {
+        self.index
+    }
+}
+
+impl Drop for SessionLease {
+    fn drop(&mut self) {
+        self.pool.slots[self.index].bridges.fetch_sub(1, Ordering::Relaxed);
+    }
+}
@@ -0,0 +1,116 @@
+//! Pre-warming streams ahead of go-live
+//!
+//! A bridge for a stream that isn't live on CloudFlare yet fails to announce and waits for
+//! the next poll to try again, so a scheduled event's first viewers wait for the poll and
+//! the whole setup on top of the publisher going live. Streams the registry flags
+//! `starting_soon`, and the `--prewarm` ones whether listed or not, aren't bridged until
+//! they're live: they're kept announced on a CF session instead, so CloudFlare subscribes
+//! as soon as the publisher shows up, and the moment their broadcast does the manager polls
+//! again and bridges them, finding the broadcast already there.
+
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use moq_lite::OriginConsumer;
+use tokio::sync::{Notify, Semaphore};
+use tokio::task::AbortHandle;
+
+use crate::announce::{self, AnnounceOptions};
+use crate::error::BridgeError;
+use crate::metrics::{Counter, Gauge};
+use crate::pool::{self, SessionPool};
+
+/// Keeps the streams about to go live announced on CloudFlare
+pub struct Prewarmer {
+    sessions: Arc<SessionPool>,
+    origin: OriginConsumer,
+    limit: Arc<Semaphore>,
+    /// Woken when a warming stream goes live, to poll and bridge it
+    wake: Arc<Notify>,
+    warming: HashMap<String, Warming>,
+}
+
+struct Warming {
+    namespace: String,
+    task: AbortHandle,
+    live: Arc<AtomicBool>,
+}
+
+impl Drop for Warming {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+impl Prewarmer {
+    pub fn new(sessions: Arc<SessionPool>, origin: OriginConsumer, limit: Arc<Semaphore>, wake: Arc<Notify>) -> Self {
+        Self {
+            sessions,
+            origin,
+            limit,
+            wake,
+            warming: HashMap::new(),
+        }
+    }
+
+    /// Warm these streams, as `(stream_id, namespace)`, and stop warming any others
+    pub fn sync(&mut self, streams: impl IntoIterator<Item = (String, String)>, options: AnnounceOptions) {
+        let streams: HashMap<_, _> = streams.into_iter().collect();
+        self.warming.retain(|stream_id, warming| streams.get(stream_id) == Some(&warming.namespace));
+
+        for (stream_id, namespace) in streams {
+            if self.warming.contains_key(&stream_id) {
+                continue;
+            }
+            tracing::info!(stream_id, namespace, "pre-warming stream");
+            let live = Arc::new(AtomicBool::new(false));
+            let task = tokio::spawn({
+                let (sessions, origin, limit) = (self.sessions.clone(), self.origin.clone(), self.limit.clone());
+                let (stream_id, namespace) = (stream_id.clone(), namespace.clone());
+                let (live, wake) = (live.clone(), self.wake.clone());
+                async move {
+                    warm(&sessions, &origin, &namespace, options, &limit).await;
+                    tracing::info!(stream_id, namespace, "pre-warmed stream is live");
+                    Counter::new("prewarmed_streams_total", "Pre-warmed streams that went live", &[]).inc();
+                    live.store(true, Ordering::Relaxed);
+                    wake.notify_one();
+                }
+            });
+            self.warming.insert(stream_id, Warming { namespace, task: task.abort_handle(), live });
+        }
+        Gauge::new("prewarming_streams", "Streams kept announced on CloudFlare ahead of go-live", &[])
+            .set(self.warming.len() as i64);
+    }
+
+    /// Whether `stream_id` is being warmed and isn't live yet, so isn't to be bridged
+    pub fn holds(&self, stream_id: &str) -> bool {
+        self.warming.get(stream_id).is_some_and(|warming| !warming.live.load(Ordering::Relaxed))
+    }
+}
+
+/// Announce `namespace` until its broadcast shows up
+async fn warm(
+    sessions: &Arc<SessionPool>,
+    origin: &OriginConsumer,
+    namespace: &str,
+    options: AnnounceOptions,
+    limit: &Semaphore,
+) {
+    let mut connected = sessions.connected();
+    loop {
+        let Some(lease) = sessions.lease() else {
+            pool::next_connect(&mut connected).await;
+            continue;
+        };
+        match announce::announce(&lease, origin, namespace, options, limit).await {
+            Ok(_) => return,
+            Err(BridgeError::NotFound { .. }) => {}
+            Err(err) => {
+                tracing::debug!(%err, namespace, "failed to pre-warm stream");
+                drop(lease);
+                tokio::time::sleep(options.timeout).await;
+            }
+        }
+    }
+}
@@ -0,0 +1,84 @@
+//! Track priority overrides
+//!
+//! When a link congests, MoQ sends the groups of higher-priority tracks first, and the
+//! priorities come from whoever subscribes: players take them from the catalog, and the
+//! relay passes theirs on to us. Publishers set them however they like, so viewers can lose
+//! audio to a video spike. `--video-priority`, `--audio-priority` and `--data-priority`
+//! override them per kind of track, and `--track-priority <pattern>=<priority>` for the
+//! upstream tracks matching the pattern, ahead of the kinds. Higher is more important, up
+//! to 255.
+//!
+//! Overrides are written into the catalog the relay-side players read, so they subscribe
+//! through the relay at our priorities, and used for our subscriptions to CloudFlare. The
+//! catalog's older list layout has a priority per rendition; its current one has a priority
+//! per kind, so a track override there applies to every rendition of the kind, the highest
+//! one winning.
+
+use std::str::FromStr;
+
+use serde_json::Value;
+
+use crate::catalog::MediaKind;
+use crate::filter::glob_match;
+
+/// A `--track-priority`, as `pattern=priority`
+#[derive(Clone, Debug)]
+pub struct TrackPriority {
+    pub pattern: String,
+    pub priority: u8,
+}
+
+impl FromStr for TrackPriority {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (pattern, priority) = s.split_once('=').ok_or("expected pattern=priority")?;
+        let priority = priority.parse().map_err(|err| format!("invalid priority {priority:?}: {err}"))?;
+        Ok(Self {
+            pattern: pattern.to_string(),
+            priority,
+        })
+    }
+}
+
+/// The priorities to give tracks in place of their publisher's
+#[derive(Clone, Debug, Default)]
+pub struct PriorityOverrides {
+    pub video: Option<u8>,
+    pub audio: Option<u8>,
+    /// Tracks the catalog doesn't list as video or audio
+    pub data: Option<u8>,
+    pub tracks: Vec<TrackPriority>,
+}
+
+impl PriorityOverrides {
+    /// The priority of an upstream track of `kind`, or None to keep the one asked for
+    pub fn get(&self, track: &str, kind: Option<MediaKind>) -> Option<u8> {
+        let pattern = self.tracks.iter().find(|rule| glob_match(&rule.pattern, track));
+        pattern.map(|rule| rule.priority).or(match kind {
+            Some(MediaKind::Video) => self.video,
+            Some(MediaKind::Audio) => self.audio,
+            None => self.data,
+        })
+    }
+
+    /// Write the priorities into a catalog section of `kind`, in either layout
+    pub(crate) fn rewrite(&self, section: &mut Value, kind: MediaKind) {
+        if let Some(list) = section.as_array_mut() {
+            for entry in list {
+                let name = entry.pointer("/track/name").and_then(Value::as_str);
+                let priority = name.and_then(|name| self.get(name, Some(kind)));
+                if let (Some(priority), Some(track)) = (priority, entry.get_mut("track").and_then(Value::as_object_mut)) {
+                    track.insert("priority".to_string(), priority.into());
+                }
+            }
+        } else if let Some(section) = section.as_object_mut() {
+            let renditions = section.get("renditions").and_then(Value::as_object);
+            let names = renditions.into_iter().flat_map(|renditions| renditions.keys());
+            let priority = names.filter_map(|name| self.get(name, Some(kind))).max();
+            if let Some(priority) = priority {
+                section.insert("priority".to_string(), priority.into());
+            }
+        }
+    }
+}
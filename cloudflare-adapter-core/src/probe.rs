@@ -0,0 +1,202 @@
+//! End-to-end latency probes
+//!
+//! With `--latency-probe <secs>`, every bridge times one frame every `secs`, in two
+//! stages, to show where delay is added:
+//!
+//! - `adapter`: from reading the frame from CF to writing it to the relay-side
+//!   broadcast, i.e. our own buffering, transforms and hooks
+//! - `relay`: from there to the relay session handing it back. Once the frame is
+//!   written, a probe naming it goes out on a `.probe` track next to the bridged ones,
+//!   and we read it back from the relay, which announces our own broadcasts to us
+//!   like any other. That's a round trip through the relay connection and the relay.
+//!
+//! The last probe of each bridge is exported as `bridge_latency_us{stream_id,stage}`
+//! and in its [BridgeStats](crate::BridgeStats). The `.probe` track isn't added to
+//! the catalog, so players never see it.
+
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use bytes::{Buf, Bytes};
+use moq_lite::{BroadcastConsumer, OriginConsumer, Path, Track, TrackConsumer, TrackProducer};
+
+use crate::health::BridgeHealth;
+use crate::inject::Injector;
+use crate::metrics::Gauge;
+
+/// The relay-side track probes go out on
+pub const PROBE_TRACK: &str = ".probe";
+
+/// Probes the relay hasn't handed back yet, beyond which the oldest are given up on
+const MAX_PENDING: usize = 16;
+
+/// How long the last probe of a bridge took
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ProbeLatency {
+    /// From reading the frame from CF to writing it to the relay-side broadcast
+    pub adapter: Duration,
+    /// From writing it there to reading its probe back from the relay, None until it's back
+    pub relay: Option<Duration>,
+}
+
+/// Times frames of one bridge on their way through
+pub struct LatencyProbe {
+    stream_id: Arc<str>,
+    every: Duration,
+    health: Arc<BridgeHealth>,
+    /// What the relay announces to us, to read our own broadcast back from
+    relay: OriginConsumer,
+    path: String,
+    /// Set every interval, taken by the next frame read from CF
+    due: AtomicBool,
+    state: Mutex<ProbeState>,
+}
+
+#[derive(Default)]
+struct ProbeState {
+    track: Option<TrackProducer>,
+    next: u64,
+    /// When each probe's frame was read and written
+    pending: VecDeque<(u64, Instant, Instant)>,
+}
+
+impl LatencyProbe {
+    /// Probe the bridge of `stream_id`, published to the relay at `path`, every `every`
+    pub fn new(stream_id: &str, every: Duration, health: Arc<BridgeHealth>, relay: OriginConsumer, path: &str) -> Arc<Self> {
+        Arc::new(Self {
+            stream_id: stream_id.into(),
+            every,
+            health,
+            relay,
+            path: path.to_string(),
+            due: AtomicBool::new(false),
+            state: Default::default(),
+        })
+    }
+
+    /// When a frame just read from CF was read, if it's the one to time
+    pub fn mark(&self) -> Option<Instant> {
+        self.due.swap(false, Ordering::Relaxed).then(Instant::now)
+    }
+
+    /// A marked frame was written to the relay-side broadcast, send its probe
+    pub fn forwarded(&self, read: Instant) {
+        let written = Instant::now();
+        let mut state = self.state.lock().unwrap();
+        let sequence = state.next;
+        let Some(track) = &mut state.track else {
+            return;
+        };
+
+        let mut group = track.append_group();
+        group.write_frame(Bytes::copy_from_slice(&sequence.to_be_bytes()));
+        group.close();
+
+        state.next += 1;
+        state.pending.push_back((sequence, read, written));
+        if state.pending.len() > MAX_PENDING {
+            state.pending.pop_front();
+        }
+        drop(state);
+
+        let adapter = written - read;
+        self.report("adapter", adapter);
+        self.health.probed(ProbeLatency { adapter, relay: None });
+    }
+
+    /// Send probes until the bridge is over
+    pub async fn run(self: Arc<Self>, injector: Injector, forwarded: BroadcastConsumer) {
+        let Some(track) = injector.track(PROBE_TRACK) else {
+            return;
+        };
+        self.state.lock().unwrap().track = Some(track);
+
+        let schedule = async {
+            let mut ticks = tokio::time::interval(self.every);
+            loop {
+                ticks.tick().await;
+                self.due.store(true, Ordering::Relaxed);
+            }
+        };
+        tokio::select! {
+            _ = schedule => {}
+            _ = self.read_back() => {}
+            _ = forwarded.closed() => {}
+        }
+
+        // Nothing's probed anymore, rather than showing the last probe forever
+        self.report("adapter", Duration::ZERO);
+        self.report("relay", Duration::ZERO);
+    }
+
+    /// Read our probes back from the relay, following it as it announces the broadcast again
+    async fn read_back(&self) {
+        let Some(mut announced) = self.relay.consume_only(&[Path::new(&self.path)]) else {
+            return std::future::pending().await;
+        };
+        let mut probes = None;
+
+        loop {
+            tokio::select! {
+                announce = announced.announced() => match announce {
+                    Some((path, broadcast)) if path.as_str() == self.path => {
+                        probes = broadcast.map(|b| b.subscribe_track(&Track::new(PROBE_TRACK)));
+                    }
+                    Some(_) => {}
+                    None => return std::future::pending().await,
+                },
+                probe = next_probe(&mut probes) => self.read(probe),
+            }
+        }
+    }
+
+    /// A probe came back from the relay
+    fn read(&self, mut probe: Bytes) {
+        if probe.len() < 8 {
+            return;
+        }
+        let sequence = probe.get_u64();
+
+        let mut state = self.state.lock().unwrap();
+        let Some(position) = state.pending.iter().position(|(s, ..)| *s == sequence) else {
+            return;
+        };
+        let (_, read, written) = state.pending[position];
+        // Anything older got lost on the way
+        state.pending.drain(..=position);
+        drop(state);
+
+        let relay = written.elapsed();
+        self.report("relay", relay);
+        self.health.probed(ProbeLatency {
+            adapter: written - read,
+            relay: Some(relay),
+        });
+        tracing::debug!(stream_id = %self.stream_id, adapter = ?(written - read), ?relay, "latency probe");
+    }
+
+    fn report(&self, stage: &str, latency: Duration) {
+        let labels = [("stream_id", &*self.stream_id), ("stage", stage)];
+        Gauge::new("bridge_latency_us", "How long the last latency probe took, by stage", &labels)
+            .set(latency.as_micros() as i64);
+    }
+}
+
+/// The next probe on `track`, dropping it if it ends until the relay announces it again
+async fn next_probe(track: &mut Option<TrackConsumer>) -> Bytes {
+    loop {
+        let Some(probes) = track else {
+            return std::future::pending().await;
+        };
+        match probes.next_group().await {
+            Ok(Some(mut group)) => {
+                if let Ok(Some(probe)) = group.read_frame().await {
+                    return probe;
+                }
+            }
+            _ => *track = None,
+        }
+    }
+}
@@ -0,0 +1,320 @@
+//! Egress through a SOCKS5 proxy
+//!
+//! `--relay-proxy` and `--cf-proxy` take `socks5://[user:password@]host:port`, for hosts
+//! that can't send UDP straight to the relay or CloudFlare. QUIC runs over UDP, so the
+//! proxy has to support SOCKS5's UDP ASSOCIATE: every connection attempt sets up an
+//! association over a TCP connection to the proxy, whose UDP relay the QUIC datagrams
+//! then go through, wrapped in SOCKS5's header. The association lasts as long as that
+//! TCP connection, so if the proxy drops it the QUIC connection times out and reconnects
+//! through a new one.
+//!
+//! HTTP CONNECT proxies only tunnel TCP, and MASQUE's CONNECT-UDP needs an HTTP/3 client
+//! we don't have, so neither is supported. Hostnames are resolved locally, not by the
+//! proxy, and `dscp` and `priority` don't apply to proxied connections.
+
+use std::fmt;
+use std::io::{self, IoSliceMut};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::pin::Pin;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::task::{Context, Poll, ready};
+
+use anyhow::Context as _;
+use moq_native::web_transport_quinn::quinn;
+use quinn::udp::{RecvMeta, Transmit};
+use quinn::{AsyncUdpSocket, UdpPoller};
+use tokio::io::{AsyncReadExt, AsyncWriteExt, ReadBuf};
+use tokio::net::TcpStream;
+use url::Url;
+
+const VERSION: u8 = 5;
+const NO_AUTH: u8 = 0;
+const PASSWORD_AUTH: u8 = 2;
+const UDP_ASSOCIATE: u8 = 3;
+const IPV4: u8 = 1;
+const DOMAIN: u8 = 3;
+const IPV6: u8 = 4;
+
+/// A SOCKS5 proxy to connect through
+#[derive(Clone)]
+pub struct Proxy {
+    /// `host:port`
+    addr: String,
+    auth: Option<(String, String)>,
+}
+
+impl fmt::Debug for Proxy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // Without the password
+        f.debug_struct("Proxy").field("addr", &self.addr).finish_non_exhaustive()
+    }
+}
+
+impl FromStr for Proxy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        let url = Url::parse(s).context("invalid proxy URL")?;
+        match url.scheme() {
+            "socks5" => {}
+            "http" | "https" => anyhow::bail!("HTTP CONNECT only tunnels TCP, use a SOCKS5 proxy with UDP ASSOCIATE"),
+            "socks5h" => anyhow::bail!("hostnames are resolved locally, use socks5://"),
+            scheme => anyhow::bail!("unsupported proxy scheme: {scheme}"),
+        }
+        let host = url.host_str().context("proxy URL has no host")?;
+        let auth = match url.username() {
+            "" => None,
+            user => Some((unescape(user), unescape(url.password().unwrap_or_default()))),
+        };
+        Ok(Self {
+            addr: format!("{host}:{}", url.port().unwrap_or(1080)),
+            auth,
+        })
+    }
+}
+
+/// A copy of `client` whose next connection goes through `proxy`, if there's one
+pub async fn client(client: &moq_native::Client, proxy: Option<&Proxy>) -> anyhow::Result<moq_native::Client> {
+    let mut client = client.clone();
+    if let Some(proxy) = proxy {
+        // Connection errors are logged without their causes
+        let endpoint = proxy.endpoint().await.map_err(|err| anyhow::anyhow!("SOCKS5 proxy {}: {err:#}", proxy.addr));
+        client.quic = endpoint?;
+    }
+    Ok(client)
+}
+
+impl Proxy {
+    /// A QUIC endpoint whose datagrams go through a new UDP association
+    async fn endpoint(&self) -> anyhow::Result<quinn::Endpoint> {
+        let mut control = TcpStream::connect(&self.addr).await.context("can't connect")?;
+        control.set_nodelay(true)?;
+        self.authenticate(&mut control).await?;
+
+        // We don't know yet which address the proxy will see our datagrams from
+        control.write_all(&[VERSION, UDP_ASSOCIATE, 0, IPV4, 0, 0, 0, 0, 0, 0]).await?;
+        let mut reply = [0; 3];
+        control.read_exact(&mut reply).await?;
+        anyhow::ensure!(reply[0] == VERSION, "not a SOCKS5 proxy");
+        anyhow::ensure!(reply[1] == 0, "UDP ASSOCIATE refused: {}", refusal(reply[1]));
+        let mut relay = read_addr(&mut control).await?;
+        if relay.ip().is_unspecified() {
+            // The relay is on the proxy itself
+            relay.set_ip(control.peer_addr()?.ip());
+        }
+        tracing::debug!(proxy = %self.addr, %relay, "UDP association set up");
+
+        let bind: SocketAddr = match relay {
+            SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+            SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+        };
+        let io = tokio::net::UdpSocket::bind(bind).await?;
+
+        // The association ends when the control connection closes
+        let proxy = self.addr.clone();
+        let control = tokio::spawn(async move {
+            let mut buf = [0; 64];
+            while control.read(&mut buf).await.is_ok_and(|read| read > 0) {}
+            tracing::warn!(%proxy, "SOCKS5 proxy closed the UDP association");
+        });
+
+        let socket = Arc::new(ProxiedSocket { io, relay, control });
+        let endpoint = quinn::Endpoint::new_with_abstract_socket(
+            quinn::EndpointConfig::default(),
+            None,
+            socket,
+            Arc::new(quinn::TokioRuntime),
+        );
+        endpoint.context("failed to create QUIC endpoint")
+    }
+
+    async fn authenticate(&self, control: &mut TcpStream) -> anyhow::Result<()> {
+        let method = if self.auth.is_some() { PASSWORD_AUTH } else { NO_AUTH };
+        control.write_all(&[VERSION, 1, method]).await?;
+        let mut reply = [0; 2];
+        control.read_exact(&mut reply).await?;
+        anyhow::ensure!(reply[0] == VERSION, "not a SOCKS5 proxy");
+        anyhow::ensure!(reply[1] == method, "the proxy refused our authentication method");
+
+        let Some((user, password)) = &self.auth else {
+            return Ok(());
+        };
+        anyhow::ensure!(user.len() < 256 && password.len() < 256, "proxy username or password too long");
+        let mut request = vec![1, user.len() as u8];
+        request.extend_from_slice(user.as_bytes());
+        request.push(password.len() as u8);
+        request.extend_from_slice(password.as_bytes());
+        control.write_all(&request).await?;
+        control.read_exact(&mut reply).await?;
+        anyhow::ensure!(reply[1] == 0, "the proxy rejected the username or password");
+        Ok(())
+    }
+}
+
+/// A percent-encoded part of a URL, decoded
+fn unescape(part: &str) -> String {
+    let mut bytes = Vec::with_capacity(part.len());
+    let mut rest = part.as_bytes();
+    while let Some((&byte, tail)) = rest.split_first() {
+        let escaped = tail.get(..2).and_then(|hex| u8::from_str_radix(std::str::from_utf8(hex).ok()?, 16).ok());
+        match (byte, escaped) {
+            (b'%', Some(escaped)) => {
+                bytes.push(escaped);
+                rest = &tail[2..];
+            }
+            _ => {
+                bytes.push(byte);
+                rest = tail;
+            }
+        }
+    }
+    String::from_utf8_lossy(&bytes).into_owned()
+}
+
+/// Why the proxy refused a request
+fn refusal(code: u8) -> &'static str {
+    match code {
+        1 => "general failure",
+        2 => "not allowed by ruleset",
+        3 => "network unreachable",
+        4 => "host unreachable",
+        5 => "connection refused",
+        7 => "command not supported",
+        8 => "address type not supported",
+        _ => "unknown error",
+    }
+}
+
+/// The address at the end of a reply
+async fn read_addr(control: &mut TcpStream) -> anyhow::Result<SocketAddr> {
+    let ip: IpAddr = match control.read_u8().await? {
+        IPV4 => {
+            let mut ip = [0; 4];
+            control.read_exact(&mut ip).await?;
+            ip.into()
+        }
+        IPV6 => {
+            let mut ip = [0; 16];
+            control.read_exact(&mut ip).await?;
+            ip.into()
+        }
+        DOMAIN => anyhow::bail!("the proxy's UDP relay is a hostname"),
+        atyp => anyhow::bail!("unknown address type {atyp}"),
+    };
+    Ok((ip, control.read_u16().await?).into())
+}
+
+/// A UDP socket that sends and receives through a SOCKS5 UDP relay
+struct ProxiedSocket {
+    io: tokio::net::UdpSocket,
+    /// The proxy's UDP relay
+    relay: SocketAddr,
+    /// Holds the association open
+    control: tokio::task::JoinHandle<()>,
+}
+
+impl fmt::Debug for ProxiedSocket {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ProxiedSocket").field("relay", &self.relay).finish_non_exhaustive()
+    }
+}
+
+impl Drop for ProxiedSocket {
+    fn drop(&mut self) {
+        self.control.abort();
+    }
+}
+
+impl AsyncUdpSocket for ProxiedSocket {
+    fn create_io_poller(self: Arc<Self>) -> Pin<Box<dyn UdpPoller>> {
+        Box::pin(Writable(self))
+    }
+
+    fn try_send(&self, transmit: &Transmit) -> io::Result<()> {
+        let size = transmit.segment_size.unwrap_or(transmit.contents.len()).max(1);
+        for datagram in transmit.contents.chunks(size) {
+            // RSV, FRAG, then where the relay sends it on to
+            let mut wrapped = vec![0, 0, 0];
+            match transmit.destination {
+                SocketAddr::V4(addr) => {
+                    wrapped.push(IPV4);
+                    wrapped.extend_from_slice(&addr.ip().octets());
+                }
+                SocketAddr::V6(addr) => {
+                    wrapped.push(IPV6);
+                    wrapped.extend_from_slice(&addr.ip().octets());
+                }
+            }
+            wrapped.extend_from_slice(&transmit.destination.port().to_be_bytes());
+            wrapped.extend_from_slice(datagram);
+            self.io.try_send_to(&wrapped, self.relay)?;
+        }
+        Ok(())
+    }
+
+    fn poll_recv(&self, cx: &mut Context, bufs: &mut [IoSliceMut<'_>], meta: &mut [RecvMeta]) -> Poll<io::Result<usize>> {
+        let (Some(buf), Some(meta)) = (bufs.first_mut(), meta.first_mut()) else {
+            return Poll::Ready(Ok(0));
+        };
+        loop {
+            let mut read = ReadBuf::new(buf);
+            let from = ready!(self.io.poll_recv_from(cx, &mut read))?;
+            let received = read.filled().len();
+            // Only the relay can send us anything, and fragments aren't worth reassembling
+            let Some((source, header)) = (from == self.relay).then(|| unwrap(read.filled())).flatten() else {
+                continue;
+            };
+            let len = received - header;
+            buf.copy_within(header..received, 0);
+            *meta = RecvMeta {
+                addr: source,
+                len,
+                stride: len,
+                ..Default::default()
+            };
+            return Poll::Ready(Ok(1));
+        }
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.io.local_addr()
+    }
+
+    fn max_transmit_segments(&self) -> usize {
+        1
+    }
+
+    fn max_receive_segments(&self) -> usize {
+        1
+    }
+
+    fn may_fragment(&self) -> bool {
+        // The proxy's own socket might
+        true
+    }
+}
+
+/// The source address of a datagram from the relay, and the length of its header
+fn unwrap(datagram: &[u8]) -> Option<(SocketAddr, usize)> {
+    let [0, 0, 0, atyp, rest @ ..] = datagram else {
+        return None;
+    };
+    let (ip, rest): (IpAddr, _) = match *atyp {
+        IPV4 => (<[u8; 4]>::try_from(rest.get(..4)?).ok()?.into(), &rest[4..]),
+        IPV6 => (<[u8; 16]>::try_from(rest.get(..16)?).ok()?.into(), &rest[16..]),
+        _ => return None,
+    };
+    let port = u16::from_be_bytes(rest.get(..2)?.try_into().ok()?);
+    Some(((ip, port).into(), datagram.len() - rest.len() + 2))
+}
+
+/// Waits for a [ProxiedSocket] to be writable
+#[derive(Debug)]
+struct Writable(Arc<ProxiedSocket>);
+
+impl UdpPoller for Writable {
+    fn poll_writable(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        self.0.io.poll_send_ready(cx)
+    }
+}
@@ -0,0 +1,80 @@
+//! Metrics push
+//!
+//! Where nothing can scrape `/metrics`, `--metrics-push-url` sends the same metrics
+//! to a Prometheus Pushgateway instead, every `--metrics-push-interval`. They're put
+//! under the `--metrics-push-job` job with an `instance` label of the host name, so
+//! replicas don't overwrite each other, and deleted again on a clean shutdown so an
+//! adapter that's gone doesn't look stuck.
+//!
+//! Remote write isn't supported: it needs protobuf and snappy, which this crate
+//! doesn't otherwise depend on. Pushgateway is scraped like any other target.
+
+use std::time::Duration;
+
+use anyhow::Context;
+
+use crate::metrics;
+
+#[derive(Clone, Debug)]
+pub struct PushOptions {
+    pub url: String,
+    pub interval: Duration,
+    pub job: String,
+}
+
+pub struct Pusher {
+    client: reqwest::Client,
+    /// Our group on the Pushgateway
+    url: String,
+    interval: Duration,
+}
+
+impl Pusher {
+    pub fn new(options: &PushOptions) -> Self {
+        let instance = std::env::var("HOSTNAME").unwrap_or_else(|_| "adapter".to_string());
+        let url = format!("{}/metrics/job/{}/instance/{instance}", options.url.trim_end_matches('/'), options.job);
+        Self {
+            client: reqwest::Client::new(),
+            url,
+            interval: options.interval,
+        }
+    }
+
+    /// Push the metrics every interval, forever
+    ///
+    /// Failed pushes are logged and retried on the next interval; they never stop the adapter.
+    pub async fn run(&self) {
+        tracing::info!(url = self.url, interval = ?self.interval, "pushing metrics");
+        let mut ticks = tokio::time::interval(self.interval);
+        ticks.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+
+        loop {
+            ticks.tick().await;
+            if let Err(err) = self.push().await {
+                tracing::warn!(err = format!("{err:#}"), "failed to push metrics");
+            }
+        }
+    }
+
+    /// Replace our group with the current metrics
+    async fn push(&self) -> anyhow::Result<()> {
+        self.client
+            .put(&self.url)
+            .header(reqwest::header::CONTENT_TYPE, "text/plain; version=0.0.4")
+            .body(metrics::render())
+            .timeout(self.interval)
+            .send()
+            .await
+            .context("request failed")?
+            .error_for_status()?;
+        Ok(())
+    }
+
+    /// Remove our group, once we're done
+    pub async fn delete(&self) {
+        let res = self.client.delete(&self.url).timeout(self.interval).send().await;
+        if let Err(err) = res.and_then(|res| res.error_for_status()) {
+            tracing::debug!(%err, "failed to delete pushed metrics");
+        }
+    }
+}
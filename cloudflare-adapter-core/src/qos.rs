@@ -0,0 +1,154 @@
+//! DSCP marking and socket priority
+//!
+//! `dscp=` and `priority=` in `--relay-quic` and `--cf-quic` mark what we send on those
+//! connections, for networks that prioritize marked media traffic. quinn sets the traffic
+//! class of every datagram it sends itself, to mark ECN, which would overwrite a DSCP set
+//! on the socket. So marked connections get a socket of their own that sends datagrams
+//! plainly and lets the socket's marking apply, at the cost of ECN and segmentation
+//! offload on those connections. Marking is only supported on Linux.
+
+use std::fmt;
+use std::io::{self, IoSliceMut};
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll, ready};
+
+use anyhow::Context as _;
+use moq_native::web_transport_quinn::quinn;
+use quinn::udp::{RecvMeta, Transmit, UdpSocketState};
+use quinn::{AsyncUdpSocket, UdpPoller};
+use tokio::io::Interest;
+
+/// Parse a DSCP as a number (0-63) or a name (`EF`, `CS0`-`CS7`, `AF11`-`AF43`)
+pub fn parse_dscp(value: &str) -> anyhow::Result<u8> {
+    if let Ok(dscp) = value.parse::<u8>() {
+        anyhow::ensure!(dscp < 64, "DSCP {dscp} is out of range (0-63)");
+        return Ok(dscp);
+    }
+
+    let name = value.to_ascii_uppercase();
+    let digits: Vec<u8> = name.bytes().skip(2).map(|b| b.wrapping_sub(b'0')).collect();
+    let dscp = match (name.get(..2), digits.as_slice()) {
+        (Some("EF"), []) => Some(46),
+        (Some("CS"), [class @ 0..=7]) => Some(class * 8),
+        (Some("AF"), [class @ 1..=4, drop @ 1..=3]) => Some(class * 8 + drop * 2),
+        _ => None,
+    };
+    dscp.with_context(|| format!("unknown DSCP: {value}"))
+}
+
+/// A QUIC endpoint bound to `bind` whose datagrams carry `dscp` and `priority`
+pub fn endpoint(bind: SocketAddr, dscp: Option<u8>, priority: Option<u32>) -> anyhow::Result<quinn::Endpoint> {
+    let endpoint = quinn::Endpoint::new_with_abstract_socket(
+        quinn::EndpointConfig::default(),
+        None,
+        socket(bind, dscp, priority)?,
+        Arc::new(quinn::TokioRuntime),
+    );
+    endpoint.context("failed to create QUIC endpoint")
+}
+
+/// A socket bound to `bind` whose datagrams carry `dscp` and `priority`
+pub fn socket(bind: SocketAddr, dscp: Option<u8>, priority: Option<u32>) -> anyhow::Result<Arc<dyn AsyncUdpSocket>> {
+    let domain = socket2::Domain::for_address(bind);
+    let socket = socket2::Socket::new(domain, socket2::Type::DGRAM, Some(socket2::Protocol::UDP))?;
+    if bind.is_ipv6() {
+        // Dual-stack, like moq-native's own socket
+        socket.set_only_v6(false)?;
+    }
+    socket.bind(&bind.into()).with_context(|| format!("failed to bind UDP socket to {bind}"))?;
+    socket.set_nonblocking(true)?;
+    mark(&socket, bind, dscp, priority)?;
+
+    let socket = std::net::UdpSocket::from(socket);
+    Ok(Arc::new(MarkedSocket {
+        state: UdpSocketState::new((&socket).into())?,
+        io: tokio::net::UdpSocket::from_std(socket)?,
+    }))
+}
+
+#[cfg(target_os = "linux")]
+fn mark(socket: &socket2::Socket, bind: SocketAddr, dscp: Option<u8>, priority: Option<u32>) -> anyhow::Result<()> {
+    if let Some(dscp) = dscp {
+        // The low two bits are ECN
+        let tos = (dscp as u32) << 2;
+        if bind.is_ipv6() {
+            socket.set_tclass_v6(tos).context("failed to set IPV6_TCLASS")?;
+        }
+        // Also for IPv4 peers of a dual-stack socket
+        socket.set_tos_v4(tos).context("failed to set IP_TOS")?;
+    }
+    if let Some(priority) = priority {
+        socket.set_priority(priority).context("failed to set SO_PRIORITY")?;
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn mark(_: &socket2::Socket, _: SocketAddr, _: Option<u8>, _: Option<u32>) -> anyhow::Result<()> {
+    anyhow::bail!("DSCP marking and socket priority are only supported on Linux")
+}
+
+/// A UDP socket that leaves the traffic class to the socket's own marking
+struct MarkedSocket {
+    io: tokio::net::UdpSocket,
+    /// For receiving, with the ECN and destination of each datagram
+    state: UdpSocketState,
+}
+
+impl fmt::Debug for MarkedSocket {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("MarkedSocket").field("io", &self.io).finish_non_exhaustive()
+    }
+}
+
+impl AsyncUdpSocket for MarkedSocket {
+    fn create_io_poller(self: Arc<Self>) -> Pin<Box<dyn UdpPoller>> {
+        Box::pin(Writable(self))
+    }
+
+    fn try_send(&self, transmit: &Transmit) -> io::Result<()> {
+        // Batches are never bigger than max_transmit_segments, but don't rely on it
+        let size = transmit.segment_size.unwrap_or(transmit.contents.len()).max(1);
+        for datagram in transmit.contents.chunks(size) {
+            self.io.try_send_to(datagram, transmit.destination)?;
+        }
+        Ok(())
+    }
+
+    fn poll_recv(&self, cx: &mut Context, bufs: &mut [IoSliceMut<'_>], meta: &mut [RecvMeta]) -> Poll<io::Result<usize>> {
+        loop {
+            ready!(self.io.poll_recv_ready(cx))?;
+            if let Ok(received) = self.io.try_io(Interest::READABLE, || self.state.recv((&self.io).into(), bufs, meta)) {
+                return Poll::Ready(Ok(received));
+            }
+        }
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.io.local_addr()
+    }
+
+    fn max_transmit_segments(&self) -> usize {
+        1
+    }
+
+    fn max_receive_segments(&self) -> usize {
+        self.state.gro_segments()
+    }
+
+    fn may_fragment(&self) -> bool {
+        self.state.may_fragment()
+    }
+}
+
+/// Waits for a [MarkedSocket] to be writable
+#[derive(Debug)]
+struct Writable(Arc<MarkedSocket>);
+
+impl UdpPoller for Writable {
+    fn poll_writable(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        self.0.io.poll_send_ready(cx)
+    }
+}
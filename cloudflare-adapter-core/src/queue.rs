@@ -0,0 +1,128 @@
+//! Admission control for new bridges
+//!
+//! With `--max-bridges`, streams the registry lists beyond the limit wait in a queue
+//! and start as running bridges end. The queue is FIFO, except that streams matching
+//! an earlier `--bridge-priority` pattern go first. Streams that leave the registry
+//! while queued are dropped from it.
+//!
+//! Priorities are classes numbered from 0, the most important: a stream's class is
+//! the one the registry gives it, else the index of the first pattern it matches, else
+//! one past the last pattern. With `--preempt`, a queued stream of a more important
+//! class than a running bridge stops the least important one and takes its place.
+
+use std::collections::{HashMap, HashSet};
+
+use crate::filter::glob_match;
+use crate::metrics::Gauge;
+
+/// Streams waiting for a free bridge slot
+#[derive(Debug, Default)]
+pub struct BridgeQueue {
+    max: Option<usize>,
+    priority: Vec<String>,
+    preempt: bool,
+    // In arrival order
+    pending: Vec<String>,
+    // Classes given by the registry
+    classes: HashMap<String, usize>,
+    // The pending count we last logged, so the gauge only logs changes
+    reported: usize,
+}
+
+impl BridgeQueue {
+    pub fn new(max: Option<usize>, priority: Vec<String>, preempt: bool) -> Self {
+        Self {
+            max,
+            priority,
+            preempt,
+            ..Default::default()
+        }
+    }
+
+    /// Streams waiting for a slot
+    pub fn len(&self) -> usize {
+        self.pending.len()
+    }
+
+    /// Sync the queue with the streams the registry currently lists, and their classes if given
+    pub fn offer<'a>(
+        &mut self,
+        listed: impl IntoIterator<Item = (&'a str, Option<usize>)>,
+        active: impl Fn(&str) -> bool,
+    ) {
+        let listed: Vec<_> = listed.into_iter().collect();
+        self.classes = listed.iter().filter_map(|&(id, class)| Some((id.to_string(), class?))).collect();
+        self.pending.retain(|stream_id| listed.iter().any(|(id, _)| id == stream_id));
+
+        for (stream_id, _) in listed {
+            if !active(stream_id) && !self.pending.iter().any(|p| p == stream_id) {
+                self.pending.push(stream_id.to_string());
+            }
+        }
+    }
+
+    /// Take the streams that can start now, given how many bridges are running
+    ///
+    /// `preemptible` are the running bridges that may be stopped to make room.
+    pub fn take_ready(&mut self, active: usize, preemptible: &HashSet<String>) -> Admission {
+        let free = self.max.map_or(usize::MAX, |max| max.saturating_sub(active));
+        let mut admission = Admission::default();
+
+        while admission.start.len() < free {
+            let Some(next) = self.next() else {
+                break;
+            };
+            admission.start.push(self.pending.remove(next));
+        }
+
+        if self.preempt && admission.start.len() == free {
+            let mut victims: Vec<&String> = preemptible.iter().collect();
+            // Least important last
+            victims.sort_by_key(|id| self.rank(id));
+
+            while let (Some(next), Some(victim)) = (self.next(), victims.last()) {
+                if self.rank(&self.pending[next]) >= self.rank(victim) {
+                    break;
+                }
+                tracing::info!(stream_id = %self.pending[next], preempted = %victim, "preempting bridge");
+                admission.preempt.push(victims.pop().unwrap().clone());
+                admission.start.push(self.pending.remove(next));
+            }
+        }
+
+        let ready = &admission.start;
+        if self.pending.len() != self.reported {
+            self.reported = self.pending.len();
+            tracing::info!(pending = self.reported, active = active + ready.len(), "pending bridges");
+            Gauge::new("pending_bridges", "Streams waiting for a bridge slot", &[]).set(self.reported as i64);
+        }
+
+        admission
+    }
+
+    /// The index of the most important pending stream
+    fn next(&self) -> Option<usize> {
+        (0..self.pending.len()).min_by_key(|&i| self.rank(&self.pending[i]))
+    }
+
+    /// The class of `stream_id`; lower starts sooner
+    fn rank(&self, stream_id: &str) -> usize {
+        if let Some(&class) = self.classes.get(stream_id) {
+            return class;
+        }
+
+        self.priority
+            .iter()
+            .position(|p| glob_match(p, stream_id))
+            .unwrap_or(self.priority.len())
+    }
+}
+
+/// What the bridge manager should do after a registry poll
+#[derive(Debug, Default)]
+pub struct Admission {
+    /// Streams to start bridging
+    pub start: Vec<String>,
+    /// Running bridges to stop, to make room
+    pub preempt: Vec<String>,
+}
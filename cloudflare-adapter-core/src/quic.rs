@@ -0,0 +1,146 @@
+//! QUIC transport tuning
+//!
+//! The relay and CloudFlare sit on very different paths, so each connection gets its
+//! own transport settings on top of moq-native's defaults, given as `key=value`:
+//!
+//! - `congestion`: `cubic` (the default), `bbr` or `newreno`
+//! - `stream-window` and `window`: the per-stream and per-connection receive windows
+//!   (bytes). quinn doesn't grow its windows, so these are both initial and maximum.
+//! - `send-window`: how much the connection may have unacknowledged (bytes)
+//! - `idle-timeout` and `keep-alive`: seconds, or 0 to disable
+//! - `dscp`: the DSCP to mark datagrams with, as a number or a name like `EF` or `AF41`
+//! - `priority`: the socket priority (`SO_PRIORITY`) for the host's queueing
+//!
+//! With `dscp` or `priority` the connection gets a socket of its own, see [crate::qos].
+
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::Context;
+use moq_native::web_transport_quinn::quinn;
+use quinn::congestion::{BbrConfig, CubicConfig, NewRenoConfig};
+
+use crate::qos;
+
+/// A congestion controller quinn ships with
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Congestion {
+    Cubic,
+    Bbr,
+    NewReno,
+}
+
+/// One transport setting
+#[derive(Clone, Copy, Debug)]
+pub enum QuicSetting {
+    Congestion(Congestion),
+    StreamWindow(u64),
+    Window(u64),
+    SendWindow(u64),
+    IdleTimeout(u64),
+    KeepAlive(u64),
+    Dscp(u8),
+    Priority(u32),
+}
+
+impl FromStr for QuicSetting {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        let (key, value) = s.split_once('=').context("expected key=value")?;
+        let number = || value.parse::<u64>().with_context(|| format!("invalid {key}: {value}"));
+
+        Ok(match key {
+            "congestion" => Self::Congestion(match value {
+                "cubic" => Congestion::Cubic,
+                "bbr" => Congestion::Bbr,
+                "newreno" => Congestion::NewReno,
+                _ => anyhow::bail!("unknown congestion controller: {value}"),
+            }),
+            "stream-window" => Self::StreamWindow(number()?),
+            "window" => Self::Window(number()?),
+            "send-window" => Self::SendWindow(number()?),
+            "idle-timeout" => Self::IdleTimeout(number()?),
+            "keep-alive" => Self::KeepAlive(number()?),
+            "dscp" => Self::Dscp(qos::parse_dscp(value)?),
+            "priority" => Self::Priority(value.parse().with_context(|| format!("invalid priority: {value}"))?),
+            _ => anyhow::bail!("unknown QUIC setting: {key}"),
+        })
+    }
+}
+
+/// A copy of `client` whose connections use `settings`
+pub fn client(client: &moq_native::Client, settings: &[QuicSetting]) -> anyhow::Result<moq_native::Client> {
+    let mut client = client.clone();
+    if !settings.is_empty() {
+        client.transport = Arc::new(transport(settings)?);
+    }
+
+    let (dscp, priority) = marking(settings);
+    if dscp.is_some() || priority.is_some() {
+        // Bound like the shared endpoint, on a port of its own
+        let mut bind = client.quic.local_addr()?;
+        bind.set_port(0);
+        client.quic = qos::endpoint(bind, dscp, priority)?;
+    }
+    Ok(client)
+}
+
+/// The DSCP and socket priority among `settings`
+pub fn marking(settings: &[QuicSetting]) -> (Option<u8>, Option<u32>) {
+    let dscp = settings.iter().rev().find_map(|setting| match setting {
+        QuicSetting::Dscp(dscp) => Some(*dscp),
+        _ => None,
+    });
+    let priority = settings.iter().rev().find_map(|setting| match setting {
+        QuicSetting::Priority(priority) => Some(*priority),
+        _ => None,
+    });
+    (dscp, priority)
+}
+
+fn transport(settings: &[QuicSetting]) -> anyhow::Result<quinn::TransportConfig> {
+    // Start from what moq-native uses
+    let mut transport = quinn::TransportConfig::default();
+    transport.max_idle_timeout(Some(Duration::from_secs(10).try_into()?));
+    transport.keep_alive_interval(Some(Duration::from_secs(4)));
+    transport.mtu_discovery_config(None);
+
+    let window = |bytes: u64| quinn::VarInt::from_u64(bytes).context("window too large");
+    let seconds = |secs: u64| (secs > 0).then(|| Duration::from_secs(secs));
+
+    for setting in settings {
+        match *setting {
+            QuicSetting::Congestion(Congestion::Cubic) => {
+                transport.congestion_controller_factory(Arc::new(CubicConfig::default()));
+            }
+            QuicSetting::Congestion(Congestion::Bbr) => {
+                transport.congestion_controller_factory(Arc::new(BbrConfig::default()));
+            }
+            QuicSetting::Congestion(Congestion::NewReno) => {
+                transport.congestion_controller_factory(Arc::new(NewRenoConfig::default()));
+            }
+            QuicSetting::StreamWindow(bytes) => {
+                transport.stream_receive_window(window(bytes)?);
+            }
+            QuicSetting::Window(bytes) => {
+                transport.receive_window(window(bytes)?);
+            }
+            QuicSetting::SendWindow(bytes) => {
+                transport.send_window(bytes);
+            }
+            QuicSetting::IdleTimeout(secs) => {
+                let timeout = seconds(secs).map(quinn::IdleTimeout::try_from).transpose()?;
+                transport.max_idle_timeout(timeout);
+            }
+            QuicSetting::KeepAlive(secs) => {
+                transport.keep_alive_interval(seconds(secs));
+            }
+            // The socket's, not the transport's
+            QuicSetting::Dscp(_) | QuicSetting::Priority(_) => {}
+        }
+    }
+
+    Ok(transport)
+}
@@ -0,0 +1,249 @@
+//! Recording bridged streams to disk
+//!
+//! Each rendition in the catalog is written as CMAF: an `init.mp4` plus one `.m4s`
+//! fragment per group, under `{dir}/{stream_id}/{session}/{track}/`, where the session is
+//! when the bridge started recording, in Unix milliseconds, so a stream bridged again
+//! doesn't overwrite what it recorded before. Names are escaped with [safe_name].
+//!
+//! Files modified longer ago than the retention window are deleted, going by a scan of
+//! the whole directory at most every minute while anything is recorded. That covers the
+//! sessions of earlier bridges and runs alike, and a track's `init.mp4` goes with the
+//! last of its fragments.
+
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::{LazyLock, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use anyhow::Context;
+use moq_lite::{BroadcastConsumer, GroupConsumer, Track, TrackConsumer};
+
+use crate::catalog::{self, Rendition, CATALOG_TRACK};
+use crate::media::read_group;
+use crate::mp4::{self, TrackConfig};
+use crate::tasks;
+
+/// How often a recording directory is scanned for files past the retention window
+const PRUNE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// When each recording directory was last pruned, so the bridges recording to it share scans
+static PRUNED: LazyLock<Mutex<HashMap<PathBuf, Instant>>> = LazyLock::new(Default::default);
+
+/// Where and for how long recordings are kept
+#[derive(Clone, Debug)]
+pub struct RecordOptions {
+    pub dir: PathBuf,
+    pub retention: Duration,
+}
+
+/// Record every rendition of `broadcast` until it closes
+pub async fn record_broadcast(stream_id: &str, broadcast: BroadcastConsumer, options: RecordOptions) -> anyhow::Result<()> {
+    let session = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_millis();
+    let dir = options.dir.join(safe_name(stream_id)).join(session.to_string());
+    let mut catalog = broadcast.subscribe_track(&Track::new(CATALOG_TRACK));
+    let mut recording = HashSet::new();
+
+    tracing::info!(stream_id, dir = %dir.display(), "recording bridge");
+
+    loop {
+        let group = tokio::select! {
+            res = catalog.next_group() => match res? {
+                Some(group) => group,
+                None => break,
+            },
+            _ = broadcast.closed() => break,
+        };
+
+        let Some(renditions) = read_catalog(group).await? else {
+            continue;
+        };
+
+        // Start recording any rendition we haven't seen before
+        for rendition in renditions {
+            if !recording.insert(rendition.track.clone()) {
+                continue;
+            }
+
+            let track = broadcast.subscribe_track(&Track::new(&rendition.track));
+            let dir = dir.join(safe_name(&rendition.track));
+            let options = options.clone();
+            let stream_id = stream_id.to_string();
+
+            tasks::spawn(async move {
+                if let Err(err) = record_track(&rendition, track, &dir, &options).await {
+                    tracing::warn!(%err, stream_id, track = %rendition.track, "recording failed");
+                }
+            });
+        }
+    }
+
+    tracing::info!(stream_id, "recording finished");
+    Ok(())
+}
+
+/// Read the catalog from the first frame of a catalog group
+async fn read_catalog(mut group: GroupConsumer) -> anyhow::Result<Option<Vec<Rendition>>> {
+    let Some(frame) = group.read_frame().await? else {
+        return Ok(None);
+    };
+
+    Ok(catalog::renditions(&frame))
+}
+
+/// Write one track's init segment and fragments
+async fn record_track(
+    rendition: &Rendition,
+    mut track: TrackConsumer,
+    dir: &Path,
+    options: &RecordOptions,
+) -> anyhow::Result<()> {
+    let config = TrackConfig::from_rendition(rendition)?;
+    let init = mp4::init_segment(&config);
+
+    tokio::fs::create_dir_all(dir).await.context("failed to create recording directory")?;
+    tokio::fs::write(dir.join("init.mp4"), &init).await?;
+
+    while let Some(group) = track.next_group().await? {
+        let sequence = group.info.sequence;
+        let frames = read_group(group).await?;
+        if frames.is_empty() {
+            continue;
+        }
+
+        let segment = mp4::media_segment(sequence as u32, &mp4::samples(&frames, config.default_duration()));
+        let path = dir.join(format!("{sequence:010}.m4s"));
+        match tokio::fs::write(&path, &segment).await {
+            // Pruned while the track stalled, so set it up again
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+                tokio::fs::create_dir_all(dir).await.context("failed to create recording directory")?;
+                tokio::fs::write(dir.join("init.mp4"), &init).await?;
+                tokio::fs::write(&path, &segment).await?;
+            }
+            res => res?,
+        }
+
+        prune(options);
+    }
+
+    Ok(())
+}
+
+/// Start a scan of the recording directory, unless one ran in the last [PRUNE_INTERVAL]
+fn prune(options: &RecordOptions) {
+    {
+        let mut pruned = PRUNED.lock().unwrap();
+        if pruned.get(&options.dir).is_some_and(|at| at.elapsed() < PRUNE_INTERVAL) {
+            return;
+        }
+        pruned.insert(options.dir.clone(), Instant::now());
+    }
+
+    let dir = options.dir.clone();
+    let Some(cutoff) = SystemTime::now().checked_sub(options.retention) else {
+        return;
+    };
+    tokio::task::spawn_blocking(move || {
+        if let Err(err) = prune_dir(&dir, cutoff) {
+            tracing::debug!(%err, dir = %dir.display(), "failed to prune recordings");
+        }
+    });
+}
+
+/// Delete what was last modified before `cutoff` under `dir`, returning whether it's now empty
+fn prune_dir(dir: &Path, cutoff: SystemTime) -> std::io::Result<bool> {
+    let mut left = 0;
+    let mut init = None;
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        let path = entry.path();
+        let metadata = entry.metadata()?;
+        if metadata.is_dir() {
+            let empty = prune_dir(&path, cutoff)?;
+            // Just pruned, or just created for a recording yet to write to it
+            if !(empty && metadata.modified()? < cutoff && std::fs::remove_dir(&path).is_ok()) {
+                left += 1;
+            }
+        } else if entry.file_name() == "init.mp4" {
+            // Fragments are no use without it
+            init = Some((path, metadata.modified()?));
+        } else if metadata.modified()? < cutoff {
+            remove(&path);
+        } else {
+            left += 1;
+        }
+    }
+
+    match init {
+        Some((path, modified)) if left == 0 && modified < cutoff => remove(&path),
+        Some(_) => left += 1,
+        None => {}
+    }
+    Ok(left == 0)
+}
+
+fn remove(path: &Path) {
+    if let Err(err) = std::fs::remove_file(path) {
+        tracing::debug!(%err, path = %path.display(), "failed to remove old recording");
+    }
+}
+
+/// Make a stream or track name safe to use as a single path component
+///
+/// Distinct names stay distinct: `_` escapes everything but letters, digits, `-` and `.`,
+/// as `__` for itself and two hex digits for each byte of anything else, like a leading
+/// `.`: `live/a` becomes `live_2fa` and `live_a` becomes `live__a`.
+pub fn safe_name(name: &str) -> String {
+    let mut safe = String::with_capacity(name.len());
+    for (i, byte) in name.bytes().enumerate() {
+        match byte {
+            b'.' if i == 0 => safe.push_str("_2e"),
+            b'a'..=b'z' | b'A'..=b'Z' | b'0'..=b'9' | b'-' | b'.' => safe.push(byte as char),
+            b'_' => safe.push_str("__"),
+            _ => safe.push_str(&format!("_{byte:02x}")),
+        }
+    }
+
+    // Never `_` otherwise, which always escapes something
+    match safe.is_empty() {
+        true => "_".to_string(),
+        false => safe,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+
+    #[test]
+    fn safe_names() {
+        assert_eq!(safe_name("video"), "video");
+        assert_eq!(safe_name("video-720p.hd"), "video-720p.hd");
+        assert_eq!(safe_name("live/a"), "live_2fa");
+        assert_eq!(safe_name("live_a"), "live__a");
+        assert_eq!(safe_name("."), "_2e");
+        assert_eq!(safe_name(".."), "_2e.");
+        assert_eq!(safe_name(".hidden"), "_2ehidden");
+        assert_eq!(safe_name("a b"), "a_20b");
+        assert_eq!(safe_name("é"), "_c3_a9");
+        assert_eq!(safe_name(""), "_");
+    }
+
+    #[test]
+    fn safe_names_stay_distinct() {
+        let names = [
+            "live/a", "live_a", "live_2fa", "live__a", "video/hd", "video_hd", "video hd", "", "_", "__", "_2e", ".",
+            "..", "...", "a.", ".a", "%2f", "A", "a", "_5f", "\u{0}", "\\0", "é", "_c3_a9",
+        ];
+        let mut seen = HashMap::new();
+        for name in names {
+            let safe = safe_name(name);
+            assert!(!safe.is_empty() && !safe.contains('/') && !matches!(safe.as_str(), "." | ".."), "{safe:?}");
+            assert!(safe.bytes().all(|byte| byte.is_ascii_alphanumeric() || b"-._".contains(&byte)), "{safe:?}");
+            if let Some(other) = seen.insert(safe.clone(), name) {
+                panic!("{name:?} and {other:?} are both {safe:?}");
+            }
+        }
+    }
+}
@@ -0,0 +1,130 @@
+//! Following CloudFlare's redirects
+//!
+//! A CF endpoint being drained or moved can answer the WebTransport CONNECT with a 3xx
+//! instead of setting the session up. web-transport-quinn only hands us the status, so
+//! the adapter asks the same URL over plain HTTPS where to go: the response's `Location`,
+//! or failing that the `h3` endpoint its `Alt-Svc` advertises. Sessions follow up to
+//! `--cf-max-redirects` hops, logging each and counting them in `cf_redirects_total`.
+//!
+//! The endpoint a session ended up at is used for the pool's reconnects from then on, so
+//! they don't go through the redirect again; when it fails to connect, the next attempt goes
+//! back to `--cloudflare-url`. The HTTPS lookup doesn't go through `--cf-proxy`.
+
+use std::collections::HashMap;
+use std::sync::{LazyLock, Mutex};
+
+use moq_lite::{OriginConsumer, OriginProducer};
+use moq_native::web_transport_quinn::ClientError;
+use url::Url;
+
+use crate::connect::{self, ConnectOptions, Connection};
+use crate::metrics::Counter;
+
+/// Where each configured URL redirected to last
+static FOLLOWED: LazyLock<Mutex<HashMap<Url, Url>>> = LazyLock::new(Default::default);
+
+/// Connect to `url` like [connect::connect], following up to `hops` redirects
+pub async fn connect(
+    client: &moq_native::Client,
+    url: &Url,
+    options: &ConnectOptions,
+    publish: Option<OriginConsumer>,
+    subscribe: Option<OriginProducer>,
+    hops: usize,
+) -> anyhow::Result<Connection> {
+    let followed = FOLLOWED.lock().unwrap().get(url).cloned();
+    if let Some(endpoint) = followed {
+        tracing::debug!(%url, %endpoint, "connecting where cloudflare redirected to last");
+        let connected = connect::connect(client, endpoint.clone(), options, publish, subscribe).await;
+        if let Err(err) = &connected {
+            tracing::warn!(%url, %endpoint, %err, "redirected endpoint failed, going back to the configured one");
+            FOLLOWED.lock().unwrap().remove(url);
+        }
+        return connected;
+    }
+
+    let mut target = url.clone();
+    let mut hop = 0;
+    loop {
+        let err = match connect::connect(client, target.clone(), options, publish.clone(), subscribe.clone()).await {
+            Ok(connection) => {
+                if hop > 0 {
+                    FOLLOWED.lock().unwrap().insert(url.clone(), target);
+                }
+                return Ok(connection);
+            }
+            Err(err) => err,
+        };
+        let Some(status) = redirect_status(&err) else {
+            return Err(err);
+        };
+        if hop == hops {
+            return Err(err.context(format!("not following more than {hops} redirects")));
+        }
+
+        hop += 1;
+        let next = lookup(&target).await.map_err(|lookup| err.context(format!("can't tell where to: {lookup:#}")))?;
+        tracing::info!(from = %target, to = %next, %status, hop, "cloudflare redirected the session");
+        Counter::new("cf_redirects_total", "Redirects followed connecting to CloudFlare", &[]).inc();
+        target = next;
+    }
+}
+
+/// The status of a CONNECT that was answered with a redirect
+///
+/// web-transport-quinn keeps the error holding it private, so it's read from the message.
+fn redirect_status(err: &anyhow::Error) -> Option<u16> {
+    err.chain().find_map(|err| {
+        let ClientError::HttpError(err) = err.downcast_ref::<ClientError>()? else {
+            return None;
+        };
+        let status: u16 = err.to_string().strip_prefix("http error status: ")?.get(..3)?.parse().ok()?;
+        (300..400).contains(&status).then_some(status)
+    })
+}
+
+/// Where `url` redirects to, asked over HTTPS
+#[cfg(feature = "redirects")]
+async fn lookup(url: &Url) -> anyhow::Result<Url> {
+    use anyhow::Context;
+
+    let mut https = url.clone();
+    if https.scheme() != "https" {
+        // Only the scheme differs, so this can't fail
+        https = Url::parse(&format!("https{}", &url.as_str()[url.scheme().len()..]))?;
+    }
+    let client = reqwest::Client::builder().redirect(reqwest::redirect::Policy::none()).build()?;
+    let response = client
+        .get(https)
+        .timeout(std::time::Duration::from_secs(5))
+        .send()
+        .await
+        .context("request failed")?;
+
+    let header = |name| response.headers().get(name).and_then(|value| value.to_str().ok());
+    if let Some(location) = header(reqwest::header::LOCATION) {
+        return url.join(location).context("invalid Location");
+    }
+    let alternative = header(reqwest::header::ALT_SVC).and_then(alt_svc).context("no Location or h3 Alt-Svc")?;
+    let mut next = url.clone();
+    let (host, port) = alternative.rsplit_once(':').context("invalid Alt-Svc")?;
+    if !host.is_empty() {
+        next.set_host(Some(host)).context("invalid Alt-Svc host")?;
+    }
+    next.set_port(Some(port.parse().context("invalid Alt-Svc port")?)).ok();
+    Ok(next)
+}
+
+#[cfg(not(feature = "redirects"))]
+async fn lookup(_url: &Url) -> anyhow::Result<Url> {
+    anyhow::bail!("built without the redirects feature")
+}
+
+/// The authority of the first `h3` alternative in an `Alt-Svc` header, like `alt.example:443`
+#[cfg(feature = "redirects")]
+fn alt_svc(header: &str) -> Option<&str> {
+    header.split(',').find_map(|alternative| {
+        let (protocol, authority) = alternative.split(';').next()?.split_once('=')?;
+        (protocol.trim() == "h3").then(|| authority.trim().trim_matches('"'))
+    })
+}
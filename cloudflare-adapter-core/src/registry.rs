@@ -0,0 +1,105 @@
+//! The stream registry
+
+use std::collections::HashMap;
+
+#[cfg(feature = "registry")]
+use crate::error::AdapterError;
+use crate::expiry;
+
+/// Fetch active CloudFlare streams from your registry
+#[cfg(feature = "registry")]
+pub(crate) async fn fetch_cloudflare_streams(
+    client: &reqwest::Client,
+    registry_url: &str,
+    origins: &[String],
+) -> Result<Vec<StreamInfo>, AdapterError> {
+    let response = async { client.get(registry_url).send().await?.json::<RegistryResponse>().await }
+        .await
+        .map_err(|err| AdapterError::Registry(err.into()))?;
+
+    // Filter to only CloudFlare-origin streams
+    Ok(response
+        .broadcasts
+        .into_iter()
+        .filter(|s| origins.contains(&s.origin))
+        .collect())
+}
+
+#[cfg(any(feature = "registry", feature = "http"))]
+#[derive(Debug, serde::Deserialize)]
+pub(crate) struct RegistryResponse {
+    pub(crate) broadcasts: Vec<StreamInfo>,
+}
+
+/// A stream the registry lists
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct StreamInfo {
+    pub stream_id: String,
+    #[serde(default = "default_origin")]
+    pub origin: String,
+    /// The stream's priority class, 0 being the most important; see `--bridge-priority`
+    #[serde(default)]
+    pub priority: Option<usize>,
+    /// The relay path to publish the stream under, instead of its stream_id
+    #[serde(default)]
+    pub publish_path: Option<String>,
+    /// Track filter patterns, replacing the global `--track-filter` rules for this stream
+    #[serde(default)]
+    pub track_filter: Option<Vec<String>>,
+    /// The `--relay-target` to publish the stream to, instead of the main relay
+    #[serde(default)]
+    pub relay: Option<String>,
+    /// The CF namespace, instead of the one built from `--cf-namespace-template`
+    #[serde(default)]
+    pub cf_namespace: Option<String>,
+    /// The stream is about to go live, so it's pre-warmed instead of bridged until it is; see `--prewarm`
+    #[serde(default)]
+    pub starting_soon: bool,
+    /// When to stop bridging the stream, in Unix seconds, sent as those or as RFC 3339
+    #[serde(default, deserialize_with = "expiry::unix_seconds")]
+    pub expires_at: Option<u64>,
+    /// Stop bridging the stream this many seconds after the last poll that listed it
+    #[serde(default, deserialize_with = "expiry::seconds")]
+    pub ttl: Option<u64>,
+    /// Everything else the registry says about the stream, for the namespace template
+    #[serde(flatten)]
+    pub fields: HashMap<String, serde_json::Value>,
+}
+
+impl StreamInfo {
+    /// A CloudFlare stream with nothing but its id
+    pub fn new(stream_id: String) -> Self {
+        Self {
+            stream_id,
+            origin: default_origin(),
+            priority: None,
+            publish_path: None,
+            track_filter: None,
+            relay: None,
+            cf_namespace: None,
+            starting_soon: false,
+            expires_at: None,
+            ttl: None,
+            fields: HashMap::new(),
+        }
+    }
+
+    /// A registry field as text, for the namespace template
+    pub(crate) fn field(&self, name: &str) -> Option<String> {
+        match name {
+            "stream_id" => Some(self.stream_id.clone()),
+            "origin" => Some(self.origin.clone()),
+            "priority" => self.priority.map(|p| p.to_string()),
+            "relay" => self.relay.clone(),
+            _ => match self.fields.get(name)? {
+                serde_json::Value::String(value) => Some(value.clone()),
+                value @ (serde_json::Value::Number(_) | serde_json::Value::Bool(_)) => Some(value.to_string()),
+                _ => None,
+            },
+        }
+    }
+}
+
+fn default_origin() -> String {
+    "cloudflare".to_string()
+}
@@ -0,0 +1,121 @@
+//! Relays we publish to
+//!
+//! Streams go to the main `--relay-url` unless their registry entry names one of the
+//! `--relay-target name=url` relays in its `relay` field. Every relay has a connection
+//! of its own, with its own path claims, congestion shedding and up/down status for
+//! spilling, and all of them use `--relay-token`.
+
+use std::collections::HashMap;
+use std::str::FromStr;
+use std::sync::Arc;
+
+use moq_lite::{Origin, OriginConsumer, OriginProducer, Produce};
+use tokio::sync::watch;
+use url::Url;
+
+use crate::paths::{Collision, PathClaims};
+use crate::filter::Scoped;
+use crate::shed::{ShedOptions, Shedder};
+use crate::throttle::EgressBudget;
+
+/// A relay the registry can send streams to, written as `name=url`
+#[derive(Clone, Debug)]
+pub struct NamedRelay {
+    pub name: String,
+    pub url: String,
+}
+
+impl FromStr for NamedRelay {
+    type Err = String;
+
+    fn from_str(spec: &str) -> Result<Self, Self::Err> {
+        let (name, url) = spec.split_once('=').ok_or("expected name=url")?;
+        let url = url.trim();
+        Url::parse(url).map_err(|err| format!("invalid relay URL: {err}"))?;
+        Ok(Self {
+            name: name.trim().to_string(),
+            url: url.to_string(),
+        })
+    }
+}
+
+/// One relay and what we exchange with it
+pub struct Relay {
+    /// The name the registry uses for it, or None for the main relay
+    pub name: Option<String>,
+    pub url: String,
+    /// Our bridged streams, and SRT and WHIP ingests on the main relay
+    pub publish: Produce<OriginProducer, OriginConsumer>,
+    /// What the relay announces, to spot other nodes publishing our paths
+    pub announced: OriginProducer,
+    pub claims: PathClaims,
+    /// Track shedding, driven by this relay's connection
+    pub shedder: Arc<Shedder>,
+    /// Whether the connection is up, for bridges that spill during outages
+    pub up: watch::Sender<bool>,
+    /// Shared by every bridge publishing here, see `--relay-egress-limit`
+    pub egress: Option<Arc<EgressBudget>>,
+}
+
+impl Relay {
+    fn new(name: Option<String>, url: String, collision: Collision, shed: ShedOptions, egress: &[Scoped<u64>]) -> Arc<Self> {
+        let label = name.as_deref().unwrap_or("main");
+        let egress = Scoped::resolve(egress, label).filter(|kbps| *kbps > 0).map(|kbps| EgressBudget::new(label, kbps));
+        let publish = Origin::produce();
+        let announced = Origin::produce().producer;
+        let claims = PathClaims::new(collision, publish.producer.clone(), announced.clone());
+
+        Arc::new(Self {
+            name,
+            url,
+            publish,
+            announced,
+            claims,
+            shedder: Shedder::new(shed),
+            up: watch::Sender::new(false),
+            egress,
+        })
+    }
+}
+
+/// The main relay and every named one
+pub struct Relays {
+    pub main: Arc<Relay>,
+    named: HashMap<String, Arc<Relay>>,
+}
+
+impl Relays {
+    pub fn new(
+        main: &str,
+        named: &[NamedRelay],
+        collision: Collision,
+        shed: &ShedOptions,
+        egress: &[Scoped<u64>],
+    ) -> Arc<Self> {
+        let named = named
+            .iter()
+            .map(|relay| {
+                let name = relay.name.clone();
+                let relay = Relay::new(Some(name.clone()), relay.url.clone(), collision, shed.clone(), egress);
+                (name, relay)
+            })
+            .collect();
+
+        Arc::new(Self {
+            main: Relay::new(None, main.to_string(), collision, shed.clone(), egress),
+            named,
+        })
+    }
+
+    /// The relay a registry entry names, or the main one
+    pub fn get(&self, name: Option<&str>) -> Option<&Arc<Relay>> {
+        match name {
+            Some(name) => self.named.get(name),
+            None => Some(&self.main),
+        }
+    }
+
+    pub fn all(&self) -> impl Iterator<Item = &Arc<Relay>> {
+        std::iter::once(&self.main).chain(self.named.values())
+    }
+}
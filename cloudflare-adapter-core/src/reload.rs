@@ -0,0 +1,132 @@
+//! Hot-reloaded stream rules
+//!
+//! With `--reload-interval`, the config file is checked for changes that often, and
+//! the stream rules in it, the [RELOADABLE] options, apply to bridge decisions from
+//! the next registry poll. Rules set in the environment or on the command line still
+//! win over the file, and changes to any other option need a restart. A file that no
+//! longer parses is logged and the rules in force are kept.
+//!
+//! Running bridges keep the rules they started with, unless `--reload-teardown` is
+//! set: then bridges of streams the new rules exclude, or would publish or filter
+//! differently, are stopped, and bridged again under the new rules on a later poll.
+
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use anyhow::Context;
+use clap::parser::ValueSource;
+use tokio::sync::watch;
+
+use crate::filter::StreamPattern;
+use crate::settings::{self, Loaded};
+use crate::AdapterConfig;
+
+/// Options that can change without a restart, by long name
+pub const RELOADABLE: &[&str] = &["include-stream", "exclude-stream", "stream-path", "track-filter"];
+
+/// Rereads the config file as it changes
+pub struct Reloader {
+    path: PathBuf,
+    profile: Option<String>,
+    tenant: Option<String>,
+    command: clap::Command,
+    /// The reloadable options the file gets to set, with their environment variables
+    options: Vec<(&'static str, String)>,
+    current: watch::Sender<Arc<AdapterConfig>>,
+}
+
+impl Reloader {
+    pub fn new(config: &AdapterConfig, command: clap::Command, matches: &clap::ArgMatches, loaded: Loaded) -> Self {
+        let options = RELOADABLE
+            .iter()
+            .filter_map(|&long| {
+                let arg = command.get_arguments().find(|arg| arg.get_long() == Some(long))?;
+                let env = arg.get_env()?.to_string_lossy().into_owned();
+                let overridden = match matches.value_source(arg.get_id().as_str()) {
+                    Some(ValueSource::CommandLine) => true,
+                    Some(ValueSource::EnvVariable) => !loaded.env.contains(&env),
+                    _ => false,
+                };
+                (!overridden).then_some((long, env))
+            })
+            .collect();
+
+        Self {
+            path: loaded.path,
+            profile: loaded.profile,
+            tenant: loaded.tenant,
+            command,
+            options,
+            current: watch::Sender::new(Arc::new(config.clone())),
+        }
+    }
+
+    /// The config with the latest rules
+    pub fn subscribe(&self) -> watch::Receiver<Arc<AdapterConfig>> {
+        self.current.subscribe()
+    }
+
+    /// Check the file every `interval`, publishing its rules when it changes
+    pub async fn run(&self, interval: Duration) -> anyhow::Result<()> {
+        let mut seen = modified(&self.path);
+        let mut ticks = tokio::time::interval(interval);
+        loop {
+            ticks.tick().await;
+
+            let now = modified(&self.path);
+            if now == seen {
+                continue;
+            }
+            seen = now;
+
+            match self.reload() {
+                Ok(config) => {
+                    tracing::info!(path = %self.path.display(), "reloaded stream rules");
+                    self.current.send_replace(Arc::new(config));
+                }
+                Err(err) => {
+                    let err = format!("{err:#}");
+                    tracing::warn!(err, path = %self.path.display(), "keeping the current stream rules");
+                }
+            }
+        }
+    }
+
+    fn reload(&self) -> anyhow::Result<AdapterConfig> {
+        let mut values = settings::read(&self.command, &self.path, self.profile.as_deref(), self.tenant.as_deref())?;
+
+        let mut config = AdapterConfig::clone(&self.current.borrow());
+        for (long, env) in &self.options {
+            let values = values.remove(env).unwrap_or_default();
+            apply(&mut config, long, values).with_context(|| format!("{long}: invalid rule"))?;
+        }
+
+        Ok(config)
+    }
+}
+
+fn apply(config: &mut AdapterConfig, long: &str, values: Vec<String>) -> anyhow::Result<()> {
+    match long {
+        "include-stream" => config.include_streams = patterns(&values)?,
+        "exclude-stream" => config.exclude_streams = patterns(&values)?,
+        "track-filter" => config.track_filters = values,
+        "stream-path" => {
+            let paths = values.iter().map(|value| value.parse()).collect::<Result<_, String>>();
+            config.stream_paths = paths.map_err(anyhow::Error::msg)?;
+        }
+        _ => anyhow::bail!("can't be reloaded"),
+    }
+
+    Ok(())
+}
+
+fn patterns(values: &[String]) -> anyhow::Result<Vec<StreamPattern>> {
+    let patterns = values.iter().map(|value| value.parse()).collect::<Result<_, String>>();
+    patterns.map_err(anyhow::Error::msg)
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|meta| meta.modified()).ok()
+}
+
@@ -0,0 +1,175 @@
+//! Registry recording and replay
+//!
+//! `--record-registry <file>` writes every stream list the bridge manager gets to a
+//! file, one JSON line each: when it came, counted from the first list, and either the
+//! streams or the error listing failed with (`--chaos` registry errors included).
+//!
+//! `--discovery replay` reads such a file back from `--replay-registry`. Each entry is
+//! listed from its recorded time until the next one's, and the manager lists again as
+//! soon as the next one is due, so streams come, go and fail to list in the same order
+//! and at the same pace as they did, e.g. to reproduce what the manager did with a
+//! flapping stream from a bug report. After the last entry it keeps listing that one.
+//!
+//! Replays run on tokio's clock, so a test with a paused clock replays a recording as
+//! fast as the manager can take it, in the same order. Tests hand [Replay::new] or
+//! [Replay::load] to [BridgeManager::with_discovery](crate::BridgeManager::with_discovery).
+
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, OnceLock};
+use std::time::Duration;
+
+use anyhow::Context;
+use tokio::io::AsyncWriteExt;
+use tokio::sync::Mutex;
+use tokio::time::Instant;
+
+use crate::discovery::{ChangeFuture, ListFuture, StreamDiscovery};
+use crate::registry::StreamInfo;
+
+/// One list, as recorded
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct Entry {
+    /// Since the first list (ms)
+    pub at_ms: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub streams: Option<Vec<StreamInfo>>,
+    /// Why listing failed, instead of the streams
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl Entry {
+    fn listed(&self) -> anyhow::Result<Vec<StreamInfo>> {
+        match &self.error {
+            Some(error) => anyhow::bail!("{error}"),
+            None => Ok(self.streams.clone().unwrap_or_default()),
+        }
+    }
+}
+
+/// Records the lists of the discovery it wraps
+pub struct Recorder {
+    inner: Arc<dyn StreamDiscovery>,
+    path: PathBuf,
+    file: Mutex<tokio::fs::File>,
+    /// When the first list came
+    started: OnceLock<Instant>,
+}
+
+impl Recorder {
+    /// Record `discovery`'s lists to `path`, replacing whatever it held
+    pub fn new(discovery: Arc<dyn StreamDiscovery>, path: &Path) -> anyhow::Result<Arc<Self>> {
+        let file = std::fs::File::create(path).with_context(|| format!("can't create {}", path.display()))?;
+        Ok(Arc::new(Self {
+            inner: discovery,
+            path: path.to_path_buf(),
+            file: Mutex::new(tokio::fs::File::from_std(file)),
+            started: OnceLock::new(),
+        }))
+    }
+
+    async fn record(&self, listed: &anyhow::Result<Vec<StreamInfo>>) {
+        let started = *self.started.get_or_init(Instant::now);
+        let entry = Entry {
+            at_ms: started.elapsed().as_millis() as u64,
+            streams: listed.as_ref().ok().cloned(),
+            error: listed.as_ref().err().map(|err| format!("{err:#}")),
+        };
+        let mut line = match serde_json::to_string(&entry) {
+            Ok(line) => line,
+            Err(err) => {
+                tracing::warn!(%err, "failed to serialize stream list");
+                return;
+            }
+        };
+        line.push('\n');
+
+        let mut file = self.file.lock().await;
+        let written = async {
+            file.write_all(line.as_bytes()).await?;
+            file.flush().await
+        };
+        if let Err(err) = written.await {
+            tracing::warn!(%err, path = %self.path.display(), "failed to record stream list");
+        }
+    }
+}
+
+impl StreamDiscovery for Recorder {
+    fn list(&self) -> ListFuture<'_> {
+        Box::pin(async move {
+            let listed = self.inner.list().await;
+            self.record(&listed).await;
+            listed
+        })
+    }
+
+    fn next_change(&self) -> ChangeFuture<'_> {
+        self.inner.next_change()
+    }
+}
+
+/// Lists a recording's entries, each from its recorded time on
+pub struct Replay {
+    entries: Vec<Entry>,
+    /// When the first list was asked for
+    started: OnceLock<Instant>,
+    finished: AtomicBool,
+}
+
+impl Replay {
+    /// Replay `entries`, in the order of their times
+    pub fn new(mut entries: Vec<Entry>) -> anyhow::Result<Self> {
+        anyhow::ensure!(!entries.is_empty(), "nothing to replay");
+        entries.sort_by_key(|entry| entry.at_ms);
+        Ok(Self {
+            entries,
+            started: OnceLock::new(),
+            finished: AtomicBool::new(false),
+        })
+    }
+
+    /// Replay the file `--record-registry` wrote
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let recording = std::fs::read_to_string(path).with_context(|| format!("can't read {}", path.display()))?;
+        let entries = recording
+            .lines()
+            .enumerate()
+            .filter(|(_, line)| !line.trim().is_empty())
+            .map(|(i, line)| serde_json::from_str(line).with_context(|| format!("{}:{}", path.display(), i + 1)))
+            .collect::<anyhow::Result<_>>()?;
+        Self::new(entries).with_context(|| format!("can't replay {}", path.display()))
+    }
+
+    fn started(&self) -> Instant {
+        *self.started.get_or_init(Instant::now)
+    }
+
+    /// The last entry that's due
+    fn current(&self) -> usize {
+        let elapsed = self.started().elapsed().as_millis() as u64;
+        self.entries.partition_point(|entry| entry.at_ms <= elapsed).saturating_sub(1)
+    }
+}
+
+impl StreamDiscovery for Replay {
+    fn list(&self) -> ListFuture<'_> {
+        Box::pin(async move {
+            let current = self.current();
+            if current + 1 == self.entries.len() && !self.finished.swap(true, Ordering::Relaxed) {
+                tracing::info!(entries = self.entries.len(), "registry replay finished, keeping the last list");
+            }
+            self.entries[current].listed()
+        })
+    }
+
+    fn next_change(&self) -> ChangeFuture<'_> {
+        Box::pin(async move {
+            match self.entries.get(self.current() + 1) {
+                Some(next) => tokio::time::sleep_until(self.started() + Duration::from_millis(next.at_ms)).await,
+                None => std::future::pending().await,
+            }
+        })
+    }
+}
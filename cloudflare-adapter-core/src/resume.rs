@@ -0,0 +1,104 @@
+//! Continuity across bridge restarts
+//!
+//! When a bridge is set up again after a CF session drop or a stall, the new CF
+//! subscription starts wherever CF happens to be, which can be a group we already
+//! forwarded. Outputs fed from the relay-side broadcast (packaging, recording) would
+//! then see it twice. We remember the last group forwarded on each track of each
+//! stream, and the next bridge for the stream skips groups up to it. A bridge never
+//! skips its own groups, since the relay may subscribe to a track again.
+//!
+//! A sequence far behind the remembered one means CF restarted its numbering, so we
+//! start over from it. Streams nobody has bridged for a while are forgotten.
+
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// How far behind the last forwarded group a sequence can be and still count as a duplicate
+const RESET_WINDOW: u64 = 16;
+
+/// How long to remember a stream after its last group
+const TTL: Duration = Duration::from_secs(300);
+
+/// The last forwarded group of every track, by stream
+#[derive(Default)]
+pub struct ResumePoints {
+    streams: Mutex<HashMap<String, StreamPoints>>,
+    // Numbers each bridge
+    bridges: AtomicU64,
+}
+
+struct StreamPoints {
+    updated: Instant,
+    // The last forwarded sequence, and the bridge that forwarded it
+    tracks: HashMap<String, (u64, u64)>,
+}
+
+impl ResumePoints {
+    pub fn new() -> Arc<Self> {
+        Default::default()
+    }
+
+    /// The resume points of one stream, for its next bridge
+    pub fn stream(self: &Arc<Self>, stream_id: &str) -> StreamResume {
+        self.streams.lock().unwrap().retain(|_, points| points.updated.elapsed() < TTL);
+
+        StreamResume {
+            points: self.clone(),
+            stream_id: stream_id.into(),
+            bridge: self.bridges.fetch_add(1, Ordering::Relaxed),
+        }
+    }
+
+    /// The last group forwarded on each of `stream_id`'s tracks
+    pub fn last_groups(&self, stream_id: &str) -> HashMap<String, u64> {
+        let streams = self.streams.lock().unwrap();
+        let Some(points) = streams.get(stream_id) else {
+            return HashMap::new();
+        };
+        points.tracks.iter().map(|(track, &(sequence, _))| (track.clone(), sequence)).collect()
+    }
+
+    /// Remember the groups an earlier run forwarded, so the next bridge carries on after them
+    pub fn restore(&self, stream_id: &str, groups: HashMap<String, u64>) {
+        // No bridge of ours has this number, so none counts as the one that forwarded them
+        let tracks = groups.into_iter().map(|(track, sequence)| (track, (sequence, u64::MAX))).collect();
+        let points = StreamPoints {
+            updated: Instant::now(),
+            tracks,
+        };
+        self.streams.lock().unwrap().insert(stream_id.to_string(), points);
+    }
+}
+
+/// The resume points of one stream
+#[derive(Clone)]
+pub struct StreamResume {
+    points: Arc<ResumePoints>,
+    stream_id: Arc<str>,
+    bridge: u64,
+}
+
+impl StreamResume {
+    /// Whether a group should be forwarded, recording it as the last one if so
+    ///
+    /// Returns false for groups an earlier bridge already forwarded.
+    pub fn admit(&self, track: &str, sequence: u64) -> bool {
+        let mut streams = self.points.streams.lock().unwrap();
+        let points = streams.entry(self.stream_id.to_string()).or_insert_with(|| StreamPoints {
+            updated: Instant::now(),
+            tracks: HashMap::new(),
+        });
+        points.updated = Instant::now();
+
+        match points.tracks.get(track) {
+            Some(&(last, bridge)) if bridge != self.bridge && sequence <= last && sequence + RESET_WINDOW > last => false,
+            Some(&(last, bridge)) if bridge == self.bridge && sequence < last => true,
+            _ => {
+                points.tracks.insert(track.to_string(), (sequence, self.bridge));
+                true
+            }
+        }
+    }
+}
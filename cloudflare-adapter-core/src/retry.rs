@@ -0,0 +1,95 @@
+//! Per-stream retries
+//!
+//! A stream whose bridge fails to come up is held back for a growing delay, following
+//! the `--backoff-*` policy, instead of being retried on every registry poll. After
+//! `--max-bridge-attempts` consecutive failures it's quarantined: we log it once, count
+//! it in the `quarantined_streams` gauge and leave it alone until it drops out of the
+//! registry. A bridge that comes up clears its stream's failures. Failures that aren't
+//! the stream's fault (see [BridgeError::is_stream_fault]) don't count.
+
+use std::collections::{HashMap, HashSet};
+use std::time::Instant;
+
+use crate::backoff::{Backoff, BackoffPolicy};
+use crate::error::BridgeError;
+use crate::metrics::Gauge;
+
+/// The failing streams, and when each may be bridged again
+pub struct StreamRetries {
+    policy: BackoffPolicy,
+    max_attempts: u32,
+    streams: HashMap<String, Retry>,
+}
+
+struct Retry {
+    backoff: Backoff,
+    attempts: u32,
+    // None once quarantined
+    until: Option<Instant>,
+}
+
+impl StreamRetries {
+    pub fn new(policy: BackoffPolicy, max_attempts: u32) -> Self {
+        Self {
+            policy,
+            max_attempts: max_attempts.max(1),
+            streams: HashMap::new(),
+        }
+    }
+
+    /// Whether `stream_id` may be bridged now
+    pub fn ready(&self, stream_id: &str) -> bool {
+        match self.streams.get(stream_id) {
+            Some(retry) => retry.until.is_some_and(|until| until <= Instant::now()),
+            None => true,
+        }
+    }
+
+    /// Forget streams the registry no longer lists, lifting their quarantine
+    pub fn retain_listed(&mut self, listed: &HashSet<&str>) {
+        self.streams.retain(|stream_id, _| listed.contains(stream_id.as_str()));
+        self.report();
+    }
+
+    /// Record a failed bridge, and hold its stream back
+    ///
+    /// Failures that aren't the stream's fault, like no CF session being up, are only
+    /// logged: the stream is tried again on the next poll.
+    pub fn failed(&mut self, stream_id: &str, err: &BridgeError) {
+        if !err.is_stream_fault() {
+            tracing::warn!(%err, stream_id, "bridge failed, retrying on the next poll");
+            return;
+        }
+
+        let policy = self.policy;
+        let retry = self.streams.entry(stream_id.to_string()).or_insert_with(|| Retry {
+            backoff: policy.start(),
+            attempts: 0,
+            until: None,
+        });
+        retry.attempts += 1;
+
+        if retry.attempts >= self.max_attempts {
+            retry.until = None;
+            tracing::error!(%err, stream_id, attempts = retry.attempts, "bridge keeps failing, quarantining stream");
+        } else {
+            let delay = retry.backoff.next();
+            retry.until = Some(Instant::now() + delay);
+            tracing::warn!(%err, stream_id, attempts = retry.attempts, retry_in = ?delay, "bridge failed");
+        }
+
+        self.report();
+    }
+
+    /// Clear the failures of a stream whose bridge came up
+    pub fn succeeded(&mut self, stream_id: &str) {
+        if self.streams.remove(stream_id).is_some() {
+            self.report();
+        }
+    }
+
+    fn report(&self) {
+        let quarantined = self.streams.values().filter(|retry| retry.until.is_none()).count();
+        Gauge::new("quarantined_streams", "Streams no longer bridged after repeated failures", &[]).set(quarantined as i64);
+    }
+}
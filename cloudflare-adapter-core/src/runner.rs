@@ -0,0 +1,289 @@
+//! Starting and supervising one bridge
+//!
+//! The bridge manager turns every stream it admits into a [Bridge]: what it forwards
+//! with, where it publishes and where it subscribes, all worked out from the config
+//! and the manager's services as it starts. [Bridge::run] then bridges the stream as a
+//! supervised task, so a panic is an error like any other, until it ends by itself or
+//! is stopped: by its handle, its watchdog heartbeat, another replica taking its lease
+//! or its registry entry expiring. How it ended goes to the lifecycle callbacks, the
+//! session history and the lease before it's handed back to the manager.
+
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use bytes::Bytes;
+use tokio::sync::{oneshot, watch};
+
+use crate::alias::TrackAliases;
+use crate::bridge::{bridge_stream, BridgeEnd, BridgeOutputs, CloudFlareSource};
+use crate::buffer::BridgeBuffers;
+use crate::cache::GroupCache;
+use crate::capture::CaptureMeter;
+use crate::catalog::LayerLimits;
+use crate::config::AdapterConfig;
+#[cfg(feature = "sentry")]
+use crate::crash;
+use crate::demand::Demand;
+use crate::duplicate::DuplicateWatch;
+use crate::error::BridgeError;
+use crate::expiry;
+use crate::filter::{Scoped, TrackFilter};
+use crate::forward::ForwardOptions;
+use crate::handle::BridgeHandle;
+#[cfg(feature = "history")]
+use crate::history::{self, Session, SessionHistory};
+use crate::hook::FrameHook;
+#[cfg(feature = "redis")]
+use crate::lease::Lease;
+use crate::lifecycle::{BridgeContext, BridgeEvents};
+use crate::manager::BridgeServices;
+use crate::paths::PathClaim;
+use crate::probe::LatencyProbe;
+use crate::registry::StreamInfo;
+use crate::relay::Relay;
+use crate::sink::{BridgeSink, FileSink, RelaySink};
+use crate::spill::Spill;
+use crate::supervise;
+use crate::tasks;
+use crate::throttle::BridgeThrottle;
+use crate::timestamp::Rebaser;
+use crate::token::BroadcastSink;
+use crate::watchdog::Stuck;
+
+/// A stream the bridge manager admitted, with its relay path claimed
+pub(crate) struct Admitted {
+    pub(crate) stream: StreamInfo,
+    pub(crate) namespace: String,
+    pub(crate) relay: Arc<Relay>,
+    pub(crate) path: String,
+    pub(crate) filter: TrackFilter,
+    pub(crate) claim: PathClaim,
+    pub(crate) handle: BridgeHandle,
+    /// Told why the bridge should end, through the handle
+    pub(crate) stopped: oneshot::Receiver<BridgeEnd>,
+    /// Served on the metadata track from the start, see `--stream-metadata`
+    pub(crate) metadata: Option<Bytes>,
+    /// When the registry entry expires, as later lists move it
+    pub(crate) expires: watch::Receiver<Option<SystemTime>>,
+    /// Ours until another replica takes it, with `--lease-redis-url`
+    #[cfg(feature = "redis")]
+    pub(crate) lease: Option<Lease>,
+}
+
+/// One stream's bridge, started and ready to run
+///
+/// The bridge manager starts one for every stream it admits and runs it on a shard,
+/// keeping its [BridgeHandle] to control it with in the meantime.
+pub struct Bridge {
+    namespace: String,
+    options: ForwardOptions,
+    outputs: BridgeOutputs,
+    source: CloudFlareSource,
+    stopped: oneshot::Receiver<BridgeEnd>,
+    handle: BridgeHandle,
+    events: BridgeEvents,
+    expires: watch::Receiver<Option<SystemTime>>,
+    #[cfg(feature = "redis")]
+    lease: Option<Lease>,
+    #[cfg(feature = "history")]
+    history: Option<Arc<SessionHistory>>,
+}
+
+impl Bridge {
+    /// Work out how `admitted` is bridged from `source`, and tell the callbacks it started
+    pub(crate) fn start(
+        admitted: Admitted,
+        source: CloudFlareSource,
+        hook: Option<Arc<dyn FrameHook>>,
+        config: &AdapterConfig,
+        services: &BridgeServices,
+    ) -> Self {
+        let Admitted { stream, namespace, relay, path, filter, claim, handle, stopped, metadata, expires, .. } = admitted;
+        let stream_id = stream.stream_id.clone();
+        let context = BridgeContext {
+            stream,
+            namespace: namespace.clone(),
+            relay: relay.name.clone(),
+            path,
+            started: SystemTime::now(),
+        };
+        let events = services.lifecycle.bridge(context, services.events.clone());
+        events.start();
+
+        let options = ForwardOptions {
+            filter,
+            limits: LayerLimits {
+                max_height: Scoped::resolve(&config.max_video_height, &stream_id),
+                max_bitrate: Scoped::resolve(&config.max_video_bitrate, &stream_id),
+            },
+            aliases: TrackAliases::for_stream(&config.track_aliases, &stream_id),
+            priorities: config.priority_overrides(),
+            rebaser: Rebaser::new(config.rebase_timestamps),
+            hook,
+            hook_tracks: TrackFilter::for_stream(&config.transform_tracks, &stream_id),
+            shedder: relay.shedder.clone(),
+            passthrough: config.passthrough(),
+            e2ee: config.e2ee,
+            interceptors: services.interceptors.clone(),
+            buffers: BridgeBuffers::new(config.bridge_buffer_limit.map(|mb| mb << 20), services.buffers.clone()),
+            backpressure: config.backpressure(),
+            spill: config.spill_options().map(|o| Spill::new(&o, &stream_id, relay.up.subscribe())),
+            cache: config.cache_retention().keeps_any().then(|| GroupCache::new(config.cache_retention())),
+            resume: services.resume.stream(&stream_id),
+            health: handle.health(),
+            paused: handle.paused(),
+            chaos: services.chaos.clone(),
+            probe: config.latency_probe.map(|secs| {
+                let every = Duration::from_secs(secs.max(1));
+                LatencyProbe::new(&stream_id, every, handle.health(), relay.announced.consume(), claim.path())
+            }),
+            throttle: BridgeThrottle::new(
+                &stream_id,
+                Scoped::resolve(&config.bridge_rate_limit, &stream_id),
+                &config.track_rate_limit,
+                relay.egress.clone(),
+            ),
+            delivery_timeout: config.delivery_timeout.map(Duration::from_millis),
+            capture: config.capture_latency.then(|| CaptureMeter::new(&stream_id, handle.health())),
+            demand: config.on_demand.map(|secs| Demand::new(Duration::from_secs(secs))),
+        };
+        let duplicates = DuplicateWatch::new(config.duplicates, &stream_id, relay.announced.consume(), claim.path());
+        let relay_sink: Arc<dyn BridgeSink> = match &services.broadcast_sessions {
+            Some(sessions) => Arc::new(BroadcastSink::new(sessions.clone(), relay.clone(), claim)),
+            None => Arc::new(RelaySink::new(relay.publish.producer.clone(), claim)),
+        };
+        let mut sinks = vec![relay_sink];
+        if let Some(record) = config.record_options(&stream_id) {
+            sinks.push(Arc::new(FileSink::new(record)));
+        }
+        sinks.extend(services.sinks.iter().cloned());
+        let outputs = BridgeOutputs {
+            sinks,
+            #[cfg(feature = "http")]
+            packager: services.packager.clone(),
+            #[cfg(feature = "http")]
+            formats: config.formats(&stream_id),
+            injectors: services.injectors.clone(),
+            handle: handle.clone(),
+            metadata,
+            ad_markers: config.ad_marker_options(),
+            udp: Scoped::resolve(&config.udp_output, &stream_id),
+            idle_timeout: config.idle_timeout.map(Duration::from_secs),
+            stall_timeout: config.stall_timeout.map(Duration::from_secs),
+            evict: config.evict_options(),
+            duplicates,
+            heartbeat: services.watchdog.register(format!("bridge {stream_id}"), Stuck::Restart),
+            #[cfg(feature = "ffmpeg")]
+            thumbnails: config.thumbnail_options(),
+        };
+
+        Self {
+            namespace,
+            options,
+            outputs,
+            source,
+            stopped,
+            handle,
+            events,
+            expires,
+            #[cfg(feature = "redis")]
+            lease: admitted.lease,
+            #[cfg(feature = "history")]
+            history: services.history.clone(),
+        }
+    }
+
+    pub fn stream_id(&self) -> &str {
+        self.handle.stream_id()
+    }
+
+    /// What the lifecycle callbacks are told about it
+    pub fn context(&self) -> &BridgeContext {
+        self.events.context()
+    }
+
+    pub fn handle(&self) -> &BridgeHandle {
+        &self.handle
+    }
+
+    /// Bridge the stream until it ends or is stopped, and report how it went
+    pub async fn run(self) -> Result<BridgeEnd, BridgeError> {
+        let Self { namespace, options, outputs, source, stopped, handle, events, expires, .. } = self;
+        let stream_id = handle.stream_id().to_string();
+        let heartbeat = outputs.heartbeat.clone();
+        let health = options.health.clone();
+        let bridge = {
+            let stream_id = stream_id.clone();
+            async move {
+                let bridge = bridge_stream(&stream_id, &namespace, options, outputs, source, stopped);
+                tasks::instrument(&stream_id, bridge).await
+            }
+        };
+        #[cfg(feature = "sentry")]
+        let bridge = crash::bind(events.context(), bridge);
+
+        // Tell the callbacks once the first frame reaches the relay
+        let activated = async {
+            health.wait_active().await;
+            events.active();
+            std::future::pending().await
+        };
+        // Stop the bridge if another replica takes it over
+        let kept = async {
+            #[cfg(feature = "redis")]
+            if let Some(lease) = &self.lease {
+                lease.keep().await;
+                handle.end(BridgeEnd::LeaseLost);
+            }
+            std::future::pending().await
+        };
+        // Stop the bridge once its registry entry expires
+        let expiring = async {
+            expiry::wait(expires).await;
+            if handle.end(BridgeEnd::Expired) {
+                tracing::info!(stream_id = %stream_id, "stream expired, stopping bridge");
+            }
+            std::future::pending().await
+        };
+        let end = tokio::select! {
+            end = supervise::bridge(&stream_id, bridge) => end,
+            _ = heartbeat.stuck() => Err(BridgeError::Stuck),
+            end = activated => end,
+            end = kept => end,
+            end = expiring => end,
+        };
+        events.ended(&end);
+        #[cfg(feature = "history")]
+        if let Some(history) = &self.history {
+            history.record(session(events.context(), &handle, &end)).await;
+        }
+        #[cfg(feature = "redis")]
+        if let Some(lease) = self.lease {
+            lease.release().await;
+        }
+        end
+    }
+}
+
+/// The history entry of a bridge that ended with `end`
+#[cfg(feature = "history")]
+fn session(context: &BridgeContext, handle: &BridgeHandle, end: &Result<BridgeEnd, BridgeError>) -> Session {
+    let stats = handle.stats();
+    let (reason, error) = match end {
+        Ok(end) => (end.name().to_string(), None),
+        Err(err) => ("failed".to_string(), Some(format!("{err:#}"))),
+    };
+    Session {
+        stream_id: context.stream.stream_id.clone(),
+        namespace: context.namespace.clone(),
+        relay: context.relay.clone(),
+        path: context.path.clone(),
+        started: history::unix(context.started),
+        ended: history::unix(SystemTime::now()),
+        bytes: stats.bytes,
+        groups: stats.groups,
+        frames: stats.frames,
+        reason,
+        error,
+    }
+}
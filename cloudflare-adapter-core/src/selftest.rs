@@ -0,0 +1,313 @@
+//! `self-test`
+//!
+//! A smoke test for deploy pipelines: publishes a generated test pattern under a fresh
+//! stream id, bridges it with an adapter running in-process and reads it back from the
+//! relay side, checking every frame arrives as it was written. It exits non-zero if
+//! they don't arrive intact within `--timeout`.
+//!
+//! With `--endpoints loopback` (the default) both ends are local
+//! [mock servers](crate::testing::MockServer), which checks the build and the bridge
+//! path itself. With `--endpoints configured` the pattern is published to
+//! `--cloudflare-url` and read back from `--relay-url`, which checks a deployment's
+//! endpoints, TLS and `--relay-token` too.
+//!
+//! The adapter runs with the configured connection and bridging options, but none that
+//! reach beyond the bridge (other relays, the HTTP server, recording, leases, the state
+//! file, metrics push) or change what's forwarded (transforms, interceptors, filters).
+//!
+//! With `--checksum` it runs with `--e2ee` too, and every frame has to
+//! arrive byte-exact: the SHA-256 of each one read back must match that of the frame as
+//! published, timestamp prefix and all.
+//!
+//! It needs the `self-test` feature, on by default.
+
+#[cfg(feature = "self-test")]
+use std::future::Future;
+use std::time::Duration;
+#[cfg(feature = "self-test")]
+use std::time::UNIX_EPOCH;
+
+#[cfg(feature = "self-test")]
+use anyhow::Context;
+#[cfg(feature = "self-test")]
+use moq_lite::{BroadcastConsumer, Origin, OriginConsumer, Session, Track};
+#[cfg(feature = "self-test")]
+use moq_native::ClientConfig;
+#[cfg(feature = "self-test")]
+use tokio::time::Instant;
+#[cfg(feature = "self-test")]
+use url::Url;
+
+#[cfg(feature = "self-test")]
+use crate::discovery::Discovery;
+#[cfg(feature = "self-test")]
+#[cfg(feature = "self-test")]
+use crate::registry::StreamInfo;
+#[cfg(feature = "self-test")]
+use crate::testing::{MockServer, SyntheticBroadcast, SyntheticOptions};
+#[cfg(feature = "self-test")]
+use crate::timestamp::RebaseMode;
+#[cfg(feature = "self-test")]
+use crate::{Adapter, AdapterHandle};
+use crate::AdapterConfig;
+
+/// Where the test pattern is bridged from and to
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum Endpoints {
+    /// Local stand-ins for CloudFlare and the relay
+    #[default]
+    Loopback,
+    /// `--cloudflare-url` and `--relay-url`
+    Configured,
+}
+
+/// Bridge the test pattern until `frames` of it arrived intact, failing if they don't
+///
+/// With `checksum`, intact means byte-exact, bridged with `--e2ee`.
+#[cfg(feature = "self-test")]
+pub async fn run(
+    config: &AdapterConfig,
+    endpoints: Endpoints,
+    frames: usize,
+    timeout: Duration,
+    checksum: bool,
+) -> anyhow::Result<()> {
+    let stream = StreamInfo::new(format!("self-test-{:08x}", rand::random::<u32>()));
+    let options = SyntheticOptions::default();
+    let (started, deadline) = (Instant::now(), Instant::now() + timeout);
+
+    let mut config = config.clone();
+    if checksum {
+        config.e2ee = true;
+        config.rebase_timestamps = RebaseMode::None;
+    }
+
+    tracing::info!(stream_id = stream.stream_id, ?endpoints, checksum, "starting self-test");
+    let mut harness = Harness::start(&config, endpoints, std::slice::from_ref(&stream), &options, deadline).await?;
+
+    let test = async {
+        let bridged = loop {
+            match harness.announced().await? {
+                (path, broadcast) if path == stream.stream_id => break broadcast,
+                _ => continue,
+            }
+        };
+        tracing::info!(elapsed = ?started.elapsed(), "bridged broadcast announced");
+        verify(&bridged, &options, frames, checksum).await
+    };
+    let res = match tokio::time::timeout_at(deadline, test).await {
+        Ok(res) => res,
+        Err(_) => Err(anyhow::anyhow!("{frames} frames didn't arrive within {timeout:?}")),
+    };
+    harness.stop().await;
+
+    let groups = res.context("self-test failed")?;
+    tracing::info!(frames, groups, elapsed = ?started.elapsed(), "self-test passed");
+    Ok(())
+}
+
+/// Synthetic streams bridged by an adapter running in-process, and a session to read
+/// them back from the relay side
+#[cfg(feature = "self-test")]
+pub(crate) struct Harness {
+    adapter: AdapterHandle,
+    /// What the relay announces to us
+    reading: OriginConsumer,
+    /// Everything that has to stay up while the adapter bridges
+    _patterns: Vec<SyntheticBroadcast>,
+    _servers: Option<(MockServer, MockServer)>,
+    _sessions: (Session, Session),
+}
+
+#[cfg(feature = "self-test")]
+impl Harness {
+    /// Publish `pattern` under each of `streams` and start bridging them, connecting before `deadline`
+    pub(crate) async fn start(
+        config: &AdapterConfig,
+        endpoints: Endpoints,
+        streams: &[StreamInfo],
+        pattern: &SyntheticOptions,
+        deadline: Instant,
+    ) -> anyhow::Result<Self> {
+        let client = ClientConfig::default().init()?;
+
+        // Local ends stay up until we're done
+        let servers = match endpoints {
+            Endpoints::Loopback => Some((MockServer::start_cloudflare().await?, MockServer::start().await?)),
+            Endpoints::Configured => None,
+        };
+
+        // The adapter bridges nothing but our streams, between the two ends
+        let mut test_config = adapter_config(config, streams);
+        if let Some((cloudflare, relay)) = &servers {
+            test_config.cloudflare_url = cloudflare.url()?.to_string();
+            test_config.relay_url = relay.url()?.to_string();
+            test_config.relay_token = None;
+            test_config.relay_token_key = None;
+            test_config.relay_token_url = None;
+        }
+
+        // Publish the patterns where the adapter will look for them, over our own session
+        let publishing = Origin::produce();
+        let mut patterns = Vec::with_capacity(streams.len());
+        for stream in streams {
+            let template = &test_config.cf_namespace_template;
+            let namespace = test_config.namespace_encoding.render(template, |name| stream.field(name))
+                .context("can't build the test stream's namespace")?;
+            let broadcast = SyntheticBroadcast::new(pattern.clone());
+            publishing.producer.publish_broadcast(&namespace, broadcast.consume());
+            patterns.push(broadcast);
+        }
+        let cloudflare_url = Url::parse(&test_config.cloudflare_url).context("invalid cloudflare url")?;
+        let publisher = client.connect(cloudflare_url, publishing.consumer, None);
+        let publisher = before(deadline, publisher).await.context("failed to publish the test pattern to cloudflare")?;
+
+        // And read them back from the relay the same way
+        let reading = Origin::produce();
+        let relay_url = match &test_config.relay_token {
+            Some(token) => Url::parse(&format!("{}/?jwt={token}", test_config.relay_url)),
+            None => Url::parse(&test_config.relay_url),
+        };
+        let subscriber = client.connect(relay_url.context("invalid relay url")?, None, reading.producer);
+        let subscriber = before(deadline, subscriber).await.context("failed to subscribe to the relay")?;
+
+        Ok(Self {
+            adapter: Adapter::builder(test_config).spawn(),
+            reading: reading.consumer,
+            _patterns: patterns,
+            _servers: servers,
+            _sessions: (publisher, subscriber),
+        })
+    }
+
+    /// The next broadcast the relay announces, and its path
+    pub(crate) async fn announced(&mut self) -> anyhow::Result<(String, BroadcastConsumer)> {
+        loop {
+            match self.reading.announced().await {
+                Some((path, Some(broadcast))) => return Ok((path.to_string(), broadcast)),
+                Some((_, None)) => continue,
+                None => anyhow::bail!("relay session closed"),
+            }
+        }
+    }
+
+    /// Stop the adapter, draining its bridges
+    pub(crate) async fn stop(self) {
+        if let Err(err) = self.adapter.shutdown().await {
+            tracing::debug!(%err, "test adapter didn't stop cleanly");
+        }
+    }
+}
+
+/// The configuration to bridge nothing but `streams` with
+#[cfg(feature = "self-test")]
+fn adapter_config(config: &AdapterConfig, streams: &[StreamInfo]) -> AdapterConfig {
+    let mut test = config.clone();
+    test.command = None;
+    test.discovery = Discovery::Static;
+    test.static_streams = streams.iter().map(|stream| stream.stream_id.clone()).collect();
+    test.include_streams.clear();
+    test.exclude_streams.clear();
+    test.canary = None;
+    test.prewarm.clear();
+    test.bridge_prefixes.clear();
+    test.stream_paths.clear();
+    test.publish_root.clear();
+    test.relay_targets.clear();
+    test.reload_interval = None;
+    test.require_connections_on_start = None;
+
+    // Nothing beyond the bridge
+    test.http_listen = None;
+    test.hls = false;
+    test.dash = false;
+    test.record_dir = None;
+    test.udp_output.clear();
+    test.spill_dir = None;
+    test.lease_redis_url = None;
+    test.state_file = None;
+    test.history_db = None;
+    test.record_registry = None;
+    test.metrics_push_url = None;
+    test.stats_url = None;
+    test.sentry_dsn = None;
+    test.chaos.clear();
+    test.soak.clear();
+    #[cfg(feature = "ffmpeg")]
+    {
+        test.thumbnail_url = None;
+        test.srt_ingest.clear();
+    }
+
+    // Nothing that changes the frames
+    test.transform_command = None;
+    test.interceptors.clear();
+    test.track_filters.clear();
+    test.track_aliases.clear();
+    test.max_video_height.clear();
+    test.max_video_bitrate.clear();
+    test.ad_marker_track = None;
+    test
+}
+
+/// Connect, or give up at `deadline`
+#[cfg(feature = "self-test")]
+async fn before<T>(deadline: Instant, connect: impl Future<Output = anyhow::Result<T>>) -> anyhow::Result<T> {
+    match tokio::time::timeout_at(deadline, connect).await {
+        Ok(res) => res,
+        Err(_) => anyhow::bail!("timed out"),
+    }
+}
+
+/// Read `frames` frames of the pattern from `bridged`, checking each, and return how many groups they took
+#[cfg(feature = "self-test")]
+async fn verify(
+    bridged: &BroadcastConsumer,
+    options: &SyntheticOptions,
+    frames: usize,
+    checksum: bool,
+) -> anyhow::Result<usize> {
+    let mut track = bridged.subscribe_track(&Track::new(&options.track));
+    let (mut received, mut groups) = (0, 0);
+
+    while received < frames {
+        let mut group = track.next_group().await?.context("track ended")?;
+        let sequence = group.info.sequence;
+        groups += 1;
+
+        // Groups superseded by newer ones can end early, but never out of order
+        let mut index = 0;
+        while received < frames {
+            let Some(frame) = group.read_frame().await? else {
+                break;
+            };
+            let written = options.check(sequence, index, &frame)?;
+            if checksum {
+                let published = options.frame(sequence, index, written.duration_since(UNIX_EPOCH).unwrap_or_default());
+                let (expected, got) = (sha256(&published), sha256(&frame));
+                anyhow::ensure!(got == expected, "frame {index} of group {sequence} has SHA-256 {got}, not {expected}");
+            }
+            (index, received) = (index + 1, received + 1);
+        }
+        tracing::debug!(sequence, frames = index, "group arrived intact");
+    }
+    Ok(groups)
+}
+
+/// The SHA-256 of a frame, in hex
+#[cfg(feature = "self-test")]
+fn sha256(frame: &[u8]) -> String {
+    let digest = ring::digest::digest(&ring::digest::SHA256, frame);
+    digest.as_ref().iter().map(|b| format!("{b:02x}")).collect()
+}
+
+#[cfg(not(feature = "self-test"))]
+pub async fn run(
+    _config: &AdapterConfig,
+    _endpoints: Endpoints,
+    _frames: usize,
+    _timeout: Duration,
+    _checksum: bool,
+) -> anyhow::Result<()> {
+    anyhow::bail!("built without the self-test feature")
+}
@@ -0,0 +1,373 @@
+//! Configuration files
+//!
+//! `--config adapter.toml` (or `CONFIG_FILE`) sets any option the CLI takes, keyed by
+//! its long flag name: `poll-interval = 10`, `shed-order = ["*1080p*", "*720p*"]`.
+//! Options that take `[stream_id=]value` lists can also be set per stream, under a
+//! `[streams."<stream_id>"]` table, which is easier to keep straight than a long list
+//! of prefixed entries for routing and filter rules.
+//!
+//! The file fills in the environment variable of each option it sets, unless the
+//! environment already sets it, so a fleet-wide file is overridden by the environment,
+//! which is overridden by the command line.
+//!
+//! Files ending in `.yaml` or `.yml` are read as YAML, with the same keys and tables as
+//! mappings: `poll-interval: 10`, and `streams:` mapping each stream to its options.
+//! Anything else is read as TOML.
+//!
+//! `--profile prod` (or `PROFILE`) also applies the `[profiles.prod]` table, whose
+//! options replace the top-level ones, so one file can hold the endpoints, poll
+//! intervals and log levels of every environment the adapter runs in.
+//!
+//! `[tenants.<name>]` tables split one deployment between customers: each holds a
+//! tenant's own registry, CF endpoint, namespace template and relay, replacing the
+//! top-level and profile options. Profiles and tenants can have `streams` tables of their
+//! own too, adding to the top-level ones. A file with tenants runs one adapter per tenant,
+//! see [tenant](crate::tenant), and `--tenant acme` (or `TENANT`) runs just that one.
+
+use std::collections::{BTreeMap, HashSet};
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+
+use anyhow::Context;
+use toml_edit::{Array, Document, DocumentMut, Item, Table, TableLike, Value};
+use yaml_rust2::{Yaml, YamlLoader};
+
+/// Options that take `[stream_id=]value` entries, and so can be set per stream
+const SCOPED: &[&str] = &[
+    "track-filter",
+    "track-alias",
+    "transform-track",
+    "max-video-height",
+    "max-video-bitrate",
+    "udp-output",
+    "stream-path",
+];
+
+/// A loaded config file
+pub struct Loaded {
+    pub path: PathBuf,
+    pub profile: Option<String>,
+    pub tenant: Option<String>,
+    /// The environment variables the file filled in
+    pub env: HashSet<String>,
+}
+
+/// Load the config file named on the command line or in the environment, if any
+pub fn load(command: &clap::Command) -> anyhow::Result<Option<Loaded>> {
+    let Some(path) = arg("config", "CONFIG_FILE").map(PathBuf::from) else {
+        return Ok(None);
+    };
+    let profile = arg("profile", "PROFILE").map(|profile| profile.to_string_lossy().into_owned());
+    let tenant = arg("tenant", "TENANT").map(|tenant| tenant.to_string_lossy().into_owned());
+
+    let mut env = HashSet::new();
+    for (var, values) in read(command, &path, profile.as_deref(), tenant.as_deref())? {
+        if std::env::var_os(&var).is_none() {
+            std::env::set_var(&var, values.join(","));
+            env.insert(var);
+        }
+    }
+
+    Ok(Some(Loaded {
+        path,
+        profile,
+        tenant,
+        env,
+    }))
+}
+
+/// The names of the `[tenants]` tables in the config file at `path`
+pub fn tenants(path: &Path) -> anyhow::Result<Vec<String>> {
+    let document = parse(path)?;
+    let Some(tenants) = document.get("tenants") else {
+        return Ok(Vec::new());
+    };
+    let tenants = tenants.as_table_like().context("tenants: expected a table of tenants")?;
+    Ok(tenants.iter().map(|(name, _)| name.to_string()).collect())
+}
+
+/// Each option the config file at `path` sets under `profile` and `tenant`, by environment variable
+pub fn read(
+    command: &clap::Command,
+    path: &Path,
+    profile: Option<&str>,
+    tenant: Option<&str>,
+) -> anyhow::Result<BTreeMap<String, Vec<String>>> {
+    let document = parse(path)?;
+
+    // Each option's values, by environment variable
+    let mut options: BTreeMap<String, Vec<String>> = BTreeMap::new();
+    for (key, item) in document.iter() {
+        // Flags are off unless given, and an explicit false would still count as given
+        if ["streams", "profiles", "tenants"].contains(&key) || item.as_value().and_then(Value::as_bool) == Some(false) {
+            continue;
+        }
+        let (env, list) = option(command, key)?;
+        options.entry(env).or_default().extend(values(key, item, list)?);
+    }
+
+    // The tenant's options win over the profile's
+    let profile = profile.map(|profile| section(&document, "profiles", profile)).transpose()?;
+    let tenant = tenant.map(|tenant| section(&document, "tenants", tenant)).transpose()?;
+    for table in [profile, tenant].into_iter().flatten() {
+        overlay(command, table, &mut options)?;
+    }
+
+    // Their streams come on top of the top-level ones
+    let tables = [profile, tenant].into_iter().flatten().map(|table| table.get("streams"));
+    for streams in std::iter::once(document.get("streams")).chain(tables).flatten() {
+        let streams = streams.as_table_like().context("streams: expected a table of streams")?;
+        for (stream_id, item) in streams.iter() {
+            let table = item.as_table_like().with_context(|| format!("streams.{stream_id}: expected a table"))?;
+            for (key, item) in table.iter() {
+                anyhow::ensure!(SCOPED.contains(&key), "streams.{stream_id}.{key}: can't be set per stream");
+                let (env, list) = option(command, key)?;
+                let values = values(key, item, list)?.into_iter().map(|value| format!("{stream_id}={value}"));
+                options.entry(env).or_default().extend(values);
+            }
+        }
+    }
+
+    Ok(options)
+}
+
+fn parse(path: &Path) -> anyhow::Result<DocumentMut> {
+    let raw = std::fs::read_to_string(path).with_context(|| format!("failed to read {}", path.display()))?;
+    let yaml = path.extension().is_some_and(|ext| ext.eq_ignore_ascii_case("yaml") || ext.eq_ignore_ascii_case("yml"));
+    let document = match yaml {
+        true => from_yaml(&raw),
+        false => Document::parse(raw).map(Document::into_mut).map_err(anyhow::Error::from),
+    };
+    document.map_err(|err| anyhow::anyhow!("failed to parse {}: {err}", path.display()))
+}
+
+/// The TOML document a YAML one stands for, so both are read the same way
+fn from_yaml(raw: &str) -> anyhow::Result<DocumentMut> {
+    let mut document = DocumentMut::new();
+    let mut documents = YamlLoader::load_from_str(raw)?;
+    anyhow::ensure!(documents.len() <= 1, "expected a single YAML document");
+    match documents.pop() {
+        None | Some(Yaml::Null) => {}
+        Some(Yaml::Hash(hash)) => {
+            for (key, value) in hash {
+                let key = yaml_key(&key)?;
+                let item = yaml_item(&key, value)?;
+                document.insert(&key, item);
+            }
+        }
+        Some(_) => anyhow::bail!("expected a mapping of options"),
+    }
+    Ok(document)
+}
+
+/// Mappings become tables, for `streams`, `profiles` and `tenants`
+fn yaml_item(key: &str, yaml: Yaml) -> anyhow::Result<Item> {
+    let Yaml::Hash(hash) = yaml else {
+        return Ok(Item::Value(yaml_value(key, yaml)?));
+    };
+    let mut table = Table::new();
+    for (name, value) in hash {
+        let name = yaml_key(&name)?;
+        let item = yaml_item(&format!("{key}.{name}"), value)?;
+        table.insert(&name, item);
+    }
+    Ok(Item::Table(table))
+}
+
+fn yaml_value(key: &str, yaml: Yaml) -> anyhow::Result<Value> {
+    let value = match yaml {
+        Yaml::String(value) => value.into(),
+        Yaml::Integer(value) => value.into(),
+        Yaml::Boolean(value) => value.into(),
+        Yaml::Real(_) => yaml.as_f64().with_context(|| format!("{key}: invalid number"))?.into(),
+        Yaml::Array(values) => {
+            let values = values.into_iter().map(|value| yaml_value(key, value));
+            Value::Array(values.collect::<anyhow::Result<Array>>()?)
+        }
+        Yaml::Null => anyhow::bail!("{key}: expected a value"),
+        _ => anyhow::bail!("{key}: expected a string, number, boolean or list"),
+    };
+    Ok(value)
+}
+
+/// Keys are names, but stream ids can read as numbers
+fn yaml_key(yaml: &Yaml) -> anyhow::Result<String> {
+    match yaml {
+        Yaml::String(key) | Yaml::Real(key) => Ok(key.clone()),
+        Yaml::Integer(key) => Ok(key.to_string()),
+        Yaml::Boolean(key) => Ok(key.to_string()),
+        _ => anyhow::bail!("expected a name for a key, found {yaml:?}"),
+    }
+}
+
+/// The `[<kind>.<name>]` table
+fn section<'a>(document: &'a DocumentMut, kind: &str, name: &str) -> anyhow::Result<&'a dyn TableLike> {
+    let table = document.get(kind).and_then(|tables| tables.get(name)).and_then(Item::as_table_like);
+    table.with_context(|| format!("no [{kind}.{name}] table"))
+}
+
+/// Replace `options` with the ones a profile or tenant table sets, but for its streams
+fn overlay(
+    command: &clap::Command,
+    table: &dyn TableLike,
+    options: &mut BTreeMap<String, Vec<String>>,
+) -> anyhow::Result<()> {
+    for (key, item) in table.iter() {
+        if key == "streams" {
+            continue;
+        }
+        let (env, list) = option(command, key)?;
+        match item.as_value().and_then(Value::as_bool) {
+            // Turning a flag off means it's not given at all
+            Some(false) => options.remove(&env),
+            _ => options.insert(env, values(key, item, list)?),
+        };
+    }
+    Ok(())
+}
+
+/// The value of `--<long>` on the command line, or of `env`, ahead of clap
+fn arg(long: &str, env: &str) -> Option<OsString> {
+    let (flag, prefix) = (format!("--{long}"), format!("--{long}="));
+    let mut args = std::env::args_os().skip(1);
+    while let Some(arg) = args.next() {
+        if arg == flag.as_str() {
+            return args.next();
+        }
+        if let Some(value) = arg.to_str().and_then(|arg| arg.strip_prefix(&prefix)) {
+            return Some(value.into());
+        }
+    }
+
+    std::env::var_os(env).filter(|value| value != &OsString::new())
+}
+
+/// The environment variable behind the option with long name `key`, and whether it's a list
+fn option(command: &clap::Command, key: &str) -> anyhow::Result<(String, bool)> {
+    let long = key.replace('_', "-");
+    anyhow::ensure!(!["config", "profile", "tenant"].contains(&long.as_str()), "{key}: can't be set from the config file");
+
+    let arg = command
+        .get_arguments()
+        .find(|arg| arg.get_long() == Some(long.as_str()))
+        .with_context(|| format!("{key}: unknown option"))?;
+    let env = arg.get_env().with_context(|| format!("{key}: can't be set from the config file"))?;
+    Ok((env.to_string_lossy().into_owned(), arg.get_value_delimiter().is_some()))
+}
+
+/// The values of one option, as they'd be passed on the command line
+fn values(key: &str, item: &Item, list: bool) -> anyhow::Result<Vec<String>> {
+    let value = item.as_value().with_context(|| format!("{key}: expected a value"))?;
+
+    let values = match value.as_array() {
+        Some(array) if list => array.iter().map(|value| scalar(key, value)).collect::<anyhow::Result<_>>()?,
+        Some(_) => anyhow::bail!("{key}: expected a single value"),
+        None => vec![scalar(key, value)?],
+    };
+
+    // Lists are joined with commas in the environment
+    anyhow::ensure!(!list || values.iter().all(|value| !value.contains(',')), "{key}: list entries can't contain commas");
+    Ok(values)
+}
+
+fn scalar(key: &str, value: &Value) -> anyhow::Result<String> {
+    if let Some(value) = value.as_str() {
+        return Ok(value.to_string());
+    }
+
+    let value = match (value.as_integer(), value.as_float(), value.as_bool()) {
+        (Some(value), _, _) => value.to_string(),
+        (_, Some(value), _) => value.to_string(),
+        (_, _, Some(value)) => value.to_string(),
+        _ => anyhow::bail!("{key}: expected a string, number or boolean"),
+    };
+    Ok(value)
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::CommandFactory;
+
+    use super::*;
+    use crate::AdapterConfig;
+
+    const TOML: &str = r#"
+poll-interval = 10
+e2ee = true
+reload-teardown = false
+shed-order = ["*1080p*", "*720p*"]
+
+[streams."live/main"]
+track-filter = "video*"
+udp-output = ["udp://239.0.0.1:5000", "udp://239.0.0.2:5000"]
+
+[streams.42]
+stream-path = "events/42"
+
+[profiles.prod]
+poll-interval = 30
+log-level = "warn"
+
+[tenants.acme]
+registry-url = "https://acme.example/streams"
+"#;
+
+    const YAML: &str = r#"
+poll-interval: 10
+e2ee: true
+reload-teardown: false
+shed-order: ["*1080p*", "*720p*"]
+streams:
+  live/main:
+    track-filter: video*
+    udp-output:
+      - udp://239.0.0.1:5000
+      - udp://239.0.0.2:5000
+  42:
+    stream-path: events/42
+profiles:
+  prod:
+    poll-interval: 30
+    log-level: warn
+tenants:
+  acme:
+    registry-url: https://acme.example/streams
+"#;
+
+    /// `read` on `contents` saved as `name`
+    fn read_file(name: &str, contents: &str, profile: Option<&str>, tenant: Option<&str>) -> BTreeMap<String, Vec<String>> {
+        let dir = std::env::temp_dir().join(format!("cloudflare-adapter-settings-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join(name);
+        std::fs::write(&path, contents).unwrap();
+        let options = read(&AdapterConfig::command(), &path, profile, tenant).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        options
+    }
+
+    #[test]
+    fn yaml_reads_like_toml() {
+        for (profile, tenant) in [(None, None), (Some("prod"), None), (Some("prod"), Some("acme"))] {
+            let toml = read_file("adapter.toml", TOML, profile, tenant);
+            for name in ["adapter.yaml", "adapter.YML"] {
+                assert_eq!(read_file(name, YAML, profile, tenant), toml, "{name} with {profile:?} and {tenant:?}");
+            }
+        }
+
+        let options = read_file("adapter.toml", TOML, Some("prod"), None);
+        assert_eq!(options["POLL_INTERVAL"], ["30"]);
+        assert_eq!(options["E2EE"], ["true"]);
+        assert!(!options.contains_key("RELOAD_TEARDOWN"));
+        assert_eq!(options["SHED_ORDER"], ["*1080p*", "*720p*"]);
+        assert_eq!(options["UDP_OUTPUT"], ["live/main=udp://239.0.0.1:5000", "live/main=udp://239.0.0.2:5000"]);
+        assert_eq!(options["STREAM_PATHS"], ["42=events/42"]);
+    }
+
+    #[test]
+    fn yaml_rejects_what_toml_cant_say() {
+        assert!(from_yaml("poll-interval:").is_err());
+        assert!(from_yaml("- poll-interval").is_err());
+        assert!(from_yaml("a: 1\n---\nb: 2").is_err());
+        assert!(from_yaml("").unwrap().is_empty());
+    }
+}
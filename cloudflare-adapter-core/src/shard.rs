@@ -0,0 +1,114 @@
+//! Bridge runtimes
+//!
+//! With a few hundred bridges on one tokio runtime, media forwarding starves the
+//! scheduler and everything else sees latency spikes. `--bridge-runtimes N` runs
+//! bridges on N separate runtimes of their own, each on its own threads, leaving the
+//! main runtime to the registry, the connections and the HTTP server. Each new bridge
+//! goes to the shard running the fewest.
+//!
+//! Every shard reports how many bridges it runs and how late its timers fire, which
+//! is a good proxy for scheduler latency.
+
+use std::future::Future;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use anyhow::Context;
+use tokio::runtime::Handle;
+
+use crate::metrics::Gauge;
+
+/// How often each shard measures its scheduling delay
+const PROBE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// The runtimes bridges are spread across
+pub struct Shards {
+    shards: Vec<Shard>,
+}
+
+struct Shard {
+    handle: Handle,
+    bridges: AtomicUsize,
+    gauge: Gauge,
+}
+
+impl Shards {
+    /// Start `count` bridge runtimes, or use the current runtime for everything if None
+    pub fn new(count: Option<usize>) -> anyhow::Result<Arc<Self>> {
+        let handles = match count {
+            None => vec![Handle::current()],
+            Some(count) => {
+                let threads = std::thread::available_parallelism().map_or(1, |n| n.get());
+                let workers = (threads / count.max(1)).max(1);
+                (0..count.max(1)).map(|index| start(index, workers)).collect::<anyhow::Result<_>>()?
+            }
+        };
+
+        let shards = handles
+            .into_iter()
+            .enumerate()
+            .map(|(index, handle)| {
+                let label = index.to_string();
+                handle.spawn(probe(label.clone()));
+                Shard {
+                    handle,
+                    bridges: AtomicUsize::new(0),
+                    gauge: Gauge::new("shard_bridges", "Bridges running on each runtime", &[("shard", &label)]),
+                }
+            })
+            .collect();
+
+        Ok(Arc::new(Self { shards }))
+    }
+
+    /// Run a bridge on the least loaded shard
+    pub fn spawn(self: &Arc<Self>, bridge: impl Future<Output = ()> + Send + 'static) {
+        let index = (0..self.shards.len())
+            .min_by_key(|&i| self.shards[i].bridges.load(Ordering::Relaxed))
+            .unwrap_or(0);
+
+        let shards = self.clone();
+        let shard = &self.shards[index];
+        shard.gauge.set(shard.bridges.fetch_add(1, Ordering::Relaxed) as i64 + 1);
+
+        shard.handle.spawn(async move {
+            bridge.await;
+            let shard = &shards.shards[index];
+            shard.gauge.set(shard.bridges.fetch_sub(1, Ordering::Relaxed) as i64 - 1);
+        });
+    }
+}
+
+/// Start a runtime on its own thread, which keeps it alive for the life of the process
+fn start(index: usize, workers: usize) -> anyhow::Result<Handle> {
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .worker_threads(workers)
+        .thread_name(format!("bridge-{index}"))
+        .enable_all()
+        .build()
+        .context("failed to start bridge runtime")?;
+
+    let handle = runtime.handle().clone();
+    std::thread::Builder::new()
+        .name(format!("bridge-{index}-main"))
+        .spawn(move || runtime.block_on(std::future::pending::<()>()))?;
+
+    Ok(handle)
+}
+
+/// Report how late timers fire on the current runtime
+async fn probe(shard: String) {
+    let gauge = Gauge::new(
+        "shard_schedule_delay_us",
+        "How late a timer fired on each runtime (microseconds)",
+        &[("shard", &shard)],
+    );
+
+    loop {
+        let start = Instant::now();
+        tokio::time::sleep(PROBE_INTERVAL).await;
+        let late = start.elapsed().saturating_sub(PROBE_INTERVAL);
+        gauge.set(late.as_micros() as i64);
+    }
+}
@@ -0,0 +1,136 @@
+//! Track shedding under relay-side congestion
+//!
+//! When the QUIC connection to the relay shows sustained loss or queueing delay, we
+//! stop forwarding new groups of the least important tracks, one level at a time, and
+//! bring them back once the connection has been healthy for a while. Levels come from
+//! `--shed-order`: tracks matching the first pattern go first. Tracks that match no
+//! pattern (and the catalog) are never shed.
+//!
+//! The per-sample congestion state is also published for the backpressure policies,
+//! whether or not anything is set up to be shed.
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use moq_native::web_transport_quinn::quinn;
+use tokio::sync::watch;
+
+use crate::filter::glob_match;
+
+/// How often the connection stats are sampled
+const SAMPLE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Consecutive congested samples before shedding another level
+const SHED_AFTER: u32 = 5;
+
+/// Consecutive healthy samples before restoring a level
+const RESTORE_AFTER: u32 = 15;
+
+/// When and what to shed
+#[derive(Clone, Debug, Default)]
+pub struct ShedOptions {
+    /// Track patterns, shed first to last
+    pub order: Vec<String>,
+    /// Packet loss ratio that counts as congestion
+    pub loss: f64,
+    /// RTT above the minimum that counts as congestion
+    pub delay: Duration,
+}
+
+/// Decides which tracks are shed, shared by every bridge on the relay connection
+pub struct Shedder {
+    options: ShedOptions,
+    // How many entries of `order` are currently shed
+    level: AtomicUsize,
+    // Whether the latest sample looked congested
+    congested: watch::Sender<bool>,
+}
+
+impl Shedder {
+    pub fn new(options: ShedOptions) -> Arc<Self> {
+        Arc::new(Self {
+            options,
+            level: AtomicUsize::new(0),
+            congested: watch::Sender::new(false),
+        })
+    }
+
+    /// A handle for checking `track`, or None if it can never be shed
+    pub fn track(self: &Arc<Self>, track: &str) -> Option<TrackShed> {
+        let index = self.options.order.iter().position(|p| glob_match(p, track))?;
+        Some(TrackShed {
+            shedder: self.clone(),
+            index,
+        })
+    }
+
+    /// Follow whether the relay connection is currently congested
+    pub fn congestion(&self) -> watch::Receiver<bool> {
+        self.congested.subscribe()
+    }
+
+    /// Adjust the shed level from the relay connection's stats until it closes
+    pub async fn monitor(&self, quic: quinn::Connection) {
+        let mut interval = tokio::time::interval(SAMPLE_INTERVAL);
+        let mut last = quic.stats().path;
+        let mut min_rtt = last.rtt;
+        let (mut congested, mut healthy) = (0, 0);
+
+        loop {
+            tokio::select! {
+                _ = interval.tick() => {},
+                _ = quic.closed() => break,
+            }
+
+            let path = quic.stats().path;
+            let sent = path.sent_packets.saturating_sub(last.sent_packets);
+            let lost = path.lost_packets.saturating_sub(last.lost_packets);
+            last = path;
+
+            min_rtt = min_rtt.min(path.rtt);
+            let loss = if sent > 0 { lost as f64 / sent as f64 } else { 0.0 };
+
+            let sample = loss > self.options.loss || path.rtt > min_rtt + self.options.delay;
+            self.congested.send_if_modified(|c| std::mem::replace(c, sample) != sample);
+
+            if sample {
+                (congested, healthy) = (congested + 1, 0);
+            } else {
+                (congested, healthy) = (0, healthy + 1);
+            }
+
+            if self.options.order.is_empty() {
+                continue;
+            }
+
+            let level = self.level.load(Ordering::Relaxed);
+            if congested >= SHED_AFTER && level < self.options.order.len() {
+                congested = 0;
+                self.level.store(level + 1, Ordering::Relaxed);
+                tracing::warn!(pattern = %self.options.order[level], loss, rtt = ?path.rtt, "relay congested, shedding tracks");
+            } else if healthy >= RESTORE_AFTER && level > 0 {
+                healthy = 0;
+                self.level.store(level - 1, Ordering::Relaxed);
+                tracing::info!(pattern = %self.options.order[level - 1], "relay recovered, restoring tracks");
+            }
+        }
+
+        // Start from scratch on the next connection
+        self.level.store(0, Ordering::Relaxed);
+        self.congested.send_replace(false);
+    }
+}
+
+/// Whether one track is currently shed
+pub struct TrackShed {
+    shedder: Arc<Shedder>,
+    // The first pattern in the shed order this track matches
+    index: usize,
+}
+
+impl TrackShed {
+    pub fn active(&self) -> bool {
+        self.index < self.shedder.level.load(Ordering::Relaxed)
+    }
+}
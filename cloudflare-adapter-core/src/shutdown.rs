@@ -0,0 +1,31 @@
+//! Graceful shutdown
+//!
+//! On SIGTERM or ctrl-c the bridge manager stops, so no new bridges start, and every
+//! running bridge is stopped, which unpublishes its broadcast from the relay. We then
+//! wait for the groups already forwarded to be sent, close the relay and CF sessions
+//! cleanly and exit. If that takes longer than `--drain-timeout`, we exit anyway, with
+//! [AdapterError::DrainTimeout](crate::AdapterError::DrainTimeout) and its own exit
+//! status so a forced shutdown shows up as one.
+
+use tokio::signal::unix::{signal as unix_signal, SignalKind};
+use tokio::sync::watch;
+
+/// Wait for a shutdown signal, returning its name
+pub async fn signal() -> anyhow::Result<&'static str> {
+    let mut terminate = unix_signal(SignalKind::terminate())?;
+
+    tokio::select! {
+        _ = terminate.recv() => Ok("SIGTERM"),
+        res = tokio::signal::ctrl_c() => {
+            res?;
+            Ok("SIGINT")
+        }
+    }
+}
+
+/// Resolve once sessions should be closed, given the receiver of the close flag
+pub async fn closing(closing: &mut watch::Receiver<bool>) {
+    if closing.wait_for(|closing| *closing).await.is_err() {
+        std::future::pending().await
+    }
+}
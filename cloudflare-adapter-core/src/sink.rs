@@ -0,0 +1,94 @@
+//! Where bridged broadcasts are published
+//!
+//! Every bridge hands its forwarded broadcast (filtered, aliased and rebased) to each of
+//! its sinks at once: the relay, the recorder if `--record-dir` selects the stream, and
+//! any sink added with [BridgeManager::with_sink](crate::BridgeManager::with_sink).
+//! The broadcast closes when the bridge stops. A sink that fails is only logged; the
+//! bridge and the other sinks carry on.
+
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+
+use moq_lite::{BroadcastConsumer, OriginProducer};
+
+use crate::paths::PathClaim;
+use crate::{record, tasks};
+pub use crate::record::RecordOptions;
+
+pub type SinkFuture<'a> = Pin<Box<dyn Future<Output = anyhow::Result<()>> + Send + 'a>>;
+
+/// Takes the broadcasts bridges forward
+pub trait BridgeSink: Send + Sync {
+    /// What the sink is called in logs
+    fn name(&self) -> &str;
+
+    /// Consume the forwarded broadcast of `stream_id`, resolving once done with it
+    fn publish<'a>(&'a self, stream_id: &'a str, broadcast: BroadcastConsumer) -> SinkFuture<'a>;
+}
+
+/// Publishes one bridge's broadcast on a relay under its claimed path
+pub(crate) struct RelaySink {
+    origin: OriginProducer,
+    claim: PathClaim,
+}
+
+impl RelaySink {
+    pub(crate) fn new(origin: OriginProducer, claim: PathClaim) -> Self {
+        Self { origin, claim }
+    }
+}
+
+impl BridgeSink for RelaySink {
+    fn name(&self) -> &str {
+        "relay"
+    }
+
+    fn publish<'a>(&'a self, stream_id: &'a str, broadcast: BroadcastConsumer) -> SinkFuture<'a> {
+        Box::pin(async move {
+            let path = self.claim.path();
+            if path != stream_id {
+                tracing::info!(stream_id, path, "publishing under mapped path");
+            }
+            self.origin.publish_broadcast(path, broadcast.clone());
+
+            // Hold the path until the broadcast is unpublished
+            broadcast.closed().await;
+            Ok(())
+        })
+    }
+}
+
+/// Records broadcasts to disk as CMAF, see `--record-dir`
+pub struct FileSink {
+    options: RecordOptions,
+}
+
+impl FileSink {
+    pub fn new(options: RecordOptions) -> Self {
+        Self { options }
+    }
+}
+
+impl BridgeSink for FileSink {
+    fn name(&self) -> &str {
+        "file"
+    }
+
+    fn publish<'a>(&'a self, stream_id: &'a str, broadcast: BroadcastConsumer) -> SinkFuture<'a> {
+        Box::pin(record::record_broadcast(stream_id, broadcast, self.options.clone()))
+    }
+}
+
+/// Hand `broadcast` to every sink, each in its own task
+pub(crate) fn publish_all(stream_id: &str, broadcast: &BroadcastConsumer, sinks: Vec<Arc<dyn BridgeSink>>) {
+    for sink in sinks {
+        let stream_id = stream_id.to_string();
+        let broadcast = broadcast.clone();
+        tasks::spawn(async move {
+            if let Err(err) = sink.publish(&stream_id, broadcast).await {
+                tracing::warn!(err = format!("{err:#}"), stream_id, sink = sink.name(), "sink failed");
+            }
+        });
+    }
+}
@@ -0,0 +1,412 @@
+//! Soak scenarios
+//!
+//! `--soak` schedules faults for the adapter to inject into itself over hours, before
+//! a release is trusted with live events. Each step is `<fault> every <interval>`:
+//!
+//! - `kill-session every 10m` closes a connected CF session
+//! - `stall-registry 2m every 1h` holds every discovery list for 2 minutes, then fails
+//!   it, like a registry that stopped answering
+//! - `fail-registry 30s every 20m` fails every discovery list for 30 seconds
+//!
+//! Durations are seconds, or suffixed `s`, `m` or `h`. Steps first run one interval in,
+//! and in a config file read naturally as a list:
+//! `soak = ["kill-session every 10m", "stall-registry 2m every 1h"]`.
+//!
+//! The soak watches what the adapter does meanwhile and times how long it takes to
+//! recover from each fault: until as many CF sessions are connected as before the
+//! kill, or until the first successful poll after a registry fault. With
+//! `--soak-duration` the adapter drains and exits once it's over; either way, however
+//! the adapter stops, the report is logged and written to `--soak-report` as JSON.
+//! It passes if the adapter recovered from every fault before the end, not counting a
+//! registry fault that was still going on.
+
+use std::fmt;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use tokio::sync::mpsc;
+use tokio::task::JoinSet;
+use tokio::time::Instant;
+
+use crate::chaos;
+use crate::discovery::{ChangeFuture, ListFuture, StreamDiscovery};
+use crate::events::{AdapterEvent, AdapterEvents};
+use crate::pool::SessionPool;
+
+/// A scheduled fault, written `<fault> every <interval>`
+#[derive(Clone, Debug)]
+pub struct SoakStep {
+    pub fault: SoakFault,
+    pub every: Duration,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub enum SoakFault {
+    KillSession,
+    StallRegistry(Duration),
+    FailRegistry(Duration),
+}
+
+impl SoakFault {
+    fn name(&self) -> &'static str {
+        match self {
+            Self::KillSession => "kill-session",
+            Self::StallRegistry(_) => "stall-registry",
+            Self::FailRegistry(_) => "fail-registry",
+        }
+    }
+}
+
+impl FromStr for SoakStep {
+    type Err = String;
+
+    fn from_str(spec: &str) -> Result<Self, Self::Err> {
+        let (fault, every) = spec
+            .split_once(" every ")
+            .ok_or_else(|| format!("{spec:?} should be <fault> every <interval>"))?;
+        let every = parse_duration(every)?;
+        if every.is_zero() {
+            return Err(format!("{spec:?} needs an interval of at least a second"));
+        }
+
+        let mut words = fault.split_whitespace();
+        let fault = match (words.next(), words.next(), words.next()) {
+            (Some("kill-session"), None, _) => SoakFault::KillSession,
+            (Some("stall-registry"), Some(length), None) => SoakFault::StallRegistry(parse_duration(length)?),
+            (Some("fail-registry"), Some(length), None) => SoakFault::FailRegistry(parse_duration(length)?),
+            _ => return Err(format!("unknown fault {:?}", fault.trim())),
+        };
+        Ok(Self { fault, every })
+    }
+}
+
+impl fmt::Display for SoakStep {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.fault {
+            SoakFault::KillSession => write!(f, "kill-session")?,
+            SoakFault::StallRegistry(length) | SoakFault::FailRegistry(length) => {
+                write!(f, "{} {}s", self.fault.name(), length.as_secs())?
+            }
+        }
+        write!(f, " every {}s", self.every.as_secs())
+    }
+}
+
+/// Seconds, or suffixed `s`, `m` or `h`
+fn parse_duration(duration: &str) -> Result<Duration, String> {
+    let duration = duration.trim();
+    let (value, unit) = match duration.find(|c: char| !c.is_ascii_digit()) {
+        Some(at) => duration.split_at(at),
+        None => (duration, "s"),
+    };
+    let scale = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 3600,
+        _ => return Err(format!("{duration:?} isn't a duration like 90s, 10m or 2h")),
+    };
+    let value: u64 = value.parse().map_err(|_| format!("{duration:?} isn't a duration like 90s, 10m or 2h"))?;
+    Ok(Duration::from_secs(value * scale))
+}
+
+/// How a soak went
+#[derive(Clone, Debug, Default, serde::Serialize)]
+pub struct SoakReport {
+    /// Whether the adapter recovered from every fault
+    pub passed: bool,
+    pub elapsed_secs: u64,
+    pub steps: Vec<StepReport>,
+    pub bridges_started: u64,
+    pub bridges_failed: u64,
+    pub bridges_closed: u64,
+    pub polls_failed: u64,
+    pub relay_disconnects: u64,
+    pub cloudflare_disconnects: u64,
+}
+
+/// How one step of a soak went
+#[derive(Clone, Debug, Default, serde::Serialize)]
+pub struct StepReport {
+    pub step: String,
+    pub injected: u64,
+    pub recovered: u64,
+    /// Still being injected when the soak ended, so not recovered from yet either
+    pub unfinished: u64,
+    pub max_recovery_ms: Option<u64>,
+    pub mean_recovery_ms: Option<u64>,
+    #[serde(skip)]
+    total_recovery: Duration,
+}
+
+/// A fault the adapter hasn't recovered from yet
+struct Recovering {
+    step: usize,
+    /// When recovery started: the kill, or the end of the registry fault
+    since: Instant,
+    until: Recovered,
+}
+
+enum Recovered {
+    /// As many CF sessions are connected as before
+    Sessions(usize),
+    /// A poll succeeded
+    Poll,
+}
+
+/// Runs a soak scenario and keeps its report
+pub struct Soak {
+    steps: Vec<SoakStep>,
+    duration: Option<Duration>,
+    report_path: Option<PathBuf>,
+    started: Instant,
+    /// The registry fault in progress, and when it ends
+    registry: Mutex<Option<(SoakFault, Instant)>>,
+    state: Mutex<SoakState>,
+}
+
+#[derive(Default)]
+struct SoakState {
+    report: SoakReport,
+    recovering: Vec<Recovering>,
+    connected: usize,
+}
+
+impl Soak {
+    pub fn new(steps: &[SoakStep], duration: Option<Duration>, report_path: Option<PathBuf>) -> Arc<Self> {
+        let report = SoakReport {
+            steps: steps
+                .iter()
+                .map(|step| StepReport {
+                    step: step.to_string(),
+                    ..Default::default()
+                })
+                .collect(),
+            ..Default::default()
+        };
+        if !steps.is_empty() {
+            let steps: Vec<_> = steps.iter().map(SoakStep::to_string).collect();
+            tracing::warn!(?steps, ?duration, "soaking");
+        }
+
+        Arc::new(Self {
+            steps: steps.to_vec(),
+            duration,
+            report_path,
+            started: Instant::now(),
+            registry: Mutex::new(None),
+            state: Mutex::new(SoakState {
+                report,
+                ..Default::default()
+            }),
+        })
+    }
+
+    /// Put the scheduled registry faults in front of `discovery`, if there are any
+    pub fn discovery(self: &Arc<Self>, discovery: Arc<dyn StreamDiscovery>) -> Arc<dyn StreamDiscovery> {
+        match self.steps.iter().any(|step| !matches!(step.fault, SoakFault::KillSession)) {
+            true => Arc::new(SoakDiscovery {
+                inner: discovery,
+                soak: self.clone(),
+            }),
+            false => discovery,
+        }
+    }
+
+    /// Resolve once the soak is over, never without `--soak-duration`
+    pub async fn finished(&self) {
+        match self.duration.filter(|_| !self.steps.is_empty()) {
+            Some(duration) => tokio::time::sleep_until(self.started + duration).await,
+            None => std::future::pending().await,
+        }
+    }
+
+    /// Inject the scheduled faults and watch the adapter recover, forever
+    pub async fn run(&self, sessions: &SessionPool, mut events: AdapterEvents) {
+        if self.steps.is_empty() {
+            return std::future::pending().await;
+        }
+
+        self.state.lock().unwrap().connected = *sessions.connected().borrow();
+        let (ticks, mut due) = mpsc::unbounded_channel();
+        let mut schedule = JoinSet::new();
+        for (index, step) in self.steps.iter().enumerate() {
+            let (ticks, every) = (ticks.clone(), step.every);
+            schedule.spawn(async move {
+                let mut interval = tokio::time::interval_at(Instant::now() + every, every);
+                loop {
+                    interval.tick().await;
+                    if ticks.send(index).is_err() {
+                        return;
+                    }
+                }
+            });
+        }
+
+        loop {
+            tokio::select! {
+                Some(index) = due.recv() => self.inject(index, sessions),
+                Some(event) = events.next() => self.observe(event),
+                else => return std::future::pending().await,
+            }
+        }
+    }
+
+    fn inject(&self, index: usize, sessions: &SessionPool) {
+        let fault = self.steps[index].fault;
+        let now = Instant::now();
+        let mut state = self.state.lock().unwrap();
+
+        let recovering = match fault {
+            SoakFault::KillSession => {
+                let connected = *sessions.connected().borrow();
+                let Some(session) = sessions.kill() else {
+                    tracing::warn!("soak: no cloudflare session to kill");
+                    return;
+                };
+                tracing::warn!(session, "soak: killing cloudflare session");
+                Recovering {
+                    step: index,
+                    since: now,
+                    until: Recovered::Sessions(connected),
+                }
+            }
+            SoakFault::StallRegistry(length) | SoakFault::FailRegistry(length) => {
+                tracing::warn!(fault = fault.name(), ?length, "soak: registry fault");
+                *self.registry.lock().unwrap() = Some((fault, now + length));
+                Recovering {
+                    step: index,
+                    since: now + length,
+                    until: Recovered::Poll,
+                }
+            }
+        };
+        state.recovering.push(recovering);
+        state.report.steps[index].injected += 1;
+        chaos::injected(fault.name());
+    }
+
+    fn observe(&self, event: AdapterEvent) {
+        let mut state = self.state.lock().unwrap();
+        let now = Instant::now();
+        let recovered = |state: &mut SoakState, done: &dyn Fn(&Recovering) -> bool| {
+            let (done, pending) = std::mem::take(&mut state.recovering).into_iter().partition(|r| done(r));
+            state.recovering = pending;
+            for recovered in done {
+                let took = now - recovered.since;
+                let step = &mut state.report.steps[recovered.step];
+                step.recovered += 1;
+                step.total_recovery += took;
+                step.max_recovery_ms = Some(step.max_recovery_ms.unwrap_or(0).max(took.as_millis() as u64));
+                step.mean_recovery_ms = Some((step.total_recovery / step.recovered as u32).as_millis() as u64);
+            }
+        };
+
+        match event {
+            AdapterEvent::Cloudflare { connected, .. } => {
+                if connected < state.connected {
+                    state.report.cloudflare_disconnects += (state.connected - connected) as u64;
+                }
+                state.connected = connected;
+                recovered(&mut state, &|r| matches!(r.until, Recovered::Sessions(n) if connected >= n));
+            }
+            AdapterEvent::Polled(Ok(_)) => recovered(&mut state, &|r| matches!(r.until, Recovered::Poll) && now >= r.since),
+            AdapterEvent::Polled(Err(_)) => state.report.polls_failed += 1,
+            AdapterEvent::Relay { up: false, .. } => state.report.relay_disconnects += 1,
+            AdapterEvent::Relay { up: true, .. } | AdapterEvent::BridgeActive(_) => {}
+            AdapterEvent::BridgeStarted(_) => state.report.bridges_started += 1,
+            AdapterEvent::BridgeFailed(..) => state.report.bridges_failed += 1,
+            AdapterEvent::BridgeClosed(..) => state.report.bridges_closed += 1,
+        }
+    }
+
+    /// The report so far
+    pub fn report(&self) -> SoakReport {
+        let state = self.state.lock().unwrap();
+        let mut report = state.report.clone();
+        let now = Instant::now();
+        for recovering in state.recovering.iter().filter(|r| r.since > now) {
+            report.steps[recovering.step].unfinished += 1;
+        }
+        report.elapsed_secs = self.started.elapsed().as_secs();
+        report.passed = report.steps.iter().all(|step| step.recovered + step.unfinished == step.injected);
+        report
+    }
+
+    /// Log the report, and write it to `--soak-report`
+    pub fn finish(&self) {
+        if self.steps.is_empty() {
+            return;
+        }
+
+        let report = self.report();
+        for step in &report.steps {
+            tracing::info!(
+                step = step.step,
+                injected = step.injected,
+                recovered = step.recovered,
+                unfinished = step.unfinished,
+                max_recovery_ms = step.max_recovery_ms,
+                mean_recovery_ms = step.mean_recovery_ms,
+                "soak step"
+            );
+        }
+        tracing::info!(
+            passed = report.passed,
+            elapsed_secs = report.elapsed_secs,
+            bridges_started = report.bridges_started,
+            bridges_failed = report.bridges_failed,
+            bridges_closed = report.bridges_closed,
+            polls_failed = report.polls_failed,
+            relay_disconnects = report.relay_disconnects,
+            cloudflare_disconnects = report.cloudflare_disconnects,
+            "soak report"
+        );
+
+        let Some(path) = &self.report_path else {
+            return;
+        };
+        let written = serde_json::to_string_pretty(&report)
+            .map_err(std::io::Error::other)
+            .and_then(|report| std::fs::write(path, report));
+        if let Err(err) = written {
+            tracing::warn!(%err, path = %path.display(), "failed to write soak report");
+        }
+    }
+}
+
+/// Reports a soak however the adapter stops, once dropped
+pub(crate) struct SoakGuard(pub(crate) Arc<Soak>);
+
+impl Drop for SoakGuard {
+    fn drop(&mut self) {
+        self.0.finish();
+    }
+}
+
+/// Stalls or fails lists during the soak's registry faults
+struct SoakDiscovery {
+    inner: Arc<dyn StreamDiscovery>,
+    soak: Arc<Soak>,
+}
+
+impl StreamDiscovery for SoakDiscovery {
+    fn list(&self) -> ListFuture<'_> {
+        let fault = *self.soak.registry.lock().unwrap();
+        match fault {
+            Some((SoakFault::StallRegistry(_), until)) if Instant::now() < until => Box::pin(async move {
+                tokio::time::sleep_until(until).await;
+                anyhow::bail!("registry stalled (soak)")
+            }),
+            Some((SoakFault::FailRegistry(_), until)) if Instant::now() < until => {
+                Box::pin(async { anyhow::bail!("registry unavailable (soak)") })
+            }
+            _ => self.inner.list(),
+        }
+    }
+
+    fn next_change(&self) -> ChangeFuture<'_> {
+        self.inner.next_change()
+    }
+}
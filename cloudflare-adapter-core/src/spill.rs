@@ -0,0 +1,238 @@
+//! Disk spill during relay outages
+//!
+//! While the relay connection is down nothing subscribes to a bridge, so whatever CF
+//! sends in the meantime is lost. With `--spill-dir`, each bridge writes the groups of
+//! every catalog rendition to `{dir}/{stream_id}/` while the relay is away, keeping the
+//! most recent ones within `--spill-limit`. When the relay subscribes to a track again,
+//! its spilled groups are forwarded ahead of the live ones. The relay only keeps the
+//! newest groups it's offered, so in practice that's at least the latest keyframe
+//! group, which saves viewers a wait for the next one from CF.
+//!
+//! Frames are stored as received from CF and go through the usual per-track rules on
+//! replay. Stream and track names are escaped with [safe_name], which keeps names that
+//! differ only in their punctuation, like `live/a` and `live_a`, from sharing files.
+
+use std::collections::{HashSet, VecDeque};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+use anyhow::Context;
+use bytes::{Buf, Bytes};
+use moq_lite::{BroadcastConsumer, Group, GroupConsumer, Track, TrackConsumer};
+use tokio::sync::watch;
+use tokio::task::JoinSet;
+
+use crate::catalog::{self, CATALOG_TRACK};
+use crate::record::safe_name;
+
+/// Where bridges spill, and how much each may keep
+#[derive(Clone, Debug)]
+pub struct SpillOptions {
+    pub dir: PathBuf,
+    pub limit: u64,
+}
+
+/// The spilled groups of one bridge
+pub struct Spill {
+    dir: PathBuf,
+    limit: u64,
+    relay_up: watch::Receiver<bool>,
+    state: Mutex<SpillState>,
+}
+
+#[derive(Default)]
+struct SpillState {
+    // Oldest first
+    groups: VecDeque<SpilledGroup>,
+    used: u64,
+}
+
+struct SpilledGroup {
+    track: String,
+    sequence: u64,
+    path: PathBuf,
+    size: u64,
+}
+
+impl Spill {
+    pub fn new(options: &SpillOptions, stream_id: &str, relay_up: watch::Receiver<bool>) -> Arc<Self> {
+        Arc::new(Self {
+            dir: options.dir.join(safe_name(stream_id)),
+            limit: options.limit,
+            relay_up,
+            state: Default::default(),
+        })
+    }
+
+    /// Spill `upstream` whenever the relay is down, until the relay status goes away
+    pub async fn run(self: Arc<Self>, upstream: BroadcastConsumer) {
+        let mut relay_up = self.relay_up.clone();
+
+        while relay_up.wait_for(|up| !up).await.is_ok() {
+            tracing::info!(dir = %self.dir.display(), "relay down, spilling bridge to disk");
+            tokio::select! {
+                res = self.clone().spill(upstream.clone()) => if let Err(err) = res {
+                    tracing::warn!(%err, dir = %self.dir.display(), "spilling failed");
+                },
+                _ = relay_up.wait_for(|up| *up) => {}
+            }
+
+            // Wait out the outage if spilling stopped early
+            if relay_up.wait_for(|up| *up).await.is_err() {
+                return;
+            }
+        }
+    }
+
+    /// Spill every rendition of `upstream` until the catalog ends
+    async fn spill(self: Arc<Self>, upstream: BroadcastConsumer) -> anyhow::Result<()> {
+        tokio::fs::create_dir_all(&self.dir).await.context("failed to create spill dir")?;
+
+        let mut catalog = upstream.subscribe_track(&Track::new(CATALOG_TRACK));
+        let mut spilling = HashSet::new();
+        // Aborted along with the spill when the relay comes back
+        let mut tracks = JoinSet::new();
+
+        while let Some(mut group) = catalog.next_group().await? {
+            let Some(frame) = group.read_frame().await? else {
+                continue;
+            };
+
+            for rendition in catalog::renditions(&frame).unwrap_or_default() {
+                if spilling.insert(rendition.track.clone()) {
+                    let track = upstream.subscribe_track(&Track::new(&rendition.track));
+                    tracks.spawn(self.clone().spill_track(track));
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn spill_track(self: Arc<Self>, mut track: TrackConsumer) -> anyhow::Result<()> {
+        let name = track.info.name.clone();
+
+        while let Some(mut group) = track.next_group().await? {
+            let sequence = group.info.sequence;
+            let mut data = Vec::new();
+            while let Some(frame) = group.read_frame().await? {
+                data.extend_from_slice(&(frame.len() as u32).to_be_bytes());
+                data.extend_from_slice(&frame);
+            }
+
+            let path = self.dir.join(format!("{}.{sequence}", safe_name(&name)));
+            tokio::fs::write(&path, &data).await.context("failed to write spilled group")?;
+            self.insert(SpilledGroup {
+                track: name.clone(),
+                sequence,
+                path,
+                size: data.len() as u64,
+            })
+            .await;
+        }
+
+        Ok(())
+    }
+
+    /// Keep track of a spilled group, deleting the oldest ones beyond the limit
+    async fn insert(&self, group: SpilledGroup) {
+        let evicted = {
+            let mut state = self.state.lock().unwrap();
+            state.used += group.size;
+            state.groups.push_back(group);
+
+            let mut evicted = Vec::new();
+            while state.used > self.limit {
+                let Some(oldest) = state.groups.pop_front() else {
+                    break;
+                };
+                state.used -= oldest.size;
+                evicted.push(oldest.path);
+            }
+            evicted
+        };
+
+        for path in evicted {
+            let _ = tokio::fs::remove_file(path).await;
+        }
+    }
+
+    /// Take the spilled groups of `track`, oldest first, to forward ahead of live ones
+    pub async fn take(&self, track: &str) -> Vec<GroupConsumer> {
+        let taken: VecDeque<SpilledGroup> = {
+            let mut state = self.state.lock().unwrap();
+            let (taken, kept) = std::mem::take(&mut state.groups).into_iter().partition(|g| g.track == track);
+            state.groups = kept;
+            state.used -= taken.iter().map(|g: &SpilledGroup| g.size).sum::<u64>();
+            taken
+        };
+
+        let mut groups = Vec::new();
+        for spilled in taken {
+            match tokio::fs::read(&spilled.path).await {
+                Ok(data) => groups.push(replay(spilled.sequence, data.into())),
+                Err(err) => tracing::warn!(%err, path = %spilled.path.display(), "failed to read spilled group"),
+            }
+            let _ = tokio::fs::remove_file(&spilled.path).await;
+        }
+
+        if !groups.is_empty() {
+            tracing::info!(track, groups = groups.len(), "replaying spilled groups");
+        }
+        groups
+    }
+
+    /// Delete everything spilled, once the bridge is done
+    pub async fn clear(&self) {
+        std::mem::take(&mut *self.state.lock().unwrap());
+        let _ = tokio::fs::remove_dir_all(&self.dir).await;
+    }
+}
+
+/// Turn a spilled group back into one that can be forwarded
+fn replay(sequence: u64, mut data: Bytes) -> GroupConsumer {
+    let mut group = Group { sequence }.produce();
+
+    while data.remaining() >= 4 {
+        let len = data.get_u32() as usize;
+        if len > data.remaining() {
+            break;
+        }
+        group.producer.write_frame(data.split_to(len));
+    }
+
+    group.producer.close();
+    group.consumer
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn streams_spill_apart() {
+        let dir = std::env::temp_dir().join(format!("cloudflare-adapter-spill-{}", std::process::id()));
+        let options = SpillOptions { dir: dir.clone(), limit: 1 << 20 };
+        let (_up, relay_up) = watch::channel(false);
+        let slash = Spill::new(&options, "live/a", relay_up.clone());
+        let underscore = Spill::new(&options, "live_a", relay_up);
+        assert_ne!(slash.dir, underscore.dir);
+
+        for spill in [&slash, &underscore] {
+            std::fs::create_dir_all(&spill.dir).unwrap();
+        }
+        // Tracks that differ the same way get their own files too
+        let files = ["video/hd", "video_hd"].map(|track| underscore.dir.join(format!("{}.0", safe_name(track))));
+        assert_ne!(files[0], files[1]);
+        for file in &files {
+            std::fs::write(file, b"group").unwrap();
+        }
+
+        slash.clear().await;
+        assert!(!slash.dir.exists());
+        assert!(files.iter().all(|file| file.exists()));
+
+        underscore.clear().await;
+        std::fs::remove_dir(&dir).unwrap();
+    }
+}
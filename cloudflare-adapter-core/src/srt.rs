@@ -0,0 +1,192 @@
+//! SRT contribution ingest
+//!
+//! Field encoders that only speak SRT push straight to the adapter. Each configured
+//! ingest runs `ffmpeg` as an SRT listener that remuxes the feed (no transcoding) to
+//! fragmented MP4 on stdout, which we republish to the relay as a hang broadcast:
+//! a `catalog.json` plus one track per video and audio stream. The listener is
+//! restarted whenever the encoder disconnects. Only built with the `ffmpeg` feature.
+
+use std::net::SocketAddr;
+use std::process::Stdio;
+use std::str::FromStr;
+use std::time::Duration;
+
+use anyhow::Context;
+use moq_lite::{Broadcast, BroadcastProducer, GroupProducer, OriginProducer, Track, TrackProducer};
+use serde_json::{json, Value};
+use tokio::process::{ChildStdout, Command};
+
+use crate::catalog::{MediaKind, CATALOG_TRACK};
+use crate::demux::{self, InitTrack};
+use crate::media::MediaFrame;
+
+/// How long to wait before listening again after a feed ends
+const RESTART_DELAY: Duration = Duration::from_secs(1);
+
+/// One SRT listener, written as `stream_id=host:port`
+#[derive(Clone, Debug)]
+pub struct SrtIngest {
+    /// The relay path the feed is published under
+    pub stream_id: String,
+    pub listen: SocketAddr,
+}
+
+impl FromStr for SrtIngest {
+    type Err = String;
+
+    fn from_str(spec: &str) -> Result<Self, Self::Err> {
+        let (stream_id, listen) = spec.split_once('=').ok_or("expected stream_id=host:port")?;
+        let listen = listen.trim().parse().map_err(|err| format!("invalid listen address: {err}"))?;
+        Ok(Self {
+            stream_id: stream_id.trim().to_string(),
+            listen,
+        })
+    }
+}
+
+/// Accept feeds on `ingest.listen` forever, publishing each one to `to_relay`
+pub async fn run_ingest(ingest: SrtIngest, passphrase: Option<String>, to_relay: OriginProducer) {
+    loop {
+        tracing::info!(stream_id = %ingest.stream_id, listen = %ingest.listen, "waiting for SRT feed");
+        match ingest_feed(&ingest, passphrase.as_deref(), &to_relay).await {
+            Ok(()) => tracing::info!(stream_id = %ingest.stream_id, "SRT feed ended"),
+            Err(err) => tracing::warn!(%err, stream_id = %ingest.stream_id, "SRT ingest failed"),
+        }
+
+        tokio::time::sleep(RESTART_DELAY).await;
+    }
+}
+
+/// Run one listener until its feed ends
+async fn ingest_feed(ingest: &SrtIngest, passphrase: Option<&str>, to_relay: &OriginProducer) -> anyhow::Result<()> {
+    let mut url = format!("srt://{}?mode=listener", ingest.listen);
+    if let Some(passphrase) = passphrase {
+        url.push_str(&format!("&passphrase={passphrase}"));
+    }
+
+    let mut child = Command::new("ffmpeg")
+        .args(["-hide_banner", "-loglevel", "error", "-i", &url, "-map", "0:v?", "-map", "0:a?", "-c", "copy"])
+        .args(["-f", "mp4", "-movflags", "empty_moov+default_base_moof+frag_every_frame", "pipe:1"])
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()
+        .context("failed to run ffmpeg")?;
+    let mut stdout = child.stdout.take().context("no ffmpeg stdout")?;
+
+    // ffmpeg only writes the init segment once an encoder has connected
+    let tracks = loop {
+        match demux::read_box(&mut stdout).await? {
+            Some((kind, moov)) if &kind == b"moov" => break demux::parse_init(&moov)?,
+            Some(_) => continue,
+            None => anyhow::bail!("ffmpeg exited before the feed started"),
+        }
+    };
+    anyhow::ensure!(!tracks.is_empty(), "feed has no supported tracks");
+
+    let mut broadcast = Broadcast::produce();
+    let mut feed = Feed::new(&mut broadcast.producer, &tracks);
+    to_relay.publish_broadcast(&ingest.stream_id, broadcast.consumer);
+    tracing::info!(stream_id = %ingest.stream_id, tracks = tracks.len(), "publishing SRT feed");
+
+    let res = tokio::select! {
+        res = feed.run(&mut stdout) => res,
+        // Nobody serves tracks we didn't publish
+        _ = async {
+            while let Some(track) = broadcast.producer.requested_track().await {
+                track.abort(moq_lite::Error::NotFound);
+            }
+        } => Ok(()),
+    };
+
+    broadcast.producer.close();
+    res
+}
+
+/// The relay-side tracks of one feed
+struct Feed<'a> {
+    init: &'a [InitTrack],
+    // Indexed like `init`, with the current group of each track
+    tracks: Vec<(TrackProducer, Option<GroupProducer>)>,
+}
+
+impl<'a> Feed<'a> {
+    fn new(broadcast: &mut BroadcastProducer, init: &'a [InitTrack]) -> Self {
+        let mut catalog = json!({});
+        let mut tracks = Vec::new();
+
+        for (i, track) in init.iter().enumerate() {
+            let (section, first) = match track.kind {
+                MediaKind::Video => ("video", init.iter().position(|t| t.kind == MediaKind::Video)),
+                MediaKind::Audio => ("audio", init.iter().position(|t| t.kind == MediaKind::Audio)),
+            };
+            // video, audio for the first of each kind, then video1, audio1, ...
+            let index = init[..i].iter().filter(|t| t.kind == track.kind).count();
+            let name = match first == Some(i) {
+                true => section.to_string(),
+                false => format!("{section}{index}"),
+            };
+
+            catalog[section]["renditions"][&name] = track.config.clone();
+            tracks.push((broadcast.create_track(Track::new(&name)), None));
+        }
+
+        let mut catalog_track = broadcast.create_track(Track::new(CATALOG_TRACK));
+        catalog_track.write_frame(Value::to_string(&catalog));
+
+        Self { init, tracks }
+    }
+
+    /// Republish fragments until ffmpeg's output ends
+    async fn run(&mut self, stdout: &mut ChildStdout) -> anyhow::Result<()> {
+        let mut moof = None;
+
+        while let Some((kind, data)) = demux::read_box(stdout).await? {
+            match &kind {
+                b"moof" => moof = Some(data),
+                b"mdat" => {
+                    let moof = moof.take().context("mdat without moof")?;
+                    for sample in demux::parse_fragment(self.init, &moof, &data)? {
+                        self.write(sample);
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        for (track, group) in self.tracks.drain(..) {
+            if let Some(group) = group {
+                group.close();
+            }
+            track.close();
+        }
+
+        Ok(())
+    }
+
+    fn write(&mut self, sample: demux::DemuxedSample) {
+        let Some(index) = self.init.iter().position(|t| t.id == sample.track_id) else {
+            return;
+        };
+        let (track, group) = &mut self.tracks[index];
+
+        // Every group starts with a keyframe, so drop anything before the first one
+        if sample.keyframe {
+            if let Some(group) = group.take() {
+                group.close();
+            }
+            *group = Some(track.append_group());
+        }
+        let Some(group) = group else {
+            return;
+        };
+
+        group.write_frame(
+            MediaFrame {
+                timestamp: sample.timestamp,
+                payload: sample.data,
+            }
+            .encode(),
+        );
+    }
+}
@@ -0,0 +1,96 @@
+//! Restart recovery
+//!
+//! With `--state-file`, the bridge manager writes the bridges it's running to a JSON
+//! file after every poll: each stream's registry entry, the CF namespace and relay
+//! path it's bridged under and the last group forwarded on each track. On startup the
+//! saved bridges are set up again as soon as a CF session connects, before the first
+//! registry poll, so a restart costs viewers seconds rather than a poll interval and
+//! the registry's latency. The first poll then takes over as usual.
+//!
+//! The file is replaced atomically, so a crash mid-write leaves the previous one.
+
+use std::collections::HashMap;
+use std::path::PathBuf;
+
+use anyhow::Context;
+use tokio::sync::Mutex;
+
+use crate::registry::StreamInfo;
+
+/// One bridge as it ran
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct SavedBridge {
+    pub stream: StreamInfo,
+    pub namespace: String,
+    pub path: String,
+    /// The last group forwarded, by track
+    #[serde(default)]
+    pub groups: HashMap<String, u64>,
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+struct Saved {
+    bridges: Vec<SavedBridge>,
+}
+
+pub struct StateFile {
+    path: PathBuf,
+    /// What we wrote last, so an unchanged state isn't written again
+    written: Mutex<String>,
+}
+
+impl StateFile {
+    pub fn new(path: PathBuf) -> Self {
+        Self {
+            path,
+            written: Mutex::new(String::new()),
+        }
+    }
+
+    /// The bridges the last run saved, none if it saved nothing we can read
+    pub async fn load(&self) -> Vec<SavedBridge> {
+        let saved = match tokio::fs::read_to_string(&self.path).await {
+            Ok(saved) => saved,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Vec::new(),
+            Err(err) => {
+                tracing::warn!(%err, path = %self.path.display(), "failed to read state file");
+                return Vec::new();
+            }
+        };
+
+        match serde_json::from_str::<Saved>(&saved) {
+            Ok(saved) => saved.bridges,
+            Err(err) => {
+                tracing::warn!(%err, path = %self.path.display(), "ignoring unreadable state file");
+                Vec::new()
+            }
+        }
+    }
+
+    /// Replace the saved bridges with `bridges`
+    pub async fn save(&self, bridges: Vec<SavedBridge>) {
+        let mut written = self.written.lock().await;
+        let state = match serde_json::to_string_pretty(&Saved { bridges }) {
+            Ok(state) => state,
+            Err(err) => {
+                tracing::warn!(%err, "failed to serialize state");
+                return;
+            }
+        };
+        if *written == state {
+            return;
+        }
+
+        match self.write(&state).await {
+            Ok(()) => *written = state,
+            Err(err) => tracing::warn!(err = format!("{err:#}"), path = %self.path.display(), "failed to write state file"),
+        }
+    }
+
+    async fn write(&self, state: &str) -> anyhow::Result<()> {
+        let tmp = self.path.with_extension("tmp");
+        tokio::fs::write(&tmp, state).await.context("failed to write")?;
+        tokio::fs::rename(&tmp, &self.path).await.context("failed to replace")?;
+        Ok(())
+    }
+}
@@ -0,0 +1,134 @@
+//! Bridge stats for the stats API
+//!
+//! The earthseed dashboard gets quality data for native streams from the relays; with
+//! `--stats-url` it gets the same for bridged ones. Every `--stats-interval` the adapter
+//! POSTs what each running bridge did since the last report:
+//!
+//! ```json
+//! {"instance": "adapter-1", "interval_secs": 10, "bridges": [{"stream_id": "s1",
+//!   "bytes": 1250000, "bitrate_bps": 1000000, "groups": 5, "frames": 300,
+//!   "failed_groups": 0, "dropped_groups": 1, "stalls": 0, "active": true, ...}]}
+//! ```
+//!
+//! `stalls` counts the times the upstream went away, whether the bridge waited it out or
+//! was restarted for `--stall-timeout`. Failed reports are logged and not retried; the next
+//! one covers its interval too.
+
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use anyhow::Context;
+use serde_json::{Value, json};
+
+use crate::bridge::BridgeEnd;
+use crate::events::{AdapterEvent, AdapterEvents};
+use crate::handle::BridgeStats;
+use crate::manager::BridgeLookup;
+
+#[derive(Clone, Debug)]
+pub struct StatsOptions {
+    pub url: String,
+    pub interval: Duration,
+}
+
+/// Posts bridge stats every interval
+pub(crate) struct StatsReporter {
+    client: reqwest::Client,
+    options: StatsOptions,
+    instance: String,
+    bridges: BridgeLookup,
+}
+
+impl StatsReporter {
+    pub(crate) fn new(options: StatsOptions, bridges: BridgeLookup) -> Self {
+        Self {
+            client: reqwest::Client::new(),
+            options,
+            instance: std::env::var("HOSTNAME").unwrap_or_else(|_| "adapter".to_string()),
+            bridges,
+        }
+    }
+
+    /// Report every interval, forever, counting the stall restarts `events` tell of
+    pub(crate) async fn run(&self, mut events: AdapterEvents) {
+        tracing::info!(url = self.options.url, interval = ?self.options.interval, "posting bridge stats");
+        let mut ticks = tokio::time::interval(self.options.interval);
+        ticks.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        ticks.tick().await;
+
+        // What each bridge had done by the last report
+        let mut reported: HashMap<String, BridgeStats> = HashMap::new();
+        // Bridges restarted since the last report, by stream
+        let mut restarts: HashMap<String, u64> = HashMap::new();
+        let mut last = Instant::now();
+        loop {
+            tokio::select! {
+                _ = ticks.tick() => {}
+                Some(event) = events.next() => {
+                    if let AdapterEvent::BridgeClosed(context, BridgeEnd::Stalled) = event {
+                        *restarts.entry(context.stream.stream_id).or_default() += 1;
+                    }
+                    continue;
+                }
+            }
+
+            let elapsed = std::mem::replace(&mut last, Instant::now()).elapsed().max(Duration::from_millis(1));
+            let running = self.bridges.all().await;
+            reported.retain(|stream_id, _| running.iter().any(|bridge| bridge.stream_id() == stream_id));
+            let bridges: Vec<Value> = running
+                .iter()
+                .map(|bridge| {
+                    let stats = bridge.stats();
+                    let restarts = restarts.remove(&stats.stream_id).unwrap_or(0);
+                    // A bridge started since is reported from the start
+                    let previous = reported.get(&stats.stream_id).filter(|previous| previous.started == stats.started);
+                    let report = report(&stats, previous, restarts, elapsed);
+                    reported.insert(stats.stream_id.clone(), stats);
+                    report
+                })
+                .collect();
+
+            let body = json!({
+                "instance": self.instance,
+                "interval_secs": elapsed.as_secs_f64(),
+                "bridges": bridges,
+            });
+            if let Err(err) = self.post(&body).await {
+                tracing::warn!(err = format!("{err:#}"), "failed to post bridge stats");
+            }
+        }
+    }
+
+    async fn post(&self, body: &Value) -> anyhow::Result<()> {
+        self.client
+            .post(&self.options.url)
+            .json(body)
+            .timeout(self.options.interval)
+            .send()
+            .await
+            .context("request failed")?
+            .error_for_status()?;
+        Ok(())
+    }
+}
+
+/// One bridge's report, over what it did since `previous` if it was running then
+fn report(stats: &BridgeStats, previous: Option<&BridgeStats>, restarts: u64, elapsed: Duration) -> Value {
+    let since = |value: fn(&BridgeStats) -> u64| value(stats) - previous.map_or(0, value);
+    let bytes = since(|s| s.bytes);
+    json!({
+        "stream_id": stats.stream_id,
+        "uptime_secs": stats.uptime.as_secs(),
+        "active": stats.active,
+        "paused": stats.paused,
+        "bytes": bytes,
+        "bitrate_bps": (bytes as f64 * 8.0 / elapsed.as_secs_f64()) as u64,
+        "groups": since(|s| s.groups),
+        "frames": since(|s| s.frames),
+        "failed_groups": since(|s| s.failed_groups),
+        "dropped_groups": since(|s| s.dropped_groups),
+        "stalls": since(|s| s.stalls) + restarts,
+        "latency_ms": stats.latency.map(|latency| latency.adapter.as_secs_f64() * 1000.0),
+        "capture_latency_ms": stats.capture_latency.map(|latency| latency.republish.as_secs_f64() * 1000.0),
+    })
+}
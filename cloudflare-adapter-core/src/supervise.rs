@@ -0,0 +1,54 @@
+//! Supervised bridge tasks
+//!
+//! Each bridge runs as a task of its own under the one that spawned it, which waits
+//! for it, so a panic in a bridge is caught instead of silently taking the task down.
+//! The panic is logged with its stream, counted in `bridge_panics_total` and returned
+//! as an error, so the bridge goes through the usual cleanup and retry policy.
+
+use std::any::Any;
+use std::future::Future;
+
+use tokio::task::JoinHandle;
+
+use crate::error::BridgeError;
+use crate::metrics::Counter;
+
+/// Run `bridge` as its own task, turning a panic into an error
+///
+/// The task is aborted if this future is dropped first.
+pub async fn bridge<T: Send + 'static>(
+    stream_id: &str,
+    bridge: impl Future<Output = Result<T, BridgeError>> + Send + 'static,
+) -> Result<T, BridgeError> {
+    let mut task = AbortOnDrop(tokio::spawn(bridge));
+
+    match (&mut task.0).await {
+        Ok(res) => res,
+        Err(err) if err.is_panic() => {
+            let panic = message(err.into_panic());
+            tracing::error!(stream_id, panic, "bridge panicked");
+            Counter::new("bridge_panics_total", "Bridge tasks that panicked", &[]).inc();
+            Err(BridgeError::Panicked(panic))
+        }
+        Err(err) => Err(BridgeError::Task(err.to_string())),
+    }
+}
+
+/// The panic message, when it's a string as it almost always is
+fn message(panic: Box<dyn Any + Send>) -> String {
+    match panic.downcast::<String>() {
+        Ok(message) => *message,
+        Err(panic) => match panic.downcast::<&'static str>() {
+            Ok(message) => message.to_string(),
+            Err(_) => "unknown panic".to_string(),
+        },
+    }
+}
+
+struct AbortOnDrop<T>(JoinHandle<T>);
+
+impl<T> Drop for AbortOnDrop<T> {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
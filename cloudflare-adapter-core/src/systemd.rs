@@ -0,0 +1,65 @@
+//! systemd notifications
+//!
+//! Run as a `Type=notify` service, the adapter tells systemd it's ready only once the
+//! main relay and a CF session are both connected, keeps `STATUS=` current with its
+//! connections and bridge count, and sends keepalives when `WatchdogSec=` is set. The
+//! protocol is a datagram per message on the `$NOTIFY_SOCKET` unix socket, so there's
+//! no libsystemd dependency.
+
+use std::os::unix::net::{SocketAddr, UnixDatagram};
+use std::time::Duration;
+
+/// Sends notifications to the service manager that started us
+pub struct Notifier {
+    socket: UnixDatagram,
+    addr: SocketAddr,
+    /// How often systemd expects a keepalive, if it watches us
+    watchdog: Option<Duration>,
+}
+
+impl Notifier {
+    /// The notifier systemd set up through the environment, None when not run by it
+    pub fn from_env() -> Option<Self> {
+        let path = std::env::var("NOTIFY_SOCKET").ok()?;
+        let addr = match path.strip_prefix('@') {
+            #[cfg(target_os = "linux")]
+            Some(name) => {
+                use std::os::linux::net::SocketAddrExt;
+                SocketAddr::from_abstract_name(name).ok()?
+            }
+            #[cfg(not(target_os = "linux"))]
+            Some(_) => return None,
+            None => SocketAddr::from_pathname(&path).ok()?,
+        };
+
+        let socket = match UnixDatagram::unbound() {
+            Ok(socket) => socket,
+            Err(err) => {
+                tracing::warn!(%err, "can't notify systemd");
+                return None;
+            }
+        };
+
+        // The watchdog is meant for us only if it names our pid, when it names one
+        let ours = std::env::var("WATCHDOG_PID").map_or(true, |pid| pid.parse() == Ok(std::process::id()));
+        let watchdog = std::env::var("WATCHDOG_USEC")
+            .ok()
+            .and_then(|usec| usec.parse().ok())
+            .filter(|_| ours)
+            .map(Duration::from_micros);
+
+        Some(Self { socket, addr, watchdog })
+    }
+
+    /// How long systemd waits for a `WATCHDOG=1` before restarting us, None if it isn't watching
+    pub fn watchdog(&self) -> Option<Duration> {
+        self.watchdog
+    }
+
+    /// Send one or more `KEY=value` lines
+    pub fn notify(&self, state: &str) {
+        if let Err(err) = self.socket.send_to_addr(state.as_bytes(), &self.addr) {
+            tracing::debug!(%err, state, "failed to notify systemd");
+        }
+    }
+}
@@ -0,0 +1,299 @@
+//! `tap`
+//!
+//! Shows what a running bridge actually forwards, without attaching a player. With
+//! `--admin`, `GET /bridges/{stream_id}/tap` subscribes to the bridge's relay-side
+//! broadcast and streams a JSON line per object: its track, group and object sequence,
+//! size and, for media tracks, hang timestamp. `?track=` taps one track, rather than the
+//! catalog and every rendition it lists, and `?payload=<bytes>` adds the start of each
+//! payload in hex. Catalog objects are summed up by the renditions they list.
+//!
+//! `cloudflare-adapter tap <stream_id> [track]` asks the instance running with the same
+//! configuration and prints the objects as they arrive, over plain HTTP/1.1 to
+//! `--http-listen` like [healthcheck](crate::healthcheck) does.
+//!
+//! A tap subscribes like a viewer does, so it takes an `--on-demand` bridge off standby.
+
+use std::collections::HashSet;
+use std::convert::Infallible;
+use std::io::{BufRead, BufReader, Write};
+use std::net::TcpStream;
+use std::pin::Pin;
+use std::task::Poll;
+use std::time::Duration;
+
+use anyhow::Context;
+use bytes::Bytes;
+use moq_lite::{BroadcastConsumer, Track, TrackConsumer};
+use serde_json::{json, Value};
+use tokio::sync::mpsc;
+use tokio::task::JoinSet;
+use url::Url;
+
+use crate::catalog::{self, CATALOG_TRACK};
+use crate::healthcheck::local;
+use crate::media::decode_varint;
+use crate::AdapterConfig;
+
+/// How many lines a tap can fall behind its reader before holding up its tracks
+const BUFFER: usize = 1024;
+
+/// What to show of each object
+pub struct TapOptions {
+    /// Only this track, rather than the catalog and its renditions
+    pub track: Option<String>,
+    /// How many payload bytes to show, in hex
+    pub payload: usize,
+    /// Stop after this many objects
+    pub count: Option<u64>,
+    /// Print the JSON lines as served, for jq and the like
+    pub json: bool,
+}
+
+/// Print the objects the bridge of `stream_id` forwards, until it ends or `--count` are shown
+pub fn run(config: &AdapterConfig, stream_id: &str, options: &TapOptions, timeout: Duration) -> anyhow::Result<()> {
+    let Some(listen) = config.http_listen else {
+        anyhow::bail!("tap needs --http-listen and --admin");
+    };
+    anyhow::ensure!(config.admin, "tap needs --admin");
+
+    let addr = local(listen);
+    let mut url = Url::parse(&format!("http://{addr}/"))?;
+    url.path_segments_mut()
+        .map_err(|_| anyhow::anyhow!("http://{addr}/ can't be a base URL"))?
+        .extend(["bridges", stream_id, "tap"]);
+    url.query_pairs_mut().append_pair("payload", &options.payload.to_string());
+    if let Some(track) = &options.track {
+        url.query_pairs_mut().append_pair("track", track);
+    }
+
+    let mut stream = TcpStream::connect_timeout(&addr, timeout).with_context(|| format!("failed to connect to {addr}"))?;
+    stream.set_write_timeout(Some(timeout))?;
+    let mut request = format!("GET {} HTTP/1.1\r\nHost: {addr}\r\nConnection: close\r\n", &url[url::Position::BeforePath..]);
+    if let Some(token) = &config.admin_token {
+        request.push_str(&format!("Authorization: Bearer {token}\r\n"));
+    }
+    request.push_str("\r\n");
+    stream.write_all(request.as_bytes()).context("failed to send request")?;
+
+    // Quiet tracks can keep us waiting for a while, so only the headers have to be quick
+    let mut reader = BufReader::new(stream);
+    reader.get_ref().set_read_timeout(Some(timeout))?;
+    let (status, chunked) = read_head(&mut reader)?;
+    match status {
+        200 => {}
+        401 => anyhow::bail!("unauthorized, check --admin-token"),
+        404 => anyhow::bail!("{stream_id} isn't bridged"),
+        status => anyhow::bail!("tap failed ({status})"),
+    }
+    reader.get_ref().set_read_timeout(None)?;
+
+    let mut shown = 0;
+    let mut object = |line: &str| {
+        print(line, options.json);
+        shown += 1;
+        options.count.is_none_or(|count| shown < count)
+    };
+    match chunked {
+        true => read_chunked(&mut reader, object),
+        false => {
+            for line in reader.lines() {
+                if !object(&line?) {
+                    break;
+                }
+            }
+            Ok(())
+        }
+    }
+}
+
+/// Read the status line and headers, returning the status and whether the body is chunked
+fn read_head(reader: &mut impl BufRead) -> anyhow::Result<(u16, bool)> {
+    let mut line = String::new();
+    reader.read_line(&mut line).context("failed to read response")?;
+    let status = line
+        .split_whitespace()
+        .nth(1)
+        .and_then(|code| code.parse().ok())
+        .context("malformed response")?;
+
+    let mut chunked = false;
+    loop {
+        line.clear();
+        reader.read_line(&mut line).context("failed to read response")?;
+        let header = line.trim_end();
+        if header.is_empty() {
+            return Ok((status, chunked));
+        }
+        if let Some((name, value)) = header.split_once(':') {
+            chunked |= name.eq_ignore_ascii_case("transfer-encoding") && value.trim().eq_ignore_ascii_case("chunked");
+        }
+    }
+}
+
+/// Hand each line of a chunked body to `line`, until the body ends or `line` returns false
+fn read_chunked(reader: &mut impl BufRead, mut line: impl FnMut(&str) -> bool) -> anyhow::Result<()> {
+    let mut pending = Vec::new();
+    loop {
+        let mut size = String::new();
+        anyhow::ensure!(reader.read_line(&mut size)? > 0, "the adapter closed the tap");
+        let size = size.trim().split(';').next().unwrap_or_default();
+        let size = usize::from_str_radix(size, 16).context("malformed chunk")?;
+        if size == 0 {
+            return Ok(());
+        }
+
+        let start = pending.len();
+        pending.resize(start + size, 0);
+        reader.read_exact(&mut pending[start..])?;
+        reader.read_line(&mut String::new())?;
+
+        while let Some(end) = pending.iter().position(|&b| b == b'\n') {
+            let rest = pending.split_off(end + 1);
+            if !line(String::from_utf8_lossy(&pending[..end]).trim_end()) {
+                return Ok(());
+            }
+            pending = rest;
+        }
+    }
+}
+
+/// Print one object, as its JSON line or a line of text
+fn print(line: &str, json: bool) {
+    let object = match serde_json::from_str::<Value>(line) {
+        Ok(object) if !json => object,
+        _ => return println!("{line}"),
+    };
+
+    let field = |name| object.get(name).map(Value::to_string).unwrap_or_default();
+    let track = object.get("track").and_then(Value::as_str).unwrap_or_default();
+    if let Some(error) = object.get("error").and_then(Value::as_str) {
+        return println!("{track} failed: {error}");
+    }
+
+    let mut text = format!("{track} group {} object {} size {}", field("group"), field("object"), field("size"));
+    if let Some(timestamp) = object.get("timestamp_us") {
+        text.push_str(&format!(" timestamp {timestamp}us"));
+    }
+    if let Some(renditions) = object.get("renditions").and_then(Value::as_array) {
+        let renditions: Vec<_> = renditions.iter().filter_map(Value::as_str).collect();
+        text.push_str(&format!(" renditions {}", renditions.join(",")));
+    }
+    if let Some(payload) = object.get("payload").and_then(Value::as_str) {
+        text.push_str(&format!(" payload {payload}"));
+    }
+    println!("{text}");
+}
+
+#[derive(Clone, Default, serde::Deserialize)]
+pub(crate) struct TapQuery {
+    /// Only this track, rather than the catalog and its renditions
+    track: Option<String>,
+    /// How many payload bytes to show, in hex
+    #[serde(default)]
+    payload: usize,
+}
+
+/// The objects tapped from a broadcast, one JSON line each, for as long as they're read
+pub(crate) struct TapLines {
+    received: mpsc::Receiver<String>,
+}
+
+impl futures_core::Stream for TapLines {
+    type Item = Result<String, Infallible>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut std::task::Context<'_>) -> Poll<Option<Self::Item>> {
+        self.received.poll_recv(cx).map(|line| line.map(Ok))
+    }
+}
+
+/// Tap `broadcast` until it ends or the lines are dropped
+pub(crate) fn tap(broadcast: BroadcastConsumer, query: TapQuery) -> TapLines {
+    let (lines, received) = mpsc::channel(BUFFER);
+    tokio::spawn(async move {
+        let tapping = async {
+            match &query.track {
+                Some(name) => {
+                    let track = broadcast.subscribe_track(&Track::new(name));
+                    tap_track(track, query.payload, lines.clone()).await
+                }
+                None => tap_catalog(&broadcast, query.payload, &lines).await,
+            }
+        };
+        tokio::select! {
+            _ = tapping => {}
+            _ = lines.closed() => {}
+        }
+    });
+    TapLines { received }
+}
+
+/// Tap the catalog, and every rendition it lists as they show up
+async fn tap_catalog(broadcast: &BroadcastConsumer, payload: usize, lines: &mpsc::Sender<String>) {
+    let mut tapped = HashSet::new();
+    let mut renditions = JoinSet::new();
+    let catalog = broadcast.subscribe_track(&Track::new(CATALOG_TRACK));
+    let catalog = tap_objects(catalog, payload, lines, |frame| {
+        for rendition in catalog::renditions(frame).unwrap_or_default() {
+            if tapped.insert(rendition.track.clone()) {
+                let track = broadcast.subscribe_track(&Track::new(&rendition.track));
+                renditions.spawn(tap_track(track, payload, lines.clone()));
+            }
+        }
+    });
+    catalog.await;
+    // The broadcast is over once its catalog is, unless a rendition is still draining
+    while renditions.join_next().await.is_some() {}
+}
+
+async fn tap_track(track: TrackConsumer, payload: usize, lines: mpsc::Sender<String>) {
+    tap_objects(track, payload, &lines, |_| {}).await
+}
+
+/// Send a line for every object of `track`, handing each to `read` too
+async fn tap_objects(
+    mut track: TrackConsumer,
+    payload: usize,
+    lines: &mpsc::Sender<String>,
+    mut read: impl FnMut(&Bytes),
+) {
+    let name = track.info.name.clone();
+    let tapped = async {
+        while let Some(mut group) = track.next_group().await? {
+            let mut index = 0;
+            while let Some(frame) = group.read_frame().await? {
+                let line = object(&name, group.info.sequence, index, &frame, payload);
+                if lines.send(format!("{line}\n")).await.is_err() {
+                    return Ok(());
+                }
+                read(&frame);
+                index += 1;
+            }
+        }
+        anyhow::Ok(())
+    };
+    if let Err(err) = tapped.await {
+        let line = json!({ "track": name, "error": format!("{err:#}") });
+        let _ = lines.send(format!("{line}\n")).await;
+    }
+}
+
+/// What's shown of one object
+fn object(track: &str, group: u64, index: u64, frame: &Bytes, payload: usize) -> Value {
+    let mut object = json!({ "track": track, "group": group, "object": index, "size": frame.len() });
+    let mut body = &frame[..];
+    if track == CATALOG_TRACK {
+        let renditions = catalog::renditions(frame).unwrap_or_default();
+        object["renditions"] = renditions.into_iter().map(|rendition| rendition.track).collect();
+    } else if let Some((timestamp, size)) = decode_varint(frame) {
+        object["timestamp_us"] = timestamp.into();
+        body = &frame[size..];
+    }
+    if payload > 0 {
+        object["payload"] = encode_hex(&body[..payload.min(body.len())]).into();
+    }
+    object
+}
+
+fn encode_hex(data: &[u8]) -> String {
+    data.iter().map(|b| format!("{b:02x}")).collect()
+}
@@ -0,0 +1,117 @@
+//! Per-bridge task metrics
+//!
+//! Past a couple hundred bridges the scheduler stalls, which every shard's timer lag
+//! shows (see [shard](crate::shard)) without saying which bridges are behind it. With the
+//! `task-metrics` feature, each bridge and every task it spawns to forward its broadcast
+//! is instrumented with a [tokio-metrics](tokio_metrics) monitor of its own, exported every
+//! [INTERVAL] by stream:
+//!
+//! - `bridge_task_polls_total` and `bridge_task_poll_us_total`: how often its tasks ran,
+//!   and for how long, so a bridge hogging the workers stands out
+//! - `bridge_task_slow_polls_total`: polls that took over 50µs
+//! - `bridge_task_wakeups_total` and `bridge_task_scheduled_us_total`: how often its
+//!   tasks were woken, and how long they waited for a worker after, which is the stall as
+//!   the bridge sees it
+//!
+//! Without the feature [spawn] is `tokio::spawn`. For a live view of every task, the
+//! binary's `tokio-console` feature serves [tokio-console](https://github.com/tokio-rs/console).
+
+use std::future::Future;
+#[cfg(feature = "task-metrics")]
+use std::time::Duration;
+
+use tokio::task::JoinHandle;
+#[cfg(feature = "task-metrics")]
+use tokio_metrics::{TaskMetrics, TaskMonitor};
+
+#[cfg(feature = "task-metrics")]
+use crate::metrics::Counter;
+
+/// How often each bridge's task metrics are exported
+#[cfg(feature = "task-metrics")]
+pub const INTERVAL: Duration = Duration::from_secs(5);
+
+#[cfg(feature = "task-metrics")]
+tokio::task_local! {
+    /// The monitor of the bridge the current task works for
+    static MONITOR: TaskMonitor;
+}
+
+/// Spawn `task` like `tokio::spawn`, counting it as the current bridge's work
+pub fn spawn<F>(task: F) -> JoinHandle<F::Output>
+where
+    F: Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    #[cfg(feature = "task-metrics")]
+    if let Ok(monitor) = MONITOR.try_with(TaskMonitor::clone) {
+        return tokio::spawn(MONITOR.scope(monitor.clone(), monitor.instrument(task)));
+    }
+    tokio::spawn(task)
+}
+
+/// Run the bridge of `stream_id`, measuring it and the tasks it spawns
+#[cfg(feature = "task-metrics")]
+pub async fn instrument<F: Future>(stream_id: &str, bridge: F) -> F::Output {
+    let monitor = TaskMonitor::new();
+    let meter = TaskMeter::new(stream_id);
+    let mut intervals = monitor.intervals();
+
+    let bridge = MONITOR.scope(monitor.clone(), monitor.instrument(bridge));
+    tokio::pin!(bridge);
+    let mut ticks = tokio::time::interval(INTERVAL);
+    let output = loop {
+        tokio::select! {
+            output = &mut bridge => break output,
+            _ = ticks.tick() => meter.add(intervals.next()),
+        }
+    };
+    meter.add(intervals.next());
+    output
+}
+
+/// Run the bridge of `stream_id`
+#[cfg(not(feature = "task-metrics"))]
+pub async fn instrument<F: Future>(_stream_id: &str, bridge: F) -> F::Output {
+    bridge.await
+}
+
+/// One bridge's task metrics
+#[cfg(feature = "task-metrics")]
+struct TaskMeter {
+    polls: Counter,
+    poll_us: Counter,
+    slow_polls: Counter,
+    wakeups: Counter,
+    scheduled_us: Counter,
+}
+
+#[cfg(feature = "task-metrics")]
+impl TaskMeter {
+    fn new(stream_id: &str) -> Self {
+        let labels = [("stream_id", stream_id)];
+        Self {
+            polls: Counter::new("bridge_task_polls_total", "Polls of a bridge's tasks", &labels),
+            poll_us: Counter::new("bridge_task_poll_us_total", "Time spent polling a bridge's tasks", &labels),
+            slow_polls: Counter::new("bridge_task_slow_polls_total", "Polls of a bridge's tasks over 50us", &labels),
+            wakeups: Counter::new("bridge_task_wakeups_total", "Times a bridge's tasks were woken", &labels),
+            scheduled_us: Counter::new(
+                "bridge_task_scheduled_us_total",
+                "Time a bridge's woken tasks waited for a worker",
+                &labels,
+            ),
+        }
+    }
+
+    /// Count what the tasks did in the last interval
+    fn add(&self, interval: Option<TaskMetrics>) {
+        let Some(interval) = interval else {
+            return;
+        };
+        self.polls.add(interval.total_poll_count);
+        self.poll_us.add(interval.total_poll_duration.as_micros() as u64);
+        self.slow_polls.add(interval.total_slow_poll_count);
+        self.wakeups.add(interval.total_scheduled_count);
+        self.scheduled_us.add(interval.total_scheduled_duration.as_micros() as u64);
+    }
+}
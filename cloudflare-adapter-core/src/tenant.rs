@@ -0,0 +1,219 @@
+//! Multi-tenant deployments
+//!
+//! A config file with `[tenants.<name>]` tables (see [settings](crate::settings)) serves
+//! several customers from one deployment: the adapter runs each tenant's adapter as a
+//! process of its own, started with the same options and `TENANT=<name>`. Their
+//! registries, CF and relay sessions, bridges and state stay apart, and one tenant's crash
+//! or misconfiguration doesn't take the others down.
+//!
+//! The supervising process prefixes each tenant's log lines with its name and restarts a
+//! tenant whose adapter fails, after the `--backoff-*` delays. SIGTERM and ctrl-c are
+//! passed on so every tenant drains, and it exits once they all have, failing if any
+//! did. Subcommands run once per tenant, so `validate-config` checks every one.
+//!
+//! Every series on a tenant's `/metrics` has a `tenant` label, so they can be told apart
+//! scraped together. Options set in the environment or on the command line apply to every
+//! tenant, and so the ones only one adapter can use at a time, like `--http-listen` and
+//! `--state-file`, have to differ between tenants, which is checked up front. That
+//! includes `--history-db`, whose sessions don't say which tenant they were.
+
+use std::collections::HashMap;
+use std::ffi::OsString;
+use std::path::PathBuf;
+use std::process::{ExitStatus, Stdio};
+use std::time::{Duration, Instant};
+
+use anyhow::Context;
+use tokio::io::{AsyncBufReadExt, BufReader};
+use tokio::process::{ChildStdout, Command};
+use tokio::sync::watch;
+use tokio::task::JoinSet;
+
+use crate::backoff::BackoffPolicy;
+use crate::settings::{self, Loaded};
+use crate::shutdown;
+
+/// Options no two tenants can share, by long name and environment variable
+const SEPARATE: &[(&str, &str)] = &[
+    ("http-listen", "HTTP_LISTEN"),
+    ("state-file", "STATE_FILE"),
+    ("record-dir", "RECORD_DIR"),
+    ("spill-dir", "SPILL_DIR"),
+    ("history-db", "HISTORY_DB"),
+];
+
+/// A tenant's adapter that ran this long is working, and its next failure starts the backoff over
+const HEALTHY: Duration = Duration::from_secs(60);
+
+/// How to start one tenant's adapter
+struct Tenant {
+    name: String,
+    exe: PathBuf,
+    args: Vec<OsString>,
+    /// The variables the top-level file options went into, for the tenant to fill in again
+    clear: Vec<String>,
+    /// How long to wait before restarting a failed adapter, or None to run it once
+    restart: Option<BackoffPolicy>,
+}
+
+/// Run an adapter for each of `tenants` from the loaded config file, until a shutdown signal
+///
+/// `matches` are parsed leniently, since the options every tenant sets for itself are
+/// missing from them.
+pub async fn supervise(
+    command: &clap::Command,
+    matches: &clap::ArgMatches,
+    loaded: &Loaded,
+    tenants: &[String],
+) -> anyhow::Result<()> {
+    check(command, loaded, tenants)?;
+    let restart = matches.subcommand_name().is_none().then(|| backoff(matches));
+
+    let exe = std::env::current_exe().context("can't find the adapter executable")?;
+    let args: Vec<OsString> = std::env::args_os().skip(1).collect();
+    let (stop, stopping) = watch::channel(false);
+
+    tracing::info!(?tenants, "running an adapter per tenant");
+    let mut running = JoinSet::new();
+    for name in tenants {
+        let tenant = Tenant {
+            name: name.clone(),
+            exe: exe.clone(),
+            args: args.clone(),
+            clear: loaded.env.iter().cloned().collect(),
+            restart,
+        };
+        running.spawn(tenant.run(stopping.clone()));
+    }
+
+    tokio::spawn(async move {
+        if let Ok(signal) = shutdown::signal().await {
+            tracing::info!(signal, "received signal, stopping every tenant");
+            stop.send_replace(true);
+        }
+    });
+
+    let mut failed = Vec::new();
+    while let Some(res) = running.join_next().await {
+        if let Err(err) = res.context("tenant task panicked")? {
+            tracing::error!(err = format!("{err:#}"), "tenant failed");
+            failed.push(err);
+        }
+    }
+
+    match failed.len() {
+        0 => Ok(()),
+        1 => Err(failed.remove(0)),
+        n => anyhow::bail!("{n} tenants failed"),
+    }
+}
+
+impl Tenant {
+    /// Keep the adapter running, restarting it when it fails, until it stops cleanly or is stopped
+    async fn run(self, mut stopping: watch::Receiver<bool>) -> anyhow::Result<()> {
+        let tenant = self.name.as_str();
+        let mut backoff = self.restart.map(BackoffPolicy::start);
+        loop {
+            let started = Instant::now();
+            let status = self.wait(&mut stopping).await?;
+            if status.success() {
+                tracing::info!(tenant, "tenant's adapter stopped");
+                return Ok(());
+            }
+            let Some(backoff) = backoff.as_mut().filter(|_| !*stopping.borrow()) else {
+                anyhow::bail!("tenant {tenant}'s adapter stopped with {status}");
+            };
+
+            if started.elapsed() >= HEALTHY {
+                backoff.reset();
+            }
+            let delay = backoff.next();
+            tracing::warn!(tenant, %status, ?delay, "tenant's adapter failed, restarting");
+            tokio::select! {
+                _ = tokio::time::sleep(delay) => {}
+                _ = shutdown::closing(&mut stopping) => anyhow::bail!("tenant {tenant}'s adapter failed with {status}"),
+            }
+        }
+    }
+
+    /// Start the adapter and wait for it to exit, passing on a shutdown
+    async fn wait(&self, stopping: &mut watch::Receiver<bool>) -> anyhow::Result<ExitStatus> {
+        let mut command = Command::new(&self.exe);
+        // The environment, since the arguments may end in a subcommand
+        command.args(&self.args).env("TENANT", &self.name).stdout(Stdio::piped()).kill_on_drop(true);
+        for var in &self.clear {
+            command.env_remove(var);
+        }
+
+        let mut child = command.spawn().with_context(|| format!("failed to start tenant {}'s adapter", self.name))?;
+        if let Some(stdout) = child.stdout.take() {
+            tokio::spawn(prefix(stdout, self.name.clone()));
+        }
+
+        let status = tokio::select! {
+            status = child.wait() => status?,
+            _ = shutdown::closing(stopping) => {
+                if !child.id().is_some_and(terminate) {
+                    child.start_kill().ok();
+                }
+                child.wait().await?
+            }
+        };
+        Ok(status)
+    }
+}
+
+/// Ask the process to drain with SIGTERM, since tokio only kills children outright
+#[cfg(unix)]
+fn terminate(pid: u32) -> bool {
+    let Ok(pid) = libc::pid_t::try_from(pid) else {
+        return false;
+    };
+    // SAFETY: kill only sends a signal, and pid is our own child's, which we haven't reaped
+    unsafe { libc::kill(pid, libc::SIGTERM) == 0 }
+}
+
+/// Elsewhere the adapter is simply killed
+#[cfg(not(unix))]
+fn terminate(_pid: u32) -> bool {
+    false
+}
+
+/// The `--backoff-*` policy the adapter itself would retry with
+fn backoff(matches: &clap::ArgMatches) -> BackoffPolicy {
+    let millis = |id| Duration::from_millis(matches.get_one::<u64>(id).copied().unwrap_or_default());
+    let float = |id| matches.get_one::<f64>(id).copied().unwrap_or_default();
+    BackoffPolicy {
+        initial: millis("backoff_initial"),
+        multiplier: float("backoff_multiplier"),
+        max: millis("backoff_max"),
+        jitter: float("backoff_jitter") / 100.0,
+    }
+}
+
+/// Copy a tenant's output to ours, each line prefixed with its name
+async fn prefix(stdout: ChildStdout, tenant: String) {
+    let mut lines = BufReader::new(stdout).lines();
+    while let Ok(Some(line)) = lines.next_line().await {
+        println!("[{tenant}] {line}");
+    }
+}
+
+/// Fail if two tenants would use the same port or files
+fn check(command: &clap::Command, loaded: &Loaded, tenants: &[String]) -> anyhow::Result<()> {
+    let mut taken: HashMap<(&str, String), &str> = HashMap::new();
+    for tenant in tenants {
+        let mut options = settings::read(command, &loaded.path, loaded.profile.as_deref(), Some(tenant))?;
+        for &(long, env) in SEPARATE {
+            // The environment only overrides the file if the file didn't fill it in
+            let given = std::env::var(env).ok().filter(|_| !loaded.env.contains(env));
+            let Some(value) = given.or_else(|| options.remove(env).map(|values| values.join(","))) else {
+                continue;
+            };
+            if let Some(other) = taken.insert((long, value.clone()), tenant) {
+                anyhow::bail!("tenants {other} and {tenant} both have {long} {value}; give each one its own");
+            }
+        }
+    }
+    Ok(())
+}
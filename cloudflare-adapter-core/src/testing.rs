@@ -0,0 +1,505 @@
+//! Test harness
+//!
+//! With the `test-util` feature, [MockServer] runs a local MoQ server that can stand
+//! in for CloudFlare, serving synthetic broadcasts, or for the relay, collecting what
+//! the adapter publishes. Point `--cloudflare-url` and `--relay-url` at two of them and
+//! the whole bridge path (announce, consume, republish) runs in `cargo test`:
+//!
+//! ```no_run
+//! # async fn example() -> anyhow::Result<()> {
+//! use cloudflare_adapter_core::testing::{MockServer, SyntheticOptions};
+//!
+//! let cloudflare = MockServer::start_cloudflare().await?;
+//! let relay = MockServer::start().await?;
+//! let _live = cloudflare.publish("earthseed.live/demo", SyntheticOptions::default());
+//!
+//! // Run an adapter with --cloudflare-url cloudflare.url() and --relay-url relay.url()
+//! // bridging "demo", then:
+//! let bridged = relay.announced("demo").await;
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! Like CF, one started with [MockServer::start_cloudflare] speaks Draft 14, whatever
+//! else the client offers, and never announces its broadcasts: its PUBLISH_NAMESPACE
+//! messages are withheld, so bridges only find them with `announce_remote`, and only get
+//! them by subscribing. One started with [MockServer::start] speaks whichever version the
+//! client prefers, moq-lite for the adapter, and announces everything like a relay. The
+//! certificate is self-signed and fetched over plain HTTP from the same port, so the URLs
+//! are `http://`.
+
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::Context;
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use moq_lite::coding::{Decode, DecodeError, Encode};
+use moq_lite::ietf::{self, Message};
+use moq_lite::{Broadcast, BroadcastConsumer, Origin, OriginConsumer, OriginProducer, Track};
+use moq_native::{Request, ServerConfig, ServerTlsConfig};
+use serde_json::json;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpListener;
+use tokio::task::JoinSet;
+use url::Url;
+use web_transport_trait::{RecvStream, SendStream, Session};
+
+use crate::catalog::CATALOG_TRACK;
+use crate::media::MediaFrame;
+
+/// A local MoQ server, serving and collecting broadcasts
+pub struct MockServer {
+    addr: SocketAddr,
+    origin: OriginProducer,
+    consumer: OriginConsumer,
+    /// Accepting sessions and serving the certificate, until dropped
+    _tasks: JoinSet<()>,
+}
+
+impl MockServer {
+    /// Start serving on a free port on localhost, announcing every broadcast like a relay
+    pub async fn start() -> anyhow::Result<Self> {
+        Self::serve(true).await
+    }
+
+    /// Start serving on a free port on localhost, announcing nothing like CloudFlare
+    pub async fn start_cloudflare() -> anyhow::Result<Self> {
+        Self::serve(false).await
+    }
+
+    async fn serve(announce: bool) -> anyhow::Result<Self> {
+        let config = ServerConfig {
+            bind: Some("127.0.0.1:0".parse()?),
+            tls: ServerTlsConfig {
+                generate: vec!["localhost".to_string()],
+                ..Default::default()
+            },
+        };
+        let mut server = config.init()?;
+        let addr = server.local_addr()?;
+        let fingerprint = server
+            .tls_info()
+            .read()
+            .map_err(|_| anyhow::anyhow!("certificate lock poisoned"))?
+            .fingerprints
+            .first()
+            .cloned()
+            .context("no certificate")?;
+
+        // The client fetches the fingerprint over TCP on the same port
+        let certificate = TcpListener::bind(addr).await.context("failed to bind certificate listener")?;
+
+        let origin = Origin::produce();
+        let mut tasks = JoinSet::new();
+        tasks.spawn(serve_fingerprint(certificate, fingerprint));
+
+        let (producer, consumer) = (origin.producer.clone(), origin.consumer.clone());
+        tasks.spawn(async move {
+            let mut sessions = JoinSet::new();
+            while let Some(request) = server.accept().await {
+                let (producer, consumer) = (producer.clone(), consumer.consume());
+                sessions.spawn(async move {
+                    let session = match announce {
+                        true => request.accept(consumer, producer).await,
+                        false => accept_unannounced(request, consumer, producer).await,
+                    };
+                    match session {
+                        Ok(session) => {
+                            let _ = session.closed().await;
+                        }
+                        Err(err) => tracing::debug!(%err, "mock session failed"),
+                    }
+                });
+            }
+        });
+
+        tracing::debug!(%addr, "mock server listening");
+        Ok(Self {
+            addr,
+            origin: origin.producer,
+            consumer: origin.consumer,
+            _tasks: tasks,
+        })
+    }
+
+    /// The URL to connect to, as `--cloudflare-url` or `--relay-url`
+    pub fn url(&self) -> anyhow::Result<Url> {
+        Ok(Url::parse(&format!("http://{}/", self.addr))?)
+    }
+
+    /// Serve a synthetic broadcast under `path` until the returned handle is dropped
+    pub fn publish(&self, path: &str, options: SyntheticOptions) -> SyntheticBroadcast {
+        let broadcast = SyntheticBroadcast::new(options);
+        self.origin.publish_broadcast(path, broadcast.consume());
+        broadcast
+    }
+
+    /// Wait for a client to publish a broadcast under `path`
+    pub async fn announced(&self, path: &str) -> BroadcastConsumer {
+        let mut announced = self.consumer.consume();
+        loop {
+            match announced.announced().await {
+                Some((announced, Some(broadcast))) if announced.as_str() == path => return broadcast,
+                Some(_) => continue,
+                None => std::future::pending().await,
+            }
+        }
+    }
+
+    /// The broadcast published under `path` right now, if any
+    pub fn broadcast(&self, path: &str) -> Option<BroadcastConsumer> {
+        self.consumer.consume_broadcast(path)
+    }
+}
+
+/// What a synthetic broadcast looks like
+#[derive(Clone, Debug)]
+pub struct SyntheticOptions {
+    /// The video track, listed in the catalog
+    pub track: String,
+    pub frames_per_group: usize,
+    pub frame_interval: Duration,
+    pub frame_size: usize,
+}
+
+impl SyntheticOptions {
+    /// Check a frame that went through the adapter is frame `index` of group `sequence`, unchanged,
+    /// returning when it was written
+    pub fn check(&self, sequence: u64, index: usize, frame: &Bytes) -> anyhow::Result<SystemTime> {
+        let frame = MediaFrame::decode(frame).context("not a hang frame")?;
+        let mut payload = frame.payload;
+        anyhow::ensure!(
+            payload.len() == self.payload_size(),
+            "frame {index} of group {sequence} is {} bytes, not {}",
+            payload.len(),
+            self.payload_size()
+        );
+
+        let (group, position) = (payload.get_u64(), payload.get_u64());
+        anyhow::ensure!(
+            (group, position) == (sequence, index as u64),
+            "frame {index} of group {sequence} is frame {position} of group {group}"
+        );
+        let written = UNIX_EPOCH + Duration::from_micros(payload.get_u64());
+        anyhow::ensure!(payload.iter().all(|&b| b == 0), "frame {index} of group {sequence} is corrupted");
+        Ok(written)
+    }
+
+    /// Frame `index` of group `sequence`, written at `written` since the epoch, exactly as published
+    pub fn frame(&self, sequence: u64, index: usize, written: Duration) -> Bytes {
+        let mut payload = BytesMut::with_capacity(self.payload_size());
+        payload.put_u64(sequence);
+        payload.put_u64(index as u64);
+        payload.put_u64(written.as_micros() as u64);
+        payload.resize(self.payload_size(), 0);
+
+        let position = sequence * self.frames_per_group.max(1) as u64 + index as u64;
+        let frame = MediaFrame {
+            timestamp: position * self.frame_interval.as_micros() as u64,
+            payload: payload.freeze(),
+        };
+        frame.encode()
+    }
+
+    /// Room for the sequence, index and write time at least
+    fn payload_size(&self) -> usize {
+        self.frame_size.max(24)
+    }
+}
+
+impl Default for SyntheticOptions {
+    fn default() -> Self {
+        Self {
+            track: "video".to_string(),
+            frames_per_group: 30,
+            frame_interval: Duration::from_millis(33),
+            frame_size: 1200,
+        }
+    }
+}
+
+/// A synthetic broadcast being served, until dropped
+pub struct SyntheticBroadcast {
+    consumer: BroadcastConsumer,
+    _tasks: JoinSet<()>,
+}
+
+impl SyntheticBroadcast {
+    /// Start producing a synthetic broadcast, to publish wherever
+    pub fn new(options: SyntheticOptions) -> Self {
+        let broadcast = Broadcast::produce();
+        let mut tasks = JoinSet::new();
+        tasks.spawn(produce(broadcast.producer, options));
+        Self {
+            consumer: broadcast.consumer,
+            _tasks: tasks,
+        }
+    }
+
+    /// What's being served, to compare the bridged broadcast against
+    pub fn consume(&self) -> BroadcastConsumer {
+        self.consumer.clone()
+    }
+}
+
+/// Write a catalog and a stream of hang frames, each group starting with a keyframe
+///
+/// A frame's payload is its group sequence, its index and the time it was written, so
+/// consumers can check what arrived and how long it took.
+async fn produce(mut broadcast: moq_lite::BroadcastProducer, options: SyntheticOptions) {
+    let catalog = json!({
+        "video": {
+            "renditions": {
+                &options.track: { "codec": "avc1.64001f", "codedWidth": 1280, "codedHeight": 720 }
+            }
+        }
+    });
+    let mut catalog_track = broadcast.create_track(Track::new(CATALOG_TRACK));
+    catalog_track.write_frame(catalog.to_string());
+    let mut track = broadcast.create_track(Track::new(&options.track));
+
+    let mut ticks = tokio::time::interval(options.frame_interval);
+    let per_group = options.frames_per_group.max(1);
+    for sequence in 0u64.. {
+        let mut group = track.append_group();
+        for index in 0..per_group {
+            ticks.tick().await;
+            let written = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+            group.write_frame(options.frame(sequence, index, written));
+        }
+        group.close();
+    }
+}
+
+/// Accept a Draft 14 session that never announces what it serves
+async fn accept_unannounced(
+    request: Request,
+    publish: OriginConsumer,
+    subscribe: OriginProducer,
+) -> anyhow::Result<moq_lite::Session> {
+    let session = match request {
+        Request::WebTransport(request) => request.ok().await?,
+        Request::Quic(request) => request.ok(),
+    };
+    Ok(moq_lite::Session::accept(Unannounced::new(session), publish, subscribe).await?)
+}
+
+/// A session that only offers Draft 14 and withholds its PUBLISH_NAMESPACE messages
+///
+/// The client's setup is rewritten to offer Draft 14 alone, and the messages on the
+/// control stream (the setup stream) that would announce a broadcast, or its end, are
+/// dropped. Every other stream is passed on as it is.
+#[derive(Clone)]
+struct Unannounced<S> {
+    session: S,
+    setup: Arc<AtomicBool>,
+}
+
+impl<S: Session> Unannounced<S> {
+    fn new(session: S) -> Self {
+        Self {
+            session,
+            setup: Default::default(),
+        }
+    }
+}
+
+impl<S: Session> Session for Unannounced<S> {
+    type SendStream = Withheld<S::SendStream>;
+    type RecvStream = Draft14Only<S::RecvStream>;
+    type Error = S::Error;
+
+    async fn accept_bi(&self) -> Result<(Self::SendStream, Self::RecvStream), Self::Error> {
+        let (send, recv) = self.session.accept_bi().await?;
+        let setup = !self.setup.swap(true, Ordering::Relaxed);
+        Ok((Withheld::new(send, setup), Draft14Only::new(recv, setup)))
+    }
+
+    async fn accept_uni(&self) -> Result<Self::RecvStream, Self::Error> {
+        Ok(Draft14Only::new(self.session.accept_uni().await?, false))
+    }
+
+    async fn open_bi(&self) -> Result<(Self::SendStream, Self::RecvStream), Self::Error> {
+        let (send, recv) = self.session.open_bi().await?;
+        Ok((Withheld::new(send, false), Draft14Only::new(recv, false)))
+    }
+
+    async fn open_uni(&self) -> Result<Self::SendStream, Self::Error> {
+        Ok(Withheld::new(self.session.open_uni().await?, false))
+    }
+
+    fn send_datagram(&self, payload: Bytes) -> Result<(), Self::Error> {
+        self.session.send_datagram(payload)
+    }
+
+    async fn recv_datagram(&self) -> Result<Bytes, Self::Error> {
+        self.session.recv_datagram().await
+    }
+
+    fn max_datagram_size(&self) -> usize {
+        self.session.max_datagram_size()
+    }
+
+    fn close(&self, code: u32, reason: &str) {
+        self.session.close(code, reason)
+    }
+
+    async fn closed(&self) -> Self::Error {
+        self.session.closed().await
+    }
+}
+
+/// A stream whose client setup, if it starts with one, offers Draft 14 alone
+struct Draft14Only<R> {
+    stream: R,
+    /// What's been read of the setup, until all of it has
+    setup: Option<BytesMut>,
+    /// The rewritten setup, and whatever came after it, still to be read
+    unread: Bytes,
+}
+
+impl<R: RecvStream> Draft14Only<R> {
+    fn new(stream: R, setup: bool) -> Self {
+        Self {
+            stream,
+            setup: setup.then(BytesMut::new),
+            unread: Bytes::new(),
+        }
+    }
+}
+
+/// `read` with its CLIENT_SETUP offering Draft 14 alone, or None until `read` holds all of it
+fn draft14_only(read: &[u8]) -> Result<Option<Bytes>, DecodeError> {
+    let mut rest = read;
+    let kind: u64 = match Decode::decode(&mut rest, ietf::Version::Draft14) {
+        Err(DecodeError::Short) => return Ok(None),
+        kind => kind?,
+    };
+    if kind != ietf::ClientSetup::ID {
+        return Err(DecodeError::InvalidValue);
+    }
+    let mut setup: ietf::ClientSetup = match Decode::decode(&mut rest, ietf::Version::Draft14) {
+        Err(DecodeError::Short) => return Ok(None),
+        setup => setup?,
+    };
+    setup.versions = vec![ietf::Version::Draft14.coding()].into();
+
+    let mut rewritten = BytesMut::new();
+    kind.encode(&mut rewritten, ietf::Version::Draft14);
+    setup.encode(&mut rewritten, ietf::Version::Draft14);
+    rewritten.put_slice(rest);
+    Ok(Some(rewritten.freeze()))
+}
+
+impl<R: RecvStream> RecvStream for Draft14Only<R> {
+    type Error = R::Error;
+
+    async fn read(&mut self, dst: &mut [u8]) -> Result<Option<usize>, Self::Error> {
+        if let Some(mut setup) = self.setup.take() {
+            self.unread = loop {
+                match draft14_only(&setup) {
+                    Ok(Some(rewritten)) => break rewritten,
+                    // Not a setup the server can take anyway, so it's left for it to refuse
+                    Err(_) => break setup.freeze(),
+                    Ok(None) => {}
+                }
+                let mut chunk = [0; 1024];
+                match self.stream.read(&mut chunk).await? {
+                    Some(size) if size > 0 => setup.put_slice(&chunk[..size]),
+                    _ => break setup.freeze(),
+                }
+            };
+        }
+
+        if self.unread.is_empty() {
+            return self.stream.read(dst).await;
+        }
+        let size = dst.len().min(self.unread.len());
+        dst[..size].copy_from_slice(&self.unread.split_to(size));
+        Ok(Some(size))
+    }
+
+    fn stop(&mut self, code: u32) {
+        self.stream.stop(code)
+    }
+
+    async fn closed(&mut self) -> Result<(), Self::Error> {
+        self.stream.closed().await
+    }
+}
+
+/// A stream that, if it's the control stream, drops the messages announcing broadcasts
+struct Withheld<W> {
+    stream: W,
+    /// The start of a control message not yet written whole
+    unsent: Option<BytesMut>,
+}
+
+impl<W: SendStream> Withheld<W> {
+    fn new(stream: W, control: bool) -> Self {
+        Self {
+            stream,
+            unsent: control.then(BytesMut::new),
+        }
+    }
+}
+
+/// The ID of the first control message in `unsent` and the whole of it, once it's all there
+fn control_message(unsent: &mut BytesMut) -> Option<(u64, Bytes)> {
+    let mut rest = &unsent[..];
+    let id: u64 = Decode::decode(&mut rest, ietf::Version::Draft14).ok()?;
+    let size: u16 = Decode::decode(&mut rest, ietf::Version::Draft14).ok()?;
+    let header = unsent.len() - rest.len();
+    (rest.len() >= size as usize).then(|| (id, unsent.split_to(header + size as usize).freeze()))
+}
+
+impl<W: SendStream> SendStream for Withheld<W> {
+    type Error = W::Error;
+
+    async fn write(&mut self, buf: &[u8]) -> Result<usize, Self::Error> {
+        let Some(unsent) = &mut self.unsent else {
+            return self.stream.write(buf).await;
+        };
+        unsent.put_slice(buf);
+        while let Some((id, message)) = control_message(unsent) {
+            if id != ietf::PublishNamespace::ID && id != ietf::PublishNamespaceDone::ID {
+                self.stream.write_all(&message).await?;
+            }
+        }
+        Ok(buf.len())
+    }
+
+    fn set_priority(&mut self, order: u8) {
+        self.stream.set_priority(order)
+    }
+
+    fn finish(&mut self) -> Result<(), Self::Error> {
+        self.stream.finish()
+    }
+
+    fn reset(&mut self, code: u32) {
+        self.stream.reset(code)
+    }
+
+    async fn closed(&mut self) -> Result<(), Self::Error> {
+        self.stream.closed().await
+    }
+}
+
+/// Answer every request with `fingerprint`, the way relays serve `/certificate.sha256`
+async fn serve_fingerprint(listener: TcpListener, fingerprint: String) {
+    let response = Bytes::from(format!(
+        "HTTP/1.1 200 OK\r\nContent-Type: text/plain\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{fingerprint}",
+        fingerprint.len()
+    ));
+    while let Ok((mut stream, _)) = listener.accept().await {
+        let response = response.clone();
+        tokio::spawn(async move {
+            let mut request = [0; 1024];
+            let _ = stream.read(&mut request).await;
+            let _ = stream.write_all(&response).await;
+        });
+    }
+}
@@ -0,0 +1,311 @@
+//! Bandwidth limits toward the relay
+//!
+//! `--bridge-rate-limit [stream_id=]<kbit/s>` caps what a bridge publishes to the relay,
+//! and `--track-rate-limit <pattern>=<kbit/s>` each of its tracks matching the pattern,
+//! so a single high-bitrate CF stream can't take a regional relay's whole uplink. A
+//! stream's own limit of 0 exempts it from the global one. The catalog is never limited.
+//!
+//! Up to a second of unused budget carries over, for bursts such as keyframes. Beyond
+//! that, frames are held back until the budget refills, and new groups of the tracks a
+//! limit covers are dropped while its budget is used up (counted with the reason
+//! `throttled`). A stream above its limit gets whole groups through at the limit, and
+//! viewers skip ahead to the next keyframe, rather than falling further and further
+//! behind.
+//!
+//! `--relay-egress-limit [relay=]<kbit/s>` is a ceiling for everything published to a
+//! relay (`main` for `--relay-url`), e.g. the VM's committed network rate. Frames of every
+//! bridge wait for its budget the same way, and while it's used up, new groups are
+//! dropped (reason `egress-budget`) only from bridges using more than their fair share:
+//! the limit split evenly, with whatever quieter bridges leave unused going to the
+//! busier ones. `egress_kbps` and `egress_fair_share_kbps` show how it's shared.
+
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+use std::time::Duration;
+
+use tokio::time::Instant;
+
+use crate::filter::glob_match;
+use crate::metrics::{Counter, Gauge};
+
+/// How much unused budget carries over, to let a burst through at once
+const BURST: Duration = Duration::from_secs(1);
+
+/// How often the rates of the bridges sharing an egress budget are measured
+const SHARE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// A `--track-rate-limit`, as `pattern=kbit/s`
+#[derive(Clone, Debug)]
+pub struct TrackRate {
+    pub pattern: String,
+    /// kbit/s
+    pub rate: u64,
+}
+
+impl FromStr for TrackRate {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (pattern, rate) = s.split_once('=').ok_or("expected pattern=kbit/s")?;
+        let rate = rate.parse().map_err(|err| format!("invalid rate {rate:?}: {err}"))?;
+        if rate == 0 {
+            return Err(format!("{s:?} needs a rate of at least 1 kbit/s"));
+        }
+        Ok(Self {
+            pattern: pattern.to_string(),
+            rate,
+        })
+    }
+}
+
+/// A token bucket, in bytes
+pub struct RateLimit {
+    /// Bytes per second
+    rate: f64,
+    bucket: Mutex<Bucket>,
+}
+
+struct Bucket {
+    /// Negative once a frame has been let through beyond the budget
+    tokens: f64,
+    at: Instant,
+}
+
+impl RateLimit {
+    pub fn new(kbps: u64) -> Arc<Self> {
+        let rate = kbps as f64 * 1000.0 / 8.0;
+        Arc::new(Self {
+            rate,
+            bucket: Mutex::new(Bucket {
+                tokens: rate * BURST.as_secs_f64(),
+                at: Instant::now(),
+            }),
+        })
+    }
+
+    /// How long until there's budget again
+    fn wait(&self) -> Duration {
+        let tokens = self.refilled().tokens;
+        match tokens < 0.0 {
+            true => Duration::from_secs_f64(-tokens / self.rate),
+            false => Duration::ZERO,
+        }
+    }
+
+    /// Spend `bytes` of the budget, which may take it below zero
+    fn take(&self, bytes: usize) {
+        self.refilled().tokens -= bytes as f64;
+    }
+
+    fn refilled(&self) -> std::sync::MutexGuard<'_, Bucket> {
+        let mut bucket = self.bucket.lock().unwrap();
+        let now = Instant::now();
+        let refill = (now - bucket.at).as_secs_f64() * self.rate;
+        bucket.tokens = (bucket.tokens + refill).min(self.rate * BURST.as_secs_f64());
+        bucket.at = now;
+        bucket
+    }
+}
+
+/// A relay's `--relay-egress-limit`, shared by the bridges publishing to it
+pub struct EgressBudget {
+    relay: String,
+    limit: Arc<RateLimit>,
+    shares: Mutex<Shares>,
+}
+
+struct Shares {
+    bridges: Vec<Weak<BridgeMeter>>,
+    measured: Instant,
+    /// What each bridge may use while the budget is used up (bytes/s)
+    fair: f64,
+}
+
+/// What one bridge sends toward an egress budget
+#[derive(Default)]
+struct BridgeMeter {
+    /// Bytes since the last measurement
+    sent: AtomicU64,
+    /// Bytes/s, smoothed over measurements
+    rate: Mutex<f64>,
+}
+
+impl EgressBudget {
+    pub fn new(relay: &str, kbps: u64) -> Arc<Self> {
+        Arc::new(Self {
+            relay: relay.to_string(),
+            limit: RateLimit::new(kbps),
+            shares: Mutex::new(Shares {
+                bridges: Vec::new(),
+                measured: Instant::now(),
+                fair: f64::INFINITY,
+            }),
+        })
+    }
+
+    fn meter(&self) -> Arc<BridgeMeter> {
+        let meter = Arc::new(BridgeMeter::default());
+        self.shares.lock().unwrap().bridges.push(Arc::downgrade(&meter));
+        meter
+    }
+
+    /// Whether the bridge measured by `meter` uses more than its fair share
+    fn over_share(&self, meter: &BridgeMeter) -> bool {
+        let fair = self.share();
+        *meter.rate.lock().unwrap() > fair
+    }
+
+    /// The fair share, measuring the bridges again first if it's time
+    fn share(&self) -> f64 {
+        let mut shares = self.shares.lock().unwrap();
+        let elapsed = shares.measured.elapsed();
+        if elapsed < SHARE_INTERVAL {
+            return shares.fair;
+        }
+        shares.measured = Instant::now();
+        shares.bridges.retain(|meter| meter.strong_count() > 0);
+
+        let mut rates: Vec<f64> = shares
+            .bridges
+            .iter()
+            .filter_map(Weak::upgrade)
+            .map(|meter| {
+                let sent = meter.sent.swap(0, Ordering::Relaxed) as f64 / elapsed.as_secs_f64();
+                let mut rate = meter.rate.lock().unwrap();
+                *rate = (*rate + sent) / 2.0;
+                *rate
+            })
+            .collect();
+        let total: f64 = rates.iter().sum();
+
+        // Max-min fairness: bridges under an even split keep what they use, and what they
+        // leave is split evenly between the rest
+        rates.sort_by(f64::total_cmp);
+        let (mut left, mut fair) = (self.limit.rate, f64::INFINITY);
+        for (i, rate) in rates.iter().enumerate() {
+            let even = left / (rates.len() - i) as f64;
+            if *rate > even {
+                fair = even;
+                break;
+            }
+            left -= rate;
+        }
+        shares.fair = fair;
+
+        let labels = [("relay", self.relay.as_str())];
+        Gauge::new("egress_kbps", "What's published to the relay (kbit/s)", &labels).set((total * 8.0 / 1000.0) as i64);
+        let shown = if fair.is_finite() { fair } else { self.limit.rate };
+        Gauge::new("egress_fair_share_kbps", "Each bridge's fair share of the relay's egress budget (kbit/s)", &labels)
+            .set((shown * 8.0 / 1000.0) as i64);
+        fair
+    }
+}
+
+/// The limits of one bridge
+pub struct BridgeThrottle {
+    stream_id: Arc<str>,
+    bridge: Option<Arc<RateLimit>>,
+    tracks: Vec<TrackRate>,
+    egress: Option<(Arc<EgressBudget>, Arc<BridgeMeter>)>,
+}
+
+impl BridgeThrottle {
+    /// `rate` is the bridge's own limit, `tracks` the `--track-rate-limit` rules and
+    /// `egress` the budget of the relay it publishes to
+    pub fn new(stream_id: &str, rate: Option<u64>, tracks: &[TrackRate], egress: Option<Arc<EgressBudget>>) -> Arc<Self> {
+        Arc::new(Self {
+            stream_id: stream_id.into(),
+            bridge: rate.filter(|rate| *rate > 0).map(RateLimit::new),
+            tracks: tracks.to_vec(),
+            egress: egress.map(|budget| {
+                let meter = budget.meter();
+                (budget, meter)
+            }),
+        })
+    }
+
+    /// The limits for the upstream track `track`, or None if it isn't limited
+    pub fn track(&self, track: &str) -> Option<TrackThrottle> {
+        let limit = self.tracks.iter().find(|rule| glob_match(&rule.pattern, track)).map(|rule| RateLimit::new(rule.rate));
+        if self.bridge.is_none() && limit.is_none() && self.egress.is_none() {
+            return None;
+        }
+        Some(TrackThrottle {
+            stream_id: self.stream_id.clone(),
+            bridge: self.bridge.clone(),
+            track: limit,
+            egress: self.egress.clone(),
+            held: Default::default(),
+        })
+    }
+}
+
+/// The limits one track's frames go through
+#[derive(Clone)]
+pub struct TrackThrottle {
+    stream_id: Arc<str>,
+    bridge: Option<Arc<RateLimit>>,
+    track: Option<Arc<RateLimit>>,
+    egress: Option<(Arc<EgressBudget>, Arc<BridgeMeter>)>,
+    /// Frames of the track being held back right now
+    held: Arc<AtomicUsize>,
+}
+
+impl TrackThrottle {
+    /// Wait until the limits have budget for a frame of `bytes`, and spend it
+    pub async fn pace(&self, bytes: usize) {
+        let mut held = false;
+        loop {
+            let wait = self.limits().map(|limit| limit.wait()).max().unwrap_or_default();
+            if wait.is_zero() {
+                break;
+            }
+            if !std::mem::replace(&mut held, true) {
+                self.held.fetch_add(1, Ordering::Relaxed);
+                Counter::new(
+                    "throttled_frames_total",
+                    "Frames held back by a rate limit",
+                    &[("stream_id", &self.stream_id)],
+                )
+                .inc();
+            }
+            tokio::time::sleep(wait).await;
+        }
+        if held {
+            self.held.fetch_sub(1, Ordering::Relaxed);
+        }
+
+        for limit in self.limits() {
+            limit.take(bytes);
+        }
+        if let Some((_, meter)) = &self.egress {
+            meter.sent.fetch_add(bytes as u64, Ordering::Relaxed);
+        }
+    }
+
+    /// Why a new group should be dropped, if a budget it would go through is used up
+    ///
+    /// The relay session serves two groups of a track at most and abandons older ones,
+    /// so a group also waits for the frames of the one before it that are held back.
+    pub fn refused(&self) -> Option<&'static str> {
+        if self.bridge.iter().chain(&self.track).any(|limit| !limit.wait().is_zero()) {
+            return Some("throttled");
+        }
+
+        let (budget, meter) = self.egress.as_ref()?;
+        let held = self.held.load(Ordering::Relaxed) > 0;
+        if budget.limit.wait().is_zero() || !(held || budget.over_share(meter)) {
+            return None;
+        }
+        let labels = [("relay", budget.relay.as_str())];
+        Counter::new("egress_dropped_groups_total", "Groups dropped to keep within the relay's egress budget", &labels)
+            .inc();
+        Some("egress-budget")
+    }
+
+    fn limits(&self) -> impl Iterator<Item = &Arc<RateLimit>> {
+        let egress = self.egress.iter().map(|(budget, _)| &budget.limit);
+        self.bridge.iter().chain(&self.track).chain(egress)
+    }
+}
@@ -0,0 +1,130 @@
+//! Live thumbnails for the earthseed directory
+//!
+//! Every so often we grab the latest keyframe of a bridged video track, wrap it in a
+//! one-sample fMP4, have `ffmpeg` decode and scale it to a JPEG, and POST the result
+//! to the registry. Only built with the `ffmpeg` feature.
+
+use std::process::Stdio;
+use std::time::Duration;
+
+use anyhow::Context;
+use moq_lite::{BroadcastConsumer, Track};
+use serde_json::Value;
+use tokio::io::AsyncWriteExt;
+use tokio::process::Command;
+
+use crate::catalog::{self, MediaKind, Rendition, CATALOG_TRACK};
+use crate::media::MediaFrame;
+use crate::mp4::{self, Sample, TrackConfig};
+
+/// How and where thumbnails are published
+#[derive(Clone, Debug)]
+pub struct ThumbnailOptions {
+    /// The endpoint to POST each JPEG to; `{stream_id}` is replaced with the stream
+    pub url: String,
+    pub interval: Duration,
+    /// Output width in pixels; the height keeps the aspect ratio
+    pub width: u32,
+}
+
+/// Publish a thumbnail every `interval` until the broadcast closes
+pub async fn run_thumbnails(stream_id: &str, broadcast: BroadcastConsumer, options: ThumbnailOptions) {
+    let http = reqwest::Client::new();
+    let url = options.url.replace("{stream_id}", stream_id);
+
+    loop {
+        tokio::select! {
+            _ = tokio::time::sleep(options.interval) => {}
+            _ = broadcast.closed() => return,
+        }
+
+        match capture(&broadcast, options.width, options.interval).await {
+            Ok(jpeg) => {
+                let res = http
+                    .post(&url)
+                    .header(reqwest::header::CONTENT_TYPE, "image/jpeg")
+                    .body(jpeg)
+                    .send()
+                    .await
+                    .and_then(|res| res.error_for_status());
+                if let Err(err) = res {
+                    tracing::warn!(%err, stream_id, "failed to upload thumbnail");
+                }
+            }
+            Err(err) => tracing::debug!(%err, stream_id, "failed to capture thumbnail"),
+        }
+    }
+}
+
+/// Decode the most recent keyframe of the smallest video rendition into a JPEG
+async fn capture(broadcast: &BroadcastConsumer, width: u32, timeout: Duration) -> anyhow::Result<Vec<u8>> {
+    // Only subscribe while capturing, so idle streams aren't pulled from CF just for us
+    let mut catalog = broadcast.subscribe_track(&Track::new(CATALOG_TRACK));
+    let frame = tokio::time::timeout(timeout, async {
+        let mut group = catalog.next_group().await?.context("catalog ended")?;
+        group.read_frame().await?.context("empty catalog group")
+    })
+    .await
+    .context("timed out waiting for catalog")??;
+
+    let rendition = catalog::renditions(&frame)
+        .context("invalid catalog")?
+        .into_iter()
+        .filter(|r| r.kind == MediaKind::Video)
+        .min_by_key(height)
+        .context("no video renditions")?;
+    let config = TrackConfig::from_rendition(&rendition)?;
+
+    // The first frame of the latest group is a keyframe
+    let mut track = broadcast.subscribe_track(&Track::new(&rendition.track));
+    let keyframe = tokio::time::timeout(timeout, async {
+        let mut group = track.next_group().await?.context("track ended")?;
+        group.read_frame().await?.context("empty group")
+    })
+    .await
+    .context("timed out waiting for keyframe")??;
+    let keyframe = MediaFrame::decode(&keyframe).context("invalid frame")?;
+
+    let mut input = mp4::init_segment(&config);
+    input.extend(mp4::media_segment(
+        1,
+        &[Sample {
+            timestamp: keyframe.timestamp,
+            duration: config.default_duration(),
+            keyframe: true,
+            data: &keyframe.payload,
+        }],
+    ));
+
+    decode_jpeg(input, width).await
+}
+
+fn height(rendition: &Rendition) -> u64 {
+    rendition.config.get("codedHeight").and_then(Value::as_u64).unwrap_or(u64::MAX)
+}
+
+/// Pipe an fMP4 through ffmpeg and return the first frame as a JPEG
+async fn decode_jpeg(input: Vec<u8>, width: u32) -> anyhow::Result<Vec<u8>> {
+    let mut child = Command::new("ffmpeg")
+        .args(["-hide_banner", "-loglevel", "error", "-f", "mp4", "-i", "pipe:0", "-frames:v", "1"])
+        .args(["-vf", &format!("scale={width}:-2"), "-f", "image2pipe", "-c:v", "mjpeg", "pipe:1"])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()
+        .context("failed to run ffmpeg")?;
+
+    // Feed stdin from another task so a full stdout pipe can't deadlock us
+    let mut stdin = child.stdin.take().context("no ffmpeg stdin")?;
+    tokio::spawn(async move {
+        let _ = stdin.write_all(&input).await;
+    });
+
+    let output = child.wait_with_output().await?;
+    if !output.status.success() || output.stdout.is_empty() {
+        anyhow::bail!("ffmpeg failed: {}", String::from_utf8_lossy(&output.stderr).trim());
+    }
+
+    Ok(output.stdout)
+}
@@ -0,0 +1,77 @@
+//! Media timestamp rebasing
+//!
+//! hang frames start with their presentation timestamp in microseconds, encoded as a
+//! QUIC varint. Some CF-origin encoders use wall-clock timestamps, which throws off
+//! relay-side latency calculations, so we can optionally rewrite them on the way through.
+
+use std::sync::{Arc, OnceLock};
+use std::time::Instant;
+
+use bytes::Bytes;
+
+use crate::media::MediaFrame;
+
+/// How to rewrite frame timestamps when republishing
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum RebaseMode {
+    /// Forward timestamps untouched
+    #[default]
+    None,
+    /// Subtract the first timestamp seen on any track, keeping tracks in sync
+    BridgeStart,
+    /// Map the first timestamp seen on any track to the time elapsed since the bridge started,
+    /// keeping tracks in sync
+    CommonEpoch,
+}
+
+/// Rebasing state shared by every track of one bridge
+pub struct Rebaser {
+    mode: RebaseMode,
+    started: Instant,
+    // Amount subtracted from every timestamp, set by the first frame on any track
+    offset: OnceLock<i64>,
+}
+
+impl Rebaser {
+    pub fn new(mode: RebaseMode) -> Arc<Self> {
+        Arc::new(Self {
+            mode,
+            started: Instant::now(),
+            offset: OnceLock::new(),
+        })
+    }
+
+    /// Create the per-track half of the rebaser
+    pub fn track(self: &Arc<Self>) -> TrackRebaser {
+        TrackRebaser { shared: self.clone() }
+    }
+}
+
+/// Per-track rebasing state, cloned into every group task of the track
+#[derive(Clone)]
+pub struct TrackRebaser {
+    shared: Arc<Rebaser>,
+}
+
+impl TrackRebaser {
+    /// Rewrite the timestamp prefix of `frame`; malformed frames are passed through
+    pub fn rebase(&self, frame: Bytes) -> Bytes {
+        if self.shared.mode == RebaseMode::None {
+            return frame;
+        }
+
+        let Some(mut media) = MediaFrame::decode(&frame) else {
+            return frame;
+        };
+        let timestamp = media.timestamp;
+
+        let offset = *self.shared.offset.get_or_init(|| match self.shared.mode {
+            RebaseMode::None => 0,
+            RebaseMode::BridgeStart => timestamp as i64,
+            RebaseMode::CommonEpoch => timestamp as i64 - self.shared.started.elapsed().as_micros() as i64,
+        });
+
+        media.timestamp = (timestamp as i64).saturating_sub(offset).max(0) as u64;
+        media.encode()
+    }
+}
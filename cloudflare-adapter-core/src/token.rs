@@ -0,0 +1,192 @@
+//! Per-broadcast relay tokens
+//!
+//! By default every bridge publishes over its relay's one connection, authorized by the
+//! node-wide `--relay-token`. With `--relay-token-key` or `--relay-token-url`, each bridge
+//! publishes over a session of its own instead, with a token that only lets it publish its
+//! broadcast's path, so a relay enforcing per-path permissions needn't hand the adapter
+//! blanket publish rights. The relay connections themselves still use `--relay-token`, to
+//! watch announcements and drive shedding and spilling.
+//!
+//! `--relay-token-key` is a JWK with an HMAC key (`HS256`, `HS384` or `HS512`), like the
+//! relay's own, to mint the tokens with: claims of `{"root": "", "put": [<path>]}`, valid
+//! for [TOKEN_TTL]. `--relay-token-url` gets them from a token service instead, POSTing
+//! `{"stream_id", "path", "relay"}` and expecting `{"token"}` back (the `token-service`
+//! feature). Either way a token is fetched for every (re)connect of the session.
+
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use anyhow::Context;
+use base64::Engine;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use moq_lite::{BroadcastConsumer, Origin, OriginConsumer};
+use serde_json::json;
+use url::Url;
+
+use crate::backoff::BackoffPolicy;
+use crate::connect::{self, ConnectOptions};
+use crate::metrics::Counter;
+use crate::paths::PathClaim;
+use crate::proxy::{self, Proxy};
+use crate::relay::Relay;
+use crate::sink::{BridgeSink, SinkFuture};
+
+/// How long minted tokens are valid; they're only checked when a session connects
+const TOKEN_TTL: Duration = Duration::from_secs(3600);
+
+/// How long a session outlives its broadcast, for the groups still on their way out
+const LINGER: Duration = Duration::from_secs(5);
+
+/// Where per-broadcast tokens come from
+pub enum TokenIssuer {
+    /// Minted with `--relay-token-key`
+    Key(SigningKey),
+    /// From `--relay-token-url`
+    #[cfg(feature = "token-service")]
+    Service { url: String, client: reqwest::Client },
+}
+
+impl TokenIssuer {
+    /// A token to publish `path` on `relay`
+    #[cfg_attr(not(feature = "token-service"), allow(unused_variables))]
+    async fn token(&self, stream_id: &str, path: &str, relay: &str) -> anyhow::Result<String> {
+        match self {
+            Self::Key(key) => key.sign(path),
+            #[cfg(feature = "token-service")]
+            Self::Service { url, client } => {
+                #[derive(serde::Deserialize)]
+                struct Issued {
+                    token: String,
+                }
+                let body = json!({ "stream_id": stream_id, "path": path, "relay": relay });
+                let response = client.post(url).json(&body).send().await?.error_for_status()?;
+                let issued: Issued = response.json().await.context("invalid token service response")?;
+                Ok(issued.token)
+            }
+        }
+    }
+}
+
+/// An HMAC key from a JWK
+pub struct SigningKey {
+    alg: &'static str,
+    kid: Option<String>,
+    key: ring::hmac::Key,
+}
+
+impl SigningKey {
+    /// Read the JWK in `path`
+    pub fn load(path: &str) -> anyhow::Result<Self> {
+        let jwk = std::fs::read_to_string(path).with_context(|| format!("failed to read {path}"))?;
+        let jwk: serde_json::Value = serde_json::from_str(&jwk).with_context(|| format!("{path} isn't a JWK"))?;
+
+        let (alg, hmac) = match jwk["alg"].as_str() {
+            Some("HS256") => ("HS256", ring::hmac::HMAC_SHA256),
+            Some("HS384") => ("HS384", ring::hmac::HMAC_SHA384),
+            Some("HS512") => ("HS512", ring::hmac::HMAC_SHA512),
+            Some(alg) => anyhow::bail!("unsupported key algorithm {alg}: only HS256, HS384 and HS512 can sign"),
+            None => anyhow::bail!("{path} has no alg"),
+        };
+        let secret = jwk["k"].as_str().with_context(|| format!("{path} has no k"))?;
+        let secret = URL_SAFE_NO_PAD.decode(secret.trim_end_matches('=')).context("invalid k")?;
+
+        Ok(Self {
+            alg,
+            kid: jwk["kid"].as_str().map(str::to_string),
+            key: ring::hmac::Key::new(hmac, &secret),
+        })
+    }
+
+    /// A token that lets its holder publish `path` and nothing else
+    fn sign(&self, path: &str) -> anyhow::Result<String> {
+        let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH)?.as_secs();
+        let mut header = json!({ "alg": self.alg, "typ": "JWT" });
+        if let Some(kid) = &self.kid {
+            header["kid"] = json!(kid);
+        }
+        let claims = json!({ "root": "", "put": [path], "iat": now, "exp": now + TOKEN_TTL.as_secs() });
+
+        let header = URL_SAFE_NO_PAD.encode(serde_json::to_vec(&header)?);
+        let claims = URL_SAFE_NO_PAD.encode(serde_json::to_vec(&claims)?);
+        let signed = format!("{header}.{claims}");
+        let signature = URL_SAFE_NO_PAD.encode(ring::hmac::sign(&self.key, signed.as_bytes()));
+        Ok(format!("{signed}.{signature}"))
+    }
+}
+
+/// How bridges connect their own sessions to the relays
+pub struct BroadcastSessions {
+    pub issuer: TokenIssuer,
+    pub client: moq_native::Client,
+    pub options: ConnectOptions,
+    pub proxy: Option<Proxy>,
+    pub backoff: BackoffPolicy,
+}
+
+/// Publishes one bridge's broadcast over a session of its own, with a token for its path
+pub(crate) struct BroadcastSink {
+    sessions: Arc<BroadcastSessions>,
+    relay: Arc<Relay>,
+    claim: PathClaim,
+}
+
+impl BroadcastSink {
+    pub(crate) fn new(sessions: Arc<BroadcastSessions>, relay: Arc<Relay>, claim: PathClaim) -> Self {
+        Self { sessions, relay, claim }
+    }
+
+    /// Connect a session publishing `origin`, which only holds the bridge's broadcast
+    async fn connect(&self, stream_id: &str, origin: OriginConsumer) -> anyhow::Result<connect::Connection> {
+        let token = self.sessions.issuer.token(stream_id, self.claim.path(), &self.relay.url).await;
+        let token = token.context("failed to get a token")?;
+        let url = Url::parse(&format!("{}/?jwt={}", self.relay.url, token))?;
+
+        let client = proxy::client(&self.sessions.client, self.sessions.proxy.as_ref()).await?;
+        connect::connect(&client, url, &self.sessions.options, Some(origin), None).await
+    }
+}
+
+impl BridgeSink for BroadcastSink {
+    fn name(&self) -> &str {
+        "relay"
+    }
+
+    fn publish<'a>(&'a self, stream_id: &'a str, broadcast: BroadcastConsumer) -> SinkFuture<'a> {
+        Box::pin(async move {
+            let (path, relay) = (self.claim.path(), self.relay.name.as_deref().unwrap_or("main"));
+            let origin = Origin::produce();
+            origin.producer.publish_broadcast(path, broadcast.clone());
+
+            let mut backoff = self.sessions.backoff.start();
+            loop {
+                match self.connect(stream_id, origin.consumer.consume()).await {
+                    Ok(connection) => {
+                        backoff.reset();
+                        tracing::info!(stream_id, path, relay, "publishing over a session of its own");
+                        tokio::select! {
+                            _ = broadcast.closed() => {
+                                tokio::time::sleep(LINGER).await;
+                                connection.session.close(moq_lite::Error::Cancel);
+                                return Ok(());
+                            }
+                            _ = connection.session.closed() => {
+                                tracing::warn!(stream_id, relay, "broadcast session closed");
+                            }
+                        }
+                    }
+                    Err(err) => {
+                        tracing::warn!(err = format!("{err:#}"), stream_id, relay, "failed to connect broadcast session");
+                        let labels = [("relay", relay)];
+                        let help = "Per-broadcast relay sessions that failed to connect";
+                        Counter::new("broadcast_session_failures_total", help, &labels).inc();
+                    }
+                }
+
+                tokio::select! {
+                    _ = backoff.wait() => {},
+                    _ = broadcast.closed() => return Ok(()),
+                }
+            }
+        })
+    }
+}
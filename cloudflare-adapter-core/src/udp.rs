@@ -0,0 +1,188 @@
+//! MPEG-TS over UDP egress
+//!
+//! Feeds confidence monitors and legacy broadcast equipment in the NOC. A bridge with
+//! a `udp://host:port` output sends the best video rendition and the first audio
+//! rendition of its catalog as MPEG-TS, seven packets per datagram; `rtp://host:port`
+//! wraps each datagram in RTP (payload type 33, RFC 2250). Renditions are picked
+//! again whenever the catalog changes.
+
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::Context;
+use moq_lite::{BroadcastConsumer, Track};
+use serde_json::Value;
+use tokio::net::UdpSocket;
+use tokio::sync::mpsc;
+use tokio::task::JoinSet;
+use url::Url;
+
+use crate::catalog::{self, MediaKind, Rendition, CATALOG_TRACK};
+use crate::media::MediaFrame;
+use crate::mpegts::{self, Codec, Muxer};
+
+/// TS packets per datagram, the usual 1316 bytes
+const PACKETS_PER_DATAGRAM: usize = 7;
+
+/// The static RTP payload type for MPEG-2 transport streams
+const RTP_MP2T: u8 = 33;
+
+/// How long to wait before retrying after the output fails
+const RETRY_DELAY: Duration = Duration::from_secs(5);
+
+/// A frame on its way to the muxer
+struct Frame {
+    kind: MediaKind,
+    frame: MediaFrame,
+    keyframe: bool,
+}
+
+/// Send `broadcast` to `url` until the broadcast closes
+pub async fn run_udp_egress(stream_id: &str, broadcast: BroadcastConsumer, url: Url) {
+    loop {
+        tokio::select! {
+            res = send_broadcast(stream_id, &broadcast, &url) => match res {
+                Ok(()) => return,
+                Err(err) => tracing::warn!(%err, stream_id, %url, "UDP egress failed"),
+            },
+            _ = broadcast.closed() => return,
+        }
+
+        tokio::select! {
+            _ = tokio::time::sleep(RETRY_DELAY) => {}
+            _ = broadcast.closed() => return,
+        }
+    }
+}
+
+async fn send_broadcast(stream_id: &str, broadcast: &BroadcastConsumer, url: &Url) -> anyhow::Result<()> {
+    let rtp = match url.scheme() {
+        "udp" => false,
+        "rtp" => true,
+        scheme => anyhow::bail!("unsupported output scheme: {scheme}"),
+    };
+    let host = url.host_str().context("missing host")?;
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    let port = url.port().context("missing port")?;
+    let target = tokio::net::lookup_host((host, port)).await?.next().context("no DNS entries")?;
+
+    let bind = if target.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" };
+    let socket = UdpSocket::bind(bind).await?;
+    socket.connect(target).await?;
+    let mut output = Output::new(socket, rtp);
+
+    tracing::info!(stream_id, %url, "sending UDP output");
+
+    let mut catalog = broadcast.subscribe_track(&Track::new(CATALOG_TRACK));
+    let mut readers = JoinSet::new();
+    let (tx, mut rx) = mpsc::channel::<Frame>(64);
+    let mut muxer = Muxer::new(None, None);
+
+    loop {
+        tokio::select! {
+            group = catalog.next_group() => {
+                let Some(mut group) = group? else {
+                    return Ok(());
+                };
+                let Some(frame) = group.read_frame().await? else {
+                    continue;
+                };
+                let Some(renditions) = catalog::renditions(&frame) else {
+                    tracing::warn!(stream_id, "invalid catalog, keeping the current renditions");
+                    continue;
+                };
+
+                // Start over with the new renditions
+                readers.abort_all();
+                let video = select(&renditions, MediaKind::Video);
+                let audio = select(&renditions, MediaKind::Audio);
+                muxer = Muxer::new(video.as_ref().map(|(_, c)| c.clone()), audio.as_ref().map(|(_, c)| c.clone()));
+
+                for (rendition, _) in video.into_iter().chain(audio) {
+                    tracing::debug!(stream_id, track = %rendition.track, "muxing rendition");
+                    let track = broadcast.subscribe_track(&Track::new(&rendition.track));
+                    readers.spawn(read_track(track, rendition.kind, tx.clone()));
+                }
+            }
+            Some(frame) = rx.recv() => {
+                let packets = muxer.write(frame.kind, frame.frame.timestamp, &frame.frame.payload, frame.keyframe);
+                output.send(&packets, frame.frame.timestamp).await?;
+            }
+        }
+    }
+}
+
+/// The best supported rendition of `kind`: the tallest video, or the first audio
+fn select(renditions: &[Rendition], kind: MediaKind) -> Option<(Rendition, Codec)> {
+    let height = |r: &Rendition| r.config.get("codedHeight").and_then(Value::as_u64).unwrap_or(0);
+    let mut supported: Vec<_> = renditions
+        .iter()
+        .filter(|r| r.kind == kind)
+        .filter_map(|r| match Codec::from_rendition(r) {
+            Ok(codec) => Some((r.clone(), codec)),
+            Err(err) => {
+                tracing::debug!(%err, track = %r.track, "can't mux rendition");
+                None
+            }
+        })
+        .collect();
+
+    match kind {
+        MediaKind::Video => supported.into_iter().max_by_key(|(r, _)| height(r)),
+        MediaKind::Audio => (!supported.is_empty()).then(|| supported.remove(0)),
+    }
+}
+
+/// Forward the frames of one track to the muxer
+async fn read_track(mut track: moq_lite::TrackConsumer, kind: MediaKind, tx: mpsc::Sender<Frame>) -> anyhow::Result<()> {
+    while let Some(mut group) = track.next_group().await? {
+        let mut keyframe = true;
+        while let Some(frame) = group.read_frame().await? {
+            let Some(frame) = MediaFrame::decode(&frame) else {
+                continue;
+            };
+            tx.send(Frame { kind, frame, keyframe }).await?;
+            keyframe = false;
+        }
+    }
+
+    Ok(())
+}
+
+/// Splits TS packets into datagrams, optionally behind an RTP header
+struct Output {
+    socket: UdpSocket,
+    // The RTP sequence number and SSRC, if we're sending RTP
+    rtp: Option<(u16, u32)>,
+}
+
+impl Output {
+    fn new(socket: UdpSocket, rtp: bool) -> Self {
+        // Doesn't need to be unpredictable, only different between outputs
+        let ssrc = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().subsec_nanos();
+        Self {
+            socket,
+            rtp: rtp.then_some((0, ssrc)),
+        }
+    }
+
+    async fn send(&mut self, packets: &[u8], timestamp: u64) -> anyhow::Result<()> {
+        for chunk in packets.chunks(PACKETS_PER_DATAGRAM * mpegts::PACKET_SIZE) {
+            let Some((sequence, ssrc)) = &mut self.rtp else {
+                self.socket.send(chunk).await?;
+                continue;
+            };
+
+            let mut datagram = Vec::with_capacity(12 + chunk.len());
+            datagram.extend_from_slice(&[0x80, RTP_MP2T]);
+            datagram.extend_from_slice(&sequence.to_be_bytes());
+            datagram.extend_from_slice(&((timestamp * 9 / 100) as u32).to_be_bytes());
+            datagram.extend_from_slice(&ssrc.to_be_bytes());
+            datagram.extend_from_slice(chunk);
+            *sequence = sequence.wrapping_add(1);
+
+            self.socket.send(&datagram).await?;
+        }
+
+        Ok(())
+    }
+}
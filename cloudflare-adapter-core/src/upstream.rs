@@ -0,0 +1,91 @@
+//! Draft 14 upstreams other than CloudFlare
+//!
+//! What the adapter calls the CloudFlare side can be any Draft 14 relay: partners run
+//! moq-rs and moxygen too. `--upstream` picks the quirks of the one at `--cloudflare-url`,
+//! and `--upstream-quirk name=value` sets any of them on its own:
+//!
+//! - `announce-remote`: CloudFlare never sends PUBLISH_NAMESPACE, so each bridge calls
+//!   `announce_remote` to make the session subscribe to its broadcast. Relays following
+//!   the draft announce their broadcasts in answer to our SUBSCRIBE_NAMESPACE, so the
+//!   bridge only waits for the announcement (see [announce](crate::announce)).
+//! - `redirects`: a CloudFlare endpoint moving a session answers with a 3xx that we look
+//!   up over HTTPS (see [redirect](crate::redirect)). Elsewhere a redirect fails the
+//!   connection, as with `--cf-max-redirects 0`.
+//!
+//! Namespace conventions are the stream's own: `--cf-namespace-template` and
+//! `--namespace-encoding` apply whatever the upstream. To bridge from several upstreams,
+//! run an instance, or a tenant, for each.
+
+use std::str::FromStr;
+
+use anyhow::Context;
+
+/// The kind of relay the bridges take their broadcasts from
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum UpstreamKind {
+    /// CloudFlare's MoQ network
+    #[default]
+    Cloudflare,
+    /// Any relay following the draft, like moq-rs or moxygen
+    #[value(alias = "moq-rs", alias = "moxygen")]
+    Draft14,
+}
+
+/// How the upstream deviates from the draft
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct UpstreamQuirks {
+    /// It doesn't announce its broadcasts, so bridges call `announce_remote`
+    pub announce_remote: bool,
+    /// It redirects sessions in a way only an HTTPS lookup resolves
+    pub redirects: bool,
+}
+
+impl UpstreamKind {
+    pub fn quirks(self) -> UpstreamQuirks {
+        match self {
+            Self::Cloudflare => UpstreamQuirks {
+                announce_remote: true,
+                redirects: true,
+            },
+            Self::Draft14 => UpstreamQuirks {
+                announce_remote: false,
+                redirects: false,
+            },
+        }
+    }
+}
+
+/// One quirk set on its own, over what `--upstream` picks
+#[derive(Clone, Copy, Debug)]
+pub enum UpstreamQuirk {
+    AnnounceRemote(bool),
+    Redirects(bool),
+}
+
+impl FromStr for UpstreamQuirk {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        let (name, value) = s.split_once('=').context("expected name=value")?;
+        let value = value.parse().with_context(|| format!("invalid {name}: {value}, expected true or false"))?;
+
+        Ok(match name {
+            "announce-remote" => Self::AnnounceRemote(value),
+            "redirects" => Self::Redirects(value),
+            _ => anyhow::bail!("unknown upstream quirk: {name}"),
+        })
+    }
+}
+
+impl UpstreamQuirks {
+    /// These quirks, with `overrides` applied in order
+    pub fn with(mut self, overrides: &[UpstreamQuirk]) -> Self {
+        for quirk in overrides {
+            match *quirk {
+                UpstreamQuirk::AnnounceRemote(on) => self.announce_remote = on,
+                UpstreamQuirk::Redirects(on) => self.redirects = on,
+            }
+        }
+        self
+    }
+}
@@ -0,0 +1,235 @@
+//! `validate-config`
+//!
+//! Checks the configuration the adapter would run with, from the config file, the
+//! environment and the command line together, without connecting to anything. Clap
+//! has already rejected unknown options, bad values and missing required ones by the
+//! time this runs, including intervals of zero seconds, which would stall or panic the
+//! timers they drive; on top of that, URLs must parse, templates must be well formed,
+//! patterns must be usable and options that only work together must be given together.
+//! Every problem is logged, and the exit status is non-zero if there are any, so CI
+//! can check a config change before it's deployed.
+//!
+//! Patterns are globs, or for stream IDs, `re:` regular expressions, which clap has
+//! already compiled. Any string is a valid glob, so only empty patterns are reported.
+
+use url::Url;
+
+use crate::discovery::Discovery;
+use crate::quic::QuicSetting;
+use crate::replay::Replay;
+use crate::{e2ee, namespace, paths, AdapterConfig};
+
+/// Log every problem with `config`, failing if there are any
+pub fn run(config: &AdapterConfig) -> anyhow::Result<()> {
+    let problems = problems(config);
+    for problem in &problems {
+        tracing::error!("{problem}");
+    }
+
+    anyhow::ensure!(problems.is_empty(), "{} problem(s) in the configuration", problems.len());
+    tracing::info!("configuration is valid");
+    Ok(())
+}
+
+fn problems(config: &AdapterConfig) -> Vec<String> {
+    let mut problems = Vec::new();
+
+    for (option, url) in [
+        ("relay-url", Some(&config.relay_url)),
+        ("cloudflare-url", Some(&config.cloudflare_url)),
+        ("registry-url", config.registry_url.as_ref()),
+        ("relay-token-url", config.relay_token_url.as_ref()),
+        ("lease-redis-url", config.lease_redis_url.as_ref()),
+        ("metrics-push-url", config.metrics_push_url.as_ref()),
+        ("stats-url", config.stats_url.as_ref()),
+    ] {
+        let Some(url) = url else { continue };
+        if let Err(err) = Url::parse(url) {
+            problems.push(format!("{option}: invalid URL {url:?}: {err}"));
+        }
+    }
+    if let Err(err) = config.token_issuer() {
+        let option = if config.relay_token_key.is_some() { "relay-token-key" } else { "relay-token-url" };
+        problems.push(format!("{option}: {err:#}"));
+    }
+    #[cfg(feature = "ffmpeg")]
+    if let Some(url) = &config.thumbnail_url {
+        if let Err(err) = Url::parse(&url.replace("{stream_id}", "stream")) {
+            problems.push(format!("thumbnail-url: invalid URL {url:?}: {err}"));
+        }
+    }
+    for output in &config.udp_output {
+        if !matches!(output.value.scheme(), "udp" | "rtp") {
+            problems.push(format!("udp-output: unsupported scheme {}", output.value.scheme()));
+        }
+    }
+
+    // Any field can come from the registry, so only the template's syntax can be checked
+    for (option, template) in [
+        ("cf-namespace-template", &config.cf_namespace_template),
+        ("publish-root", &config.publish_root),
+    ] {
+        if let Err(err) = namespace::render(template, |name| (!name.is_empty()).then(String::new)) {
+            problems.push(format!("{option}: {err}"));
+        }
+    }
+
+    for (option, patterns) in [("include-stream", &config.include_streams), ("exclude-stream", &config.exclude_streams)] {
+        for pattern in patterns {
+            if matches!(pattern.to_string().as_str(), "" | "re:") {
+                problems.push(format!("{option}: {:?} is an empty pattern", pattern.to_string()));
+            }
+        }
+    }
+    for (option, patterns) in [
+        ("bridge-priority", &config.bridge_priority),
+        ("track-filter", &config.track_filters),
+        ("transform-track", &config.transform_tracks),
+        ("passthrough-track", &config.passthrough_tracks),
+        ("record-stream", &config.record_streams),
+        ("shed-order", &config.shed_order),
+        ("hls-stream", &config.hls_streams),
+        ("dash-stream", &config.dash_streams),
+    ] {
+        for rule in patterns {
+            let pattern = rule.split_once('=').map_or(rule.as_str(), |(_, pattern)| pattern);
+            if pattern.trim_start_matches('!').is_empty() {
+                problems.push(format!("{option}: {rule:?} has an empty pattern"));
+            }
+        }
+    }
+    if config.canary.is_some_and(|percent| !(0.0..=100.0).contains(&percent)) {
+        problems.push("canary: expected a percentage between 0 and 100".to_string());
+    }
+    for alias in &config.track_aliases {
+        if !alias.contains("->") {
+            problems.push(format!("track-alias: {alias:?} has no `->`"));
+        }
+    }
+    for path in &config.stream_paths {
+        if path.stream_id.is_none() {
+            problems.push(format!("stream-path: {:?} isn't mapped from a stream_id", path.value));
+        }
+        if let Err(err) = paths::sanitize(&path.value) {
+            problems.push(format!("stream-path: {:?}: {err}", path.value));
+        }
+    }
+    for limit in &config.relay_egress_limit {
+        let Some(relay) = &limit.stream_id else { continue };
+        if relay != "main" && !config.relay_targets.iter().any(|target| &target.name == relay) {
+            problems.push(format!("relay-egress-limit: no relay-target named {relay:?}"));
+        }
+    }
+    for (option, proxied, settings) in [
+        ("relay-quic", config.relay_proxy.is_some(), &config.relay_quic),
+        ("cf-quic", config.cf_proxy.is_some(), &config.cf_quic),
+    ] {
+        let marked = settings.iter().any(|setting| matches!(setting, QuicSetting::Dscp(_) | QuicSetting::Priority(_)));
+        if proxied && marked {
+            problems.push(format!("{option}: dscp and priority don't apply through a proxy"));
+        }
+    }
+
+    if config.e2ee {
+        for option in e2ee::conflicts(config) {
+            problems.push(format!("e2ee: {option} would change or read encrypted frames"));
+        }
+    }
+
+    // Options that do nothing without another
+    for (option, given, needed, present) in [
+        ("ad-marker-output", config.ad_marker_output.is_some(), "ad-marker-track", config.ad_marker_track.is_some()),
+        ("inject-token", config.inject_token.is_some(), "inject", config.inject),
+        ("admin-token", config.admin_token.is_some(), "admin", config.admin),
+        ("hls-stream", !config.hls_streams.is_empty(), "hls", config.hls),
+        ("dash-stream", !config.dash_streams.is_empty(), "dash", config.dash),
+        ("record-stream", !config.record_streams.is_empty(), "record-dir", config.record_dir.is_some()),
+        ("transform-track", !config.transform_tracks.is_empty(), "transform-command", config.transform_command.is_some()),
+        ("evict-cooldown", config.evict_cooldown.is_some(), "evict-error-rate", config.evict_error_rate.is_some()),
+        ("webhook-token", config.webhook_token.is_some(), "discovery webhook", config.discovery == Discovery::Webhook),
+        ("static-stream", !config.static_streams.is_empty(), "discovery static", config.discovery == Discovery::Static),
+        ("replay-registry", config.replay_registry.is_some(), "discovery replay", config.discovery == Discovery::Replay),
+        ("canary-rest", config.canary_rest, "canary", config.canary.is_some()),
+    ] {
+        if given && !present {
+            problems.push(format!("{option} has no effect without {needed}"));
+        }
+    }
+
+    if let (Discovery::Replay, Some(path)) = (config.discovery, &config.replay_registry) {
+        if let Err(err) = Replay::load(path) {
+            problems.push(format!("replay-registry: {err:#}"));
+        }
+    }
+
+    let percentages = [("evict-error-rate", config.evict_error_rate), ("backoff-jitter", Some(config.backoff_jitter))];
+    for (option, percent) in percentages {
+        match percent {
+            Some(percent) if !(0.0..=100.0).contains(&percent) => {
+                problems.push(format!("{option}: {percent} isn't a percentage"));
+            }
+            _ => {}
+        }
+    }
+    if config.backoff_initial > config.backoff_max {
+        problems.push(format!("backoff-initial: {}ms is longer than backoff-max", config.backoff_initial));
+    }
+    if config.poll_interval == 0 {
+        problems.push("poll-interval: must be at least a second".to_string());
+    }
+    if config.latency_probe == Some(0) {
+        problems.push("latency-probe: must be at least a second".to_string());
+    }
+    if config.part_duration >= config.segment_duration * 1000 {
+        problems.push(format!("part-duration: {}ms isn't shorter than a segment", config.part_duration));
+    }
+    if let (Some(bridge), Some(total)) = (config.bridge_buffer_limit, config.buffer_limit) {
+        if bridge > total {
+            problems.push(format!("bridge-buffer-limit: {bridge}MB is more than buffer-limit"));
+        }
+    }
+
+    problems
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::Parser;
+
+    use super::*;
+
+    fn parse(args: &[&str]) -> Result<AdapterConfig, clap::Error> {
+        AdapterConfig::try_parse_from(["cloudflare-adapter", "--relay-url", "https://relay.example"].iter().chain(args))
+    }
+
+    #[test]
+    fn rejects_zero_intervals() {
+        let zeros: &[&[&str]] = &[
+            &["--config", "adapter.toml", "--reload-interval", "0"],
+            &["--evict-error-rate", "5", "--evict-window", "0"],
+            &["--watchdog-timeout", "0"],
+            #[cfg(feature = "ffmpeg")]
+            &["--thumbnail-interval", "0"],
+        ];
+        for args in zeros {
+            let err = parse(args).err().unwrap_or_else(|| panic!("{args:?} parsed"));
+            assert_eq!(err.kind(), clap::error::ErrorKind::ValueValidation, "{args:?}");
+        }
+    }
+
+    #[test]
+    fn stream_patterns() {
+        let err = parse(&["--include-stream", "re:live/("]).err().unwrap();
+        assert_eq!(err.kind(), clap::error::ErrorKind::ValueValidation);
+
+        let config = parse(&["--include-stream", "re:^live/,", "--exclude-stream", "re:"]).unwrap();
+        let problems = problems(&config);
+        assert_eq!(problems, [r#"include-stream: "" is an empty pattern"#, r#"exclude-stream: "re:" is an empty pattern"#]);
+    }
+
+    #[test]
+    fn accepts_intervals_of_a_second() {
+        let config = parse(&["--evict-error-rate", "5", "--watchdog-timeout", "1"]).unwrap();
+        assert_eq!(problems(&config), Vec::<String>::new());
+    }
+}
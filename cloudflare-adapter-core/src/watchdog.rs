@@ -0,0 +1,147 @@
+//! Watchdog for stuck subsystems
+//!
+//! The relay and CF connection loops, the bridge manager and every bridge beat a
+//! heartbeat as they make progress, and keep it beating while they wait on something
+//! that's expected to take a while, like a session staying open or the poll interval.
+//! With `--watchdog-timeout`, a component whose heartbeat goes quiet for that long is
+//! stuck. A stuck bridge is torn down, so the next poll bridges its stream again. Any
+//! other stuck component fails the process, leaving the restart to whatever supervises
+//! it: a wedged bridge manager otherwise leaves the adapter up but doing nothing.
+
+use std::future::Future;
+use std::sync::{Arc, Mutex, Weak};
+use std::time::{Duration, Instant};
+
+use tokio::sync::Notify;
+
+use crate::metrics::Counter;
+
+/// How often heartbeats are checked
+const CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// What to do about a stuck component
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Stuck {
+    /// Tear it down; its owner waits on [Heartbeat::stuck]
+    Restart,
+    /// Fail the process
+    Exit,
+}
+
+/// Every registered heartbeat
+pub struct Watchdog {
+    timeout: Option<Duration>,
+    beats: Mutex<Vec<Weak<Beat>>>,
+}
+
+struct Beat {
+    name: String,
+    stuck: Stuck,
+    last: Mutex<Instant>,
+    notify: Notify,
+}
+
+impl Watchdog {
+    /// A watchdog with heartbeats due every `timeout`, or one that never fires if None
+    pub fn new(timeout: Option<Duration>) -> Arc<Self> {
+        Arc::new(Self {
+            timeout,
+            beats: Default::default(),
+        })
+    }
+
+    /// Start watching a component, until every clone of the heartbeat is dropped
+    pub fn register(&self, name: impl Into<String>, stuck: Stuck) -> Heartbeat {
+        let beat = Arc::new(Beat {
+            name: name.into(),
+            stuck,
+            last: Mutex::new(Instant::now()),
+            notify: Notify::new(),
+        });
+
+        if self.timeout.is_some() {
+            self.beats.lock().unwrap().push(Arc::downgrade(&beat));
+        }
+
+        Heartbeat {
+            beat,
+            interval: self.timeout.map(|timeout| timeout / 4),
+        }
+    }
+
+    /// Check heartbeats until a component that can't be restarted gets stuck
+    pub async fn run(&self) -> anyhow::Result<()> {
+        let Some(timeout) = self.timeout else {
+            return std::future::pending().await;
+        };
+
+        let mut interval = tokio::time::interval(CHECK_INTERVAL);
+        loop {
+            interval.tick().await;
+
+            let mut stuck = Vec::new();
+            self.beats.lock().unwrap().retain(|beat| {
+                let Some(beat) = beat.upgrade() else {
+                    return false;
+                };
+                if beat.last.lock().unwrap().elapsed() > timeout {
+                    stuck.push(beat);
+                }
+                true
+            });
+
+            for beat in stuck {
+                let quiet = beat.last.lock().unwrap().elapsed();
+                match beat.stuck {
+                    Stuck::Exit => anyhow::bail!("{} made no progress for {quiet:?}", beat.name),
+                    Stuck::Restart => {
+                        tracing::warn!(component = %beat.name, ?quiet, "no progress, restarting");
+                        Counter::new("watchdog_restarts_total", "Components restarted for making no progress", &[]).inc();
+
+                        // Give the restart a full timeout before firing again
+                        *beat.last.lock().unwrap() = Instant::now();
+                        beat.notify.notify_one();
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Proof of progress from one component
+#[derive(Clone)]
+pub struct Heartbeat {
+    beat: Arc<Beat>,
+    // None if the watchdog is disabled
+    interval: Option<Duration>,
+}
+
+impl Heartbeat {
+    pub fn beat(&self) {
+        *self.beat.last.lock().unwrap() = Instant::now();
+    }
+
+    /// Await a future that's expected to take a while, beating until it's done
+    pub async fn pulse<F: Future>(&self, fut: F) -> F::Output {
+        let Some(interval) = self.interval else {
+            return fut.await;
+        };
+
+        tokio::pin!(fut);
+        let mut ticks = tokio::time::interval(interval);
+        loop {
+            tokio::select! {
+                output = &mut fut => {
+                    self.beat();
+                    return output;
+                }
+                _ = ticks.tick() => self.beat(),
+            }
+        }
+    }
+
+    /// Resolve once the watchdog decides the component should restart
+    pub async fn stuck(&self) {
+        self.beat.notify.notified().await
+    }
+}
@@ -0,0 +1,788 @@
+//! WHIP/WHEP gateway
+//!
+//! Lets WebRTC players and encoders that only speak WHIP and WHEP take part in the
+//! streams we bridge, over the embedded HTTP server:
+//!
+//! - `--whep` plays every bridged stream to WHEP players at `POST /whep/{stream_id}`:
+//!   the tallest H.264 rendition and the first Opus one of the bridge's relay-side
+//!   broadcast, picked again whenever its catalog changes, with length-prefixed video
+//!   rewritten to Annex B like [mpegts](crate::mpegts) does
+//! - `--whip` takes feeds from WHIP encoders at `POST /whip/{stream_id}` and publishes
+//!   them to the main relay as a hang broadcast, under the path a bridged stream with
+//!   that ID would get and claimed the same way, so the two can't collide; encoders have
+//!   to send `--whip-token` as a bearer token when it's set
+//!
+//! The request body is the peer's SDP offer and the response our answer, with a
+//! `Location` to `DELETE` to end the session. Only H.264 and Opus are negotiated. Every
+//! session gets a UDP port of its own on `--webrtc-address`, offered as its only
+//! candidate, so that address has to be reachable from the peers. Only built with the
+//! `webrtc` feature.
+
+use std::collections::HashMap;
+use std::future::Future;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+use anyhow::Context;
+use axum::extract::{Path, State};
+use axum::http::{header, HeaderMap, StatusCode};
+use axum::response::{IntoResponse, Response};
+use axum::routing::{delete, post};
+use axum::Router;
+use bytes::Bytes;
+use moq_lite::{
+    Broadcast, BroadcastConsumer, BroadcastProducer, GroupProducer, OriginProducer, Track, TrackConsumer, TrackProducer,
+};
+use serde_json::{json, Value};
+use str0m::change::{SdpAnswer, SdpOffer};
+use str0m::config::CryptoProvider;
+use str0m::format::Codec;
+use str0m::media::{Frequency, KeyframeRequestKind, MediaData, MediaTime, Mid};
+use str0m::net::{Protocol, Receive};
+use str0m::{Candidate, Event, IceConnectionState, Input, Output, Rtc, RtcConfig};
+use tokio::net::UdpSocket;
+use tokio::sync::{mpsc, oneshot};
+use tokio::task::JoinSet;
+
+use crate::catalog::{self, MediaKind, Rendition, CATALOG_TRACK};
+use crate::config::AdapterConfig;
+use crate::manager::BridgeLookup;
+use crate::media::MediaFrame;
+use crate::mpegts;
+use crate::paths::PathClaim;
+use crate::registry::StreamInfo;
+use crate::relay::Relay;
+
+/// How many frames a player can fall behind its broadcast before holding up its tracks
+const BUFFER: usize = 64;
+
+/// Room for the largest datagram a peer sends
+const DATAGRAM: usize = 2000;
+
+/// The WHIP and WHEP sessions of the embedded HTTP server
+pub(crate) struct Gateway {
+    whep: bool,
+    whip: bool,
+    /// Required of WHIP encoders as a bearer token when set
+    token: Option<String>,
+    address: IpAddr,
+    /// The stream rules WHIP feeds get their paths from
+    config: AdapterConfig,
+    bridges: BridgeLookup,
+    /// Where WHIP feeds are published
+    relay: Arc<Relay>,
+    crypto: Arc<CryptoProvider>,
+    /// What ends each session, by its `Location`
+    sessions: Mutex<HashMap<String, oneshot::Sender<()>>>,
+}
+
+impl Gateway {
+    /// The gateway `--whep` and `--whip` ask for, if either does
+    pub(crate) fn new(config: &AdapterConfig, bridges: BridgeLookup, relay: Arc<Relay>) -> Option<Arc<Self>> {
+        if !config.whep && !config.whip {
+            return None;
+        }
+        Some(Arc::new(Self {
+            whep: config.whep,
+            whip: config.whip,
+            token: config.whip_token.clone(),
+            address: config.webrtc_address?,
+            config: config.clone(),
+            bridges,
+            relay,
+            crypto: Arc::new(str0m::crypto::from_feature_flags()),
+            sessions: Default::default(),
+        }))
+    }
+
+    /// Answer `offer` with a session of its own, run by `session` until it ends or is deleted
+    async fn answer<F>(
+        self: Arc<Self>,
+        kind: &'static str,
+        stream_id: &str,
+        offer: SdpOffer,
+        session: impl FnOnce(Peer, oneshot::Receiver<()>) -> F,
+    ) -> Response
+    where
+        F: Future<Output = anyhow::Result<()>> + Send + 'static,
+    {
+        let (peer, answer) = match Peer::accept(offer, self.address, self.crypto.clone()).await {
+            Ok(accepted) => accepted,
+            Err(err) => {
+                tracing::warn!(err = format!("{err:#}"), kind, stream_id, "failed to answer WebRTC offer");
+                return (StatusCode::INTERNAL_SERVER_ERROR, format!("{err:#}")).into_response();
+            }
+        };
+
+        let location = format!("/{kind}/{stream_id}/{:016x}", rand::random::<u64>());
+        let (end, stop) = oneshot::channel();
+        self.sessions.lock().unwrap().insert(location.clone(), end);
+        tracing::info!(kind, stream_id, session = %location, peer = %peer.local, "WebRTC session started");
+
+        let running = session(peer, stop);
+        let (gateway, session) = (self.clone(), location.clone());
+        tokio::spawn(async move {
+            match running.await {
+                Ok(()) => tracing::info!(kind, %session, "WebRTC session ended"),
+                Err(err) => tracing::warn!(err = format!("{err:#}"), kind, %session, "WebRTC session failed"),
+            }
+            gateway.sessions.lock().unwrap().remove(&session);
+        });
+
+        let headers = [(header::CONTENT_TYPE, "application/sdp".to_string()), (header::LOCATION, location)];
+        (StatusCode::CREATED, headers, answer.to_sdp_string()).into_response()
+    }
+
+    /// Whether a WHIP request carries the token, when there is one
+    fn authorized(&self, headers: &HeaderMap) -> bool {
+        let Some(token) = &self.token else {
+            return true;
+        };
+        let bearer = headers
+            .get(header::AUTHORIZATION)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.strip_prefix("Bearer "));
+        bearer == Some(token.as_str())
+    }
+
+    /// End the session at `location`, if it's still running
+    fn end(&self, location: &str) -> Response {
+        match self.sessions.lock().unwrap().remove(location) {
+            Some(end) => {
+                let _ = end.send(());
+                StatusCode::OK.into_response()
+            }
+            None => StatusCode::NOT_FOUND.into_response(),
+        }
+    }
+}
+
+/// The WHIP and WHEP routes, to be merged into the embedded HTTP server
+pub(crate) fn routes(gateway: Arc<Gateway>) -> Router {
+    let mut router = Router::new();
+    if gateway.whep {
+        router = router
+            .route("/whep/{stream_id}", post(play_offer))
+            .route("/whep/{stream_id}/{session}", delete(end_play));
+    }
+    if gateway.whip {
+        router = router
+            .route("/whip/{stream_id}", post(ingest_offer))
+            .route("/whip/{stream_id}/{session}", delete(end_ingest));
+    }
+    router.with_state(gateway)
+}
+
+async fn play_offer(State(gateway): State<Arc<Gateway>>, Path(stream_id): Path<String>, body: String) -> Response {
+    let Some(broadcast) = gateway.bridges.get(&stream_id).await.and_then(|bridge| bridge.forwarded()) else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+    let offer = match SdpOffer::from_sdp_string(&body) {
+        Ok(offer) => offer,
+        Err(err) => return (StatusCode::BAD_REQUEST, format!("invalid SDP offer: {err}")).into_response(),
+    };
+
+    gateway.answer("whep", &stream_id, offer, move |peer, stop| play(peer, broadcast, stop)).await
+}
+
+async fn ingest_offer(
+    State(gateway): State<Arc<Gateway>>,
+    Path(stream_id): Path<String>,
+    headers: HeaderMap,
+    body: String,
+) -> Response {
+    if !gateway.authorized(&headers) {
+        return StatusCode::UNAUTHORIZED.into_response();
+    }
+    let offer = match SdpOffer::from_sdp_string(&body) {
+        Ok(offer) => offer,
+        Err(err) => return (StatusCode::BAD_REQUEST, format!("invalid SDP offer: {err}")).into_response(),
+    };
+
+    let stream = StreamInfo::new(stream_id.clone());
+    let claim = match gateway.config.publish_path(&stream).and_then(|path| gateway.relay.claims.claim(&stream_id, &path)) {
+        Ok(claim) => claim,
+        Err(err) => return (StatusCode::CONFLICT, format!("{err:#}")).into_response(),
+    };
+
+    let origin = gateway.relay.publish.producer.clone();
+    gateway.answer("whip", &stream_id, offer, move |peer, stop| ingest(peer, origin, claim, stop)).await
+}
+
+async fn end_play(State(gateway): State<Arc<Gateway>>, Path((stream_id, session)): Path<(String, String)>) -> Response {
+    gateway.end(&format!("/whep/{stream_id}/{session}"))
+}
+
+async fn end_ingest(
+    State(gateway): State<Arc<Gateway>>,
+    Path((stream_id, session)): Path<(String, String)>,
+    headers: HeaderMap,
+) -> Response {
+    if !gateway.authorized(&headers) {
+        return StatusCode::UNAUTHORIZED.into_response();
+    }
+    gateway.end(&format!("/whip/{stream_id}/{session}"))
+}
+
+/// One peer's WebRTC session, over a UDP socket of its own
+struct Peer {
+    rtc: Rtc,
+    socket: UdpSocket,
+    local: SocketAddr,
+    buf: Vec<u8>,
+    /// When str0m wants to be told time passed, if no datagram arrives first
+    deadline: Instant,
+}
+
+impl Peer {
+    /// A session answering `offer`, on a new port of `address`
+    async fn accept(offer: SdpOffer, address: IpAddr, crypto: Arc<CryptoProvider>) -> anyhow::Result<(Self, SdpAnswer)> {
+        let mut peer = Self::bind(address, crypto).await?;
+        let answer = peer.rtc.sdp_api().accept_offer(offer).context("can't accept SDP offer")?;
+        Ok((peer, answer))
+    }
+
+    async fn bind(address: IpAddr, crypto: Arc<CryptoProvider>) -> anyhow::Result<Self> {
+        let mut rtc = RtcConfig::new()
+            .set_crypto_provider(crypto)
+            .clear_codecs()
+            .enable_h264(true)
+            .enable_opus(true, false)
+            .build(Instant::now());
+        let socket = UdpSocket::bind((address, 0)).await.context("failed to bind WebRTC socket")?;
+        let local = socket.local_addr()?;
+        let candidate = Candidate::host(local, "udp").with_context(|| format!("can't offer {local} to peers"))?;
+        rtc.add_local_candidate(candidate);
+
+        Ok(Self {
+            rtc,
+            socket,
+            local,
+            buf: vec![0; DATAGRAM],
+            deadline: Instant::now(),
+        })
+    }
+
+    /// Send what str0m has queued, up to its next event, or None once it waits for input
+    async fn poll(&mut self) -> anyhow::Result<Option<Event>> {
+        loop {
+            match self.rtc.poll_output()? {
+                Output::Transmit(transmit) => {
+                    self.socket.send_to(&transmit.contents, transmit.destination).await?;
+                }
+                Output::Event(event) => return Ok(Some(event)),
+                Output::Timeout(deadline) => {
+                    self.deadline = deadline;
+                    return Ok(None);
+                }
+            }
+        }
+    }
+
+    /// Hand str0m the next datagram, or tell it its deadline passed without one
+    async fn receive(&mut self) -> anyhow::Result<()> {
+        let received = tokio::time::timeout_at(self.deadline.into(), self.socket.recv_from(&mut self.buf)).await;
+        let input = match received {
+            Ok(received) => {
+                let (len, source) = received?;
+                // Whatever isn't STUN, DTLS or RTP isn't for str0m
+                let Ok(receive) = Receive::new(Protocol::Udp, source, self.local, &self.buf[..len]) else {
+                    return Ok(());
+                };
+                Input::Receive(Instant::now(), receive)
+            }
+            Err(_) => Input::Timeout(Instant::now()),
+        };
+        self.rtc.handle_input(input)?;
+        Ok(())
+    }
+
+    /// Write one frame of media `mid` carries as `codec`
+    fn write(&mut self, mid: Mid, codec: Codec, time: MediaTime, data: Vec<u8>) -> anyhow::Result<()> {
+        let Some(writer) = self.rtc.writer(mid) else {
+            return Ok(());
+        };
+        let Some(pt) = writer.payload_params().find(|p| p.spec().codec == codec).map(|p| p.pt()) else {
+            return Ok(());
+        };
+        writer.write(pt, Instant::now(), time, data)?;
+        Ok(())
+    }
+
+    /// Ask the peer for a keyframe on `mid`, for video it's sending us
+    fn request_keyframe(&mut self, mid: Mid) {
+        if let Some(mut writer) = self.rtc.writer(mid) {
+            let _ = writer.request_keyframe(None, KeyframeRequestKind::Pli);
+        }
+    }
+}
+
+/// A frame on its way to a player
+struct Sample {
+    kind: MediaKind,
+    /// Presentation timestamp in microseconds
+    timestamp: u64,
+    keyframe: bool,
+    data: Vec<u8>,
+}
+
+/// How a rendition we play is carried
+#[derive(Clone)]
+enum Playable {
+    /// Parameter sets in Annex B form, and the NAL length size (None if frames are already Annex B)
+    H264(Vec<u8>, Option<usize>),
+    Opus,
+}
+
+/// Play `broadcast` to a WHEP player until either goes away
+async fn play(mut peer: Peer, broadcast: BroadcastConsumer, mut stop: oneshot::Receiver<()>) -> anyhow::Result<()> {
+    let (tx, mut rx) = mpsc::channel(BUFFER);
+    let mut reading = JoinSet::new();
+    reading.spawn(read_broadcast(broadcast, tx));
+
+    // What the player receives video and audio on, from its offer
+    let mut mids = HashMap::new();
+    let mut connected = false;
+    // Video starts at a keyframe, so players don't have to wait for one to show anything
+    let mut keyframed = false;
+
+    loop {
+        while let Some(event) = peer.poll().await? {
+            match event {
+                Event::MediaAdded(added) if added.direction.is_sending() => {
+                    mids.entry(added.kind).or_insert(added.mid);
+                }
+                Event::Connected => connected = true,
+                Event::IceConnectionStateChange(IceConnectionState::Disconnected) => return Ok(()),
+                _ => {}
+            }
+        }
+
+        tokio::select! {
+            res = peer.receive() => res?,
+            sample = rx.recv() => {
+                let Some(sample) = sample else {
+                    // The broadcast ended, or we couldn't read it
+                    return match reading.join_next().await {
+                        Some(Ok(Err(err))) => Err(err),
+                        _ => Ok(()),
+                    };
+                };
+                let (kind, codec, rate) = match sample.kind {
+                    MediaKind::Video => (str0m::media::MediaKind::Video, Codec::H264, Frequency::NINETY_KHZ),
+                    MediaKind::Audio => (str0m::media::MediaKind::Audio, Codec::Opus, Frequency::FORTY_EIGHT_KHZ),
+                };
+                if sample.kind == MediaKind::Video {
+                    keyframed |= sample.keyframe;
+                    if !keyframed {
+                        continue;
+                    }
+                }
+                if let (true, Some(&mid)) = (connected, mids.get(&kind)) {
+                    let time = MediaTime::from_micros(sample.timestamp).rebase(rate);
+                    peer.write(mid, codec, time, sample.data)?;
+                }
+            }
+            _ = &mut stop => return Ok(()),
+        }
+    }
+}
+
+/// Send the frames of the renditions we'd play of `broadcast` to `tx`, picking them again as its catalog changes
+async fn read_broadcast(broadcast: BroadcastConsumer, tx: mpsc::Sender<Sample>) -> anyhow::Result<()> {
+    let mut catalog = broadcast.subscribe_track(&Track::new(CATALOG_TRACK));
+    let mut readers = JoinSet::new();
+
+    while let Some(mut group) = catalog.next_group().await? {
+        let Some(frame) = group.read_frame().await? else {
+            continue;
+        };
+        let Some(renditions) = catalog::renditions(&frame) else {
+            tracing::warn!("invalid catalog, keeping the current renditions");
+            continue;
+        };
+
+        // Start over with the new renditions
+        readers.abort_all();
+        for (rendition, playable) in select(&renditions) {
+            tracing::debug!(track = %rendition.track, "playing rendition over WebRTC");
+            let track = broadcast.subscribe_track(&Track::new(&rendition.track));
+            readers.spawn(read_track(track, rendition.kind, playable, tx.clone()));
+        }
+    }
+
+    Ok(())
+}
+
+/// The renditions a WebRTC player gets: the tallest H.264 video, and the first Opus audio
+fn select(renditions: &[Rendition]) -> Vec<(Rendition, Playable)> {
+    let height = |r: &Rendition| r.config.get("codedHeight").and_then(Value::as_u64).unwrap_or(0);
+    let video = renditions
+        .iter()
+        .filter(|r| r.kind == MediaKind::Video)
+        .filter_map(|r| match mpegts::Codec::from_rendition(r) {
+            Ok(mpegts::Codec::H264(params, size)) => Some((r.clone(), Playable::H264(params, size))),
+            _ => None,
+        })
+        .max_by_key(|(r, _)| height(r));
+    let audio = renditions
+        .iter()
+        .find(|r| r.kind == MediaKind::Audio && r.config.get("codec").and_then(Value::as_str) == Some("opus"))
+        .map(|r| (r.clone(), Playable::Opus));
+
+    video.into_iter().chain(audio).collect()
+}
+
+/// Forward the frames of one track to the player, as WebRTC carries them
+async fn read_track(
+    mut track: TrackConsumer,
+    kind: MediaKind,
+    playable: Playable,
+    tx: mpsc::Sender<Sample>,
+) -> anyhow::Result<()> {
+    while let Some(mut group) = track.next_group().await? {
+        let mut keyframe = true;
+        while let Some(frame) = group.read_frame().await? {
+            let Some(frame) = MediaFrame::decode(&frame) else {
+                continue;
+            };
+            let data = match &playable {
+                Playable::H264(params, size) => {
+                    let mut data = Vec::with_capacity(params.len() + frame.payload.len() + 16);
+                    mpegts::annex_b(&mut data, params, *size, &frame.payload, keyframe);
+                    data
+                }
+                Playable::Opus => frame.payload.to_vec(),
+            };
+            tx.send(Sample { kind, timestamp: frame.timestamp, keyframe, data }).await?;
+            keyframe = false;
+        }
+    }
+
+    Ok(())
+}
+
+/// Publish a WHIP encoder's feed to `origin` at the claimed path, until it stops sending
+async fn ingest(
+    mut peer: Peer,
+    origin: OriginProducer,
+    claim: PathClaim,
+    mut stop: oneshot::Receiver<()>,
+) -> anyhow::Result<()> {
+    let broadcast = Broadcast::produce();
+    origin.publish_broadcast(claim.path(), broadcast.consumer);
+    let mut feed = Feed::new(broadcast.producer);
+
+    let res = async {
+        loop {
+            while let Some(event) = peer.poll().await? {
+                match event {
+                    Event::MediaData(data) if feed.write(&data) => peer.request_keyframe(data.mid),
+                    Event::IceConnectionStateChange(IceConnectionState::Disconnected) => return Ok(()),
+                    _ => {}
+                }
+            }
+
+            tokio::select! {
+                res = peer.receive() => res?,
+                // Nobody serves tracks we didn't publish
+                Some(track) = feed.broadcast.requested_track() => track.abort(moq_lite::Error::NotFound),
+                _ = &mut stop => return Ok(()),
+            }
+        }
+    }
+    .await;
+
+    feed.close();
+    res
+}
+
+/// The relay-side tracks of one WHIP feed, added to its catalog as their first frames arrive
+struct Feed {
+    broadcast: BroadcastProducer,
+    catalog: Value,
+    catalog_track: TrackProducer,
+    video: Option<Ingested>,
+    audio: Option<Ingested>,
+    started: Instant,
+}
+
+/// One track of a WHIP feed
+struct Ingested {
+    track: TrackProducer,
+    group: Option<GroupProducer>,
+    /// The RTP time of the first frame, in microseconds, which timestamps are counted from
+    first: u64,
+    /// When the first frame arrived, after the feed started
+    offset: u64,
+}
+
+impl Ingested {
+    fn timestamp(&self, data: &MediaData) -> u64 {
+        self.offset + rtp_micros(data).saturating_sub(self.first)
+    }
+}
+
+impl Feed {
+    fn new(mut broadcast: BroadcastProducer) -> Self {
+        let catalog_track = broadcast.create_track(Track::new(CATALOG_TRACK));
+        Self {
+            broadcast,
+            catalog: json!({}),
+            catalog_track,
+            video: None,
+            audio: None,
+            started: Instant::now(),
+        }
+    }
+
+    /// Republish one frame, returning whether the encoder has to send a keyframe first
+    fn write(&mut self, data: &MediaData) -> bool {
+        match data.params.spec().codec {
+            Codec::H264 => self.write_video(data),
+            Codec::Opus => {
+                self.write_audio(data);
+                false
+            }
+            _ => false,
+        }
+    }
+
+    fn write_video(&mut self, data: &MediaData) -> bool {
+        if self.video.is_none() {
+            // The catalog needs the codec of the first SPS, which comes with a keyframe
+            let Some(codec) = data.is_keyframe().then(|| avc_codec(&data.data)).flatten() else {
+                return true;
+            };
+            self.video = Some(self.add("video", json!({ "codec": codec }), data));
+        }
+        let Some(video) = &mut self.video else {
+            return true;
+        };
+
+        // Every group starts with a keyframe, so drop anything until the next one after a gap
+        if data.is_keyframe() || !data.contiguous {
+            if let Some(group) = video.group.take() {
+                group.close();
+            }
+        }
+        if data.is_keyframe() {
+            video.group = Some(video.track.append_group());
+        }
+        let timestamp = video.timestamp(data);
+        let Some(group) = &mut video.group else {
+            return true;
+        };
+        group.write_frame(MediaFrame { timestamp, payload: Bytes::copy_from_slice(&data.data) }.encode());
+        false
+    }
+
+    fn write_audio(&mut self, data: &MediaData) {
+        if self.audio.is_none() {
+            let channels = data.params.spec().channels.unwrap_or(2);
+            let config = json!({ "codec": "opus", "sampleRate": 48_000, "numberOfChannels": channels });
+            self.audio = Some(self.add("audio", config, data));
+        }
+        let Some(audio) = &mut self.audio else {
+            return;
+        };
+
+        let timestamp = audio.timestamp(data);
+        let mut group = audio.track.append_group();
+        group.write_frame(MediaFrame { timestamp, payload: Bytes::copy_from_slice(&data.data) }.encode());
+        group.close();
+    }
+
+    /// Add a track for the first frame of `section`, and publish the catalog listing it
+    fn add(&mut self, section: &str, config: Value, first: &MediaData) -> Ingested {
+        self.catalog[section]["renditions"][section] = config;
+        self.catalog_track.write_frame(Value::to_string(&self.catalog));
+        tracing::info!(track = section, "ingesting WHIP track");
+
+        Ingested {
+            track: self.broadcast.create_track(Track::new(section)),
+            group: None,
+            first: rtp_micros(first),
+            offset: first.network_time.saturating_duration_since(self.started).as_micros() as u64,
+        }
+    }
+
+    fn close(mut self) {
+        for ingested in [self.video.take(), self.audio.take()].into_iter().flatten() {
+            if let Some(group) = ingested.group {
+                group.close();
+            }
+            ingested.track.close();
+        }
+        self.catalog_track.close();
+        self.broadcast.close();
+    }
+}
+
+fn rtp_micros(data: &MediaData) -> u64 {
+    data.time.rebase(Frequency::MICROS).numer()
+}
+
+/// The WebCodecs codec string of the first SPS in an Annex B access unit, with the parameter sets in band
+fn avc_codec(data: &[u8]) -> Option<String> {
+    let mut rest = data;
+    while let Some(start) = rest.windows(3).position(|w| w == [0, 0, 1]) {
+        rest = &rest[start + 3..];
+        if let [header, profile, compatibility, level, ..] = rest {
+            if header & 0x1f == 7 {
+                return Some(format!("avc3.{profile:02x}{compatibility:02x}{level:02x}"));
+            }
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+    use std::time::Duration;
+
+    use moq_lite::Origin;
+    use str0m::media::Direction;
+
+    use super::*;
+    use crate::paths::{Collision, PathClaims};
+
+    /// An IDR access unit with its parameter sets, as an encoder sends it
+    const KEYFRAME: &[&[u8]] = &[
+        &[0, 0, 0, 1, 0x67, 0x42, 0xe0, 0x1f, 0xda, 0x01, 0x40, 0x16, 0xe8, 0x06, 0xd0, 0xa1, 0x35],
+        &[0, 0, 0, 1, 0x68, 0xce, 0x06, 0xe2],
+        &[0, 0, 0, 1, 0x65, 0x88, 0x84, 0x00, 0x33, 0xff, 0xc0, 0x11, 0x22],
+    ];
+
+    /// A peer on loopback offering to send or receive video and audio
+    async fn offer(direction: Direction) -> anyhow::Result<(Peer, SdpOffer, str0m::change::SdpPendingOffer, [Mid; 2])> {
+        let crypto = Arc::new(str0m::crypto::from_feature_flags());
+        let mut peer = Peer::bind(Ipv4Addr::LOCALHOST.into(), crypto).await?;
+        let mut api = peer.rtc.sdp_api();
+        let video = api.add_media(str0m::media::MediaKind::Video, direction, None, None, None);
+        let audio = api.add_media(str0m::media::MediaKind::Audio, direction, None, None, None);
+        let (offer, pending) = api.apply().context("nothing to offer")?;
+        Ok((peer, offer, pending, [video, audio]))
+    }
+
+    /// Connect a peer offering `direction` to a gateway session, returning both
+    async fn connect(direction: Direction) -> anyhow::Result<(Peer, Peer, [Mid; 2])> {
+        let (mut client, offer, pending, mids) = offer(direction).await?;
+        let crypto = Arc::new(str0m::crypto::from_feature_flags());
+        let (session, answer) = Peer::accept(offer, Ipv4Addr::LOCALHOST.into(), crypto).await?;
+        client.rtc.sdp_api().accept_answer(pending, answer)?;
+        Ok((client, session, mids))
+    }
+
+    /// Send a keyframe and an Opus packet every 20ms, as a WHIP encoder would
+    async fn encode(mut client: Peer, [video, audio]: [Mid; 2]) -> anyhow::Result<()> {
+        let mut ticks = tokio::time::interval(Duration::from_millis(20));
+        let (mut connected, started) = (false, Instant::now());
+        loop {
+            while let Some(event) = client.poll().await? {
+                connected |= matches!(event, Event::Connected);
+            }
+            tokio::select! {
+                res = client.receive() => res?,
+                _ = ticks.tick(), if connected => {
+                    let time = MediaTime::from_micros(started.elapsed().as_micros() as u64);
+                    client.write(video, Codec::H264, time.rebase(Frequency::NINETY_KHZ), KEYFRAME.concat())?;
+                    client.write(audio, Codec::Opus, time.rebase(Frequency::FORTY_EIGHT_KHZ), vec![0xfc, 0xff, 0xfe])?;
+                }
+            }
+        }
+    }
+
+    /// Receive from a WHEP session until both video and audio arrive, returning the first video
+    async fn watch(mut client: Peer) -> anyhow::Result<Vec<u8>> {
+        let (mut video, mut audio) = (None, false);
+        loop {
+            while let Some(event) = client.poll().await? {
+                if let Event::MediaData(data) = event {
+                    match data.params.spec().codec {
+                        Codec::H264 => video = video.or(Some(data.data.to_vec())),
+                        Codec::Opus => audio = true,
+                        _ => {}
+                    }
+                }
+            }
+            if let (Some(video), true) = (&video, audio) {
+                return Ok(video.clone());
+            }
+            client.receive().await?;
+        }
+    }
+
+    #[test]
+    fn reads_avc_codec() {
+        assert_eq!(avc_codec(&KEYFRAME.concat()).as_deref(), Some("avc3.42e01f"));
+        assert_eq!(avc_codec(&[0, 0, 1, 0x68, 0xce, 0x06, 0xe2]), None);
+        assert_eq!(avc_codec(&[0, 0, 1, 0x67, 0x42]), None);
+    }
+
+    #[test]
+    fn selects_playable_renditions() {
+        let rendition = |track: &str, kind, config| Rendition { track: track.to_string(), kind, config };
+        let renditions = [
+            rendition("low", MediaKind::Video, json!({ "codec": "avc3.42e01f", "codedHeight": 360 })),
+            rendition("high", MediaKind::Video, json!({ "codec": "avc3.64001f", "codedHeight": 720 })),
+            rendition("hevc", MediaKind::Video, json!({ "codec": "hvc1.1.6.L93.B0", "codedHeight": 1080 })),
+            rendition("aac", MediaKind::Audio, json!({ "codec": "mp4a.40.2" })),
+            rendition("opus", MediaKind::Audio, json!({ "codec": "opus" })),
+        ];
+        let tracks: Vec<_> = select(&renditions).into_iter().map(|(r, _)| r.track).collect();
+        assert_eq!(tracks, ["high", "opus"]);
+    }
+
+    #[tokio::test]
+    async fn republishes_whip_and_plays_whep() -> anyhow::Result<()> {
+        let (local, remote) = (Origin::produce(), Origin::produce());
+        let claims = PathClaims::new(Collision::Refuse, local.producer.clone(), remote.producer);
+        let claim = claims.claim("demo", "live/demo")?;
+
+        let (encoder, session, mids) = connect(Direction::SendOnly).await?;
+        let (_stop_ingest, stop) = oneshot::channel();
+        let ingesting = tokio::spawn(ingest(session, local.producer.clone(), claim, stop));
+        let encoding = tokio::spawn(encode(encoder, mids));
+
+        let published = tokio::time::timeout(Duration::from_secs(10), async {
+            let broadcast = loop {
+                match local.consumer.consume_broadcast("live/demo") {
+                    Some(broadcast) => break broadcast,
+                    None => tokio::time::sleep(Duration::from_millis(10)).await,
+                }
+            };
+            let mut catalog = broadcast.subscribe_track(&Track::new(CATALOG_TRACK));
+            loop {
+                let mut group = catalog.next_group().await?.context("catalog ended")?;
+                let frame = group.read_frame().await?.context("empty catalog")?;
+                let catalog: Value = serde_json::from_slice(&frame)?;
+                if catalog.get("audio").is_some() && catalog.get("video").is_some() {
+                    return anyhow::Ok((broadcast, catalog));
+                }
+            }
+        });
+        let (broadcast, catalog) = published.await??;
+        assert_eq!(catalog["video"]["renditions"]["video"]["codec"], "avc3.42e01f");
+        assert_eq!(catalog["audio"]["renditions"]["audio"]["codec"], "opus");
+
+        let mut video = broadcast.subscribe_track(&Track::new("video"));
+        let mut group = video.next_group().await?.context("video ended")?;
+        let frame = group.read_frame().await?.context("empty group")?;
+        let frame = MediaFrame::decode(&frame).context("not a media frame")?;
+        assert_eq!(avc_codec(&frame.payload).as_deref(), Some("avc3.42e01f"));
+
+        let (player, session, _) = connect(Direction::RecvOnly).await?;
+        let (_stop_play, stop) = oneshot::channel();
+        let playing = tokio::spawn(play(session, broadcast, stop));
+        let played = tokio::time::timeout(Duration::from_secs(10), watch(player)).await??;
+        assert_eq!(avc_codec(&played).as_deref(), Some("avc3.42e01f"));
+
+        encoding.abort();
+        playing.abort();
+        ingesting.abort();
+        Ok(())
+    }
+}
@@ -0,0 +1,133 @@
+//! Bridging whole namespaces
+//!
+//! Conference rooms create a broadcast per participant as they join, which the registry
+//! can't enumerate. With `--bridge-prefix earthseed.live/*`, every broadcast CloudFlare
+//! announces under `earthseed.live/` is bridged as it appears, alongside whatever the
+//! discovery lists: a broadcast at `earthseed.live/room1/alice` becomes stream
+//! `room1/alice`, subscribed at its full path. The filters, priorities and paths apply
+//! to them like to any other stream, and a stream the discovery also lists is only
+//! bridged once, from its registry entry. With `--namespace-encoding escape`, the stream
+//! IDs have their escapes undone.
+//!
+//! It takes the CF side announcing the broadcasts, which CloudFlare itself doesn't yet
+//! (see [announce](crate::announce)): until it does, only broadcasts something else
+//! subscribed to turn up, so prefixes are for CF-compatible relays that announce.
+
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+
+use moq_lite::{OriginConsumer, Path};
+use tokio::sync::watch;
+use tokio::task::AbortHandle;
+
+use crate::discovery::{ChangeFuture, ListFuture, StreamDiscovery};
+use crate::namespace::NamespaceEncoding;
+use crate::registry::StreamInfo;
+
+/// Adds the broadcasts announced under some prefixes to another discovery's list
+pub struct PrefixDiscovery {
+    inner: Arc<dyn StreamDiscovery>,
+    /// The broadcasts announced right now: stream ID to CF namespace
+    announced: Mutex<watch::Receiver<BTreeMap<String, String>>>,
+    task: AbortHandle,
+}
+
+impl PrefixDiscovery {
+    /// Follow `origin`'s announcements under `prefixes`, if there are any
+    pub fn wrap(
+        inner: Arc<dyn StreamDiscovery>,
+        origin: &OriginConsumer,
+        prefixes: &[String],
+        encoding: NamespaceEncoding,
+    ) -> Arc<dyn StreamDiscovery> {
+        // `earthseed.live/*` and `earthseed.live/` are the same prefix
+        let prefixes: Vec<String> = prefixes.iter().map(|prefix| prefix.trim_end_matches(['*', '/']).to_string()).collect();
+        let watched: Vec<_> = prefixes.iter().map(|prefix| Path::new(prefix)).collect();
+        let Some(announced) = (!prefixes.is_empty()).then(|| origin.consume_only(&watched)).flatten() else {
+            return inner;
+        };
+
+        let (sender, receiver) = watch::channel(BTreeMap::new());
+        let task = tokio::spawn(follow(announced, prefixes, encoding, sender));
+        Arc::new(Self {
+            inner,
+            announced: Mutex::new(receiver),
+            task: task.abort_handle(),
+        })
+    }
+}
+
+impl Drop for PrefixDiscovery {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+impl StreamDiscovery for PrefixDiscovery {
+    fn list(&self) -> ListFuture<'_> {
+        Box::pin(async move {
+            let mut streams = self.inner.list().await?;
+            let announced = self.announced.lock().unwrap().borrow_and_update().clone();
+            for (stream_id, namespace) in announced {
+                if streams.iter().all(|s| s.stream_id != stream_id) {
+                    streams.push(StreamInfo {
+                        cf_namespace: Some(namespace),
+                        ..StreamInfo::new(stream_id)
+                    });
+                }
+            }
+            Ok(streams)
+        })
+    }
+
+    fn next_change(&self) -> ChangeFuture<'_> {
+        // A clone has seen what the last list did
+        let mut announced = self.announced.lock().unwrap().clone();
+        Box::pin(async move {
+            tokio::select! {
+                _ = self.inner.next_change() => {}
+                Ok(()) = announced.changed() => {}
+            }
+        })
+    }
+}
+
+/// Keep `announced` up to date with the broadcasts under `prefixes`
+async fn follow(
+    mut origin: OriginConsumer,
+    prefixes: Vec<String>,
+    encoding: NamespaceEncoding,
+    announced: watch::Sender<BTreeMap<String, String>>,
+) {
+    tracing::info!(?prefixes, "bridging every broadcast announced under the prefixes");
+    while let Some((path, broadcast)) = origin.announced().await {
+        let namespace = path.as_str();
+        let Some(rest) = prefixes.iter().find_map(|prefix| under(namespace, prefix)) else {
+            continue;
+        };
+        let stream_id = encoding.stream_id(rest);
+
+        match broadcast {
+            Some(_) => {
+                tracing::debug!(stream_id, namespace, "broadcast announced under a bridged prefix");
+                announced.send_modify(|announced| {
+                    announced.insert(stream_id, namespace.to_string());
+                });
+            }
+            None => {
+                announced.send_modify(|announced| {
+                    announced.remove(&stream_id);
+                });
+            }
+        }
+    }
+}
+
+/// The rest of `namespace` after `prefix`, if it's under it
+fn under<'a>(namespace: &'a str, prefix: &str) -> Option<&'a str> {
+    let rest = match prefix {
+        "" => namespace,
+        _ => namespace.strip_prefix(prefix)?.strip_prefix('/')?,
+    };
+    (!rest.is_empty()).then_some(rest)
+}
@@ -0,0 +1,53 @@
+//! Bridging a synthetic broadcast from a mock CloudFlare, which never announces it, to a mock relay
+
+use std::time::Duration;
+
+use anyhow::Context;
+use clap::Parser;
+use cloudflare_adapter_core::testing::{MockServer, SyntheticOptions};
+use cloudflare_adapter_core::{Adapter, AdapterConfig};
+use moq_lite::Track;
+
+/// Long enough for a bridge to announce and start forwarding on a slow CI runner
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+#[tokio::test]
+async fn bridges_synthetic_broadcast() -> anyhow::Result<()> {
+    let cloudflare = MockServer::start_cloudflare().await?;
+    let relay = MockServer::start().await?;
+    let pattern = SyntheticOptions::default();
+    let _live = cloudflare.publish("earthseed.live/demo", pattern.clone());
+
+    let (cloudflare_url, relay_url) = (cloudflare.url()?.to_string(), relay.url()?.to_string());
+    let config = AdapterConfig::parse_from([
+        "cloudflare-adapter",
+        "--cloudflare-url",
+        &cloudflare_url,
+        "--relay-url",
+        &relay_url,
+        "--discovery",
+        "static",
+        "--static-stream",
+        "demo",
+    ]);
+    let adapter = Adapter::builder(config).spawn();
+
+    // Only there once the bridge called announce_remote and subscribed
+    let bridged = tokio::time::timeout(TIMEOUT, relay.announced("demo")).await.context("demo was never bridged")?;
+    let mut track = bridged.subscribe_track(&Track::new(&pattern.track));
+
+    // Groups arrive whole and unchanged, however far into one the bridge started
+    for _ in 0..2 {
+        let group = tokio::time::timeout(TIMEOUT, track.next_group()).await.context("no group arrived")?;
+        let mut group = group?.context("track ended")?;
+        let sequence = group.info.sequence;
+        for index in 0..pattern.frames_per_group {
+            let frame = tokio::time::timeout(TIMEOUT, group.read_frame()).await.context("no frame arrived")?;
+            let frame = frame?.with_context(|| format!("group {sequence} ended after {index} frames"))?;
+            pattern.check(sequence, index, &frame)?;
+        }
+    }
+
+    adapter.shutdown().await?;
+    Ok(())
+}
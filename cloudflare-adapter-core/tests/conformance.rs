@@ -0,0 +1,264 @@
+//! Draft 14 messages against moq-lite's codecs
+//!
+//! These are written out by hand from the wire formats of draft-ietf-moq-transport-14,
+//! field by field, rather than recorded from a session, so that neither side of the
+//! check is moq-lite's own encoder: the control messages we exchange with CF, and the
+//! subgroup streams its objects arrive on. What we send carries what moq-lite puts in it
+//! (its versions, its setup parameters); what CF sends is any valid encoding, so the
+//! checks catch a codec that only understands itself.
+
+use std::path::PathBuf;
+
+use cloudflare_adapter_core::conformance::{self, Captured, Direction, Stream};
+
+/// CLIENT_SETUP, as we send it
+const CLIENT_SETUP: &[&str] = &[
+    "20",               // type
+    "0030",             // length
+    "03",               // number of supported versions
+    "c0000000ff0dad02", // moq-lite-02, as an 8-byte varint
+    "c0000000ff0dad01", // moq-lite-01
+    "c0000000ff00000e", // draft-14
+    "02",               // number of parameters
+    "02",               // MAX_REQUEST_ID
+    "c0000000ffffffff", // u32::MAX
+    "07",               // MOQT_IMPLEMENTATION
+    "0b",               // length
+    "6d6f712d6c6974652d7273", // "moq-lite-rs"
+];
+
+/// SERVER_SETUP, as CF answers it
+const SERVER_SETUP: &[&str] = &[
+    "21",               // type
+    "000c",             // length
+    "c0000000ff00000e", // selected version: draft-14
+    "01",               // number of parameters
+    "02",               // MAX_REQUEST_ID
+    "4064",             // 100, as a 2-byte varint
+];
+
+/// MAX_REQUEST_ID, as CF raises it
+const MAX_REQUEST_ID: &[&str] = &[
+    "15",   // type
+    "0002", // length
+    "40c8", // request ID: 200
+];
+
+/// GOAWAY, as CF sends it before going down
+const GOAWAY: &[&str] = &[
+    "10",   // type
+    "001a", // length
+    "19",   // new session URI length
+    "68747470733a2f2f72656c61792e6578616d706c652f6d6f71", // "https://relay.example/moq"
+];
+
+/// PUBLISH_NAMESPACE, as a relay announces a broadcast
+const PUBLISH_NAMESPACE: &[&str] = &[
+    "06",                           // type
+    "0017",                         // length
+    "01",                           // request ID
+    "02",                           // namespace tuple: two fields
+    "0e",                           // length
+    "6561727468736565642e6c697665", // "earthseed.live"
+    "04",                           // length
+    "64656d6f",                     // "demo"
+    "00",                           // number of parameters
+];
+
+/// PUBLISH_NAMESPACE_OK, as we accept it
+const PUBLISH_NAMESPACE_OK: &[&str] = &[
+    "07",   // type
+    "0001", // length
+    "01",   // request ID
+];
+
+/// SUBSCRIBE, as we send it for a track
+const SUBSCRIBE: &[&str] = &[
+    "03",                           // type
+    "0021",                         // length
+    "00",                           // request ID
+    "02",                           // namespace tuple: two fields
+    "0e",                           // length
+    "6561727468736565642e6c697665", // "earthseed.live"
+    "04",                           // length
+    "64656d6f",                     // "demo"
+    "05",                           // track name length
+    "766964656f",                   // "video"
+    "80",                           // subscriber priority
+    "02",                           // group order: descending
+    "01",                           // forward
+    "02",                           // filter type: largest object
+    "00",                           // number of parameters
+];
+
+/// SUBSCRIBE_OK, as CF accepts it with content already published
+const SUBSCRIBE_OK: &[&str] = &[
+    "04",   // type
+    "0009", // length
+    "00",   // request ID
+    "02",   // track alias
+    "00",   // expires: never
+    "02",   // group order: descending
+    "01",   // content exists
+    "44d2", // largest location: group 1234
+    "05",   // largest location: object 5
+    "00",   // number of parameters
+];
+
+/// SUBSCRIBE_ERROR, as CF turns down a track it doesn't have
+const SUBSCRIBE_ERROR: &[&str] = &[
+    "05",                             // type
+    "0012",                           // length
+    "02",                             // request ID
+    "04",                             // error code: TRACK_DOES_NOT_EXIST
+    "0f",                             // reason phrase length
+    "747261636b206e6f7420666f756e64", // "track not found"
+];
+
+/// UNSUBSCRIBE, as we send it when a bridge stops
+const UNSUBSCRIBE: &[&str] = &[
+    "0a",   // type
+    "0001", // length
+    "00",   // request ID
+];
+
+/// PUBLISH_DONE, as CF ends a subscription when the broadcast does
+const PUBLISH_DONE: &[&str] = &[
+    "0b",                     // type
+    "000f",                   // length
+    "00",                     // request ID
+    "02",                     // status code: TRACK_ENDED
+    "0c",                     // stream count
+    "0b",                     // reason phrase length
+    "747261636b20656e646564", // "track ended"
+];
+
+/// A subgroup stream ended by an END_OF_GROUP object
+const SUBGROUP: &[&str] = &[
+    "10",                 // type: subgroup ID 0, no extensions, no end of group
+    "02",                 // track alias
+    "44d2",               // group ID: 1234
+    "80",                 // publisher priority
+    "00",                 // object ID delta
+    "09",                 // payload length
+    "6b65796672616d6521", // "keyframe!"
+    "00",                 // object ID delta
+    "05",                 // payload length
+    "68656c6c6f",         // "hello"
+    "00",                 // object ID delta
+    "00",                 // payload length: none, so a status follows
+    "03",                 // status: END_OF_GROUP
+];
+
+/// A subgroup stream with extension headers that ends with the group
+const SUBGROUP_WITH_EXTENSIONS: &[&str] = &[
+    "1d",         // type: explicit subgroup ID, extensions, contains end of group
+    "02",         // track alias
+    "44d3",       // group ID: 1235
+    "00",         // subgroup ID
+    "80",         // publisher priority
+    "00",         // object ID delta
+    "02",         // extension headers length
+    "3c00",       // type 0x3c, even so a varint value follows: 0
+    "05",         // payload length
+    "68656c6c6f", // "hello"
+    "00",         // object ID delta
+    "00",         // extension headers length
+    "00",         // payload length: none, so a status follows
+    "00",         // status: normal, an empty object
+];
+
+fn message(direction: Direction, fields: &[&str]) -> Captured {
+    Captured {
+        direction,
+        stream: Stream::Control,
+        hex: fields.concat(),
+    }
+}
+
+fn subgroup(direction: Direction, fields: &[&str]) -> Captured {
+    Captured {
+        stream: Stream::Subgroup,
+        ..message(direction, fields)
+    }
+}
+
+fn conforms(message: Captured) {
+    if let Err(problem) = conformance::check(&message) {
+        panic!("{}: {problem}", message.hex);
+    }
+}
+
+#[test]
+fn setup_conforms() {
+    conforms(message(Direction::Sent, CLIENT_SETUP));
+    conforms(message(Direction::Received, SERVER_SETUP));
+    conforms(message(Direction::Received, MAX_REQUEST_ID));
+    conforms(message(Direction::Received, GOAWAY));
+}
+
+#[test]
+fn announcing_conforms() {
+    conforms(message(Direction::Received, PUBLISH_NAMESPACE));
+    conforms(message(Direction::Sent, PUBLISH_NAMESPACE_OK));
+}
+
+#[test]
+fn subscribing_conforms() {
+    conforms(message(Direction::Sent, SUBSCRIBE));
+    conforms(message(Direction::Received, SUBSCRIBE_OK));
+    conforms(message(Direction::Received, SUBSCRIBE_ERROR));
+    conforms(message(Direction::Sent, UNSUBSCRIBE));
+    conforms(message(Direction::Received, PUBLISH_DONE));
+}
+
+#[test]
+fn objects_conform() {
+    conforms(subgroup(Direction::Received, SUBGROUP));
+    conforms(subgroup(Direction::Received, SUBGROUP_WITH_EXTENSIONS));
+}
+
+#[test]
+fn nonconforming_messages_fail() {
+    let fails = |message: Captured| assert!(conformance::check(&message).is_err(), "{} conforms", message.hex);
+
+    // A length one byte short of the payload
+    let mut short = SUBSCRIBE.to_vec();
+    short[1] = "0020";
+    fails(message(Direction::Sent, &short));
+    // A SUBSCRIBE with a DELIVERY_TIMEOUT parameter, which moq-lite would drop if it sent it
+    let mut timeout = SUBSCRIBE.to_vec();
+    timeout[1] = "0023";
+    timeout[14] = "010232";
+    fails(message(Direction::Sent, &timeout));
+    // A SUBSCRIBE_OK that expires, which moq-lite doesn't support
+    let mut expires = SUBSCRIBE_OK.to_vec();
+    expires[4] = "0a";
+    fails(message(Direction::Received, &expires));
+    // A payload shorter than its length says
+    let mut truncated = SUBGROUP.to_vec();
+    truncated.truncate(6);
+    fails(subgroup(Direction::Received, &truncated));
+    // An object that doesn't exist, a status moq-lite doesn't read
+    let mut missing = SUBGROUP.to_vec();
+    missing[12] = "01";
+    fails(subgroup(Direction::Received, &missing));
+}
+
+#[test]
+fn capture_file_conforms() -> anyhow::Result<()> {
+    let capture = PathBuf::from(env!("CARGO_TARGET_TMPDIR")).join("draft14.jsonl");
+    let mut lines = String::new();
+    for message in [
+        message(Direction::Sent, CLIENT_SETUP),
+        message(Direction::Received, SERVER_SETUP),
+        message(Direction::Sent, SUBSCRIBE),
+        message(Direction::Received, SUBSCRIBE_OK),
+        subgroup(Direction::Received, SUBGROUP),
+    ] {
+        lines.push_str(&serde_json::to_string(&message)?);
+        lines.push('\n');
+    }
+    std::fs::write(&capture, lines)?;
+    conformance::run(&[capture], None)
+}
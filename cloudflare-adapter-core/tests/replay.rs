@@ -0,0 +1,64 @@
+//! Replaying a recorded registry, with a stream that flaps, on a paused clock
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::Context;
+use clap::Parser;
+use cloudflare_adapter_core::events::AdapterEvent;
+use cloudflare_adapter_core::replay::{Entry, Replay};
+use cloudflare_adapter_core::testing::{MockServer, SyntheticOptions};
+use cloudflare_adapter_core::{Adapter, AdapterConfig, BridgeEnd, StreamInfo};
+
+/// Far more than the replay takes, in the paused clock's time
+const TIMEOUT: Duration = Duration::from_secs(600);
+
+/// Listed for a while with a short `ttl`, then dropped, twice over, once the sessions are up
+fn flapping() -> Vec<Entry> {
+    let listed = |at_ms| Entry {
+        at_ms,
+        streams: Some(vec![StreamInfo { ttl: Some(2), ..StreamInfo::new("demo".to_string()) }]),
+        error: None,
+    };
+    let dropped = |at_ms| Entry { at_ms, streams: Some(Vec::new()), error: None };
+    vec![dropped(0), listed(5_000), dropped(10_000), listed(20_000), dropped(25_000)]
+}
+
+#[tokio::test(start_paused = true)]
+async fn replays_a_flapping_stream() -> anyhow::Result<()> {
+    let cloudflare = MockServer::start_cloudflare().await?;
+    let relay = MockServer::start().await?;
+    let _live = cloudflare.publish("earthseed.live/demo", SyntheticOptions::default());
+
+    let (cloudflare_url, relay_url) = (cloudflare.url()?.to_string(), relay.url()?.to_string());
+    let config = AdapterConfig::parse_from([
+        "cloudflare-adapter",
+        "--cloudflare-url",
+        &cloudflare_url,
+        "--relay-url",
+        &relay_url,
+        "--discovery",
+        "static",
+        "--static-stream",
+        "demo",
+    ]);
+    // The replay stands in for the static list
+    let adapter = Adapter::builder(config).with_discovery(Arc::new(Replay::new(flapping())?)).spawn();
+    let mut events = adapter.events();
+
+    let mut seen = Vec::new();
+    while seen.len() < 4 {
+        let event = tokio::time::timeout(TIMEOUT, events.next()).await.context("the replay stalled")?;
+        match event.context("the adapter stopped")? {
+            AdapterEvent::BridgeStarted(context) => seen.push(format!("started {}", context.stream.stream_id)),
+            AdapterEvent::BridgeClosed(context, end) => seen.push(format!("{end:?} {}", context.stream.stream_id)),
+            AdapterEvent::BridgeFailed(context, err) => anyhow::bail!("{} failed: {err}", context.stream.stream_id),
+            _ => {}
+        }
+    }
+    let expired = format!("{:?} demo", BridgeEnd::Expired);
+    assert_eq!(seen, ["started demo", &expired, "started demo", &expired]);
+
+    adapter.shutdown().await?;
+    Ok(())
+}